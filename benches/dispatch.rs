@@ -0,0 +1,82 @@
+// Compares the byte interpreter (`VM::run`) against the pre-decoded
+// instruction cache (`VM::run_decoded`), with and without superinstructions,
+// on a tight counting loop. With the `jit` feature the JIT is measured too.
+//
+// Run with `cargo bench --bench dispatch [--features jit]`.
+use std::time::{Duration, Instant};
+
+use iridium::vm::VM;
+
+const ITERATIONS: u16 = 60_000;
+const RUNS: u32 = 20;
+
+// counts $0 up to `iterations` then halts
+fn counting_loop(iterations: u16) -> Vec<u8> {
+    let [hi, lo] = iterations.to_be_bytes();
+    vec![
+        0, 0, 0, 0, // load $0 #0
+        0, 1, hi, lo, // load $1 #iterations
+        0, 2, 0, 12, // load $2 #12
+        18, 0, 0, 0, // inc $0
+        10, 0, 1, 0, // neq $0 $1
+        15, 2, 0, 0, // jeq $2
+        5, 0, 0, 0, // hlt
+    ]
+}
+
+fn time(run: fn(&mut VM)) -> Duration {
+    let program = counting_loop(ITERATIONS);
+    let mut total = Duration::ZERO;
+    for _ in 0..RUNS {
+        let mut vm = VM::new();
+        vm.program = program.clone();
+        let start = Instant::now();
+        run(&mut vm);
+        total += start.elapsed();
+        assert_eq!(vm.registers[0], ITERATIONS as i32);
+    }
+    total / RUNS
+}
+
+fn main() {
+    let byte = time(|vm| vm.run());
+    let decoded = time(|vm| {
+        vm.superinstruction_flag = false;
+        vm.run_decoded()
+    });
+    let fused = time(|vm| vm.run_decoded());
+    let instructions = 3 + 3 * ITERATIONS as u64 + 1;
+    println!(
+        "byte interpreter: {:>10.3?} per run ({:.1} ns/instruction)",
+        byte,
+        byte.as_nanos() as f64 / instructions as f64
+    );
+    println!(
+        "decoded cache:    {:>10.3?} per run ({:.1} ns/instruction)",
+        decoded,
+        decoded.as_nanos() as f64 / instructions as f64
+    );
+    println!(
+        "superinstructions:{:>10.3?} per run ({:.1} ns/instruction)",
+        fused,
+        fused.as_nanos() as f64 / instructions as f64
+    );
+    #[cfg(feature = "jit")]
+    {
+        let jit = time(|vm| {
+            vm.jit_threshold = 100;
+            vm.run_jit()
+        });
+        println!(
+            "jit:              {:>10.3?} per run ({:.1} ns/instruction, {:.2}x)",
+            jit,
+            jit.as_nanos() as f64 / instructions as f64,
+            byte.as_secs_f64() / jit.as_secs_f64()
+        );
+    }
+    println!(
+        "speed-up:         {:.2}x decoded, {:.2}x with superinstructions",
+        byte.as_secs_f64() / decoded.as_secs_f64(),
+        byte.as_secs_f64() / fused.as_secs_f64()
+    );
+}
@@ -0,0 +1,529 @@
+use std::fmt;
+
+use crate::assembler::macro_parsers::{MAX_EXPANDED_LINES, MAX_MACRO_DEPTH};
+use crate::instruction::OperandKind;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum AssemblerErrorKind {
+    // nothing at this point of the line parses as an instruction
+    ExpectedInstruction,
+    InvalidRegister {
+        register: u8,
+        count: usize,
+    },
+    // a register number too big to encode in an operand
+    RegisterOutOfRange {
+        number: String,
+    },
+    MissingOpcode,
+    // an operand that cannot be encoded in an instruction, such as a directive
+    InvalidOperand,
+    UndefinedLabel {
+        name: String,
+    },
+    DuplicateLabel {
+        name: String,
+    },
+    UnknownInstruction {
+        name: String,
+    },
+    // operands that do not match the ones the opcode takes
+    WrongOperands {
+        mnemonic: &'static str,
+        expected: &'static [OperandKind],
+        found: Vec<OperandKind>,
+    },
+    UnknownDirective {
+        name: String,
+    },
+    WrongDirectiveOperands {
+        directive: String,
+        expected: &'static str,
+    },
+    BadAlignment {
+        value: i64,
+    },
+    // a value too big for the data directive it is given to
+    ValueOutOfRange {
+        value: i64,
+        directive: String,
+    },
+    ImmediateOutOfRange {
+        value: i64,
+    },
+    // a name in an expression that is neither a label nor a constant
+    UndefinedName {
+        name: String,
+    },
+    DuplicateConstant {
+        name: String,
+    },
+    // constants whose values need each other
+    CircularConstant {
+        name: String,
+    },
+    // a constant that needs labels declared after it, used where the value
+    // must be known before the labels are, such as in a `.space`
+    ConstantNotYetKnown {
+        name: String,
+    },
+    Overflow,
+    NumberOutOfRange,
+    BadNumber {
+        literal: String,
+    },
+    // a character in single quotes that is not one character, e.g. `'AB'`
+    BadCharacter {
+        literal: String,
+    },
+    // nothing that can be a value where an expression needs one
+    MissingOperand,
+    // a binary operator at the end of its line
+    TrailingOperator {
+        op: String,
+    },
+    UnclosedParen,
+    DivisionByZero,
+    // more data than fits in the read-only data segment
+    DataTooLarge,
+    // more instructions than fit in the code segment
+    CodeTooLarge,
+    UnterminatedComment,
+    // a `.macro` the source ends inside
+    UnterminatedMacro {
+        name: String,
+    },
+    // an `.endm` outside of any macro
+    UnexpectedEndm,
+    NestedMacroDefinition,
+    DuplicateMacro {
+        name: String,
+    },
+    MacroArguments {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    UnknownParameter {
+        name: String,
+        macro_name: String,
+    },
+    // macros invoking macros more than MAX_MACRO_DEPTH deep, usually
+    // because one invokes itself
+    MacroTooDeep {
+        name: String,
+    },
+    // an invocation expanding to more than MAX_EXPANDED_LINES lines
+    ExpansionTooLarge {
+        name: String,
+    },
+    IncludeNotFound {
+        path: String,
+    },
+    IncludeUnreadable {
+        path: String,
+        reason: String,
+    },
+    // an `.include` of a file that is still being included
+    IncludeCycle {
+        path: String,
+    },
+    IncludeInMacro,
+    BadShift {
+        amount: i64,
+    },
+}
+
+impl fmt::Display for AssemblerErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssemblerErrorKind::ExpectedInstruction => write!(f, "expected an instruction"),
+            AssemblerErrorKind::InvalidRegister { register, count } => write!(
+                f,
+                "register ${} does not exist, the VM has {} registers",
+                register, count
+            ),
+            AssemblerErrorKind::RegisterOutOfRange { number } => {
+                write!(f, "register {} out of range", number)
+            }
+            AssemblerErrorKind::MissingOpcode => write!(f, "instruction has no opcode"),
+            AssemblerErrorKind::InvalidOperand => {
+                write!(f, "operand cannot be encoded in an instruction")
+            }
+            AssemblerErrorKind::UndefinedLabel { name } => write!(f, "no label named {}", name),
+            AssemblerErrorKind::DuplicateLabel { name } => {
+                write!(f, "label {} is already declared", name)
+            }
+            AssemblerErrorKind::UnknownInstruction { name } => {
+                write!(f, "no instruction named {}", name)
+            }
+            AssemblerErrorKind::UnknownDirective { name } => {
+                write!(f, "no directive named .{}", name)
+            }
+            AssemblerErrorKind::WrongDirectiveOperands {
+                directive,
+                expected,
+            } => write!(f, ".{} expects {}", directive, expected),
+            AssemblerErrorKind::BadAlignment { value } => {
+                write!(f, "alignment {} is not a power of two", value)
+            }
+            AssemblerErrorKind::ValueOutOfRange { value, directive } => {
+                write!(f, "{} does not fit in .{}", value, directive)
+            }
+            AssemblerErrorKind::ImmediateOutOfRange { value } => {
+                write!(f, "{} does not fit in a 16 bit immediate", value)
+            }
+            AssemblerErrorKind::UndefinedName { name } => {
+                write!(f, "no label or constant named {}", name)
+            }
+            AssemblerErrorKind::DuplicateConstant { name } => {
+                write!(f, "{} is already declared", name)
+            }
+            AssemblerErrorKind::CircularConstant { name } => {
+                write!(f, "{} depends on itself", name)
+            }
+            AssemblerErrorKind::ConstantNotYetKnown { name } => write!(
+                f,
+                "{} uses labels declared after it, so it is not known here",
+                name
+            ),
+            AssemblerErrorKind::Overflow => write!(f, "result does not fit in 32 bits"),
+            AssemblerErrorKind::NumberOutOfRange => write!(f, "number does not fit in 32 bits"),
+            AssemblerErrorKind::BadNumber { literal } => write!(f, "invalid number {}", literal),
+            AssemblerErrorKind::BadCharacter { literal } => {
+                write!(f, "invalid character {}", literal)
+            }
+            AssemblerErrorKind::MissingOperand => write!(f, "expected a value"),
+            AssemblerErrorKind::TrailingOperator { op } => {
+                write!(f, "{} has no value after it", op)
+            }
+            AssemblerErrorKind::UnclosedParen => write!(f, "( is never closed"),
+            AssemblerErrorKind::DivisionByZero => write!(f, "division by zero"),
+            AssemblerErrorKind::UnterminatedComment => write!(f, "comment is never closed"),
+            AssemblerErrorKind::UnterminatedMacro { name } => {
+                write!(f, ".macro {} is never closed with .endm", name)
+            }
+            AssemblerErrorKind::UnexpectedEndm => write!(f, ".endm without a .macro"),
+            AssemblerErrorKind::NestedMacroDefinition => {
+                write!(f, "macros cannot be defined inside other macros")
+            }
+            AssemblerErrorKind::DuplicateMacro { name } => {
+                write!(f, "macro {} is already defined", name)
+            }
+            AssemblerErrorKind::MacroArguments {
+                name,
+                expected,
+                found,
+            } => {
+                let plural = if *expected == 1 { "" } else { "s" };
+                write!(
+                    f,
+                    "macro {} expects {} argument{}, found {}",
+                    name, expected, plural, found
+                )
+            }
+            AssemblerErrorKind::UnknownParameter { name, macro_name } => {
+                write!(f, "macro {} has no parameter named {}", macro_name, name)
+            }
+            AssemblerErrorKind::MacroTooDeep { name } => write!(
+                f,
+                "expanding {} nests macros more than {} deep",
+                name, MAX_MACRO_DEPTH
+            ),
+            AssemblerErrorKind::ExpansionTooLarge { name } => write!(
+                f,
+                "expanding {} writes out more than {} lines",
+                name, MAX_EXPANDED_LINES
+            ),
+            AssemblerErrorKind::IncludeNotFound { path } => {
+                write!(f, "cannot find {} to include", path)
+            }
+            AssemblerErrorKind::IncludeUnreadable { path, reason } => {
+                write!(f, "cannot read {}: {}", path, reason)
+            }
+            AssemblerErrorKind::IncludeCycle { path } => write!(f, "{} includes itself", path),
+            AssemblerErrorKind::IncludeInMacro => {
+                write!(f, ".include cannot be used inside a macro")
+            }
+            AssemblerErrorKind::DataTooLarge => {
+                write!(f, "data does not fit in the read-only data segment")
+            }
+            AssemblerErrorKind::CodeTooLarge => {
+                write!(f, "instructions do not fit in the code segment")
+            }
+            AssemblerErrorKind::BadShift { amount } => {
+                write!(f, "cannot shift by {} bits", amount)
+            }
+            AssemblerErrorKind::WrongOperands {
+                mnemonic,
+                expected,
+                found,
+            } => {
+                // only the count is worth giving when the kinds are right
+                let found = match expected.first() {
+                    Some(kind)
+                        if expected
+                            .iter()
+                            .chain(found.iter())
+                            .all(|other| other == kind) =>
+                    {
+                        found.len().to_string()
+                    }
+                    _ => describe_operands(found),
+                };
+                write!(
+                    f,
+                    "{} expects {}, found {}",
+                    mnemonic,
+                    describe_operands(expected),
+                    found
+                )
+            }
+        }
+    }
+}
+
+// An error in one part of an instruction, such as an operand. `remaining` is
+// how many bytes of the source are left where that part starts; without it
+// the error is reported at the start of the instruction.
+#[derive(Debug, PartialEq, Clone)]
+pub struct OperandError {
+    pub kind: AssemblerErrorKind,
+    pub remaining: Option<usize>,
+}
+
+impl From<AssemblerErrorKind> for OperandError {
+    fn from(kind: AssemblerErrorKind) -> Self {
+        OperandError {
+            kind,
+            remaining: None,
+        }
+    }
+}
+
+// e.g. `3 registers`, or `a register and a value` when the kinds are mixed
+fn describe_operands(operands: &[OperandKind]) -> String {
+    let noun = |kind: &OperandKind| match kind {
+        OperandKind::Register => "register",
+        OperandKind::Immediate => "value",
+    };
+    match operands {
+        [] => "no operands".to_string(),
+        [first, ..] if operands.iter().all(|kind| kind == first) => {
+            let plural = if operands.len() == 1 { "" } else { "s" };
+            format!("{} {}{}", operands.len(), noun(first), plural)
+        }
+        _ => {
+            let described: Vec<String> = operands
+                .iter()
+                .map(|kind| format!("a {}", noun(kind)))
+                .collect();
+            let (last, rest) = described.split_last().unwrap();
+            format!("{} and {}", rest.join(", "), last)
+        }
+    }
+}
+
+// A problem found while assembling, with where it is in the source. `line`
+// and `column` count from 1.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AssemblerError {
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+    pub kind: AssemblerErrorKind,
+    pub source_line: String, // the whole line the error is on
+    // where the error came from, such as the macro invocations that
+    // expanded to the line it is on, innermost first
+    pub notes: Vec<ErrorNote>,
+}
+
+impl AssemblerError {
+    // An error at byte `offset` of `source`
+    pub fn new(
+        file: &str,
+        source: &str,
+        offset: usize,
+        kind: AssemblerErrorKind,
+    ) -> AssemblerError {
+        let (line, column) = line_and_column(source, offset);
+        AssemblerError {
+            file: file.to_string(),
+            line,
+            column,
+            kind,
+            source_line: line_at(source, offset),
+            notes: vec![],
+        }
+    }
+
+    pub fn message(&self) -> String {
+        self.kind.to_string()
+    }
+
+    // The source line with a caret under the column, e.g.
+    //   3 | load $40 #1
+    //     | ^
+    pub fn snippet(&self) -> String {
+        snippet(self.line, self.column, &self.source_line)
+    }
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: error: {}\n{}",
+            self.file,
+            self.line,
+            self.column,
+            self.kind,
+            self.snippet()
+        )?;
+        for note in &self.notes {
+            write!(f, "\n{}", note)?;
+        }
+        Ok(())
+    }
+}
+
+// More about where an error is, e.g.
+//   test.iasm:7:1: note: in expansion of macro swap
+#[derive(Debug, PartialEq, Clone)]
+pub struct ErrorNote {
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+    pub message: String,
+    pub source_line: String,
+}
+
+impl ErrorNote {
+    // A note about byte `offset` of `source`
+    pub fn new(file: &str, source: &str, offset: usize, message: String) -> ErrorNote {
+        let (line, column) = line_and_column(source, offset);
+        ErrorNote {
+            file: file.to_string(),
+            line,
+            column,
+            message,
+            source_line: line_at(source, offset),
+        }
+    }
+}
+
+impl fmt::Display for ErrorNote {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: note: {}\n{}",
+            self.file,
+            self.line,
+            self.column,
+            self.message,
+            snippet(self.line, self.column, &self.source_line)
+        )
+    }
+}
+
+// The whole line byte `offset` of `source` is on
+fn line_at(source: &str, offset: usize) -> String {
+    let line_start = source[..offset]
+        .rfind('\n')
+        .map_or(0, |newline| newline + 1);
+    let line_end = source[offset..]
+        .find('\n')
+        .map_or(source.len(), |newline| offset + newline);
+    source[line_start..line_end]
+        .trim_end_matches('\r')
+        .to_string()
+}
+
+// `source_line` with a caret under `column`
+fn snippet(line: u32, column: u32, source_line: &str) -> String {
+    let number = line.to_string();
+    // tabs are kept so the caret lines up however they are displayed
+    let indent: String = source_line
+        .chars()
+        .take(column as usize - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    format!(
+        "{} | {}\n{} | {}^",
+        number,
+        source_line,
+        " ".repeat(number.len()),
+        indent
+    )
+}
+
+// The line and column of byte `offset` of `source`, both counting from 1
+pub fn line_and_column(source: &str, offset: usize) -> (u32, u32) {
+    let before = &source[..offset];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    (
+        before.matches('\n').count() as u32 + 1,
+        before[line_start..].chars().count() as u32 + 1,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_position() {
+        let source = "hlt\n\tload $40 #1\r\nhlt";
+        let error = AssemblerError::new(
+            "test.iasm",
+            source,
+            10,
+            AssemblerErrorKind::InvalidRegister {
+                register: 40,
+                count: 32,
+            },
+        );
+        assert_eq!((error.line, error.column), (2, 7));
+        assert_eq!(error.source_line, "\tload $40 #1");
+        assert_eq!(
+            error.to_string(),
+            "test.iasm:2:7: error: register $40 does not exist, the VM has 32 registers\n\
+             2 | \tload $40 #1\n  | \t     ^"
+        );
+        assert_eq!(line_and_column(source, source.len()), (3, 4));
+    }
+
+    #[test]
+    fn test_wrong_operands() {
+        let message = |mnemonic, expected, found| {
+            AssemblerErrorKind::WrongOperands {
+                mnemonic,
+                expected,
+                found,
+            }
+            .to_string()
+        };
+        let three = &[OperandKind::Register; 3];
+        let load = &[OperandKind::Register, OperandKind::Immediate];
+        assert_eq!(
+            message("add", three, vec![OperandKind::Register]),
+            "add expects 3 registers, found 1"
+        );
+        assert_eq!(
+            message("jmp", &three[..1], vec![OperandKind::Immediate]),
+            "jmp expects 1 register, found 1 value"
+        );
+        assert_eq!(
+            message("hlt", &[], vec![OperandKind::Register; 2]),
+            "hlt expects no operands, found 2 registers"
+        );
+        assert_eq!(
+            message("load", load, vec![OperandKind::Register; 2]),
+            "load expects a register and a value, found 2 registers"
+        );
+        assert_eq!(
+            message("add", three, vec![]),
+            "add expects 3 registers, found 0"
+        );
+    }
+}
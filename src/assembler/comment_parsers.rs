@@ -0,0 +1,130 @@
+// Comments: `;` and `//` run to the end of the line, `/* ... */` can be
+// anywhere whitespace can and may span lines. Rather than every parser
+// skipping them, they are blanked out of the source before it is parsed,
+// byte for byte so positions do not move, and kept alongside the program so
+// tools such as a formatter can put them back.
+
+// A comment as written, delimiters included
+#[derive(Debug, PartialEq, Clone)]
+pub struct Comment {
+    pub text: String,
+    // how many bytes of the source are left from where the comment starts,
+    // like `Program::remaining`
+    pub remaining: usize,
+}
+
+impl Comment {
+    // False for a block comment the source ends inside
+    pub fn is_terminated(&self) -> bool {
+        !self.text.starts_with("/*") || (self.text.len() >= 4 && self.text.ends_with("*/"))
+    }
+}
+
+// `source` with every comment replaced by spaces, line breaks kept, and the
+// comments that were taken out
+pub fn strip_comments(source: &str) -> (String, Vec<Comment>) {
+    let bytes = source.as_bytes();
+    let mut stripped = Vec::with_capacity(bytes.len());
+    let mut comments = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &source[i..];
+        let len = if rest.starts_with(';') || rest.starts_with("//") {
+            rest.find('\n').unwrap_or(rest.len())
+        } else if let Some(body) = rest.strip_prefix("/*") {
+            body.find("*/").map_or(rest.len(), |end| end + 4)
+        } else {
+            // quotes are copied whole, so comment markers inside them stay,
+            // anything else a character at a time
+            let len =
+                quoted_len(rest).unwrap_or_else(|| rest.chars().next().map_or(1, char::len_utf8));
+            stripped.extend(&bytes[i..i + len]);
+            i += len;
+            continue;
+        };
+        comments.push(Comment {
+            text: rest[..len].to_string(),
+            remaining: rest.len(),
+        });
+        stripped.extend(
+            bytes[i..i + len]
+                .iter()
+                .map(|&b| if b == b'\n' { b'\n' } else { b' ' }),
+        );
+        i += len;
+    }
+    // only ASCII bytes were swapped in, for whole characters
+    (String::from_utf8(stripped).unwrap(), comments)
+}
+
+// The length of the string or character literal at the start of `input`, up
+// to the end of the line if it is not closed
+pub fn quoted_len(input: &str) -> Option<usize> {
+    let quote = input.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let mut escaped = false;
+    for (index, c) in input.char_indices().skip(1) {
+        match c {
+            '\n' => return Some(index),
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            _ if c == quote => return Some(index + 1),
+            _ => {}
+        }
+    }
+    Some(input.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_comments() {
+        let source =
+            "; header\nload $0 #1 // one\n/* two\nlines */ hlt ; done\n.asciiz \"a;b\" ; x\n";
+        let (stripped, comments) = strip_comments(source);
+        assert_eq!(stripped.len(), source.len());
+        assert_eq!(
+            stripped,
+            "        \nload $0 #1       \n      \n         hlt       \n.asciiz \"a;b\"    \n"
+        );
+        let texts: Vec<&str> = comments.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec!["; header", "// one", "/* two\nlines */", "; done", "; x"]
+        );
+        for comment in &comments {
+            let start = source.len() - comment.remaining;
+            assert_eq!(&source[start..start + comment.text.len()], comment.text);
+            assert!(comment.is_terminated());
+        }
+    }
+
+    #[test]
+    fn test_unterminated_block_comment() {
+        let (stripped, comments) = strip_comments("hlt /* é\n");
+        assert_eq!(stripped, "hlt      \n");
+        assert!(!comments[0].is_terminated());
+        let (_, comments) = strip_comments("/*/");
+        assert!(!comments[0].is_terminated());
+        let (stripped, _) = strip_comments("load $0 #';'\n");
+        assert_eq!(stripped, "load $0 #';'\n");
+    }
+
+    #[test]
+    fn test_non_ascii() {
+        let source = "load $0 #1 é ; ü
+zähler: hlt /* ß */
+";
+        let (stripped, comments) = strip_comments(source);
+        assert_eq!(stripped.len(), source.len());
+        assert_eq!(
+            stripped,
+            "load $0 #1 é     
+zähler: hlt         
+"
+        );
+        let texts: Vec<&str> = comments.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, vec!["; ü", "/* ß */"]);
+    }
+}
@@ -1,9 +1,23 @@
-use crate::assembler::instruction_parsers::AssemblerInstruction;
-use crate::assembler::label_parsers::label_declaration;
-use crate::assembler::operand_parsers::operand;
+// Data directives, which put initialised data in the read-only data segment
+// rather than instructions in the code segment:
+//   .byte #1 #2    one byte per value
+//   .half #1000    two bytes per value, aligned to 2
+//   .word @msg     four bytes per value, aligned to 4
+//   .space #16     that many zero bytes
+//   .align #8      pads to a multiple of the value, which must be a power of two
+//   .asciiz "hi"   the string's UTF-8 bytes and a terminating zero
+// `.equ NAME value` and its synonym `.define` declare constants instead, see
+// `expression_parsers`.
+// Values are big endian like the rest of the VM.
+use crate::assembler::instruction_parsers::{split_line, AssemblerInstruction};
+use crate::assembler::operand_parsers::{immediate, string_operand};
 use crate::assembler::Token;
-use nom::alpha1;
 use nom::types::CompleteStr;
+use nom::{alpha1, space, Err, ErrorKind, IResult};
+
+use super::assembler_errors::{AssemblerErrorKind, OperandError};
+use super::expression_parsers::{expression, immediate_value, Expression};
+use super::SymbolTable;
 
 named!(directive_declaration<CompleteStr, Token>,
   do_parse!(
@@ -15,36 +29,298 @@ named!(directive_declaration<CompleteStr, Token>,
   )
 );
 
-named!(directive_combined<CompleteStr, AssemblerInstruction>,
-    ws!(
-        do_parse!(
-            tag!(".") >>
-            name: directive_declaration >>
-            o1: opt!(operand) >>
-            o2: opt!(operand) >>
-            o3: opt!(operand) >>
-            (
-                AssemblerInstruction{
-                    opcode: None,
-                    directive: Some(name),
-                    label: None,
-                    operand1: o1,
-                    operand2: o2,
-                    operand3: o3,
+// A directive and its operands, which are a string, any number of values, or
+// for `.equ` and `.define` a name and a value. Like an instruction's, they
+// must be on the directive's line.
+pub fn directive(input: CompleteStr) -> IResult<CompleteStr, AssemblerInstruction> {
+    let (input, name) = directive_declaration(input)?;
+    let (line, rest) = split_line(input);
+    let (line, _) = opt!(line, space)?;
+    let (line, mut data) = match (&name, constant(line)) {
+        (Token::Directive { name }, Ok(parsed)) if name == "equ" || name == "define" => parsed,
+        _ => match string_operand(line) {
+            Ok((line, string)) => (line, vec![string]),
+            Err(_) => many0!(line, immediate)?,
+        },
+    };
+    if !line.trim().is_empty() {
+        return Err(Err::Error(error_position!(line, ErrorKind::Custom(0))));
+    }
+    for token in data.iter_mut() {
+        token.shift(rest.len());
+    }
+    Ok((
+        rest,
+        AssemblerInstruction {
+            opcode: None,
+            label: None,
+            directive: Some(name),
+            operand1: None,
+            operand2: None,
+            operand3: None,
+            extra_operands: vec![],
+            data,
+        },
+    ))
+}
+
+// The operands of `.equ NAME value`, where the value is an expression and
+// may be written with or without a `#`
+fn constant(input: CompleteStr) -> IResult<CompleteStr, Vec<Token>> {
+    let len = input
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(input.len());
+    let name = &input[..len];
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        return Err(Err::Error(error_position!(input, ErrorKind::Custom(0))));
+    }
+    let (input, _) = space(CompleteStr(&input[len..]))?;
+    let (input, _) = opt!(input, tag!("#"))?;
+    let (input, expr) = expression(input)?;
+    Ok((
+        input,
+        vec![
+            Token::ConstantDeclaration {
+                name: name.to_string(),
+            },
+            Token::Expression { expr },
+        ],
+    ))
+}
+
+impl AssemblerInstruction {
+    fn directive_name(&self) -> &str {
+        match &self.directive {
+            Some(Token::Directive { name }) => name,
+            _ => "",
+        }
+    }
+
+    // The name and value a `.equ` or `.define` declares
+    pub fn constant(&self) -> Option<(&str, &Expression)> {
+        match self.data.as_slice() {
+            [Token::ConstantDeclaration { name }, Token::Expression { expr }] => Some((name, expr)),
+            _ => None,
+        }
+    }
+
+    // The alignment a directive's data needs and how many bytes it takes,
+    // which is all labels need to know before the values are resolved.
+    // Values that decide the layout can only use constants and labels
+    // declared before them.
+    pub fn data_layout(&self, symbols: &SymbolTable) -> Result<(usize, usize), OperandError> {
+        let name = self.directive_name();
+        let expected = |expected| {
+            OperandError::from(AssemblerErrorKind::WrongDirectiveOperands {
+                directive: name.to_string(),
+                expected,
+            })
+        };
+        match (name, self.data.as_slice()) {
+            ("byte" | "half" | "word", values) => {
+                let width = data_width(name);
+                if values.is_empty() || !values.iter().all(is_value) {
+                    return Err(expected("at least one value"));
                 }
-            )
-        )
-    )
-);
+                Ok((width, width * values.len()))
+            }
+            ("space", [token]) if is_value(token) => {
+                let value = immediate_value(token, symbols)?;
+                match usize::try_from(value) {
+                    Ok(len) => Ok((1, len)),
+                    Err(_) => Err(token_error(
+                        token,
+                        AssemblerErrorKind::ValueOutOfRange {
+                            value,
+                            directive: name.to_string(),
+                        },
+                    )),
+                }
+            }
+            ("align", [token]) if is_value(token) => {
+                let value = immediate_value(token, symbols)?;
+                if value > 0 && (value as u32).is_power_of_two() {
+                    Ok((value as usize, 0))
+                } else {
+                    Err(token_error(
+                        token,
+                        AssemblerErrorKind::BadAlignment { value },
+                    ))
+                }
+            }
+            ("space" | "align", _) => Err(expected("a number")),
+            ("asciiz", [Token::StringOperand { value }]) => Ok((1, value.len() + 1)),
+            ("asciiz", _) => Err(expected("a string")),
+            ("equ" | "define", _) => Err(expected("a name and a value")),
+            _ => Err(AssemblerErrorKind::UnknownDirective {
+                name: name.to_string(),
+            }
+            .into()),
+        }
+    }
 
-// Will try to parse out any of the Directive forms
-named!(pub directive<CompleteStr, AssemblerInstruction>,
-    do_parse!(
-        ins: alt!(
-            directive_combined
-        ) >>
-        (
-            ins
-        )
+    // The bytes a directive assembles to, not counting the padding that
+    // aligns them. Only called once `data_layout` has accepted the directive.
+    pub fn data_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, OperandError> {
+        let name = self.directive_name();
+        let mut bytes = vec![];
+        match name {
+            "byte" | "half" | "word" => {
+                let width = data_width(name);
+                for token in &self.data {
+                    let value = immediate_value(token, symbols)?;
+                    // negative values are stored as two's complement
+                    let bits = 8 * width as u32;
+                    if value < -(1 << (bits - 1)) || value >= 1 << bits {
+                        return Err(token_error(
+                            token,
+                            AssemblerErrorKind::ValueOutOfRange {
+                                value,
+                                directive: name.to_string(),
+                            },
+                        ));
+                    }
+                    bytes.extend(&value.to_be_bytes()[8 - width..]);
+                }
+            }
+            "space" => bytes.resize(self.data_layout(symbols)?.1, 0),
+            "asciiz" => {
+                if let [Token::StringOperand { value }] = self.data.as_slice() {
+                    bytes.extend(value.as_bytes());
+                    bytes.push(0);
+                }
+            }
+            _ => {}
+        }
+        Ok(bytes)
+    }
+}
+
+// An error about an operand, at the operand when its position is known
+fn token_error(token: &Token, kind: AssemblerErrorKind) -> OperandError {
+    OperandError {
+        kind,
+        remaining: match token {
+            Token::Expression { expr } => Some(expr.remaining),
+            _ => None,
+        },
+    }
+}
+
+fn data_width(name: &str) -> usize {
+    match name {
+        "half" => 2,
+        "word" => 4,
+        _ => 1,
+    }
+}
+
+fn is_value(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::IntegerOperand { .. } | Token::LabelUsage { .. } | Token::Expression { .. }
     )
-);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> AssemblerInstruction {
+        let (rest, directive) = directive(CompleteStr(source)).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        directive
+    }
+
+    #[test]
+    fn test_parse_directive() {
+        let bytes = parse(".byte #1 #2 @end");
+        assert_eq!(
+            bytes.directive,
+            Some(Token::Directive {
+                name: "byte".to_string()
+            })
+        );
+        assert_eq!(bytes.data.len(), 3);
+        let string = parse(".asciiz \"hi\"");
+        assert_eq!(
+            string.data,
+            vec![Token::StringOperand {
+                value: "hi".to_string()
+            }]
+        );
+        assert!(directive(CompleteStr(".byte #1 $2")).is_err());
+        let (rest, _) = directive(CompleteStr(".word #1\n#2")).unwrap();
+        assert_eq!(rest, CompleteStr("\n#2"));
+    }
+
+    #[test]
+    fn test_parse_constant() {
+        let equ = parse(".equ BUF_SIZE #(4 * 8)");
+        let (name, expr) = equ.constant().unwrap();
+        assert_eq!(name, "BUF_SIZE");
+        assert_eq!(expr.evaluate(&SymbolTable::new()), Ok(32));
+        assert!(parse(".define LIMIT 10 - 1").constant().is_some());
+        assert_eq!(parse(".equ #1").constant(), None);
+    }
+
+    #[test]
+    fn test_data_layout() {
+        let symbols = SymbolTable::new();
+        let layout = |source| {
+            parse(source)
+                .data_layout(&symbols)
+                .map_err(|error| error.kind.to_string())
+        };
+        assert_eq!(layout(".byte #1 #2"), Ok((1, 2)));
+        assert_eq!(layout(".half #1 #2"), Ok((2, 4)));
+        assert_eq!(layout(".word #1"), Ok((4, 4)));
+        assert_eq!(layout(".space #10"), Ok((1, 10)));
+        assert_eq!(layout(".space #2 * 5"), Ok((1, 10)));
+        assert_eq!(layout(".align #8"), Ok((8, 0)));
+        assert_eq!(layout(".asciiz \"hi\""), Ok((1, 3)));
+        assert_eq!(
+            layout(".align #3"),
+            Err("alignment 3 is not a power of two".to_string())
+        );
+        assert_eq!(
+            layout(".word"),
+            Err(".word expects at least one value".to_string())
+        );
+        assert_eq!(
+            layout(".asciiz #1"),
+            Err(".asciiz expects a string".to_string())
+        );
+        assert_eq!(
+            layout(".equ #1"),
+            Err(".equ expects a name and a value".to_string())
+        );
+        assert_eq!(layout(".data"), Err("no directive named .data".to_string()));
+    }
+
+    #[test]
+    fn test_data_bytes() {
+        let symbols = SymbolTable::new();
+        let bytes = |source| {
+            parse(source)
+                .data_bytes(&symbols)
+                .map_err(|error| error.kind)
+        };
+        assert_eq!(bytes(".byte #1 #255"), Ok(vec![1, 255]));
+        assert_eq!(bytes(".byte #-1"), Ok(vec![255]));
+        assert_eq!(bytes(".half #258"), Ok(vec![1, 2]));
+        assert_eq!(bytes(".word #65536"), Ok(vec![0, 1, 0, 0]));
+        assert_eq!(bytes(".space #3"), Ok(vec![0, 0, 0]));
+        assert_eq!(bytes(".asciiz \"hi\""), Ok(vec![b'h', b'i', 0]));
+        assert_eq!(
+            bytes(".byte #256").unwrap_err().to_string(),
+            "256 does not fit in .byte"
+        );
+        assert_eq!(
+            bytes(".word @nowhere"),
+            Err(AssemblerErrorKind::UndefinedLabel {
+                name: "nowhere".to_string()
+            })
+        );
+    }
+}
@@ -0,0 +1,476 @@
+// Constant expressions, evaluated when assembling, e.g. `(BUF_SIZE * 4) + 1`
+// or `end - start`. Names stand for constants declared with `.equ` or
+// `.define` and for the addresses of labels. The operators, loosest binding
+// first, are
+//   |   ^   &   << >>   + -   * / %   and the prefixes - ~
+// and parentheses group. Values are 32 bits, signed or unsigned, and any
+// result outside that range is an error.
+//
+// Numbers are decimal, hex with `0x`, binary with `0b` or octal with `0o`,
+// and may have `_` between digits, e.g. `1_000_000` or `0xFF`. A character
+// in single quotes stands for its code point, e.g. `'A'` or `'\n'`.
+use nom::types::CompleteStr;
+use nom::IResult;
+
+use crate::assembler::assembler_errors::{AssemblerErrorKind, OperandError};
+use crate::assembler::comment_parsers::quoted_len;
+use crate::assembler::{SymbolTable, Token};
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    // how many bytes of the source are left from where this part of the
+    // expression starts, so errors can point at it
+    pub remaining: usize,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExpressionKind {
+    Number(i64),
+    // a word that starts like a number but is not one, e.g. `0b102`
+    BadNumber(String),
+    Name(String),
+    // a malformed expression, see `expression`
+    Invalid(AssemblerErrorKind),
+    Unary(UnaryOp, Box<Expression>),
+    Binary(BinaryOp, Box<Expression>, Box<Expression>),
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum BinaryOp {
+    Or,
+    Xor,
+    And,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+// The binary operators by precedence, loosest first
+const PRECEDENCE: &[&[(&str, BinaryOp)]] = &[
+    &[("|", BinaryOp::Or)],
+    &[("^", BinaryOp::Xor)],
+    &[("&", BinaryOp::And)],
+    &[("<<", BinaryOp::Shl), (">>", BinaryOp::Shr)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+    &[
+        ("*", BinaryOp::Mul),
+        ("/", BinaryOp::Div),
+        ("%", BinaryOp::Rem),
+    ],
+];
+
+const MIN_VALUE: i64 = i32::MIN as i64;
+const MAX_VALUE: i64 = u32::MAX as i64;
+
+impl Expression {
+    fn new(kind: ExpressionKind, remaining: usize) -> Expression {
+        Expression { kind, remaining }
+    }
+
+    fn error(&self, kind: AssemblerErrorKind) -> OperandError {
+        OperandError {
+            kind,
+            remaining: Some(self.remaining),
+        }
+    }
+
+    pub fn evaluate(&self, symbols: &SymbolTable) -> Result<i64, OperandError> {
+        let value = match &self.kind {
+            ExpressionKind::Number(value) => {
+                if !(MIN_VALUE..=MAX_VALUE).contains(value) {
+                    return Err(self.error(AssemblerErrorKind::NumberOutOfRange));
+                }
+                Some(*value)
+            }
+            ExpressionKind::BadNumber(literal) => {
+                return Err(self.error(AssemblerErrorKind::BadNumber {
+                    literal: literal.clone(),
+                }))
+            }
+            ExpressionKind::Invalid(kind) => return Err(self.error(kind.clone())),
+            ExpressionKind::Name(name) => Some(symbols.value(name).ok_or_else(|| {
+                self.error(AssemblerErrorKind::UndefinedName { name: name.clone() })
+            })?),
+            ExpressionKind::Unary(op, operand) => {
+                let value = operand.evaluate(symbols)?;
+                match op {
+                    UnaryOp::Negate => value.checked_neg(),
+                    // complemented as a signed 32 bit value, so ~0 is -1
+                    UnaryOp::Not => Some(!(value as i32) as i64),
+                }
+            }
+            ExpressionKind::Binary(op, left, right) => {
+                let a = left.evaluate(symbols)?;
+                let b = right.evaluate(symbols)?;
+                match op {
+                    BinaryOp::Div | BinaryOp::Rem if b == 0 => {
+                        return Err(right.error(AssemblerErrorKind::DivisionByZero))
+                    }
+                    BinaryOp::Shl | BinaryOp::Shr if !(0..32).contains(&b) => {
+                        return Err(right.error(AssemblerErrorKind::BadShift { amount: b }))
+                    }
+                    BinaryOp::Or => Some(a | b),
+                    BinaryOp::Xor => Some(a ^ b),
+                    BinaryOp::And => Some(a & b),
+                    BinaryOp::Shl => Some(a << b),
+                    BinaryOp::Shr => Some(a >> b),
+                    BinaryOp::Add => a.checked_add(b),
+                    BinaryOp::Sub => a.checked_sub(b),
+                    BinaryOp::Mul => a.checked_mul(b),
+                    BinaryOp::Div => a.checked_div(b),
+                    BinaryOp::Rem => a.checked_rem(b),
+                }
+            }
+        };
+        match value {
+            Some(value) if (MIN_VALUE..=MAX_VALUE).contains(&value) => Ok(value),
+            _ => Err(self.error(AssemblerErrorKind::Overflow)),
+        }
+    }
+
+    // Moves the positions along, for expressions parsed from the start of a
+    // slice that ends `by` bytes before the end of the source
+    pub fn shift(&mut self, by: usize) {
+        self.remaining += by;
+        match &mut self.kind {
+            ExpressionKind::Unary(_, operand) => operand.shift(by),
+            ExpressionKind::Binary(_, left, right) => {
+                left.shift(by);
+                right.shift(by);
+            }
+            ExpressionKind::Number(_)
+            | ExpressionKind::BadNumber(_)
+            | ExpressionKind::Name(_)
+            | ExpressionKind::Invalid(_) => {}
+        }
+    }
+}
+
+impl Token {
+    // See `Expression::shift`
+    pub fn shift(&mut self, by: usize) {
+        match self {
+            Token::Expression { expr } => expr.shift(by),
+            Token::RegisterOutOfRange { remaining, .. } => *remaining += by,
+            _ => {}
+        }
+    }
+}
+
+// The value of an operand that stands for a number
+pub fn immediate_value(token: &Token, symbols: &SymbolTable) -> Result<i64, OperandError> {
+    match token {
+        Token::IntegerOperand { value } => Ok(*value as i64),
+        // labels stand for their address
+        Token::LabelUsage { name } => match symbols.symbol_value(name) {
+            Some(address) => Ok(address as i64),
+            None => Err(AssemblerErrorKind::UndefinedLabel { name: name.clone() }.into()),
+        },
+        Token::Expression { expr } => expr.evaluate(symbols),
+        _ => Err(AssemblerErrorKind::InvalidOperand.into()),
+    }
+}
+
+// An expression at the start of `input`. One that is malformed still parses, to the end of its line, as an `Invalid` expression that
+// reports what is wrong with it, and where, when evaluated.
+pub fn expression(input: CompleteStr) -> IResult<CompleteStr, Expression> {
+    let mut parser = Parser {
+        input: &input,
+        pos: 0,
+    };
+    match parser.binary(0) {
+        Ok(expression) => Ok((CompleteStr(&input[parser.pos..]), expression)),
+        Err((kind, remaining)) => {
+            let line_end = input.find('\n').unwrap_or(input.len());
+            Ok((
+                CompleteStr(&input[line_end..]),
+                Expression::new(ExpressionKind::Invalid(kind), remaining),
+            ))
+        }
+    }
+}
+
+// What is wrong with a malformed expression, and how many bytes of the input
+// are left from where
+type ParseError = (AssemblerErrorKind, usize);
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn remaining(&self) -> usize {
+        self.input.len() - self.pos
+    }
+
+    // Spaces and tabs may separate the parts of an expression, but it ends
+    // with its line
+    fn skip_space(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start_matches([' ', '\t']).len();
+    }
+
+    fn eat(&mut self, text: &str) -> bool {
+        self.skip_space();
+        if self.rest().starts_with(text) {
+            self.pos += text.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn at_line_end(&mut self) -> bool {
+        self.skip_space();
+        matches!(self.rest().chars().next(), None | Some('\n') | Some('\r'))
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expression, ParseError> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        'operators: loop {
+            let before = self.pos;
+            for (text, op) in PRECEDENCE[level] {
+                // `<` and `>` alone are not operators, so `<<` cannot be
+                // mistaken for anything shorter
+                if self.eat(text) {
+                    let at = self.remaining() + text.len();
+                    if self.at_line_end() {
+                        let op = text.to_string();
+                        return Err((AssemblerErrorKind::TrailingOperator { op }, at));
+                    }
+                    let right = self.binary(level + 1)?;
+                    let remaining = left.remaining;
+                    left = Expression::new(
+                        ExpressionKind::Binary(*op, Box::new(left), Box::new(right)),
+                        remaining,
+                    );
+                    continue 'operators;
+                }
+            }
+            self.pos = before;
+            return Ok(left);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expression, ParseError> {
+        self.skip_space();
+        let remaining = self.remaining();
+        let op = if self.eat("-") {
+            UnaryOp::Negate
+        } else if self.eat("~") {
+            UnaryOp::Not
+        } else {
+            return self.primary();
+        };
+        let operand = self.unary()?;
+        Ok(Expression::new(
+            ExpressionKind::Unary(op, Box::new(operand)),
+            remaining,
+        ))
+    }
+
+    fn primary(&mut self) -> Result<Expression, ParseError> {
+        self.skip_space();
+        let remaining = self.remaining();
+        if self.eat("(") {
+            let inner = self.binary(0)?;
+            return if self.eat(")") {
+                Ok(inner)
+            } else {
+                Err((AssemblerErrorKind::UnclosedParen, remaining))
+            };
+        }
+        let rest = self.rest();
+        let first = match rest.chars().next() {
+            Some(first) => first,
+            None => return Err((AssemblerErrorKind::MissingOperand, remaining)),
+        };
+        if first == '\'' {
+            let (value, len) = char_literal(rest).ok_or_else(|| {
+                let len = quoted_len(rest).unwrap_or(rest.len());
+                let literal = rest[..len].to_string();
+                (AssemblerErrorKind::BadCharacter { literal }, remaining)
+            })?;
+            self.pos += len;
+            return Ok(Expression::new(
+                ExpressionKind::Number(value as i64),
+                remaining,
+            ));
+        }
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        let word = &rest[..len];
+        let kind = if first.is_ascii_digit() {
+            match parse_number(word) {
+                Some(value) => ExpressionKind::Number(value),
+                None => ExpressionKind::BadNumber(word.to_string()),
+            }
+        } else if first.is_ascii_alphabetic() || first == '_' {
+            ExpressionKind::Name(word.to_string())
+        } else {
+            return Err((AssemblerErrorKind::MissingOperand, remaining));
+        };
+        self.pos += len;
+        Ok(Expression::new(kind, remaining))
+    }
+}
+
+// The value of a number literal, None if `word` is not one. Values too big
+// for an i64 come back as i64::MAX, which evaluating reports as out of range.
+fn parse_number(word: &str) -> Option<i64> {
+    let word = word.to_ascii_lowercase();
+    let (radix, digits) = match word.get(..2) {
+        Some("0x") => (16, &word[2..]),
+        Some("0b") => (2, &word[2..]),
+        Some("0o") => (8, &word[2..]),
+        _ => (10, &word[..]),
+    };
+    // separators only go between digits, one at a time
+    if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+        return None;
+    }
+    let digits: String = digits.chars().filter(|c| *c != '_').collect();
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    Some(i64::from_str_radix(&digits, radix).unwrap_or(i64::MAX))
+}
+
+// A character in single quotes at the start of `input`, with the same escapes
+// as strings, and how many bytes it takes
+fn char_literal(input: &str) -> Option<(char, usize)> {
+    let mut chars = input.char_indices().skip(1);
+    let value = match chars.next()? {
+        (_, '\\') => match chars.next()?.1 {
+            'n' => '\n',
+            't' => '\t',
+            '0' => '\0',
+            '\\' => '\\',
+            '\'' => '\'',
+            _ => return None,
+        },
+        (_, '\'') | (_, '\n') => return None,
+        (_, c) => c,
+    };
+    match chars.next()? {
+        (index, '\'') => Some((value, index + 1)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{Symbol, SymbolType};
+
+    fn evaluate(source: &str, symbols: &SymbolTable) -> Result<i64, OperandError> {
+        let (rest, expression) = expression(CompleteStr(source)).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        expression.evaluate(symbols)
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new(
+            "BUF_SIZE".to_string(),
+            SymbolType::Constant,
+            16,
+        ));
+        symbols.add_symbol(Symbol::new("start".to_string(), SymbolType::Label, 4));
+        symbols.add_symbol(Symbol::new("end".to_string(), SymbolType::Label, 24));
+        let value = |source| evaluate(source, &symbols).unwrap();
+        assert_eq!(value("(BUF_SIZE * 4) + 1"), 65);
+        assert_eq!(value("end - start"), 20);
+        assert_eq!(value("1 + 2 * 3"), 7);
+        assert_eq!(value("1 << 4 | 1"), 17);
+        assert_eq!(value("-3 % 2"), -1);
+        assert_eq!(value("~0"), -1);
+        assert_eq!(value("240 & 12"), 0);
+        assert_eq!(value("4294967295"), 4294967295);
+        assert_eq!(value("0xFFFF_FFFF"), 4294967295);
+        assert_eq!(value("-0x80"), -128);
+        assert_eq!(value("'z' - 'a'"), 25);
+        assert_eq!(value("'\\''"), 39);
+    }
+
+    #[test]
+    fn test_literal_errors() {
+        let symbols = SymbolTable::new();
+        let error = |source| evaluate(source, &symbols).unwrap_err().kind.to_string();
+        assert_eq!(error("99999999999"), "number does not fit in 32 bits");
+        assert_eq!(
+            error("1 + 99999999999999999999999"),
+            "number does not fit in 32 bits"
+        );
+        assert_eq!(error("0b102"), "invalid number 0b102");
+        assert_eq!(error("0x"), "invalid number 0x");
+        assert_eq!(error("12ab"), "invalid number 12ab");
+        assert_eq!(error("1 + 'ab'"), "invalid character 'ab'");
+        assert_eq!(error("'a"), "invalid character 'a");
+    }
+
+    #[test]
+    fn test_errors() {
+        let symbols = SymbolTable::new();
+        let error = |source| evaluate(source, &symbols).unwrap_err();
+        let source = "1 + (65536 * 65536)";
+        assert_eq!(
+            error(source),
+            OperandError {
+                kind: AssemblerErrorKind::Overflow,
+                remaining: Some(source.len() - 5),
+            }
+        );
+        assert_eq!(error("7 / (2 - 2)").remaining, Some(6));
+        assert_eq!(error("1 << 32").kind.to_string(), "cannot shift by 32 bits");
+        assert_eq!(
+            error("2 * missing").kind.to_string(),
+            "no label or constant named missing"
+        );
+        let (rest, _) = expression(CompleteStr("1 + 2 #3")).unwrap();
+        assert_eq!(rest, CompleteStr(" #3"));
+    }
+
+    #[test]
+    fn test_malformed() {
+        let symbols = SymbolTable::new();
+        // the message, and the offset it is reported at
+        let error = |source: &str| {
+            let error = evaluate(source, &symbols).unwrap_err();
+            (
+                error.kind.to_string(),
+                source.len() - error.remaining.unwrap(),
+            )
+        };
+        assert_eq!(error("(1 + )"), ("expected a value".to_string(), 5));
+        assert_eq!(error("2 * (3 + 4"), ("( is never closed".to_string(), 4));
+        assert_eq!(error("1 +"), ("+ has no value after it".to_string(), 2));
+        assert_eq!(error("1 << "), ("<< has no value after it".to_string(), 2));
+        assert_eq!(error("-"), ("expected a value".to_string(), 1));
+        assert_eq!(error(")"), ("expected a value".to_string(), 0));
+        // the rest of the line goes with a malformed expression
+        let (rest, _) = expression(CompleteStr("(1 + ) 2\nhlt")).unwrap();
+        assert_eq!(rest, CompleteStr("\nhlt"));
+    }
+}
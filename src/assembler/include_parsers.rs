@@ -0,0 +1,79 @@
+// `.include "path.iasm"` puts the lines of another file where it is. The path
+// is looked for relative to the directory of the file that includes it, then
+// in each of the assembler's include paths in turn. A file is only ever
+// included once; including it again does nothing, unless it is still being
+// included, which would never end and is an error. Includes are handled
+// with macros, see `macro_parsers`.
+use std::path::{Path, PathBuf};
+
+use nom::types::CompleteStr;
+
+use crate::assembler::operand_parsers::string_operand;
+use crate::assembler::Token;
+
+// The path an `.include` names, given what follows `.include` on its line
+pub fn include_operand(operand: &str) -> Option<String> {
+    if !operand.starts_with(char::is_whitespace) {
+        return None;
+    }
+    match string_operand(CompleteStr(operand.trim_start())) {
+        Ok((rest, Token::StringOperand { value })) if rest.trim().is_empty() => Some(value),
+        _ => None,
+    }
+}
+
+// Where the file `path` names is, for an `.include` in a file in `dir`
+pub fn resolve_include(path: &str, dir: &Path, include_paths: &[PathBuf]) -> Option<PathBuf> {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        return Some(path.to_path_buf()).filter(|path| path.is_file());
+    }
+    std::iter::once(dir)
+        .chain(include_paths.iter().map(PathBuf::as_path))
+        .map(|dir| dir.join(path))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_include_operand() {
+        assert_eq!(
+            include_operand(" \"lib.iasm\" "),
+            Some("lib.iasm".to_string())
+        );
+        assert_eq!(include_operand("\"lib.iasm\""), None);
+        assert_eq!(include_operand(" lib.iasm"), None);
+        assert_eq!(include_operand(" \"a\" \"b\""), None);
+    }
+
+    #[test]
+    fn test_resolve_include() {
+        let dir = std::env::temp_dir().join(format!("iridium-resolve-{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("src/local.iasm"), "hlt\n").unwrap();
+        fs::write(dir.join("lib/shared.iasm"), "hlt\n").unwrap();
+        let paths = [dir.join("lib")];
+        let src = dir.join("src");
+        assert_eq!(
+            resolve_include("local.iasm", &src, &paths),
+            Some(src.join("local.iasm"))
+        );
+        assert_eq!(
+            resolve_include("shared.iasm", &src, &paths),
+            Some(dir.join("lib/shared.iasm"))
+        );
+        assert_eq!(resolve_include("shared.iasm", &src, &[]), None);
+        let absolute = dir.join("lib/shared.iasm");
+        assert_eq!(
+            resolve_include(absolute.to_str().unwrap(), &src, &[]),
+            Some(absolute)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,14 @@
+use crate::assembler::directive_parsers::directive;
+use crate::assembler::label_parsers::label_declaration;
 use crate::assembler::opcode_parsers::*;
-use crate::assembler::operand_parsers::integer_operand;
-use crate::assembler::register_parsers::register;
+use crate::assembler::operand_parsers::operand;
 use crate::assembler::Token;
-use nom::multispace;
+use crate::instruction::{OpcodeInfo, OperandKind};
 use nom::types::CompleteStr;
+use nom::{space, Err, ErrorKind, IResult};
 
-use super::directive_parsers::directive;
+use super::assembler_errors::{AssemblerErrorKind, OperandError};
+use super::expression_parsers::immediate_value;
 use super::SymbolTable;
 
 #[derive(Debug, PartialEq)]
@@ -16,52 +19,103 @@ pub struct AssemblerInstruction {
     pub operand1: Option<Token>,
     pub operand2: Option<Token>,
     pub operand3: Option<Token>,
+    // any operands after the third, which no instruction takes but which are
+    // kept so that the count can be reported
+    pub extra_operands: Vec<Token>,
+    // the operands of a data directive, which can take any number
+    pub data: Vec<Token>,
 }
 
 impl AssemblerInstruction {
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
-        let mut res: Vec<u8> = vec![];
-        match self.opcode {
-            Some(Token::Op { code }) => match code {
-                _ => {
-                    res.push(code as u8);
-                }
-            },
-            _ => {
-                print!("Non-opcode found in opcode field");
-                std::process::exit(1);
-            }
-        };
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, OperandError> {
+        let mut res: Vec<u8> = vec![self.check_operands()?.byte];
 
-        for operand in vec![&self.operand1, &self.operand2, &self.operand3] {
-            if let Some(token) = operand {
-                AssemblerInstruction::extract_operand(token, &mut res);
-            }
+        let operands = [&self.operand1, &self.operand2, &self.operand3];
+        for token in operands.into_iter().flatten() {
+            AssemblerInstruction::extract_operand(token, symbols, &mut res)?;
         }
 
         while res.len() < 4 {
             res.push(0);
         }
 
-        return res;
+        Ok(res)
     }
 
-    fn extract_operand(t: &Token, res: &mut Vec<u8>) {
+    // Checks the operands against the ones the opcode table gives the opcode
+    pub fn check_operands(&self) -> Result<&'static OpcodeInfo, AssemblerErrorKind> {
+        let info = match &self.opcode {
+            Some(Token::Op { code }) => code.info(),
+            Some(Token::UnknownOp { name }) => {
+                return Err(AssemblerErrorKind::UnknownInstruction { name: name.clone() })
+            }
+            _ => None,
+        };
+        let info = info.ok_or(AssemblerErrorKind::MissingOpcode)?;
+        let mut found = vec![];
+        for token in [&self.operand1, &self.operand2, &self.operand3]
+            .into_iter()
+            .flatten()
+            .chain(&self.extra_operands)
+        {
+            found.push(match token {
+                Token::Register { .. } | Token::RegisterOutOfRange { .. } => OperandKind::Register,
+                Token::IntegerOperand { .. }
+                | Token::LabelUsage { .. }
+                | Token::Expression { .. } => OperandKind::Immediate,
+                _ => return Err(AssemblerErrorKind::InvalidOperand),
+            });
+        }
+        if found != info.operands {
+            return Err(AssemblerErrorKind::WrongOperands {
+                mnemonic: info.mnemonic,
+                expected: info.operands,
+                found,
+            });
+        }
+        Ok(info)
+    }
+
+    fn extract_operand(
+        t: &Token,
+        symbols: &SymbolTable,
+        res: &mut Vec<u8>,
+    ) -> Result<(), OperandError> {
         match t {
             Token::Register { reg_num } => {
                 res.push(*reg_num);
+                return Ok(());
             }
-            Token::IntegerOperand { value } => {
-                let byte1 = *value as u16;
-                let byte2 = byte1 >> 8;
-                res.push(byte2 as u8);
-                res.push(byte1 as u8);
-            }
-            _ => {
-                print!("Invalid operand found");
-                std::process::exit(1);
+            Token::RegisterOutOfRange { number, remaining } => {
+                return Err(OperandError {
+                    kind: AssemblerErrorKind::RegisterOutOfRange {
+                        number: number.clone(),
+                    },
+                    remaining: Some(*remaining),
+                })
             }
+            _ => {}
+        }
+        let value = immediate_value(t, symbols)?;
+        // a negative value is stored as 16 bit two's complement; LOAD
+        // zero-extends its immediate, so `#-5` loads 65531, which wraps back
+        // to -5 in 16 bit arithmetic
+        if !(i16::MIN as i64..=u16::MAX as i64).contains(&value) {
+            let kind = AssemblerErrorKind::ImmediateOutOfRange { value };
+            return Err(match t {
+                Token::Expression { expr } => OperandError {
+                    kind,
+                    remaining: Some(expr.remaining),
+                },
+                _ => kind.into(),
+            });
         }
+        res.extend((value as u16).to_be_bytes());
+        Ok(())
+    }
+
+    pub fn is_directive(&self) -> bool {
+        self.directive.is_some()
     }
 
     pub fn is_label(&self) -> bool {
@@ -70,142 +124,54 @@ impl AssemblerInstruction {
 
     pub fn label_name(&self) -> Option<String> {
         match &self.label {
-            Some(l) => match l {
-                Token::LabelDeclaration { name } => Some(name.clone()),
-                _ => None,
-            },
-            None => None,
+            Some(Token::LabelDeclaration { name }) => Some(name.clone()),
+            _ => None,
         }
     }
 }
 
-// for instructions that take two operands, e.g. LOAD $0 #100
-named!(pub instruction_two_int<CompleteStr, AssemblerInstruction>,
-    do_parse!(
-        o: opcode_load >>
-        r: register >>
-        i: integer_operand >>
-        (
-            AssemblerInstruction {
-                opcode: Some(o),
-                label: None,
-                directive: None,
-                operand1: Some(r),
-                operand2: Some(i),
-                operand3: None,
-            }
-        )
-    )
-);
-
-// for instructions that take two register operands, e.g. EQ $0 $1
-named!(pub instruction_two_reg<CompleteStr, AssemblerInstruction>,
-    do_parse!(
-        o: opcode >>
-        r1: register >>
-        r2: register >>
-        (
-            AssemblerInstruction {
-                opcode: Some(o),
-                label: None,
-                directive: None,
-                operand1: Some(r1),
-                operand2: Some(r2),
-                operand3: None,
-            }
-        )
-    )
-);
-
-// for instructions that take three register operands, e.g. ADD $0 $1 $2
-named!(pub instruction_three<CompleteStr, AssemblerInstruction>,
-    do_parse!(
-        o: opcode >>
-        r1: register >>
-        r2: register >>
-        r3: register >>
-        (
-            AssemblerInstruction {
-                opcode: Some(o),
-                label: None,
-                directive: None,
-                operand1: Some(r1),
-                operand2: Some(r2),
-                operand3: Some(r3),
-            }
-        )
-    )
-);
-
-// for instructions that take one register operand, e.g. JMP $0
-named!(pub instruction_one<CompleteStr, AssemblerInstruction>,
-    do_parse!(
-        o: opcode >>
-        r: register >>
-        (
-            AssemblerInstruction {
-                opcode: Some(o),
-                label: None,
-                directive: None,
-                operand1: Some(r),
-                operand2: None,
-                operand3: None,
-            }
-        )
-    )
-);
-
-// for instructions that take no operands, e.g. HLTs
-named!(instruction_zero<CompleteStr, AssemblerInstruction>,
-    do_parse!(
-        o: opcode >>
-        opt!(multispace) >>
-        (
-            AssemblerInstruction{
-                opcode: Some(o),
-                label: None,
-                directive: None,
-                operand1: None,
-                operand2: None,
-                operand3: None,
-            }
-        )
-    )
-);
-
-// named!(instruction_combined<CompleteStr, AssemblerInstruction>,
-//     do_parse!(
-//         l: opt!(label_declaraction) >>
-//         o: opcode >>
-//         o1: opt!(operand) >>
-//         o2: opt!(operand) >>
-//         o3: opt!(operand) >>
-//         (
-//             AssemblerInstruction {
-//                 opcode: Some(o),
-//                 label: l,
-//                 operand1: o1,
-//                 operand2: o2,
-//                 operand3: o3,
-//             }
-//         )
-//     )
-// );
-
-named!(pub instruction<CompleteStr, AssemblerInstruction>,
-    do_parse!(
-        ins: alt!(
-            instruction_two_int |
-            instruction_two_reg |
-            instruction_three |
-            instruction_one |
-            instruction_zero
-            // instruction |
-            // directive
+// an instruction, optionally preceded by the declaration of a label for it,
+// which may be on a line of its own. Any operands on the rest of the line are
+// taken; whether they suit the opcode is checked when assembling.
+pub fn instruction(input: CompleteStr) -> IResult<CompleteStr, AssemblerInstruction> {
+    let (input, label) = opt!(input, label_declaration)?;
+    if let Ok((rest, directive)) = directive(input) {
+        return Ok((rest, AssemblerInstruction { label, ..directive }));
+    }
+    let (input, opcode) = opcode(input)?;
+    let (line, rest) = split_line(input);
+    let (line, _) = opt!(line, space)?;
+    let (line, mut operands) = many0!(line, operand)?;
+    if !line.trim().is_empty() {
+        return Err(Err::Error(error_position!(line, ErrorKind::Custom(0))));
+    }
+    for operand in operands.iter_mut() {
+        operand.shift(rest.len());
+    }
+    let mut operands = operands.drain(..);
+    Ok((
+        rest,
+        AssemblerInstruction {
+            opcode: Some(opcode),
+            label,
+            directive: None,
+            operand1: operands.next(),
+            operand2: operands.next(),
+            operand3: operands.next(),
+            extra_operands: operands.collect(),
+            data: vec![],
+        },
+    ))
+}
 
-        ) >> (ins)
+// Splits off the rest of the line, where an instruction's operands must be
+pub fn split_line(input: CompleteStr) -> (CompleteStr, CompleteStr) {
+    let line_end = input.find('\n').unwrap_or(input.len());
+    (
+        CompleteStr(&input[..line_end]),
+        CompleteStr(&input[line_end..]),
     )
-);
+}
 
 #[cfg(test)]
 mod tests {
@@ -214,34 +180,38 @@ mod tests {
 
     #[test]
     fn test_parse_instruction_form_two() {
-        let result = instruction_two_int(CompleteStr("load $0 #100\n"));
+        let result = instruction(CompleteStr("load $0 #100\n"));
         assert_eq!(
             result,
             Ok((
-                CompleteStr(""),
+                CompleteStr("\n"),
                 AssemblerInstruction {
                     opcode: Some(Token::Op { code: Opcode::LOAD }),
                     label: None,
                     directive: None,
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: Some(Token::IntegerOperand { value: 100 }),
-                    operand3: None
+                    operand3: None,
+                    extra_operands: vec![],
+                    data: vec![]
                 }
             ))
         );
 
-        let result = instruction_two_reg(CompleteStr("eq $0 $1\n"));
+        let result = instruction(CompleteStr("eq $0 $1\n"));
         assert_eq!(
             result,
             Ok((
-                CompleteStr(""),
+                CompleteStr("\n"),
                 AssemblerInstruction {
                     opcode: Some(Token::Op { code: Opcode::EQ }),
                     label: None,
                     directive: None,
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: Some(Token::Register { reg_num: 1 }),
-                    operand3: None
+                    operand3: None,
+                    extra_operands: vec![],
+                    data: vec![]
                 }
             ))
         );
@@ -249,18 +219,20 @@ mod tests {
 
     #[test]
     fn test_parse_instruction_form_zero() {
-        let result = instruction_zero(CompleteStr("hlt\n"));
+        let result = instruction(CompleteStr("hlt\n"));
         assert_eq!(
             result,
             Ok((
-                CompleteStr(""),
+                CompleteStr("\n"),
                 AssemblerInstruction {
                     opcode: Some(Token::Op { code: Opcode::HLT }),
                     label: None,
                     directive: None,
                     operand1: None,
                     operand2: None,
-                    operand3: None
+                    operand3: None,
+                    extra_operands: vec![],
+                    data: vec![]
                 }
             ))
         );
@@ -268,37 +240,103 @@ mod tests {
 
     #[test]
     fn test_parse_instruction_form_one() {
-        let result = instruction_one(CompleteStr("jmp $0\n"));
+        let result = instruction(CompleteStr("jmp $0\n"));
         assert_eq!(
             result,
             Ok((
-                CompleteStr(""),
+                CompleteStr("\n"),
                 AssemblerInstruction {
                     opcode: Some(Token::Op { code: Opcode::JMP }),
                     label: None,
                     directive: None,
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: None,
-                    operand3: None
+                    operand3: None,
+                    extra_operands: vec![],
+                    data: vec![]
                 }
             ))
         );
     }
 
+    #[test]
+    fn test_parse_labelled_instruction() {
+        let (rest, labelled) = instruction(CompleteStr("loop: inc $0\nhlt")).unwrap();
+        assert_eq!(rest, CompleteStr("\nhlt"));
+        assert_eq!(labelled.label_name(), Some("loop".to_string()));
+        let (_, own_line) = instruction(CompleteStr("end:\n  hlt\n")).unwrap();
+        assert_eq!(own_line.label_name(), Some("end".to_string()));
+        assert_eq!(own_line.opcode, Some(Token::Op { code: Opcode::HLT }));
+        let (_, usage) = instruction(CompleteStr("load $2 @loop\n")).unwrap();
+        assert_eq!(
+            usage.operand2,
+            Some(Token::LabelUsage {
+                name: "loop".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_operands_stay_on_their_line() {
+        let (rest, hlt) = instruction(CompleteStr("hlt\n$1")).unwrap();
+        assert_eq!(rest, CompleteStr("\n$1"));
+        assert_eq!(hlt.operand1, None);
+        let (rest, add) = instruction(CompleteStr("add $0 $1 $2 $3\n")).unwrap();
+        assert_eq!(rest, CompleteStr("\n"));
+        assert_eq!(add.extra_operands, vec![Token::Register { reg_num: 3 }]);
+        assert!(instruction(CompleteStr("hlt?\n")).is_err());
+    }
+
+    #[test]
+    fn test_check_operands() {
+        let check = |source| {
+            instruction(CompleteStr(source))
+                .unwrap()
+                .1
+                .check_operands()
+                .map(|info| info.opcode)
+        };
+        assert_eq!(check("load $0 @end"), Ok(Opcode::LOAD));
+        assert_eq!(
+            check("add $0").unwrap_err().to_string(),
+            "add expects 3 registers, found 1"
+        );
+        assert_eq!(
+            check("add $0 $1 $2 $3").unwrap_err().to_string(),
+            "add expects 3 registers, found 4"
+        );
+        assert_eq!(
+            check("jmp #5").unwrap_err().to_string(),
+            "jmp expects 1 register, found 1 value"
+        );
+        assert_eq!(
+            check("hlt $1 $2").unwrap_err().to_string(),
+            "hlt expects no operands, found 2 registers"
+        );
+        assert_eq!(
+            check("halt"),
+            Err(AssemblerErrorKind::UnknownInstruction {
+                name: "halt".to_string()
+            })
+        );
+    }
+
     #[test]
     fn test_parse_instruction_form_three() {
-        let result = instruction_three(CompleteStr("add $0 $1 $2\n"));
+        let result = instruction(CompleteStr("add $0 $1 $2\n"));
         assert_eq!(
             result,
             Ok((
-                CompleteStr(""),
+                CompleteStr("\n"),
                 AssemblerInstruction {
                     opcode: Some(Token::Op { code: Opcode::ADD }),
                     label: None,
                     directive: None,
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: Some(Token::Register { reg_num: 1 }),
-                    operand3: Some(Token::Register { reg_num: 2 })
+                    operand3: Some(Token::Register { reg_num: 2 }),
+                    extra_operands: vec![],
+                    data: vec![]
                 }
             ))
         );
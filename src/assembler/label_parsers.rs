@@ -1,13 +1,25 @@
 use nom::types::CompleteStr;
-use nom::{alphanumeric, multispace};
+use nom::{multispace, Err, ErrorKind, IResult};
 
 use crate::assembler::Token;
 
+// A label's name, which follows the same rule as a constant's: a letter or
+// `_`, then any letters, digits and `_`
+fn label_name(input: CompleteStr) -> IResult<CompleteStr, CompleteStr> {
+    if !input.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        return Err(Err::Error(error_position!(input, ErrorKind::Custom(0))));
+    }
+    let len = input
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(input.len());
+    Ok((CompleteStr(&input[len..]), CompleteStr(&input[..len])))
+}
+
 // Looks for a user-defined label, such as `label1:`
 named!(pub label_declaration<CompleteStr, Token>,
     ws!(
         do_parse!(
-            name: alphanumeric >>
+            name: label_name >>
             tag!(":") >>
             opt!(multispace) >>
             (
@@ -22,7 +34,7 @@ named!(pub label_usage<CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!("@") >>
-            name: alphanumeric >>
+            name: label_name >>
             opt!(multispace) >>
             (
                 Token::LabelUsage{name: name.to_string()}
@@ -38,7 +50,7 @@ mod tests {
     #[test]
     fn test_parse_label_declaration() {
         let result = label_declaration(CompleteStr("test:"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, token) = result.unwrap();
         assert_eq!(
             token,
@@ -47,13 +59,13 @@ mod tests {
             }
         );
         let result = label_declaration(CompleteStr("test"));
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
     }
 
     #[test]
     fn test_parse_label_usage() {
         let result = label_usage(CompleteStr("@test"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, token) = result.unwrap();
         assert_eq!(
             token,
@@ -62,6 +74,27 @@ mod tests {
             }
         );
         let result = label_usage(CompleteStr("test"));
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_label_names() {
+        let (rest, token) = label_declaration(CompleteStr("loop_end: hlt")).unwrap();
+        assert_eq!(
+            token,
+            Token::LabelDeclaration {
+                name: "loop_end".to_string()
+            }
+        );
+        assert_eq!(rest, CompleteStr("hlt"));
+        let (_, token) = label_usage(CompleteStr("@_loop2")).unwrap();
+        assert_eq!(
+            token,
+            Token::LabelUsage {
+                name: "_loop2".to_string()
+            }
+        );
+        assert!(label_declaration(CompleteStr("2nd:")).is_err());
+        assert!(label_usage(CompleteStr("@2nd")).is_err());
     }
 }
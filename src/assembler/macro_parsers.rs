@@ -0,0 +1,833 @@
+// Macros, which give a name to lines that are written out again wherever the
+// name is used:
+//   .macro swap a, b
+//       add \a \b \a
+//       sub \a \b \b
+//       sub \a \b \a
+//   .endm
+//   swap $1, $2
+// `\name` in the body is replaced by the argument given for that parameter,
+// and labels declared in the body are renamed in each expansion so a macro
+// can be used more than once: `loop` in the third expansion becomes
+// `loopM3`. A macro must be defined before it is used, and may use other
+// macros, up to MAX_MACRO_DEPTH deep. However they are nested, the lines of
+// macro bodies written out for one invocation are limited to
+// MAX_EXPANDED_LINES, as a few macros each using the last twice would
+// otherwise expand to more lines than fit in memory.
+//
+// Macros are expanded in the source text before it is parsed, after
+// comments are stripped, and so are `.include`s, see `include_parsers`.
+// `Expansion` remembers which file and where in it each line of the expanded
+// text came from so errors can be reported in the source as written.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::assembler::assembler_errors::{AssemblerError, AssemblerErrorKind, ErrorNote};
+use crate::assembler::comment_parsers::{quoted_len, strip_comments};
+use crate::assembler::include_parsers::{include_operand, resolve_include};
+
+pub const MAX_MACRO_DEPTH: usize = 64;
+pub const MAX_EXPANDED_LINES: usize = 100_000;
+
+// The source with every macro and include expanded
+#[derive(Debug)]
+pub struct Expansion {
+    pub text: String,
+    // the file assembled first, then each file it includes in the order
+    // they are included
+    pub files: Vec<SourceFile>,
+    lines: Vec<LineOrigin>,
+}
+
+#[derive(Debug)]
+pub struct SourceFile {
+    pub name: String,
+    pub text: String,
+    stripped: String, // `text` with its comments blanked out
+    // the file and offset of the `.include` that included it
+    included_from: Option<(usize, usize)>,
+}
+
+// Where a line of the expanded text came from
+#[derive(Debug)]
+struct LineOrigin {
+    start: usize, // where the line starts in the expanded text
+    file: usize,
+    // the parts of the line, in order; the first is at 0
+    pieces: Vec<Piece>,
+    // the invocations the line was expanded from, innermost first
+    sites: Vec<Site>,
+}
+
+// Part of an expanded line, either copied from its file or put in its place,
+// like an argument for a parameter
+#[derive(Debug, Clone, Copy)]
+struct Piece {
+    at: usize,     // where it starts in the expanded line
+    source: usize, // where what it came from starts in the file
+    copied: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Site {
+    name: String,
+    file: usize,
+    offset: usize, // where the macro's name is in the file
+}
+
+impl Expansion {
+    // The file and byte of it that byte `offset` of the expanded text came
+    // from. Anything put in place of a parameter or label comes from the
+    // start of the name it replaced.
+    pub fn source_offset(&self, offset: usize) -> (usize, usize) {
+        let origin = self.origin(offset);
+        let offset = offset - origin.start;
+        let piece = origin
+            .pieces
+            .iter()
+            .rev()
+            .find(|piece| piece.at <= offset)
+            .unwrap_or(&origin.pieces[0]);
+        if piece.copied {
+            (origin.file, piece.source + offset - piece.at)
+        } else {
+            (origin.file, piece.source)
+        }
+    }
+
+    // `error`, found in the expanded text, moved to where it is in the file
+    // it came from, with a note for each macro invocation and include it
+    // came through
+    pub fn relocate(&self, error: AssemblerError) -> AssemblerError {
+        let origin = &self.lines[error.line as usize - 1];
+        let line = self.text[origin.start..].split('\n').next().unwrap_or("");
+        let column = line
+            .char_indices()
+            .nth(error.column as usize - 1)
+            .map_or(line.len(), |(index, _)| index);
+        let (file, offset) = self.source_offset(origin.start + column);
+        let mut relocated = file_error(&self.files, file, offset, error.kind);
+        let mut notes = site_notes(&self.files, &origin.sites);
+        notes.append(&mut relocated.notes);
+        notes.extend(error.notes);
+        relocated.notes = notes;
+        relocated
+    }
+
+    fn origin(&self, offset: usize) -> &LineOrigin {
+        let index = self.lines.partition_point(|line| line.start <= offset);
+        &self.lines[index.saturating_sub(1)]
+    }
+}
+
+// An error at byte `offset` of `file`, with a note for each include it is
+// in
+fn file_error(
+    files: &[SourceFile],
+    file: usize,
+    offset: usize,
+    kind: AssemblerErrorKind,
+) -> AssemblerError {
+    let source = &files[file];
+    let mut error = AssemblerError::new(&source.name, &source.text, offset, kind);
+    let mut included = file;
+    while let Some((parent, offset)) = files[included].included_from {
+        let message = format!("{} is included here", files[included].name);
+        error.notes.push(note(files, parent, offset, message));
+        included = parent;
+    }
+    error
+}
+
+fn note(files: &[SourceFile], file: usize, offset: usize, message: String) -> ErrorNote {
+    ErrorNote::new(&files[file].name, &files[file].text, offset, message)
+}
+
+fn site_notes(files: &[SourceFile], sites: &[Site]) -> Vec<ErrorNote> {
+    sites
+        .iter()
+        .map(|site| {
+            let message = format!("in expansion of macro {}", site.name);
+            note(files, site.file, site.offset, message)
+        })
+        .collect()
+}
+
+// Expands the macros and includes in `source`, which is read from `file`.
+// Included files are looked for next to the file including them and then in
+// `include_paths`.
+pub fn expand_source(
+    file: &str,
+    source: &str,
+    include_paths: &[PathBuf],
+) -> (Expansion, Vec<AssemblerError>) {
+    let mut expander = Expander {
+        include_paths,
+        files: vec![],
+        macros: HashMap::new(),
+        identifiers: HashSet::new(),
+        expansions: 0,
+        expanded_lines: 0,
+        included: HashSet::new(),
+        including: vec![],
+        lines: vec![],
+        errors: vec![],
+    };
+    let main = expander.add_file(file.to_string(), source.to_string(), None);
+    // the file being assembled counts as included when it is a file at all
+    if let Ok(path) = fs::canonicalize(file) {
+        expander.included.insert(path.clone());
+        expander.including.push(path);
+    }
+    expander.expand_file(main);
+    let mut text = String::new();
+    let mut lines = vec![];
+    for (index, (line, sites)) in expander.lines.into_iter().enumerate() {
+        if index > 0 {
+            text.push('\n');
+        }
+        lines.push(LineOrigin {
+            start: text.len(),
+            file: line.file,
+            pieces: line.pieces,
+            sites,
+        });
+        text.push_str(&line.text);
+    }
+    let expansion = Expansion {
+        text,
+        files: expander.files,
+        lines,
+    };
+    (expansion, expander.errors)
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    name: String,
+    params: Vec<String>,
+    file: usize,
+    offset: usize,       // where its `.macro` is in its file
+    body: Vec<usize>,    // where each line of its body starts
+    labels: Vec<String>, // the labels its body declares
+}
+
+// A line of expanded text and where in `file` its parts came from
+struct Line {
+    text: String,
+    file: usize,
+    pieces: Vec<Piece>,
+}
+
+impl Line {
+    fn new(file: usize) -> Line {
+        Line {
+            text: String::new(),
+            file,
+            pieces: vec![],
+        }
+    }
+
+    // A line of a file as it is
+    fn copied(text: &str, file: usize, start: usize) -> Line {
+        let mut line = Line::new(file);
+        line.push(text, start, true);
+        line
+    }
+
+    fn source_offset(&self, offset: usize) -> usize {
+        let piece = self.pieces.iter().rev().find(|piece| piece.at <= offset);
+        match piece {
+            Some(piece) if piece.copied => piece.source + offset - piece.at,
+            Some(piece) => piece.source,
+            None => self.pieces[0].source,
+        }
+    }
+
+    fn push(&mut self, text: &str, source: usize, copied: bool) {
+        let at = self.text.len();
+        // copies of consecutive source text stay one piece
+        let continues = self
+            .pieces
+            .last()
+            .is_some_and(|last| copied && last.copied && last.source + at - last.at == source);
+        if !continues {
+            self.pieces.push(Piece { at, source, copied });
+        }
+        self.text.push_str(text);
+    }
+}
+
+struct Expander<'a> {
+    include_paths: &'a [PathBuf],
+    files: Vec<SourceFile>,
+    macros: HashMap<String, Macro>,
+    // every word in the files, which renamed labels must not clash with
+    identifiers: HashSet<String>,
+    expansions: usize,
+    // body lines written out for the invocation being expanded, see
+    // MAX_EXPANDED_LINES
+    expanded_lines: usize,
+    // every file included so far, and the ones still being included
+    included: HashSet<PathBuf>,
+    including: Vec<PathBuf>,
+    lines: Vec<(Line, Vec<Site>)>,
+    errors: Vec<AssemblerError>,
+}
+
+impl<'a> Expander<'a> {
+    fn add_file(
+        &mut self,
+        name: String,
+        text: String,
+        included_from: Option<(usize, usize)>,
+    ) -> usize {
+        let (stripped, comments) = strip_comments(&text);
+        self.identifiers.extend(
+            stripped
+                .split(|c: char| !is_word_char(c))
+                .filter(|word| !word.is_empty())
+                .map(str::to_string),
+        );
+        let file = self.files.len();
+        self.files.push(SourceFile {
+            name,
+            text,
+            stripped,
+            included_from,
+        });
+        for comment in comments.iter().filter(|c| !c.is_terminated()) {
+            let offset = self.files[file].text.len() - comment.remaining;
+            self.error(file, offset, AssemblerErrorKind::UnterminatedComment);
+        }
+        file
+    }
+
+    fn expand_file(&mut self, file: usize) {
+        let mut defining: Option<Macro> = None;
+        let mut start = 0;
+        let stripped = self.files[file].stripped.clone();
+        for text in stripped.split('\n') {
+            let indent = text.len() - text.trim_start().len();
+            let directive = directive_name(text);
+            match (&mut defining, directive) {
+                (Some(_), Some("endm")) => {
+                    let definition = defining.take().unwrap();
+                    self.define(definition);
+                }
+                (Some(_), Some("macro")) => self.error(
+                    file,
+                    start + indent,
+                    AssemblerErrorKind::NestedMacroDefinition,
+                ),
+                (Some(definition), _) => {
+                    if let Some(label) = label_len(text).map(|len| &text[indent..len - 1]) {
+                        definition.labels.push(label.to_string());
+                    }
+                    definition.body.push(start);
+                }
+                (None, Some("macro")) => match macro_header(&text[indent + ".macro".len()..]) {
+                    Some((name, params)) => {
+                        defining = Some(Macro {
+                            name,
+                            params,
+                            file,
+                            offset: start + indent,
+                            body: vec![],
+                            labels: vec![],
+                        })
+                    }
+                    None => self.error(
+                        file,
+                        start + indent,
+                        AssemblerErrorKind::WrongDirectiveOperands {
+                            directive: "macro".to_string(),
+                            expected: "a name and parameter names",
+                        },
+                    ),
+                },
+                (None, Some("endm")) => {
+                    self.error(file, start + indent, AssemblerErrorKind::UnexpectedEndm)
+                }
+                (None, Some("include")) => {
+                    self.lines.push((Line::copied("", file, start), vec![]));
+                    let operand = &text[indent + ".include".len()..];
+                    self.include(file, start + indent, operand);
+                    start += text.len() + 1;
+                    continue;
+                }
+                (None, _) => {
+                    self.line(Line::copied(text, file, start), &[], 0);
+                    start += text.len() + 1;
+                    continue;
+                }
+            }
+            // definitions leave blank lines behind
+            self.lines.push((Line::copied("", file, start), vec![]));
+            start += text.len() + 1;
+        }
+        if let Some(definition) = defining {
+            let kind = AssemblerErrorKind::UnterminatedMacro {
+                name: definition.name,
+            };
+            self.error(file, definition.offset, kind);
+        }
+    }
+
+    // Expands the file the `.include` at `offset` of `file` names, unless it
+    // has been already
+    fn include(&mut self, file: usize, offset: usize, operand: &str) {
+        let Some(name) = include_operand(operand) else {
+            let kind = AssemblerErrorKind::WrongDirectiveOperands {
+                directive: "include".to_string(),
+                expected: "a file name in quotes",
+            };
+            return self.error(file, offset, kind);
+        };
+        let dir = Path::new(&self.files[file].name)
+            .parent()
+            .unwrap_or(Path::new(""));
+        let Some(path) = resolve_include(&name, dir, self.include_paths) else {
+            return self.error(
+                file,
+                offset,
+                AssemblerErrorKind::IncludeNotFound { path: name },
+            );
+        };
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if self.including.contains(&canonical) {
+            return self.error(
+                file,
+                offset,
+                AssemblerErrorKind::IncludeCycle { path: name },
+            );
+        }
+        if !self.included.insert(canonical.clone()) {
+            return;
+        }
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) => {
+                let kind = AssemblerErrorKind::IncludeUnreadable {
+                    path: name,
+                    reason: e.to_string(),
+                };
+                return self.error(file, offset, kind);
+            }
+        };
+        let included = self.add_file(path.display().to_string(), text, Some((file, offset)));
+        self.including.push(canonical);
+        self.expand_file(included);
+        self.including.pop();
+    }
+
+    fn define(&mut self, definition: Macro) {
+        if self.macros.contains_key(&definition.name) {
+            let kind = AssemblerErrorKind::DuplicateMacro {
+                name: definition.name,
+            };
+            self.error(definition.file, definition.offset, kind);
+        } else {
+            self.macros.insert(definition.name.clone(), definition);
+        }
+    }
+
+    // Adds `line` to the expanded text, or what it expands to if it invokes
+    // a macro. False when expanding it went too deep, which abandons every
+    // expansion `line` is part of.
+    fn line(&mut self, line: Line, sites: &[Site], depth: usize) -> bool {
+        if depth > 0 && directive_name(&line.text) == Some("include") {
+            let mut error = file_error(
+                &self.files,
+                line.file,
+                line.source_offset(0),
+                AssemblerErrorKind::IncludeInMacro,
+            );
+            error.notes.splice(0..0, site_notes(&self.files, sites));
+            self.errors.push(error);
+            return true;
+        }
+        let label_end = label_len(&line.text).unwrap_or(0);
+        let rest = &line.text[label_end..];
+        let name_start = label_end + rest.len() - rest.trim_start().len();
+        let name_len = line.text[name_start..]
+            .find(|c: char| !is_word_char(c))
+            .unwrap_or(line.text.len() - name_start);
+        let name_end = name_start + name_len;
+        let invoked = self.macros.get(&line.text[name_start..name_end]);
+        let definition = match invoked {
+            Some(definition)
+                if !line.text[name_end..].starts_with(|c: char| !c.is_whitespace()) =>
+            {
+                definition.clone()
+            }
+            _ => {
+                self.lines.push((line, sites.to_vec()));
+                return true;
+            }
+        };
+        // a label before the invocation is kept on a line of its own
+        if label_end > 0 {
+            let mut label = Line::new(line.file);
+            label.push(&line.text[..label_end], line.source_offset(0), true);
+            self.lines.push((label, sites.to_vec()));
+        }
+        let mut chain = vec![Site {
+            name: definition.name.clone(),
+            file: line.file,
+            offset: line.source_offset(name_start),
+        }];
+        chain.extend(sites.iter().cloned());
+        if depth == 0 {
+            self.expanded_lines = 0;
+        }
+        let args = split_arguments(&line.text[name_end..]);
+        self.expand(&definition, &args, &chain, depth)
+    }
+
+    fn expand(&mut self, definition: &Macro, args: &[&str], chain: &[Site], depth: usize) -> bool {
+        let site = &chain[0];
+        if depth >= MAX_MACRO_DEPTH {
+            let kind = AssemblerErrorKind::MacroTooDeep {
+                name: definition.name.clone(),
+            };
+            let mut error = file_error(&self.files, site.file, site.offset, kind);
+            error.notes.insert(0, self.defined_here(definition));
+            self.errors.push(error);
+            return false;
+        }
+        if args.len() != definition.params.len() {
+            let kind = AssemblerErrorKind::MacroArguments {
+                name: definition.name.clone(),
+                expected: definition.params.len(),
+                found: args.len(),
+            };
+            let mut error = file_error(&self.files, site.file, site.offset, kind);
+            let mut notes = site_notes(&self.files, &chain[1..]);
+            notes.append(&mut error.notes);
+            notes.push(self.defined_here(definition));
+            error.notes = notes;
+            self.errors.push(error);
+            return true;
+        }
+        let suffix = self.label_suffix(&definition.labels);
+        for &start in &definition.body {
+            self.expanded_lines += 1;
+            if self.expanded_lines > MAX_EXPANDED_LINES {
+                // reported where the expansion started, in the file as written
+                let outermost = chain.last().unwrap();
+                let kind = AssemblerErrorKind::ExpansionTooLarge {
+                    name: outermost.name.clone(),
+                };
+                let error = file_error(&self.files, outermost.file, outermost.offset, kind);
+                self.errors.push(error);
+                return false;
+            }
+            let line = self.substitute(definition, start, args, &suffix, chain);
+            if !self.line(line, chain, depth + 1) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // The line of `definition`'s body at `start` with its parameters
+    // replaced by `args` and its labels renamed with `suffix`
+    fn substitute(
+        &mut self,
+        definition: &Macro,
+        start: usize,
+        args: &[&str],
+        suffix: &str,
+        chain: &[Site],
+    ) -> Line {
+        let stripped = &self.files[definition.file].stripped;
+        let text = stripped[start..]
+            .split('\n')
+            .next()
+            .unwrap_or("")
+            .to_string();
+        let mut line = Line::new(definition.file);
+        let mut index = 0;
+        while index < text.len() {
+            let rest = &text[index..];
+            let word_len = rest.find(|c: char| !is_word_char(c)).unwrap_or(rest.len());
+            if let Some(len) = quoted_len(rest) {
+                line.push(&rest[..len], start + index, true);
+                index += len;
+            } else if let Some(after) = rest.strip_prefix('\\') {
+                let name_len = after
+                    .find(|c: char| !is_word_char(c))
+                    .unwrap_or(after.len());
+                let name = &after[..name_len];
+                match definition.params.iter().position(|param| param == name) {
+                    Some(param) => line.push(args[param], start + index, false),
+                    None => {
+                        if !name.is_empty() {
+                            let kind = AssemblerErrorKind::UnknownParameter {
+                                name: name.to_string(),
+                                macro_name: definition.name.clone(),
+                            };
+                            let mut error =
+                                file_error(&self.files, definition.file, start + index, kind);
+                            let mut notes = site_notes(&self.files, chain);
+                            notes.append(&mut error.notes);
+                            error.notes = notes;
+                            self.errors.push(error);
+                        }
+                        line.push(&rest[..name_len + 1], start + index, true);
+                    }
+                }
+                index += name_len + 1;
+            } else if word_len > 0 {
+                let word = &rest[..word_len];
+                // `$` starts a register, never a label
+                let register = text[..index].ends_with('$');
+                if !register && definition.labels.iter().any(|label| label == word) {
+                    line.push(&format!("{}{}", word, suffix), start + index, false);
+                } else {
+                    line.push(word, start + index, true);
+                }
+                index += word_len;
+            } else {
+                let len = rest.chars().next().map_or(1, char::len_utf8);
+                line.push(&rest[..len], start + index, true);
+                index += len;
+            }
+        }
+        if line.pieces.is_empty() {
+            line.push("", start, true);
+        }
+        line
+    }
+
+    // What to add to the labels of the next expansion so they are unique.
+    // The number after the last `M` tells expansions apart.
+    fn label_suffix(&mut self, labels: &[String]) -> String {
+        loop {
+            self.expansions += 1;
+            let suffix = format!("M{}", self.expansions);
+            let clashes = labels
+                .iter()
+                .any(|label| self.identifiers.contains(&format!("{}{}", label, suffix)));
+            if !clashes {
+                return suffix;
+            }
+        }
+    }
+
+    fn defined_here(&self, definition: &Macro) -> ErrorNote {
+        let message = format!("macro {} is defined here", definition.name);
+        note(&self.files, definition.file, definition.offset, message)
+    }
+
+    fn error(&mut self, file: usize, offset: usize, kind: AssemblerErrorKind) {
+        let error = file_error(&self.files, file, offset, kind);
+        self.errors.push(error);
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// The name of the directive `line` is, if it is one
+fn directive_name(line: &str) -> Option<&str> {
+    let rest = line.trim_start().strip_prefix('.')?;
+    let len = rest.find(|c: char| !is_word_char(c)).unwrap_or(rest.len());
+    Some(&rest[..len])
+}
+
+// How much of `line` is a label declaration, `:` included
+fn label_len(line: &str) -> Option<usize> {
+    let rest = line.trim_start();
+    if !rest.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        return None;
+    }
+    let len = rest.find(|c: char| !is_word_char(c)).unwrap_or(rest.len());
+    if rest[len..].starts_with(':') {
+        Some(line.len() - rest.len() + len + 1)
+    } else {
+        None
+    }
+}
+
+// The name and parameters after `.macro`, separated by commas or spaces
+fn macro_header(header: &str) -> Option<(String, Vec<String>)> {
+    if !header.is_empty() && !header.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let mut words = header
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|word| !word.is_empty());
+    let name = words.next()?;
+    let params: Vec<String> = words.map(str::to_string).collect();
+    let is_name = |word: &str| {
+        word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && word.chars().all(is_word_char)
+    };
+    let unique = params
+        .iter()
+        .enumerate()
+        .all(|(index, param)| !params[..index].contains(param));
+    if is_name(name) && params.iter().all(|param| is_name(param)) && unique {
+        Some((name.to_string(), params))
+    } else {
+        None
+    }
+}
+
+// The comma separated arguments of an invocation; commas in strings and
+// character literals do not separate
+fn split_arguments(text: &str) -> Vec<&str> {
+    if text.trim().is_empty() {
+        return vec![];
+    }
+    let mut args = vec![];
+    let mut arg_start = 0;
+    let mut index = 0;
+    while index < text.len() {
+        let rest = &text[index..];
+        if let Some(len) = quoted_len(rest) {
+            index += len;
+        } else if rest.starts_with(',') {
+            args.push(text[arg_start..index].trim());
+            index += 1;
+            arg_start = index;
+        } else {
+            index += rest.chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    args.push(text[arg_start..].trim());
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(source: &str) -> (Expansion, Vec<AssemblerError>) {
+        expand_source("test.iasm", source, &[])
+    }
+
+    #[test]
+    fn test_expand_macros() {
+        let source = ".macro inc2 r\ninc \\r\ninc \\r\n.endm\nhlt\ninc2 $3\n";
+        let (expansion, errors) = expand(source);
+        assert_eq!(errors, vec![]);
+        assert_eq!(expansion.text, "\n\n\n\nhlt\ninc $3\ninc $3\n");
+        // `$3` in the first expansion comes from `\r` in the first body line
+        assert_eq!(expansion.source_offset(12), (0, 18));
+        assert_eq!(expansion.source_offset(8), (0, 14));
+        assert_eq!(expansion.source_offset(19), (0, 25));
+        assert_eq!(expansion.source_offset(5), (0, 35));
+
+        let (expansion, _) = expand("hlt\n");
+        assert_eq!(expansion.text, "hlt\n");
+        assert_eq!(expansion.source_offset(2), (0, 2));
+    }
+
+    #[test]
+    fn test_local_labels() {
+        let source = ".macro wait\nloop: jmp @loop\n.endm\nwait\nstart: wait\nloopM1: hlt\n";
+        let (expansion, errors) = expand(source);
+        assert_eq!(errors, vec![]);
+        assert_eq!(
+            expansion.text,
+            "\n\n\nloopM2: jmp @loopM2\nstart:\nloopM3: jmp @loopM3\nloopM1: hlt\n"
+        );
+    }
+
+    #[test]
+    fn test_nested_macros() {
+        let source =
+            ".macro one r\ninc \\r\n.endm\n.macro two a, b\none \\a\none \\b\n.endm\ntwo $1, $2\n";
+        let (expansion, errors) = expand(source);
+        assert_eq!(errors, vec![]);
+        assert!(expansion.text.ends_with("inc $1\ninc $2\n"));
+
+        let (_, errors) = expand(".macro again\nagain\nagain\n.endm\nagain\n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].message(),
+            format!(
+                "expanding again nests macros more than {} deep",
+                MAX_MACRO_DEPTH
+            )
+        );
+        assert_eq!((errors[0].line, errors[0].column), (2, 1));
+    }
+
+    #[test]
+    fn test_expansion_limit() {
+        // each macro uses the one before twice, so m20 would be 2^20 lines
+        let mut source = ".macro m0\ninc $0\n.endm\n".to_string();
+        for n in 1..=20 {
+            source += &format!(".macro m{}\nm{}\nm{}\n.endm\n", n, n - 1, n - 1);
+        }
+        source += "hlt\n  m20\nm4\n";
+        let (expansion, errors) = expand(&source);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].message(),
+            format!(
+                "expanding m20 writes out more than {} lines",
+                MAX_EXPANDED_LINES
+            )
+        );
+        assert_eq!((errors[0].line, errors[0].column), (85, 3));
+        // the limit is per invocation, so later ones still expand
+        assert!(expansion.text.ends_with(&"inc $0\n".repeat(16)));
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let messages = |source| -> Vec<String> {
+            expand(source)
+                .1
+                .iter()
+                .map(|error| error.message())
+                .collect()
+        };
+        assert_eq!(
+            messages(".macro m a\ninc \\b\n.endm\nm $1\nm\n"),
+            vec![
+                "macro m has no parameter named b",
+                "macro m expects 1 argument, found 0"
+            ]
+        );
+        assert_eq!(
+            messages(".macro m\n.include \"x.iasm\"\n.endm\nm\n.include x.iasm\n"),
+            vec![
+                ".include cannot be used inside a macro",
+                ".include expects a file name in quotes"
+            ]
+        );
+        assert_eq!(
+            messages(".macro m\n.macro n\n.endm\n.endm\n.macro m\n.endm\n.macro\n.macro x\n"),
+            vec![
+                "macros cannot be defined inside other macros",
+                ".endm without a .macro",
+                "macro m is already defined",
+                ".macro expects a name and parameter names",
+                ".macro x is never closed with .endm",
+            ]
+        );
+        let (_, errors) = expand(".macro m a, b\n.endm\nm $1\n");
+        assert_eq!(
+            errors[0].to_string(),
+            "test.iasm:3:1: error: macro m expects 2 arguments, found 1\n\
+             3 | m $1\n  | ^\n\
+             test.iasm:1:1: note: macro m is defined here\n\
+             1 | .macro m a, b\n  | ^"
+        );
+    }
+
+    #[test]
+    fn test_split_arguments() {
+        assert_eq!(split_arguments(""), Vec::<&str>::new());
+        assert_eq!(split_arguments(" $1, #2 + 3 "), vec!["$1", "#2 + 3"]);
+        assert_eq!(split_arguments(" \"a,b\", #','"), vec!["\"a,b\"", "#','"]);
+    }
+}
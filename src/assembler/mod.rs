@@ -1,10 +1,23 @@
+use assembler_errors::{line_and_column, AssemblerError, AssemblerErrorKind, OperandError};
+use expression_parsers::Expression;
+use macro_parsers::{expand_source, Expansion};
 use nom::types::CompleteStr;
-use program_parsers::{program, Program};
+use program_parsers::{program_with_errors, Program};
+use std::path::PathBuf;
 
+use crate::config::{VmConfig, DEFAULT_REGISTER_COUNT};
+use crate::debuginfo::{canonical_file_name, DebugInfo, LineEntry};
+use crate::executable::{Executable, ExecutableSymbol};
 use crate::instruction::Opcode;
+use crate::memory::{CODE_BASE, CODE_LIMIT, INSTRUCTION_WIDTH, RODATA_BASE, RODATA_LIMIT};
+pub mod assembler_errors;
+pub mod comment_parsers;
 pub mod directive_parsers;
+pub mod expression_parsers;
+pub mod include_parsers;
 pub mod instruction_parsers;
 pub mod label_parsers;
+pub mod macro_parsers;
 pub mod opcode_parsers;
 pub mod operand_parsers;
 pub mod program_parsers;
@@ -13,17 +26,45 @@ pub mod register_parsers;
 #[derive(Debug, PartialEq)]
 pub enum Token {
     Op { code: Opcode },
+    // a mnemonic that is not in the opcode table
+    UnknownOp { name: String },
     Register { reg_num: u8 },
+    // `$` and a number too big to encode, with how many bytes of the source
+    // are left from where it starts
+    RegisterOutOfRange { number: String, remaining: usize },
     IntegerOperand { value: i32 },
     LabelDeclaration { name: String },
     LabelUsage { name: String },
     Directive { name: String },
+    StringOperand { value: String },
+    // a `#` operand that is more than a plain number
+    Expression { expr: Expression },
+    // the name a `.equ` or `.define` directive declares
+    ConstantDeclaration { name: String },
 }
 
 #[derive(Debug)]
 pub struct Assembler {
     pub phase: AssemblerPhase,
     pub symbols: SymbolTable,
+    register_count: usize, // registers the program may use, from the VM config
+    // the name of the file being assembled, when executables should carry
+    // debug information naming it
+    source_name: Option<String>,
+    // where to look for files `.include`s name, after the directory of the
+    // file including them
+    include_paths: Vec<PathBuf>,
+    // where each instruction of the last program assembled came from
+    pub debug_info: DebugInfo,
+    // the read-only data the data directives of the last program assembled
+    // put at RODATA_BASE
+    pub ro_data: Vec<u8>,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Assembler {
@@ -31,51 +72,339 @@ impl Assembler {
         Assembler {
             phase: AssemblerPhase::First,
             symbols: SymbolTable::new(),
+            register_count: DEFAULT_REGISTER_COUNT,
+            source_name: None,
+            include_paths: vec![],
+            debug_info: DebugInfo::new(),
+            ro_data: vec![],
         }
     }
 
-    pub fn assemble(&mut self, raw: &str) -> Option<Vec<u8>> {
-        match program(CompleteStr(raw)) {
-            Ok((_, program)) => {
-                self.process_first_phase(&program);
-                Some(self.process_second_phase(&program))
-            }
-            Err(e) => {
-                println!("Error: {:?}", e);
-                None
-            }
+    // An assembler for programs that will run on a VM created with `config`
+    pub fn with_config(config: &VmConfig) -> Assembler {
+        Assembler {
+            register_count: config.register_count(),
+            ..Assembler::new()
         }
     }
 
-    fn process_first_phase(&mut self, program: &Program) {
-        self.extract_labels(program);
+    // Names the file being assembled in the line table, and has
+    // `assemble_executable` include a debug section
+    pub fn with_source_name(mut self, name: &str) -> Assembler {
+        self.source_name = Some(name.to_string());
+        self
+    }
+
+    // Adds a directory to look for included files in, after the ones added
+    // before it
+    pub fn with_include_path<P: Into<PathBuf>>(mut self, path: P) -> Assembler {
+        self.include_paths.push(path.into());
+        self
+    }
+
+    // Assembles `raw` into bytecode, or reports every problem found in it.
+    // Any data the program declares is left in `ro_data`.
+    pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let (expansion, mut errors) = expand_source(self.source_name(), raw, &self.include_paths);
+        // everything past here works on the expanded source, and its errors
+        // are moved back to the files as written
+        let text = expansion.text.as_str();
+        let (program, unparsed) = program_with_errors(CompleteStr(text));
+        let mut found: Vec<AssemblerError> = unparsed
+            .iter()
+            .map(|remaining| {
+                AssemblerError::new(
+                    self.source_name(),
+                    text,
+                    text.len() - remaining,
+                    AssemblerErrorKind::ExpectedInstruction,
+                )
+            })
+            .collect();
+        found.extend(self.invalid_registers(text, &program));
+        self.debug_info = self.line_table(&expansion, &program);
+        let data_offsets = self.process_first_phase(text, &program, &mut found);
+        let bytecode = self.process_second_phase(text, &program, &data_offsets, &mut found);
+        errors.extend(found.into_iter().map(|error| expansion.relocate(error)));
+        if errors.is_empty() {
+            Ok(bytecode)
+        } else {
+            errors.sort_by_key(|error| (error.line, error.column));
+            Err(errors)
+        }
+    }
+
+    // Assembles `raw` into an executable file, starting at the `main` label if
+    // the program has one and at its first instruction otherwise
+    pub fn assemble_executable(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let code = self.assemble(raw)?;
+        let entry = self.symbols.symbol_value("main").unwrap_or(0);
+        let mut executable = Executable::new(code, self.ro_data.clone(), entry);
+        if self.source_name.is_some() {
+            executable.debug_info = Some(self.debug_info.clone());
+        }
+        executable.symbols = self.labels();
+        Ok(executable.to_bytes())
+    }
+
+    // The labels of the last program assembled and their addresses
+    pub fn labels(&self) -> Vec<ExecutableSymbol> {
+        self.symbols
+            .symbols
+            .iter()
+            .filter(|symbol| matches!(symbol.symbol_type, SymbolType::Label))
+            .map(|symbol| ExecutableSymbol {
+                name: symbol.name.clone(),
+                address: symbol.value as u32,
+            })
+            .collect()
+    }
+
+    // The file name errors and debug information refer to
+    fn source_name(&self) -> &str {
+        self.source_name.as_deref().unwrap_or("<input>")
+    }
+
+    // Maps each instruction to the line and column it starts at, in the
+    // macro definition for instructions a macro expanded to
+    fn line_table(&self, expansion: &Expansion, program: &Program) -> DebugInfo {
+        let lines = program
+            .instructions
+            .iter()
+            .zip(&program.remaining)
+            .filter(|(instruction, _)| !instruction.is_directive())
+            .enumerate()
+            .map(|(index, (_, remaining))| {
+                let (file, offset) = expansion.source_offset(expansion.text.len() - remaining);
+                let (line, column) = line_and_column(&expansion.files[file].text, offset);
+                LineEntry {
+                    address: (CODE_BASE + index * INSTRUCTION_WIDTH) as u32,
+                    file: file as u16,
+                    line,
+                    column,
+                }
+            })
+            .collect();
+        DebugInfo {
+            files: expansion
+                .files
+                .iter()
+                .map(|file| canonical_file_name(&file.name))
+                .collect(),
+            lines,
+        }
+    }
+
+    fn process_first_phase(
+        &mut self,
+        raw: &str,
+        program: &Program,
+        errors: &mut Vec<AssemblerError>,
+    ) -> Vec<Option<usize>> {
+        let data_offsets = self.extract_labels(raw, program, errors);
         self.phase = AssemblerPhase::Second;
+        data_offsets
     }
 
-    fn process_second_phase(&self, program: &Program) -> Vec<u8> {
+    // Assembles the instructions into bytecode and the data directives into
+    // `ro_data`, each directive at the offset the first phase gave it
+    fn process_second_phase(
+        &mut self,
+        raw: &str,
+        program: &Program,
+        data_offsets: &[Option<usize>],
+        errors: &mut Vec<AssemblerError>,
+    ) -> Vec<u8> {
         let mut bytecode = vec![];
-        for instruction in &program.instructions {
-            let mut bytes = instruction.to_bytes(&self.symbols);
-            println!("{:?}", bytes);
-            bytecode.append(&mut bytes);
+        let mut ro_data = vec![];
+        let items = program.instructions.iter().zip(&program.remaining);
+        for ((instruction, remaining), data_offset) in items.zip(data_offsets) {
+            let result = match data_offset {
+                Some(offset) => instruction.data_bytes(&self.symbols).map(|bytes| {
+                    ro_data.resize(*offset, 0);
+                    ro_data.extend(bytes);
+                }),
+                // directives the first phase could not lay out are already reported
+                None if instruction.is_directive() => continue,
+                None => instruction
+                    .to_bytes(&self.symbols)
+                    .map(|mut bytes| bytecode.append(&mut bytes)),
+            };
+            if let Err(error) = result {
+                errors.push(self.operand_error(raw, *remaining, error));
+            }
         }
+        self.ro_data = ro_data;
         bytecode
     }
 
-    fn extract_labels(&mut self, program: &Program) {
-        let mut address = 0;
-        for instruction in &program.instructions {
-            if instruction.is_label() {
-                match instruction.label_name() {
-                    Some(name) => {
-                        let symbol = Symbol::new(name, SymbolType::Label, address);
+    // Places an error about part of the instruction `remaining` bytes from the
+    // end of `raw`
+    fn operand_error(&self, raw: &str, remaining: usize, error: OperandError) -> AssemblerError {
+        let remaining = error.remaining.unwrap_or(remaining);
+        AssemblerError::new(self.source_name(), raw, raw.len() - remaining, error.kind)
+    }
+
+    // Reports every register operand outside the configured register count
+    fn invalid_registers(&self, raw: &str, program: &Program) -> Vec<AssemblerError> {
+        let mut errors = vec![];
+        for (instruction, remaining) in program.instructions.iter().zip(&program.remaining) {
+            let operands = [
+                &instruction.operand1,
+                &instruction.operand2,
+                &instruction.operand3,
+            ];
+            for operand in operands {
+                if let Some(Token::Register { reg_num }) = operand {
+                    if *reg_num as usize >= self.register_count {
+                        errors.push(AssemblerError::new(
+                            self.source_name(),
+                            raw,
+                            raw.len() - remaining,
+                            AssemblerErrorKind::InvalidRegister {
+                                register: *reg_num,
+                                count: self.register_count,
+                            },
+                        ));
+                    }
+                }
+            }
+        }
+        errors
+    }
+
+    // Gives every label the address of what it is declared on, so the second
+    // phase can resolve uses of labels declared after them, and evaluates
+    // constants in the order they are declared. A constant naming something
+    // declared after it is evaluated once every label has its address, so
+    // `.equ LEN end - start` works ahead of `end:`. Instructions are laid out
+    // from CODE_BASE and data from RODATA_BASE; the offset into the data
+    // segment of each data directive is returned, None for everything else
+    // and for directives that are malformed.
+    fn extract_labels(
+        &mut self,
+        raw: &str,
+        program: &Program,
+        errors: &mut Vec<AssemblerError>,
+    ) -> Vec<Option<usize>> {
+        self.symbols = SymbolTable::new();
+        let mut code_len = 0;
+        let mut data_len: usize = 0;
+        let mut data_offsets = vec![];
+        // constants left until the labels are known
+        let mut deferred: Vec<(&str, &Expression, usize)> = vec![];
+        for (instruction, remaining) in program.instructions.iter().zip(&program.remaining) {
+            let declared = |symbols: &SymbolTable, name: &str| {
+                symbols.value(name).is_some() || deferred.iter().any(|(other, ..)| *other == name)
+            };
+            if let Some((name, expr)) = instruction.constant() {
+                data_offsets.push(None);
+                if declared(&self.symbols, name) {
+                    let kind = AssemblerErrorKind::DuplicateConstant {
+                        name: name.to_string(),
+                    };
+                    errors.push(self.operand_error(raw, *remaining, kind.into()));
+                    continue;
+                }
+                match expr.evaluate(&self.symbols) {
+                    Ok(value) => {
+                        let symbol = Symbol::new(name.to_string(), SymbolType::Constant, value);
                         self.symbols.add_symbol(symbol);
                     }
-                    None => {}
+                    Err(OperandError {
+                        kind: AssemblerErrorKind::UndefinedName { .. },
+                        ..
+                    }) => deferred.push((name, expr, *remaining)),
+                    Err(error) => errors.push(self.operand_error(raw, *remaining, error)),
+                }
+                continue;
+            }
+            let address = if instruction.is_directive() {
+                let layout = instruction
+                    .data_layout(&self.symbols)
+                    .map_err(|error| match error.kind {
+                        AssemblerErrorKind::UndefinedName { name }
+                            if declared(&self.symbols, &name) =>
+                        {
+                            OperandError {
+                                kind: AssemblerErrorKind::ConstantNotYetKnown { name },
+                                ..error
+                            }
+                        }
+                        _ => error,
+                    })
+                    .and_then(|(alignment, len)| {
+                        let offset = data_len.div_ceil(alignment) * alignment;
+                        if offset + len > RODATA_LIMIT - RODATA_BASE {
+                            return Err(AssemblerErrorKind::DataTooLarge.into());
+                        }
+                        Ok((offset, len))
+                    });
+                match layout {
+                    Ok((offset, len)) => {
+                        data_len = offset + len;
+                        data_offsets.push(Some(offset));
+                        RODATA_BASE + offset
+                    }
+                    Err(error) => {
+                        errors.push(self.operand_error(raw, *remaining, error));
+                        data_offsets.push(None);
+                        continue;
+                    }
+                }
+            } else {
+                // reported once, at the first instruction that does not fit
+                if code_len == CODE_LIMIT - CODE_BASE {
+                    let kind = AssemblerErrorKind::CodeTooLarge;
+                    errors.push(self.operand_error(raw, *remaining, kind.into()));
+                }
+                code_len += INSTRUCTION_WIDTH;
+                data_offsets.push(None);
+                CODE_BASE + code_len - INSTRUCTION_WIDTH
+            };
+            if let Some(name) = instruction.label_name() {
+                if declared(&self.symbols, &name) {
+                    errors.push(AssemblerError::new(
+                        self.source_name(),
+                        raw,
+                        raw.len() - remaining,
+                        AssemblerErrorKind::DuplicateLabel { name },
+                    ));
+                } else {
+                    let symbol = Symbol::new(name, SymbolType::Label, address as i64);
+                    self.symbols.add_symbol(symbol);
                 }
             }
-            address += 4;
         }
+        // deferred constants may use each other, so they are evaluated in
+        // rounds until a round adds none
+        loop {
+            let before = deferred.len();
+            deferred.retain(|(name, expr, _)| match expr.evaluate(&self.symbols) {
+                Ok(value) => {
+                    let symbol = Symbol::new(name.to_string(), SymbolType::Constant, value);
+                    self.symbols.add_symbol(symbol);
+                    false
+                }
+                Err(_) => true,
+            });
+            if deferred.len() == before {
+                break;
+            }
+        }
+        for (name, expr, remaining) in &deferred {
+            if let Err(mut error) = expr.evaluate(&self.symbols) {
+                if let AssemblerErrorKind::UndefinedName { name: other } = &error.kind {
+                    if deferred.iter().any(|(deferred, ..)| deferred == other) {
+                        error.kind = AssemblerErrorKind::CircularConstant {
+                            name: name.to_string(),
+                        };
+                    }
+                }
+                errors.push(self.operand_error(raw, *remaining, error));
+            }
+        }
+        data_offsets
     }
 }
 
@@ -88,16 +417,16 @@ pub enum AssemblerPhase {
 #[derive(Debug)]
 pub struct Symbol {
     name: String,
-    offset: u32,
+    value: i64, // the address of a label
     symbol_type: SymbolType,
 }
 
 impl Symbol {
-    pub fn new(name: String, symbol_type: SymbolType, offset: u32) -> Symbol {
+    pub fn new(name: String, symbol_type: SymbolType, value: i64) -> Symbol {
         Symbol {
             name,
             symbol_type,
-            offset,
+            value,
         }
     }
 }
@@ -105,6 +434,7 @@ impl Symbol {
 #[derive(Debug)]
 pub enum SymbolType {
     Label,
+    Constant, // declared with `.equ` or `.define`
 }
 
 #[derive(Debug)]
@@ -112,6 +442,12 @@ pub struct SymbolTable {
     symbols: Vec<Symbol>,
 }
 
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SymbolTable {
     pub fn new() -> SymbolTable {
         SymbolTable { symbols: vec![] }
@@ -121,14 +457,23 @@ impl SymbolTable {
         self.symbols.push(s);
     }
 
+    // The address of a label
     pub fn symbol_value(&self, s: &str) -> Option<u32> {
         for symbol in &self.symbols {
-            if symbol.name == s {
-                return Some(symbol.offset);
+            if symbol.name == s && matches!(symbol.symbol_type, SymbolType::Label) {
+                return Some(symbol.value as u32);
             }
         }
         None
     }
+
+    // The value of a label or constant
+    pub fn value(&self, s: &str) -> Option<i64> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == s)
+            .map(|symbol| symbol.value)
+    }
 }
 
 #[cfg(test)]
@@ -144,22 +489,521 @@ mod tests {
         sym.add_symbol(new_symbol);
         assert_eq!(sym.symbols.len(), 1);
         let v = sym.symbol_value("test");
-        assert_eq!(true, v.is_some());
+        assert!(v.is_some());
         let v = v.unwrap();
         assert_eq!(v, 12);
         let v = sym.symbol_value("does_not_exist");
-        assert_eq!(v.is_some(), false);
+        assert!(v.is_none());
+    }
+
+    #[test]
+    fn test_assemble_checks_registers() {
+        let config = VmConfig::builder().register_count(4).build().unwrap();
+        let mut asm = Assembler::with_config(&config);
+        assert!(asm.assemble("load $3 #1\n").is_ok());
+        let mut asm = Assembler::with_config(&config);
+        let errors = asm
+            .assemble("load $3 #1\ninc $4\nload $9 #2\n")
+            .unwrap_err();
+        let registers: Vec<AssemblerErrorKind> = errors.into_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            registers,
+            vec![
+                AssemblerErrorKind::InvalidRegister {
+                    register: 4,
+                    count: 4
+                },
+                AssemblerErrorKind::InvalidRegister {
+                    register: 9,
+                    count: 4
+                },
+            ]
+        );
+
+        // too big for an operand at all, reported where it is written
+        let errors = asm
+            .assemble("hlt\nload $256 #1\nadd $0 $1 $99999\n")
+            .unwrap_err();
+        let messages: Vec<(u32, u32, String)> = errors
+            .iter()
+            .map(|error| (error.line, error.column, error.message()))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (2, 6, "register 256 out of range".to_string()),
+                (3, 11, "register 99999 out of range".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_executable() {
+        let mut asm = Assembler::new();
+        let bytes = asm.assemble_executable("load $0 #100\nhlt\n").unwrap();
+        let executable = Executable::from_bytes(&bytes).unwrap();
+        assert_eq!(executable.code, vec![0, 0, 0, 100, 5, 0, 0, 0]);
+        assert_eq!(executable.entry, 0);
+        let mut vm = VM::new();
+        vm.load_executable(&bytes).unwrap();
+        vm.run();
+        assert_eq!(vm.registers[0], 100);
+    }
+
+    #[test]
+    fn test_reports_every_error() {
+        let mut asm = Assembler::new().with_source_name("bad.iasm");
+        let errors = asm.assemble("load $0 #1\n  ?? $0\nhlt\n%\n").unwrap_err();
+        let rendered: Vec<String> = errors.iter().map(AssemblerError::to_string).collect();
+        assert_eq!(
+            rendered,
+            vec![
+                "bad.iasm:2:3: error: expected an instruction\n2 |   ?? $0\n  |   ^",
+                "bad.iasm:4:1: error: expected an instruction\n4 | %\n  | ^",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_checks_operands() {
+        let mut asm = Assembler::new();
+        let errors = asm
+            .assemble("add $0\nhalt\nload $1 #2\njmp #5\nadd $0 $1 $2 $3\n")
+            .unwrap_err();
+        let messages: Vec<(u32, String)> = errors
+            .iter()
+            .map(|error| (error.line, error.message()))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (1, "add expects 3 registers, found 1".to_string()),
+                (2, "no instruction named halt".to_string()),
+                (4, "jmp expects 1 register, found 1 value".to_string()),
+                (5, "add expects 3 registers, found 4".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let mut asm = Assembler::new().with_source_name("count.iasm");
+        asm.assemble("load $0 #1\n\n  inc $0\nhlt\n").unwrap();
+        let debug_info = &asm.debug_info;
+        assert_eq!(debug_info.files, vec!["count.iasm".to_string()]);
+        let positions: Vec<(u32, u32, u32)> = debug_info
+            .lines
+            .iter()
+            .map(|entry| (entry.address, entry.line, entry.column))
+            .collect();
+        assert_eq!(positions, vec![(0, 1, 1), (4, 3, 3), (8, 4, 1)]);
+
+        let bytes = asm.assemble_executable("load $0 #1\nhlt\n").unwrap();
+        let executable = Executable::from_bytes(&bytes).unwrap();
+        assert_eq!(executable.debug_info.unwrap().lines.len(), 2);
+        let bytes = Assembler::new().assemble_executable("hlt\n").unwrap();
+        assert_eq!(Executable::from_bytes(&bytes).unwrap().debug_info, None);
+    }
+
+    #[test]
+    fn test_data_directives() {
+        let mut asm = Assembler::new().with_source_name("data.iasm");
+        let source = "main: load $0 @value\n  loadm $0 $1\n  load $2 @msg\n  loadm $2 $3\n  hlt\n\
+                      msg: .asciiz \"hi\"\nvalue: .word #70000\ntable: .half @msg @value\n";
+        let bytes = asm.assemble_executable(source).unwrap();
+        assert_eq!(
+            asm.ro_data,
+            vec![b'h', b'i', 0, 0, 0, 1, 0x11, 0x70, 0x80, 0, 0x80, 4]
+        );
+        assert_eq!(
+            asm.symbols.symbol_value("value"),
+            Some(RODATA_BASE as u32 + 4)
+        );
+        assert_eq!(
+            asm.symbols.symbol_value("table"),
+            Some(RODATA_BASE as u32 + 8)
+        );
+        // only instructions have lines in the line table
+        assert_eq!(asm.debug_info.lines.len(), 5);
+
+        let mut vm = VM::new();
+        vm.load_executable(&bytes).unwrap();
+        vm.run();
+        assert_eq!(vm.registers[1], 70000);
+        assert_eq!(vm.registers[3], i32::from_be_bytes([b'h', b'i', 0, 0]));
+
+        let errors = asm
+            .assemble(".align #3\n.byte #300\n.text\nhlt\n")
+            .unwrap_err();
+        let messages: Vec<(u32, String)> = errors
+            .iter()
+            .map(|error| (error.line, error.message()))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (1, "alignment 3 is not a power of two".to_string()),
+                (2, "300 does not fit in .byte".to_string()),
+                (3, "no directive named .text".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_constants() {
+        let mut asm = Assembler::new();
+        let source = ".equ BUF_SIZE 16\n.define LAST #BUF_SIZE - 1\n\
+                      start: load $0 #(BUF_SIZE * 4) + 1\n  load $1 #end - start\n\
+                      end: load $2 #LAST << 2 | 1\nbuf: .space #BUF_SIZE\n";
+        let program = asm.assemble(source).unwrap();
+        assert_eq!(&program[..4], &[0, 0, 0, 65]);
+        assert_eq!(&program[4..8], &[0, 1, 0, 8]);
+        assert_eq!(&program[8..12], &[0, 2, 0, 61]);
+        assert_eq!(asm.ro_data.len(), 16);
+
+        let source = "load $0 #1 + (70000 * 70000)\nload $1 #2 / (1 - 1)\n\
+                      load $2 #70000\n.equ A 1\n.equ A 2\n.word #B\n";
+        let errors = asm.assemble(source).unwrap_err();
+        let messages: Vec<(u32, u32, String)> = errors
+            .iter()
+            .map(|error| (error.line, error.column, error.message()))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (1, 15, "result does not fit in 32 bits".to_string()),
+                (2, 15, "division by zero".to_string()),
+                (3, 1, "70000 does not fit in a 16 bit immediate".to_string()),
+                (5, 1, "A is already declared".to_string()),
+                (6, 8, "no label or constant named B".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_constants_using_later_labels() {
+        let mut asm = Assembler::new();
+        let source = ".equ LEN end - start
+.equ WORDS LEN / 4
+                      start: load $0 #LEN
+load $1 #WORDS
+end: hlt
+";
+        let program = asm.assemble(source).unwrap();
+        assert_eq!(&program[..8], &[0, 0, 0, 8, 0, 1, 0, 2]);
+        assert_eq!(asm.symbols.value("WORDS"), Some(2));
+
+        let source = ".equ A B + 1
+.equ B A
+.equ C end
+.space #C
+                      .equ D missing
+end: hlt
+";
+        let errors = asm.assemble(source).unwrap_err();
+        let messages: Vec<(u32, String)> = errors
+            .iter()
+            .map(|error| (error.line, error.message()))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (1, "A depends on itself".to_string()),
+                (2, "B depends on itself".to_string()),
+                (
+                    4,
+                    "C uses labels declared after it, so it is not known here".to_string()
+                ),
+                (5, "no label or constant named missing".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_code_too_large() {
+        let mut asm = Assembler::new();
+        let fits = "hlt\n".repeat((CODE_LIMIT - CODE_BASE) / INSTRUCTION_WIDTH);
+        assert!(asm.assemble(&fits).is_ok());
+        let errors = asm.assemble(&(fits + "hlt\nhlt\n")).unwrap_err();
+        let messages: Vec<(u32, String)> = errors
+            .iter()
+            .map(|error| (error.line, error.message()))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![(
+                8193,
+                "instructions do not fit in the code segment".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_malformed_expressions() {
+        let mut asm = Assembler::new();
+        let source = "load $0 #(1 + )
+load $1 #1 +
+load $2 #(2 * 3
+.equ N #4 *
+.word #)
+";
+        let errors = asm.assemble(source).unwrap_err();
+        let messages: Vec<(u32, u32, String)> = errors
+            .iter()
+            .map(|error| (error.line, error.column, error.message()))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (1, 15, "expected a value".to_string()),
+                (2, 12, "+ has no value after it".to_string()),
+                (3, 10, "( is never closed".to_string()),
+                (4, 11, "* has no value after it".to_string()),
+                (5, 8, "expected a value".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_literals() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble("load $0 #0xFFFF\nload $1 #0x1_F\nload $2 #'A'\nload $3 #-5 + 10\nhlt\n")
+            .unwrap();
+        assert_eq!(&program[..12], &[0, 0, 255, 255, 0, 1, 0, 31, 0, 2, 0, 65]);
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(&vm.registers[..4], &[65535, 31, 65, 5]);
+        let errors = asm.assemble("load $0 #99999999999\n").unwrap_err();
+        assert_eq!(
+            (errors[0].column, errors[0].message()),
+            (10, "number does not fit in 32 bits".to_string())
+        );
+        // negative immediates are 16 bit two's complement, zero-extended
+        let program = asm.assemble("load $0 #-5\nload $1 #-32768\nhlt\n").unwrap();
+        assert_eq!(&program[..8], &[0, 0, 0xFF, 0xFB, 0, 1, 0x80, 0]);
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(&vm.registers[..2], &[65531, 32768]);
+        for (source, message) in [
+            (
+                "load $0 #-32769\n",
+                "-32769 does not fit in a 16 bit immediate",
+            ),
+            ("load $0 #1_\n", "invalid number 1_"),
+            ("load $0 #1__0\n", "invalid number 1__0"),
+            ("load $0 #0x_1\n", "invalid number 0x_1"),
+            ("load $0 #_1\n", "no label or constant named _1"),
+        ] {
+            let errors = asm.assemble(source).unwrap_err();
+            assert_eq!(
+                (errors[0].column, errors[0].message()),
+                (10, message.to_string()),
+                "{}",
+                source
+            );
+        }
+        // a plain number is reported at its instruction
+        let errors = asm.assemble("load $0 #65536\n").unwrap_err();
+        assert_eq!(
+            (errors[0].column, errors[0].message()),
+            (1, "65536 does not fit in a 16 bit immediate".to_string())
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        let mut asm = Assembler::new();
+        let source = ".equ N #2 ; two\n; counts down\n\nloop: // top\nload $0 /* n */ #N\n\n/* a\nblock */ hlt ; end\n";
+        let program = asm.assemble(source).unwrap();
+        assert_eq!(program, vec![0, 0, 0, 2, 5, 0, 0, 0]);
+        assert_eq!(asm.symbols.symbol_value("loop"), Some(0));
+        let errors = asm.assemble("hlt\nhlt /* open\nhlt\n").unwrap_err();
+        assert_eq!(
+            (errors[0].line, errors[0].column, errors[0].message()),
+            (2, 5, "comment is never closed".to_string())
+        );
+        // characters outside ASCII are errors where they are, not a panic
+        let program = asm.assemble("hlt ; é\n").unwrap();
+        assert_eq!(program, vec![5, 0, 0, 0]);
+        let errors = asm.assemble("hlt\nload $0 #1 é\n").unwrap_err();
+        assert_eq!((errors[0].line, errors[0].column), (2, 1));
+
+        let (program, _) = program_with_errors(CompleteStr(source));
+        let texts: Vec<&str> = program.comments.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec![
+                "; two",
+                "; counts down",
+                "// top",
+                "/* n */",
+                "/* a\nblock */",
+                "; end"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_includes() {
+        let dir = std::env::temp_dir().join(format!("iridium-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        let write = |name: &str, source: &str| std::fs::write(dir.join(name), source).unwrap();
+        let path = |name: &str| dir.join(name).display().to_string();
+        let canonical = |name: &str| canonical_file_name(&path(name));
+        write(
+            "defs.iasm",
+            ".equ N #3\n.macro twice r\ninc \\r\ninc \\r\n.endm\n",
+        );
+        write("lib/shared.iasm", ".include \"../defs.iasm\"\ninc $2\n");
+        // defs.iasm is only included once, however many times it is named
+        write(
+            "main.iasm",
+            ".include \"defs.iasm\"\n.include \"shared.iasm\"\nload $1 #N\ntwice $1\nhlt\n",
+        );
+        let main = path("main.iasm");
+        let mut asm = Assembler::new()
+            .with_source_name(&main)
+            .with_include_path(dir.join("lib"));
+        let program = asm
+            .assemble(&std::fs::read_to_string(&main).unwrap())
+            .unwrap();
+        assert_eq!(program.len(), 20);
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!((vm.registers[1], vm.registers[2]), (5, 1));
+        assert_eq!(
+            asm.debug_info.files,
+            vec![
+                canonical("main.iasm"),
+                canonical("defs.iasm"),
+                canonical("lib/shared.iasm")
+            ]
+        );
+        // however the path to a file is written, it is found under one name
+        let twice: Vec<usize> = asm
+            .debug_info
+            .addresses_of_line(&canonical("lib/../defs.iasm"), 3)
+            .collect();
+        assert_eq!(twice, vec![8]);
+        let places: Vec<(u16, u32)> = asm
+            .debug_info
+            .lines
+            .iter()
+            .map(|entry| (entry.file, entry.line))
+            .collect();
+        assert_eq!(places, vec![(2, 2), (0, 3), (1, 3), (1, 4), (0, 5)]);
+
+        // a.iasm includes b.iasm, which includes a.iasm again
+        write("a.iasm", ".include \"b.iasm\"\n");
+        write(
+            "b.iasm",
+            "hlt\n.include \"a.iasm\"\n.include \"none.iasm\"\n",
+        );
+        let errors = Assembler::new()
+            .with_source_name(&path("a.iasm"))
+            .assemble(".include \"b.iasm\"\n")
+            .unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            format!(
+                "{b}:2:1: error: a.iasm includes itself\n\
+                 2 | .include \"a.iasm\"\n  | ^\n\
+                 {a}:1:1: note: {b} is included here\n\
+                 1 | .include \"b.iasm\"\n  | ^",
+                a = path("a.iasm"),
+                b = path("b.iasm")
+            )
+        );
+        assert_eq!(errors[1].message(), "cannot find none.iasm to include");
+
+        write("bad.iasm", "load $40 #1\n");
+        let errors = Assembler::new()
+            .with_source_name(&main)
+            .assemble("hlt\n.include \"bad.iasm\"\n")
+            .unwrap_err();
+        assert_eq!(errors[0].file, path("bad.iasm"));
+        assert_eq!(errors[0].notes[0].line, 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_macros() {
+        let mut asm = Assembler::new();
+        // counts \r down to 0 twice, each through its own `loop`
+        let source = ".macro countdown r, from\nload \\r #\\from\nloop: dec \\r\nload $9 @loop\nneq \\r $0\njeq $9\n.endm\ncountdown $1, 3 ; first\ncountdown $2, 5\nhlt\n";
+        let program = asm.assemble(source).unwrap();
+        assert_eq!(program.len(), 44);
+        assert_eq!(asm.symbols.symbol_value("loopM2"), Some(24));
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!((vm.registers[1], vm.registers[2]), (0, 0));
+
+        let errors = Assembler::new()
+            .with_source_name("m.iasm")
+            .assemble(".macro clear r\nload \\r #0\n.endm\nhlt\nclear $40\n")
+            .unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "m.iasm:2:1: error: register $40 does not exist, the VM has 32 registers\n\
+             2 | load \\r #0\n  | ^\n\
+             m.iasm:5:1: note: in expansion of macro clear\n\
+             5 | clear $40\n  | ^"
+        );
     }
 
     #[test]
     fn test_assemble_program() {
         let mut asm = Assembler::new();
+        // counts $0 down to 0, jumping back to `test` through $2
         let test_string =
-            "load $0 #100\nload $1 #1\nload $2 #0\ntest: inc $0\nneq $0 $2\njmpe @test\nhlt";
+            "load $0 #100\nload $1 #0\nload $2 @test\ntest: dec $0\nneq $0 $1\njeq $2\nhlt";
         let program = asm.assemble(test_string).unwrap();
+        assert_eq!(program.len(), 28);
+        assert_eq!(&program[8..12], &[0, 2, 0, 12]);
         let mut vm = VM::new();
-        assert_eq!(program.len(), 21);
         vm.add_bytes(program);
-        assert_eq!(vm.program.len(), 21);
+        vm.run();
+        assert_eq!(vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut asm = Assembler::new();
+        // used before it is declared, and declared on a line of its own
+        let program = asm
+            .assemble("load $0 @end\njmp $0\nhlt\nend:\n  inc $1\n")
+            .unwrap();
+        assert_eq!(&program[0..4], &[0, 0, 0, 12]);
+        assert_eq!(asm.symbols.symbol_value("end"), Some(12));
+
+        // names may use `_` like constants, but not start with a digit
+        let source = "load $2 @loop_end\n_skip: jmp $2\ninc $1\nloop_end: hlt\n";
+        let bytes = asm.assemble_executable(source).unwrap();
+        assert_eq!(asm.symbols.symbol_value("loop_end"), Some(12));
+        assert_eq!(asm.symbols.symbol_value("_skip"), Some(4));
+        let mut vm = VM::new();
+        vm.load_executable(&bytes).unwrap();
+        vm.run();
+        assert_eq!(vm.registers[1], 0);
+        assert!(asm.assemble("1st: hlt\n").is_err());
+
+        let errors = asm.assemble("a: hlt\nload $0 @b\na: hlt\n").unwrap_err();
+        let kinds: Vec<AssemblerErrorKind> = errors.into_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                AssemblerErrorKind::UndefinedLabel {
+                    name: "b".to_string()
+                },
+                AssemblerErrorKind::DuplicateLabel {
+                    name: "a".to_string()
+                },
+            ]
+        );
     }
 }
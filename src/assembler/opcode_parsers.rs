@@ -9,10 +9,14 @@ named!(pub opcode_load<CompleteStr, Token>,
     )
 );
 
+// any word in the mnemonic position, so unknown ones can be reported by name
 named!(pub opcode<CompleteStr, Token>,
     map!(
         alpha1,
-        |opcode_str: CompleteStr| Token::Op { code: Opcode::from(opcode_str) }
+        |opcode_str: CompleteStr| match Opcode::from_mnemonic(&opcode_str) {
+            Some(code) => Token::Op { code },
+            None => Token::UnknownOp { name: opcode_str.to_string() },
+        }
     )
 );
 
@@ -29,25 +33,30 @@ mod tests {
     fn test_opcode_load() {
         // First tests that the opcode is detected and parsed correctly
         let result = opcode_load(CompleteStr("load"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, token) = result.unwrap();
         assert_eq!(token, Token::Op { code: Opcode::LOAD });
         assert_eq!(rest, CompleteStr(""));
 
         // Tests that an invalid opcode isn't recognized
         let result = opcode_load(CompleteStr("aold"));
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
     }
 
     #[test]
     fn test_opcode() {
         let result = opcode(CompleteStr("load"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, token) = result.unwrap();
         assert_eq!(token, Token::Op { code: Opcode::LOAD });
         assert_eq!(rest, CompleteStr(""));
         let result = opcode(CompleteStr("aold"));
         let (_, token) = result.unwrap();
-        assert_eq!(token, Token::Op { code: Opcode::IGL });
+        assert_eq!(
+            token,
+            Token::UnknownOp {
+                name: "aold".to_string()
+            }
+        );
     }
 }
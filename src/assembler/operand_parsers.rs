@@ -1,26 +1,70 @@
-use nom::digit;
 use nom::types::CompleteStr;
+use nom::{multispace, Err, ErrorKind, IResult};
 
+use crate::assembler::expression_parsers::{expression, ExpressionKind};
+use crate::assembler::label_parsers::label_usage;
 use crate::assembler::register_parsers::register;
 use crate::assembler::Token;
 
-named!(pub integer_operand<CompleteStr, Token>,
-    ws!(
-        do_parse!(
-            tag!("#") >>
-            value: digit >>
-            (
-                Token::IntegerOperand {
-                    value: value.parse::<i32>().unwrap()
-                }
-            )
-        )
+// `#` and a constant expression, e.g. #100 or #(SIZE * 4) + 1. Plain numbers
+// that fit in an i32 are kept as they are.
+pub fn integer_operand(input: CompleteStr) -> IResult<CompleteStr, Token> {
+    let (input, _) = opt!(input, multispace)?;
+    let (input, _) = tag!(input, "#")?;
+    let (input, expr) = expression(input)?;
+    let (input, _) = opt!(input, multispace)?;
+    let token = match expr.kind {
+        ExpressionKind::Number(value) if i32::try_from(value).is_ok() => Token::IntegerOperand {
+            value: value as i32,
+        },
+        _ => Token::Expression { expr },
+    };
+    Ok((input, token))
+}
+
+// a value known when assembling: a number, or the address of a label
+named!(pub immediate<CompleteStr, Token>,
+    alt!(
+        integer_operand |
+        label_usage
     )
 );
 
+// a string in double quotes on one line, e.g. "hi\n". The escapes are \n, \t,
+// \0, \\ and \".
+pub fn string_operand(input: CompleteStr) -> IResult<CompleteStr, Token> {
+    let fail = || Err(Err::Error(error_position!(input, ErrorKind::Custom(0))));
+    let mut chars = input.char_indices();
+    if chars.next().map(|(_, c)| c) != Some('"') {
+        return fail();
+    }
+    let mut value = String::new();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => {
+                return Ok((
+                    CompleteStr(&input[index + 1..]),
+                    Token::StringOperand { value },
+                ))
+            }
+            '\\' => value.push(match chars.next() {
+                Some((_, 'n')) => '\n',
+                Some((_, 't')) => '\t',
+                Some((_, '0')) => '\0',
+                Some((_, '\\')) => '\\',
+                Some((_, '"')) => '"',
+                _ => return fail(),
+            }),
+            '\n' => return fail(),
+            c => value.push(c),
+        }
+    }
+    fail()
+}
+
 named!(pub operand<CompleteStr, Token>,
     alt!(
-        integer_operand |
+        immediate |
         register
     )
 );
@@ -28,18 +72,82 @@ named!(pub operand<CompleteStr, Token>,
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::assembler_errors::AssemblerErrorKind;
+    use crate::assembler::expression_parsers::Expression;
 
     #[test]
     fn test_parse_integer_operand() {
         let result = integer_operand(CompleteStr("#123"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, token) = result.unwrap();
         assert_eq!(token, Token::IntegerOperand { value: 123 });
 
-        let result = integer_operand(CompleteStr("#a"));
-        assert_eq!(result.is_ok(), false);
+        // anything after a `#` is an expression, if a malformed one
+        let (_, token) = integer_operand(CompleteStr("#+")).unwrap();
+        assert!(matches!(
+            token,
+            Token::Expression {
+                expr: Expression {
+                    kind: ExpressionKind::Invalid(AssemblerErrorKind::MissingOperand),
+                    ..
+                }
+            }
+        ));
+
+        let (_, token) = integer_operand(CompleteStr("#SIZE * 2")).unwrap();
+        assert!(matches!(token, Token::Expression { .. }));
 
         let result = integer_operand(CompleteStr("123"));
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
+
+        for (source, value) in [
+            ("#0xFF", 255),
+            ("#0b1010", 10),
+            ("#0o17", 15),
+            ("#'A'", 65),
+            ("#'\\n'", 10),
+            ("#1_000_000", 1_000_000),
+        ] {
+            let (_, token) = integer_operand(CompleteStr(source)).unwrap();
+            assert_eq!(token, Token::IntegerOperand { value }, "{}", source);
+        }
+        // negative, out of range and malformed numbers are left for
+        // evaluating to report
+        for source in ["#-5", "#99999999999", "#0b102"] {
+            let (_, token) = integer_operand(CompleteStr(source)).unwrap();
+            assert!(matches!(token, Token::Expression { .. }), "{}", source);
+        }
+        let (_, token) = integer_operand(CompleteStr("#'AB'")).unwrap();
+        assert!(matches!(token, Token::Expression { .. }));
+    }
+
+    #[test]
+    fn test_parse_immediate() {
+        assert_eq!(
+            immediate(CompleteStr("@loop")),
+            Ok((
+                CompleteStr(""),
+                Token::LabelUsage {
+                    name: "loop".to_string()
+                }
+            ))
+        );
+        assert!(immediate(CompleteStr("$1")).is_err());
+    }
+
+    #[test]
+    fn test_parse_string_operand() {
+        assert_eq!(
+            string_operand(CompleteStr("\"hi\\n \\\"x\\\"\" #1")),
+            Ok((
+                CompleteStr(" #1"),
+                Token::StringOperand {
+                    value: "hi\n \"x\"".to_string()
+                }
+            ))
+        );
+        assert!(string_operand(CompleteStr("\"open\n\"")).is_err());
+        assert!(string_operand(CompleteStr("\"\\q\"")).is_err());
+        assert!(string_operand(CompleteStr("hi")).is_err());
     }
 }
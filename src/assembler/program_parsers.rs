@@ -1,5 +1,9 @@
+use nom::multispace;
 use nom::types::CompleteStr;
+use nom::IResult;
 
+use crate::assembler::assembler_errors::OperandError;
+use crate::assembler::comment_parsers::{strip_comments, Comment};
 use crate::assembler::instruction_parsers::{instruction, AssemblerInstruction};
 
 use super::SymbolTable;
@@ -7,24 +11,76 @@ use super::SymbolTable;
 #[derive(Debug, PartialEq)]
 pub struct Program {
     pub instructions: Vec<AssemblerInstruction>,
+    // for each instruction, how many bytes of the source are left from where
+    // it starts, which finds it in whatever text the program was parsed from
+    pub remaining: Vec<usize>,
+    // in source order, placed the same way as the instructions
+    pub comments: Vec<Comment>,
 }
 
 impl Program {
-    pub fn to_bytes(&self) -> Vec<u8> {
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, OperandError> {
         let mut program = vec![];
-        for instruction in &self.instructions {
-            program.append(&mut instruction.to_bytes(&SymbolTable::new()));
+        // data directives assemble into a separate segment, see Assembler
+        for instruction in self.instructions.iter().filter(|i| !i.is_directive()) {
+            program.append(&mut instruction.to_bytes(symbols)?);
         }
-        return program;
+        Ok(program)
     }
 }
 
+fn located_instruction(input: CompleteStr) -> IResult<CompleteStr, (usize, AssemblerInstruction)> {
+    let (input, _) = opt!(input, multispace)?;
+    let remaining = input.len();
+    let (rest, instruction) = instruction(input)?;
+    Ok((rest, (remaining, instruction)))
+}
+
+// Parses as much of `input` as possible, comments included. Wherever no
+// instruction can be parsed the rest of the line is skipped; the second list
+// says how many bytes of the source were left at each of those places.
+pub fn program_with_errors(input: CompleteStr) -> (Program, Vec<usize>) {
+    let (stripped, comments) = strip_comments(&input);
+    let mut input = CompleteStr(&stripped);
+    let mut program = Program {
+        instructions: vec![],
+        remaining: vec![],
+        comments,
+    };
+    let mut errors = vec![];
+    loop {
+        if let Ok((rest, _)) = multispace(input) {
+            input = rest;
+        }
+        if input.is_empty() {
+            break;
+        }
+        match located_instruction(input) {
+            Ok((rest, (remaining, instruction))) => {
+                program.remaining.push(remaining);
+                program.instructions.push(instruction);
+                input = rest;
+            }
+            Err(_) => {
+                errors.push(input.len());
+                let skip = input.find('\n').map_or(input.len(), |newline| newline + 1);
+                input = CompleteStr(&input[skip..]);
+            }
+        }
+    }
+    (program, errors)
+}
+
+// A program without comments, see `program_with_errors` for one with them
 named!(pub program<CompleteStr, Program>,
     do_parse!(
-        instructions: many1!(instruction) >>
+        instructions: many1!(located_instruction) >>
+        opt!(multispace) >>
         (
             Program {
-                instructions: instructions
+                remaining: instructions.iter().map(|(remaining, _)| *remaining).collect(),
+                comments: vec![],
+                instructions: instructions.into_iter().map(|(_, instruction)| instruction).collect(),
             }
         )
     )
@@ -36,19 +92,29 @@ mod tests {
     #[test]
     fn test_parse_program() {
         let result = program(CompleteStr("load $0 #100\n"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (leftover, p) = result.unwrap();
         assert_eq!(leftover, CompleteStr(""));
         assert_eq!(1, p.instructions.len());
+        assert_eq!(p.remaining, vec![13]);
         // TODO: Figure out an ergonomic way to test the AssemblerInstruction returned
     }
 
+    #[test]
+    fn test_parse_program_with_errors() {
+        let source = "load $0 #1\n$1\n  hlt\n???\n";
+        let (p, errors) = program_with_errors(CompleteStr(source));
+        assert_eq!(p.instructions.len(), 2);
+        assert_eq!(p.remaining, vec![24, 8]);
+        assert_eq!(errors, vec![13, 4]);
+    }
+
     #[test]
     fn test_program_to_bytes() {
         let result = program(CompleteStr("load $0 #100\n"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, program) = result.unwrap();
-        let bytecode = program.to_bytes();
+        let bytecode = program.to_bytes(&SymbolTable::new()).unwrap();
         assert_eq!(bytecode.len(), 4);
         println!("{:?}", bytecode);
     }
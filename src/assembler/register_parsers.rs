@@ -1,21 +1,25 @@
-use nom::digit;
 use nom::types::CompleteStr;
+use nom::{digit, multispace, IResult};
 
 use crate::assembler::Token;
 
-named!(pub register <CompleteStr, Token>,
-    ws!(
-        do_parse!(
-            tag!("$") >>
-            reg_num: digit >>
-            (
-                Token::Register{
-                  reg_num: reg_num.parse::<u8>().unwrap()
-                }
-            )
-        )
-    )
-);
+// `$` and a register number. Numbers too big for a register operand still
+// parse, so the error can point at them rather than at the whole line.
+pub fn register(input: CompleteStr) -> IResult<CompleteStr, Token> {
+    let (input, _) = opt!(input, multispace)?;
+    let remaining = input.len();
+    let (input, _) = tag!(input, "$")?;
+    let (input, digits) = digit(input)?;
+    let (input, _) = opt!(input, multispace)?;
+    let token = match digits.parse::<u8>() {
+        Ok(reg_num) => Token::Register { reg_num },
+        Err(_) => Token::RegisterOutOfRange {
+            number: digits.to_string(),
+            remaining,
+        },
+    };
+    Ok((input, token))
+}
 
 #[cfg(test)]
 mod tests {
@@ -24,12 +28,22 @@ mod tests {
     #[test]
     fn test_parse_register() {
         let res = register(CompleteStr("$0"));
-        assert_eq!(res.is_ok(), true);
+        assert!(res.is_ok());
         let res = register(CompleteStr("0"));
-        assert_eq!(res.is_ok(), false);
+        assert!(res.is_err());
         let res = register(CompleteStr("$a"));
-        assert_eq!(res.is_ok(), false);
+        assert!(res.is_err());
         let res = register(CompleteStr("$"));
-        assert_eq!(res.is_ok(), false);
+        assert!(res.is_err());
+        assert_eq!(
+            register(CompleteStr(" $256 #1")),
+            Ok((
+                CompleteStr("#1"),
+                Token::RegisterOutOfRange {
+                    number: "256".to_string(),
+                    remaining: 7
+                }
+            ))
+        );
     }
 }
@@ -0,0 +1,234 @@
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use crate::memory::{DEFAULT_HEAP_LIMIT, DEFAULT_STACK_SIZE, MAX_HEAP_SIZE, MAX_STACK_SIZE};
+
+pub const DEFAULT_REGISTER_COUNT: usize = 32;
+// register operands are a single byte
+pub const MAX_REGISTER_COUNT: usize = 256;
+
+// What the VM does when an ADD, SUB, MUL, DIV, INC or DEC result does not fit
+// in a register. Dividing by zero faults in either mode.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ArithmeticMode {
+    // results wrap around, as two's complement hardware would
+    Wrapping,
+    // the instruction raises an overflow fault instead
+    Checked,
+}
+
+// Where the VM writes the messages it prints while running, such as the one
+// printed by HLT
+#[derive(Debug, Clone)]
+pub enum OutputSink {
+    Stdout,
+    Discard,
+    // appended to a buffer the host can read back, mostly useful in tests
+    Buffer(Arc<Mutex<Vec<u8>>>),
+}
+
+impl OutputSink {
+    // Creates a buffer sink along with a handle to the buffer it fills
+    pub fn buffer() -> (OutputSink, Arc<Mutex<Vec<u8>>>) {
+        let buffer = Arc::new(Mutex::new(vec![]));
+        (OutputSink::Buffer(buffer.clone()), buffer)
+    }
+
+    pub fn write_line(&self, line: &str) {
+        match self {
+            OutputSink::Stdout => println!("{}", line),
+            OutputSink::Discard => {}
+            OutputSink::Buffer(buffer) => {
+                let mut buffer = buffer.lock().unwrap();
+                writeln!(buffer, "{}", line).unwrap();
+            }
+        }
+    }
+}
+
+// Settings a VM is created with, see `VM::with_config`. The fields are private
+// so that every config other than the default goes through
+// `VmConfigBuilder::build` and its checks.
+#[derive(Debug, Clone)]
+pub struct VmConfig {
+    register_count: usize,
+    max_heap_bytes: usize, // ALOC faults rather than grow the heap past this
+    stack_size: usize,     // size of the stack segment in bytes
+    arithmetic: ArithmeticMode,
+    output: OutputSink,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig {
+            register_count: DEFAULT_REGISTER_COUNT,
+            max_heap_bytes: DEFAULT_HEAP_LIMIT,
+            stack_size: DEFAULT_STACK_SIZE,
+            arithmetic: ArithmeticMode::Wrapping,
+            output: OutputSink::Stdout,
+        }
+    }
+}
+
+impl VmConfig {
+    pub fn builder() -> VmConfigBuilder {
+        VmConfigBuilder {
+            config: VmConfig::default(),
+        }
+    }
+
+    pub fn register_count(&self) -> usize {
+        self.register_count
+    }
+
+    pub fn max_heap_bytes(&self) -> usize {
+        self.max_heap_bytes
+    }
+
+    pub fn stack_size(&self) -> usize {
+        self.stack_size
+    }
+
+    pub fn arithmetic(&self) -> ArithmeticMode {
+        self.arithmetic
+    }
+
+    pub fn output(&self) -> &OutputSink {
+        &self.output
+    }
+}
+
+// Builds a VmConfig, starting from the defaults, e.g.
+// `VmConfig::builder().register_count(8).max_heap_bytes(1024).build()`
+#[derive(Debug, Clone)]
+pub struct VmConfigBuilder {
+    config: VmConfig,
+}
+
+impl VmConfigBuilder {
+    pub fn register_count(mut self, count: usize) -> VmConfigBuilder {
+        self.config.register_count = count;
+        self
+    }
+
+    pub fn max_heap_bytes(mut self, bytes: usize) -> VmConfigBuilder {
+        self.config.max_heap_bytes = bytes;
+        self
+    }
+
+    pub fn stack_size(mut self, bytes: usize) -> VmConfigBuilder {
+        self.config.stack_size = bytes;
+        self
+    }
+
+    pub fn arithmetic(mut self, mode: ArithmeticMode) -> VmConfigBuilder {
+        self.config.arithmetic = mode;
+        self
+    }
+
+    pub fn output(mut self, sink: OutputSink) -> VmConfigBuilder {
+        self.config.output = sink;
+        self
+    }
+
+    // Checks that every setting fits the VM's instruction encoding and address space
+    pub fn build(self) -> Result<VmConfig, ConfigError> {
+        let config = self.config;
+        if config.register_count == 0 || config.register_count > MAX_REGISTER_COUNT {
+            return Err(ConfigError::RegisterCount(config.register_count));
+        }
+        if config.max_heap_bytes > MAX_HEAP_SIZE {
+            return Err(ConfigError::HeapLimit(config.max_heap_bytes));
+        }
+        if config.stack_size > MAX_STACK_SIZE {
+            return Err(ConfigError::StackSize(config.stack_size));
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ConfigError {
+    RegisterCount(usize),
+    HeapLimit(usize),
+    StackSize(usize),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::RegisterCount(count) => write!(
+                f,
+                "register count {} is not between 1 and {}",
+                count, MAX_REGISTER_COUNT
+            ),
+            ConfigError::HeapLimit(bytes) => write!(
+                f,
+                "heap limit of {} bytes is larger than the {} bytes the heap segment can address",
+                bytes, MAX_HEAP_SIZE
+            ),
+            ConfigError::StackSize(bytes) => write!(
+                f,
+                "stack size of {} bytes does not fit below the heap, the maximum is {} bytes",
+                bytes, MAX_STACK_SIZE
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_defaults() {
+        let config = VmConfig::builder().build().unwrap();
+        assert_eq!(config.register_count, DEFAULT_REGISTER_COUNT);
+        assert_eq!(config.max_heap_bytes, DEFAULT_HEAP_LIMIT);
+        assert_eq!(config.stack_size, DEFAULT_STACK_SIZE);
+        assert_eq!(config.arithmetic, ArithmeticMode::Wrapping);
+    }
+
+    #[test]
+    fn test_builder_validation() {
+        let config = VmConfig::builder()
+            .register_count(8)
+            .max_heap_bytes(64)
+            .stack_size(0)
+            .arithmetic(ArithmeticMode::Checked)
+            .build()
+            .unwrap();
+        assert_eq!(config.register_count, 8);
+        assert_eq!(config.max_heap_bytes, 64);
+        assert_eq!(
+            VmConfig::builder().register_count(0).build().unwrap_err(),
+            ConfigError::RegisterCount(0)
+        );
+        assert_eq!(
+            VmConfig::builder().register_count(257).build().unwrap_err(),
+            ConfigError::RegisterCount(257)
+        );
+        assert_eq!(
+            VmConfig::builder()
+                .max_heap_bytes(MAX_HEAP_SIZE + 1)
+                .build()
+                .unwrap_err(),
+            ConfigError::HeapLimit(MAX_HEAP_SIZE + 1)
+        );
+        assert_eq!(
+            VmConfig::builder()
+                .stack_size(MAX_STACK_SIZE + 1)
+                .build()
+                .unwrap_err(),
+            ConfigError::StackSize(MAX_STACK_SIZE + 1)
+        );
+    }
+
+    #[test]
+    fn test_buffer_sink() {
+        let (sink, buffer) = OutputSink::buffer();
+        sink.write_line("HLT encountered");
+        assert_eq!(buffer.lock().unwrap().as_slice(), b"HLT encountered\n");
+    }
+}
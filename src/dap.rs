@@ -0,0 +1,649 @@
+// A Debug Adapter Protocol server, so editors can debug `.iasm` programs.
+// Messages are JSON with a `Content-Length` header, read from and written to
+// the streams passed to `DapServer::serve`, normally stdin and stdout.
+//
+// `launch` takes the path of the source file as `program` and an optional
+// `stopOnEntry`. Registers, flags and the heap are shown as variables, and
+// faults stop the program with an exception.
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::assembler::Assembler;
+use crate::config::{OutputSink, VmConfig};
+use crate::debugger::{Debugger, StopReason};
+use crate::debuginfo::canonical_file_name;
+use crate::json::Json;
+use crate::memory::HEAP_BASE;
+use crate::vm::VM;
+
+// there is only ever one thread
+const THREAD_ID: i64 = 1;
+const REGISTERS_REFERENCE: i64 = 1;
+const FLAGS_REFERENCE: i64 = 2;
+const HEAP_REFERENCE: i64 = 3;
+// bytes shown per heap variable
+const HEAP_ROW: usize = 16;
+
+pub struct DapServer {
+    seq: i64,
+    debugger: Option<Debugger>,
+    source_path: String,
+    stop_on_entry: bool,
+    output: Arc<Mutex<Vec<u8>>>, // what the program printed, sent as output events
+    done: bool,                  // the client disconnected
+}
+
+impl Default for DapServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DapServer {
+    pub fn new() -> DapServer {
+        DapServer {
+            seq: 0,
+            debugger: None,
+            source_path: String::new(),
+            stop_on_entry: false,
+            output: Arc::new(Mutex::new(vec![])),
+            done: false,
+        }
+    }
+
+    // Answers requests from `reader` until the client disconnects or closes
+    // the stream
+    pub fn serve<R: BufRead, W: Write>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        while !self.done {
+            let request = match read_message(reader)? {
+                Some(request) => request,
+                None => break,
+            };
+            for message in self.handle(&request) {
+                write_message(writer, &message)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Handles one request, returning the response followed by any events
+    pub fn handle(&mut self, request: &Json) -> Vec<Json> {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+        let empty = Json::Object(vec![]);
+        let args = request.get("arguments").unwrap_or(&empty);
+        let request_seq = request.get("seq").and_then(Json::as_i64).unwrap_or(0);
+        let result = match command {
+            "initialize" => Ok((
+                Json::object(vec![
+                    ("supportsConfigurationDoneRequest", Json::from(true)),
+                    ("supportsStepBack", Json::from(true)),
+                ]),
+                vec![self.event("initialized", Json::Object(vec![]))],
+            )),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "configurationDone" => {
+                let events = if self.stop_on_entry {
+                    vec![self.stopped("entry", None)]
+                } else {
+                    self.resume(Debugger::run_until_stop)
+                };
+                Ok((Json::Object(vec![]), events))
+            }
+            "threads" => Ok((
+                Json::object(vec![(
+                    "threads",
+                    Json::Array(vec![Json::object(vec![
+                        ("id", Json::from(THREAD_ID)),
+                        ("name", Json::from("main")),
+                    ])]),
+                )]),
+                vec![],
+            )),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok((self.scopes(), vec![])),
+            "variables" => self.variables(args),
+            "continue" => Ok((
+                Json::object(vec![("allThreadsContinued", Json::from(true))]),
+                self.resume(Debugger::run_until_stop),
+            )),
+            "next" => Ok((Json::Object(vec![]), self.resume(Debugger::step_over))),
+            "stepIn" => Ok((Json::Object(vec![]), self.resume(Debugger::step_into))),
+            "stepBack" => Ok((Json::Object(vec![]), self.resume(Debugger::step_back))),
+            "reverseContinue" => Ok((Json::Object(vec![]), self.resume(Debugger::run_backward))),
+            "disconnect" => {
+                self.done = true;
+                Ok((Json::Object(vec![]), vec![]))
+            }
+            _ => Err(format!("unsupported request {}", command)),
+        };
+        let mut response = vec![
+            ("type", Json::from("response")),
+            ("request_seq", Json::from(request_seq)),
+            ("command", Json::from(command)),
+        ];
+        let events = match result {
+            Ok((body, events)) => {
+                response.push(("success", Json::from(true)));
+                response.push(("body", body));
+                events
+            }
+            Err(message) => {
+                response.push(("success", Json::from(false)));
+                response.push(("message", Json::from(message)));
+                vec![]
+            }
+        };
+        // the response is numbered before the events that follow it
+        let mut messages = vec![Json::object(response)];
+        messages.extend(events);
+        for message in &mut messages {
+            if let Json::Object(members) = message {
+                self.seq += 1;
+                members.insert(0, ("seq".to_string(), Json::from(self.seq)));
+            }
+        }
+        messages
+    }
+
+    fn event(&self, event: &str, body: Json) -> Json {
+        Json::object(vec![
+            ("type", Json::from("event")),
+            ("event", Json::from(event)),
+            ("body", body),
+        ])
+    }
+
+    fn stopped(&self, reason: &str, text: Option<String>) -> Json {
+        let mut body = vec![
+            ("reason", Json::from(reason)),
+            ("threadId", Json::from(THREAD_ID)),
+            ("allThreadsStopped", Json::from(true)),
+        ];
+        if let Some(text) = text {
+            body.push(("description", Json::from(text.clone())));
+            body.push(("text", Json::from(text)));
+        }
+        self.event("stopped", Json::object(body))
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<(Json, Vec<Json>), String> {
+        let path = args
+            .get("program")
+            .and_then(Json::as_str)
+            .ok_or("launch needs the path of the program to debug")?;
+        let source =
+            fs::read_to_string(path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+        let executable = Assembler::new()
+            .with_source_name(path)
+            .assemble_executable(&source)
+            .map_err(|errors| {
+                let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                errors.join("\n")
+            })?;
+        let config = VmConfig::builder()
+            .output(OutputSink::Buffer(self.output.clone()))
+            .build()
+            .unwrap();
+        let mut vm = VM::with_config(config);
+        vm.load_executable(&executable)
+            .map_err(|e| format!("{} does not load: {}", path, e))?;
+        self.debugger = Some(Debugger::new(vm));
+        self.source_path = path.to_string();
+        self.stop_on_entry = args
+            .get("stopOnEntry")
+            .and_then(Json::as_bool)
+            .unwrap_or(false);
+        Ok((Json::Object(vec![]), vec![]))
+    }
+
+    fn debugger(&mut self) -> Result<&mut Debugger, String> {
+        self.debugger
+            .as_mut()
+            .ok_or_else(|| "no program has been launched".to_string())
+    }
+
+    // Replaces the breakpoints with ones on the given source lines. Lines
+    // without an instruction cannot hold a breakpoint and are not verified.
+    fn set_breakpoints(&mut self, args: &Json) -> Result<(Json, Vec<Json>), String> {
+        let lines: Vec<i64> = args
+            .get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or(&[])
+            .iter()
+            .filter_map(|b| b.get("line").and_then(Json::as_i64))
+            .collect();
+        let path = args
+            .get("source")
+            .and_then(|source| source.get("path"))
+            .and_then(Json::as_str)
+            .unwrap_or(&self.source_path);
+        let path = canonical_file_name(path);
+        let debugger = self.debugger()?;
+        let ids: Vec<usize> = debugger.breakpoints().iter().map(|b| b.id).collect();
+        for id in ids {
+            debugger.remove_breakpoint(id).unwrap();
+        }
+        let mut breakpoints = vec![];
+        for line in lines {
+            let address = debugger
+                .vm
+                .debug_info()
+                .and_then(|debug_info| debug_info.addresses_of_line(&path, line as u32).next());
+            let mut breakpoint = vec![
+                ("verified", Json::from(address.is_some())),
+                ("line", Json::from(line)),
+            ];
+            if let Some(address) = address {
+                let id = debugger.add_breakpoint(address, None);
+                breakpoint.insert(0, ("id", Json::from(id as i64)));
+            }
+            breakpoints.push(Json::object(breakpoint));
+        }
+        Ok((
+            Json::object(vec![("breakpoints", Json::Array(breakpoints))]),
+            vec![],
+        ))
+    }
+
+    // Runs the debugger and describes where it stopped, with any output the
+    // program printed on the way
+    fn resume(&mut self, run: fn(&mut Debugger) -> StopReason) -> Vec<Json> {
+        let reason = match self.debugger.as_mut() {
+            Some(debugger) => run(debugger),
+            None => return vec![],
+        };
+        let mut events = vec![];
+        let output = std::mem::take(&mut *self.output.lock().unwrap());
+        if !output.is_empty() {
+            let body = Json::object(vec![
+                ("category", Json::from("stdout")),
+                (
+                    "output",
+                    Json::from(String::from_utf8_lossy(&output).into_owned()),
+                ),
+            ]);
+            events.push(self.event("output", body));
+        }
+        match reason {
+            StopReason::Step | StopReason::HistoryStart => events.push(self.stopped("step", None)),
+            StopReason::Breakpoint { .. } => events.push(self.stopped("breakpoint", None)),
+            StopReason::Watchpoint { .. } => {
+                events.push(self.stopped("data breakpoint", Some(reason.to_string())))
+            }
+            StopReason::Faulted(fault) => {
+                let text = self.debugger.as_ref().unwrap().vm.describe_fault(&fault);
+                events.push(self.stopped("exception", Some(text)))
+            }
+            StopReason::Halted { .. } | StopReason::Finished => {
+                let exited = self.event("exited", Json::object(vec![("exitCode", Json::from(0))]));
+                events.push(exited);
+                events.push(self.event("terminated", Json::Object(vec![])));
+            }
+        }
+        events
+    }
+
+    fn stack_trace(&mut self) -> Result<(Json, Vec<Json>), String> {
+        let vm = &self.debugger()?.vm;
+        let pc = vm.pc();
+        let location = vm.location(pc);
+        // frames are named after the label they are in, as there are no functions
+        let name = match location.symbol {
+            Some((name, _)) => name.to_string(),
+            None => format!("{:#06x}", pc),
+        };
+        let (path, line, column) = match location.source {
+            Some(source) => (source.file, source.line, source.column),
+            None => (self.source_path.as_str(), 0, 0),
+        };
+        let frame = Json::object(vec![
+            ("id", Json::from(1)),
+            ("name", Json::from(name)),
+            ("source", Json::object(vec![("path", Json::from(path))])),
+            ("line", Json::from(line as i64)),
+            ("column", Json::from(column as i64)),
+            (
+                "instructionPointerReference",
+                Json::from(format!("{:#x}", pc)),
+            ),
+        ]);
+        Ok((
+            Json::object(vec![
+                ("stackFrames", Json::Array(vec![frame])),
+                ("totalFrames", Json::from(1)),
+            ]),
+            vec![],
+        ))
+    }
+
+    fn scopes(&self) -> Json {
+        let scope = |name: &str, reference: i64| {
+            Json::object(vec![
+                ("name", Json::from(name)),
+                ("variablesReference", Json::from(reference)),
+                ("expensive", Json::from(reference == HEAP_REFERENCE)),
+            ])
+        };
+        Json::object(vec![(
+            "scopes",
+            Json::Array(vec![
+                scope("Registers", REGISTERS_REFERENCE),
+                scope("Flags", FLAGS_REFERENCE),
+                scope("Heap", HEAP_REFERENCE),
+            ]),
+        )])
+    }
+
+    fn variables(&mut self, args: &Json) -> Result<(Json, Vec<Json>), String> {
+        let reference = args
+            .get("variablesReference")
+            .and_then(Json::as_i64)
+            .unwrap_or(0);
+        let vm = &self.debugger()?.vm;
+        let variables: Vec<(String, String)> = match reference {
+            REGISTERS_REFERENCE => vm
+                .registers
+                .iter()
+                .enumerate()
+                .map(|(register, value)| (format!("${}", register), value.to_string()))
+                .collect(),
+            FLAGS_REFERENCE => vec![
+                ("pc".to_string(), format!("{:#06x}", vm.pc())),
+                ("equal_flag".to_string(), vm.equal_flag().to_string()),
+                ("remainder".to_string(), vm.remainder().to_string()),
+            ],
+            HEAP_REFERENCE => vm
+                .heap()
+                .chunks(HEAP_ROW)
+                .enumerate()
+                .map(|(row, bytes)| {
+                    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                    (
+                        format!("{:#010x}", HEAP_BASE + row * HEAP_ROW),
+                        hex.join(" "),
+                    )
+                })
+                .collect(),
+            _ => return Err(format!("no variables with reference {}", reference)),
+        };
+        let variables = variables
+            .into_iter()
+            .map(|(name, value)| {
+                Json::object(vec![
+                    ("name", Json::from(name)),
+                    ("value", Json::from(value)),
+                    ("variablesReference", Json::from(0)),
+                ])
+            })
+            .collect();
+        Ok((
+            Json::object(vec![("variables", Json::Array(variables))]),
+            vec![],
+        ))
+    }
+}
+
+// Reads a message, returning None at the end of the stream
+pub fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Json>> {
+    let mut len = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                len = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let len = len.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing length"))?;
+    // the body grows as it arrives rather than trusting the length up front
+    let mut body = vec![];
+    reader.take(len as u64).read_to_end(&mut body)?;
+    if body.len() < len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "message ended early",
+        ));
+    }
+    let text = String::from_utf8_lossy(&body);
+    Json::parse(&text)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+
+    use super::*;
+
+    // writes `source` to a file of its own and returns the path
+    fn source_file(name: &str, source: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("iridium-dap-{}-{}.iasm", name, std::process::id()));
+        fs::write(&path, source).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    // counts $0 up to 3 on lines 4-6, then halts
+    const COUNTING_LOOP: &str = "load $1 #3\nload $2 #8\n\ninc $0\nneq $0 $1\njeq $2\nhlt\n";
+
+    // Plays a recorded exchange: each request is sent in turn and must
+    // produce exactly the recorded messages
+    fn replay(server: &mut DapServer, exchange: &[(&str, Vec<&str>)]) {
+        for (seq, (request, expected)) in exchange.iter().enumerate() {
+            let request = Json::parse(request).unwrap();
+            let messages: Vec<String> = server
+                .handle(&request)
+                .iter()
+                .map(Json::to_string)
+                .collect();
+            assert_eq!(messages, *expected, "request {}", seq + 1);
+        }
+    }
+
+    #[test]
+    fn test_recorded_session() {
+        let path = source_file("session", COUNTING_LOOP);
+        let mut server = DapServer::new();
+        let launch = format!(
+            r#"{{"seq":2,"type":"request","command":"launch","arguments":{{"program":"{}","stopOnEntry":true}}}}"#,
+            path
+        );
+        let source = format!(r#"{{"path":"{}"}}"#, canonical_file_name(&path));
+        // the client may name the file by another path to it
+        let dir = std::env::temp_dir().join(".");
+        let file = std::path::Path::new(&path).file_name().unwrap();
+        let set_breakpoints = format!(
+            r#"{{"seq":3,"type":"request","command":"setBreakpoints","arguments":{{"source":{{"path":"{}"}},"breakpoints":[{{"line":3}},{{"line":5}}]}}}}"#,
+            dir.join(file).display()
+        );
+        let stack_trace = format!(
+            r#"{{"seq":1,"type":"response","request_seq":6,"command":"stackTrace","success":true,"body":{{"stackFrames":[{{"id":1,"name":"0x000c","source":{},"line":5,"column":1,"instructionPointerReference":"0xc"}}],"totalFrames":1}}}}"#,
+            source
+        );
+        replay(
+            &mut server,
+            &[
+                (
+                    r#"{"seq":1,"type":"request","command":"initialize","arguments":{"adapterID":"iridium"}}"#,
+                    vec![
+                        r#"{"seq":1,"type":"response","request_seq":1,"command":"initialize","success":true,"body":{"supportsConfigurationDoneRequest":true,"supportsStepBack":true}}"#,
+                        r#"{"seq":2,"type":"event","event":"initialized","body":{}}"#,
+                    ],
+                ),
+                (
+                    &launch,
+                    vec![
+                        r#"{"seq":3,"type":"response","request_seq":2,"command":"launch","success":true,"body":{}}"#,
+                    ],
+                ),
+                (
+                    &set_breakpoints,
+                    vec![
+                        r#"{"seq":4,"type":"response","request_seq":3,"command":"setBreakpoints","success":true,"body":{"breakpoints":[{"verified":false,"line":3},{"id":1,"verified":true,"line":5}]}}"#,
+                    ],
+                ),
+                (
+                    r#"{"seq":4,"type":"request","command":"configurationDone"}"#,
+                    vec![
+                        r#"{"seq":5,"type":"response","request_seq":4,"command":"configurationDone","success":true,"body":{}}"#,
+                        r#"{"seq":6,"type":"event","event":"stopped","body":{"reason":"entry","threadId":1,"allThreadsStopped":true}}"#,
+                    ],
+                ),
+                (
+                    r#"{"seq":5,"type":"request","command":"continue","arguments":{"threadId":1}}"#,
+                    vec![
+                        r#"{"seq":7,"type":"response","request_seq":5,"command":"continue","success":true,"body":{"allThreadsContinued":true}}"#,
+                        r#"{"seq":8,"type":"event","event":"stopped","body":{"reason":"breakpoint","threadId":1,"allThreadsStopped":true}}"#,
+                    ],
+                ),
+            ],
+        );
+        // sequence numbers carry on, only the shape of the rest is checked
+        let request = Json::parse(
+            r#"{"seq":6,"type":"request","command":"stackTrace","arguments":{"threadId":1}}"#,
+        )
+        .unwrap();
+        let mut response = server.handle(&request).remove(0);
+        if let Json::Object(members) = &mut response {
+            members[0].1 = Json::from(1);
+        }
+        assert_eq!(response.to_string(), stack_trace);
+
+        let request = Json::parse(r#"{"seq":7,"type":"request","command":"variables","arguments":{"variablesReference":1}}"#).unwrap();
+        let response = server.handle(&request).remove(0);
+        let variables = response.get("body").unwrap().get("variables").unwrap();
+        assert_eq!(
+            variables.as_array().unwrap()[0]
+                .get("value")
+                .unwrap()
+                .as_str(),
+            Some("1")
+        );
+
+        let request = Json::parse(r#"{"seq":8,"type":"request","command":"setBreakpoints","arguments":{"breakpoints":[]}}"#).unwrap();
+        server.handle(&request);
+        let request = Json::parse(r#"{"seq":9,"type":"request","command":"continue"}"#).unwrap();
+        let events: Vec<String> = server
+            .handle(&request)
+            .iter()
+            .skip(1)
+            .map(|event| event.get("event").unwrap().as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(events, vec!["output", "exited", "terminated"]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_fault_stops_with_exception() {
+        let path = source_file("fault", "load $0 #2\njmp $0\n");
+        let mut server = DapServer::new();
+        let launch = format!(
+            r#"{{"seq":1,"command":"launch","arguments":{{"program":"{}"}}}}"#,
+            path
+        );
+        server.handle(&Json::parse(&launch).unwrap());
+        let messages =
+            server.handle(&Json::parse(r#"{"seq":2,"command":"configurationDone"}"#).unwrap());
+        let body = messages[1].get("body").unwrap();
+        assert_eq!(body.get("reason").unwrap().as_str(), Some("exception"));
+        assert_eq!(
+            body.get("text").unwrap().as_str(),
+            Some(
+                format!(
+                    "fault at {}:2:1: jump to 0x0002 is not on an instruction boundary",
+                    path
+                )
+                .as_str()
+            )
+        );
+        // stepping back undoes the fault
+        let messages = server.handle(&Json::parse(r#"{"seq":3,"command":"stepBack"}"#).unwrap());
+        assert_eq!(
+            messages[1]
+                .get("body")
+                .unwrap()
+                .get("reason")
+                .unwrap()
+                .as_str(),
+            Some("step")
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_serve() {
+        let mut input = vec![];
+        write_message(
+            &mut input,
+            &Json::parse(
+                r#"{"seq":1,"command":"launch","arguments":{"program":"/nonexistent.iasm"}}"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        write_message(
+            &mut input,
+            &Json::parse(r#"{"seq":2,"command":"disconnect"}"#).unwrap(),
+        )
+        .unwrap();
+        write_message(
+            &mut input,
+            &Json::parse(r#"{"seq":3,"command":"threads"}"#).unwrap(),
+        )
+        .unwrap();
+        let mut output = vec![];
+        DapServer::new()
+            .serve(&mut BufReader::new(&input[..]), &mut output)
+            .unwrap();
+
+        let mut reader = BufReader::new(&output[..]);
+        let failed = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(failed.get("success"), Some(&Json::Bool(false)));
+        assert!(failed
+            .get("message")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .starts_with("unable to read"));
+        let disconnected = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(
+            disconnected.get("command").unwrap().as_str(),
+            Some("disconnect")
+        );
+        // nothing is answered after disconnecting
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_message_length() {
+        let message = |text: &str| read_message(&mut BufReader::new(text.as_bytes()));
+        assert_eq!(
+            message("Content-Length: 2\r\n\r\n{}").unwrap(),
+            Some(Json::object(vec![]))
+        );
+        // a length far past the body is an error, not an allocation that size
+        let error = message("Content-Length: 18446744073709551615\r\n\r\n{}").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(message("\r\n{}").is_err());
+    }
+}
@@ -0,0 +1,620 @@
+// A debugger layer over the VM: breakpoints on addresses or labels, optionally
+// conditional on a register value, watchpoints on registers and memory, and
+// stepping that reports why execution stopped. Every step records how to undo
+// it, so execution can also be stepped and run backwards.
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::decoder::{decode, Comparison, DecodedInstruction};
+use crate::fault::Fault;
+use crate::trace::Snapshot;
+use crate::vm::VM;
+
+// Breaks only when `$register <cmp> value` holds
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Condition {
+    pub register: u8,
+    pub cmp: Comparison,
+    pub value: i32,
+}
+
+impl Condition {
+    fn holds(&self, vm: &VM) -> bool {
+        match vm.registers.get(self.register as usize) {
+            Some(value) => self.cmp.evaluate(*value, self.value),
+            None => false,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self.cmp {
+            Comparison::Eq => "==",
+            Comparison::Neq => "!=",
+            Comparison::Gt => ">",
+            Comparison::Lt => "<",
+            Comparison::Gtq => ">=",
+            Comparison::Ltq => "<=",
+        };
+        write!(f, "${} {} {}", self.register, op, self.value)
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub address: usize,
+    pub condition: Option<Condition>,
+}
+
+// What a watchpoint keeps an eye on
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Watch {
+    Register(u8),
+    Memory { address: usize, len: usize },
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Watch::Register(register) => write!(f, "${}", register),
+            Watch::Memory { address, len } => write!(f, "{} bytes at {:#010x}", len, address),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Watchpoint {
+    pub id: usize,
+    pub watch: Watch,
+}
+
+// The value under a watch: a register's contents or the watched bytes, or
+// None when the register or memory does not exist
+#[derive(Debug, PartialEq, Clone)]
+pub enum WatchValue {
+    Register(Option<i32>),
+    Memory(Option<Vec<u8>>),
+}
+
+impl fmt::Display for WatchValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatchValue::Register(Some(value)) => write!(f, "{}", value),
+            WatchValue::Memory(Some(bytes)) => write!(f, "{:02x?}", bytes),
+            WatchValue::Register(None) | WatchValue::Memory(None) => write!(f, "unavailable"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum StopReason {
+    // a single step finished without anything else happening
+    Step,
+    // about to execute the instruction at `pc`
+    Breakpoint {
+        id: usize,
+        pc: usize,
+    },
+    // the instruction at `pc` changed a watched value
+    Watchpoint {
+        id: usize,
+        pc: usize,
+        old: WatchValue,
+        new: WatchValue,
+    },
+    Halted {
+        pc: usize,
+    },
+    Faulted(Fault),
+    // the pc ran off the end of the program
+    Finished,
+    // stepping backwards reached the oldest step still in the history
+    HistoryStart,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Step => write!(f, "stepped"),
+            StopReason::Breakpoint { id, pc } => write!(f, "breakpoint {} at {:#06x}", id, pc),
+            StopReason::Watchpoint { id, pc, old, new } => write!(
+                f,
+                "watchpoint {} changed by {:#06x}: {} -> {}",
+                id, pc, old, new
+            ),
+            StopReason::Halted { pc } => write!(f, "halted at {:#06x}", pc),
+            StopReason::Faulted(fault) => write!(f, "{}", fault),
+            StopReason::Finished => write!(f, "program finished"),
+            StopReason::HistoryStart => write!(f, "reached the start of the recorded history"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DebugError {
+    UnknownLabel(String),
+    UnknownBreakpoint(usize),
+    UnknownWatchpoint(usize),
+}
+
+impl fmt::Display for DebugError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DebugError::UnknownLabel(name) => write!(f, "no label named {}", name),
+            DebugError::UnknownBreakpoint(id) => write!(f, "no breakpoint {}", id),
+            DebugError::UnknownWatchpoint(id) => write!(f, "no watchpoint {}", id),
+        }
+    }
+}
+
+// the number of steps remembered for stepping backwards, unless configured otherwise
+pub const DEFAULT_HISTORY_LIMIT: usize = 100_000;
+
+pub struct Debugger {
+    pub vm: VM,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    next_id: usize,              // breakpoints and watchpoints share ids
+    history: VecDeque<Snapshot>, // the state before each recorded step, oldest first
+    history_limit: usize,        // older steps are forgotten past this many
+}
+
+impl Debugger {
+    pub fn new(vm: VM) -> Debugger {
+        Debugger {
+            vm,
+            breakpoints: vec![],
+            watchpoints: vec![],
+            next_id: 1,
+            history: VecDeque::new(),
+            history_limit: DEFAULT_HISTORY_LIMIT,
+        }
+    }
+
+    // Bounds the memory used for stepping backwards; 0 turns recording off
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history_limit = limit;
+        while self.history.len() > limit {
+            self.history.pop_front();
+        }
+    }
+
+    // How many steps can currently be undone
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    // Forgets the recorded steps, for when the VM was changed outside the
+    // debugger and undoing them would no longer make sense
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+    }
+
+    fn next_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    pub fn add_breakpoint(&mut self, address: usize, condition: Option<Condition>) -> usize {
+        let id = self.next_id();
+        self.breakpoints.push(Breakpoint {
+            id,
+            address,
+            condition,
+        });
+        id
+    }
+
+    // Breaks at a label from the symbols of the loaded executable
+    pub fn add_label_breakpoint(
+        &mut self,
+        name: &str,
+        condition: Option<Condition>,
+    ) -> Result<usize, DebugError> {
+        let address = self
+            .vm
+            .symbols()
+            .iter()
+            .find(|symbol| symbol.name == name)
+            .map(|symbol| symbol.address as usize)
+            .ok_or_else(|| DebugError::UnknownLabel(name.to_string()))?;
+        Ok(self.add_breakpoint(address, condition))
+    }
+
+    pub fn remove_breakpoint(&mut self, id: usize) -> Result<(), DebugError> {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|b| b.id != id);
+        if self.breakpoints.len() == len {
+            return Err(DebugError::UnknownBreakpoint(id));
+        }
+        Ok(())
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watch: Watch) -> usize {
+        let id = self.next_id();
+        self.watchpoints.push(Watchpoint { id, watch });
+        id
+    }
+
+    pub fn remove_watchpoint(&mut self, id: usize) -> Result<(), DebugError> {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| w.id != id);
+        if self.watchpoints.len() == len {
+            return Err(DebugError::UnknownWatchpoint(id));
+        }
+        Ok(())
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn watch_value(&self, watch: Watch) -> WatchValue {
+        match watch {
+            Watch::Register(register) => {
+                WatchValue::Register(self.vm.registers.get(register as usize).copied())
+            }
+            Watch::Memory { address, len } => WatchValue::Memory(
+                self.vm
+                    .read_memory(address, len)
+                    .ok()
+                    .map(|bytes| bytes.to_vec()),
+            ),
+        }
+    }
+
+    // The first breakpoint at the pc whose condition holds
+    fn breakpoint_hit(&self) -> Option<StopReason> {
+        let pc = self.vm.pc();
+        self.breakpoints
+            .iter()
+            .find(|b| b.address == pc && b.condition.is_none_or(|c| c.holds(&self.vm)))
+            .map(|b| StopReason::Breakpoint { id: b.id, pc })
+    }
+
+    // Executes the instruction at the pc, ignoring any breakpoint there
+    pub fn step_into(&mut self) -> StopReason {
+        let pc = self.vm.pc();
+        if let Some(fault) = self.vm.fault() {
+            return StopReason::Faulted(fault);
+        }
+        if pc >= self.vm.program.len() {
+            return StopReason::Finished;
+        }
+        let halts = decode(&self.vm.program, pc) == DecodedInstruction::Hlt;
+        let watched: Vec<WatchValue> = self
+            .watchpoints
+            .iter()
+            .map(|w| self.watch_value(w.watch))
+            .collect();
+        if self.history_limit > 0 {
+            if self.history.len() == self.history_limit {
+                self.history.pop_front();
+            }
+            self.history.push_back(self.vm.snapshot());
+        }
+        self.vm.execute_instruction();
+        if let Some(fault) = self.vm.fault() {
+            return StopReason::Faulted(fault);
+        }
+        for (watchpoint, old) in self.watchpoints.iter().zip(watched) {
+            let new = self.watch_value(watchpoint.watch);
+            if new != old {
+                return StopReason::Watchpoint {
+                    id: watchpoint.id,
+                    pc,
+                    old,
+                    new,
+                };
+            }
+        }
+        if halts {
+            return StopReason::Halted { pc };
+        }
+        StopReason::Step
+    }
+
+    // Undoes the last step, leaving the pc on the instruction it executed.
+    // Watchpoints are reported with `old` and `new` in execution order, so
+    // `new` is the value the undone instruction had written.
+    pub fn step_back(&mut self) -> StopReason {
+        let snapshot = match self.history.pop_back() {
+            Some(snapshot) => snapshot,
+            None => return StopReason::HistoryStart,
+        };
+        let watched: Vec<WatchValue> = self
+            .watchpoints
+            .iter()
+            .map(|w| self.watch_value(w.watch))
+            .collect();
+        self.vm.restore(&snapshot);
+        for (watchpoint, new) in self.watchpoints.iter().zip(watched) {
+            let old = self.watch_value(watchpoint.watch);
+            if new != old {
+                return StopReason::Watchpoint {
+                    id: watchpoint.id,
+                    pc: snapshot.pc,
+                    old,
+                    new,
+                };
+            }
+        }
+        StopReason::Step
+    }
+
+    // Runs backwards until reaching a breakpoint or undoing a change to a
+    // watched value, or until the history runs out
+    pub fn run_backward(&mut self) -> StopReason {
+        loop {
+            match self.step_back() {
+                StopReason::Step => {}
+                reason => return reason,
+            }
+            if let Some(reason) = self.breakpoint_hit() {
+                return reason;
+            }
+        }
+    }
+
+    // Steps over the instruction at the pc. Without a CALL instruction there
+    // is nothing to step over, so this is the same as `step_into` for now.
+    pub fn step_over(&mut self) -> StopReason {
+        self.step_into()
+    }
+
+    // Runs until a breakpoint or watchpoint is hit, or the program halts,
+    // faults or finishes. A breakpoint at the pc when this is called does not
+    // stop it, so calling it again after a breakpoint carries on.
+    pub fn run_until_stop(&mut self) -> StopReason {
+        let mut first = true;
+        loop {
+            if !first {
+                if let Some(reason) = self.breakpoint_hit() {
+                    return reason;
+                }
+            }
+            first = false;
+            match self.step_into() {
+                StopReason::Step => {}
+                reason => return reason,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executable::Executable;
+    use crate::executable::ExecutableSymbol;
+    use crate::fault::FaultKind;
+    use crate::memory::HEAP_BASE;
+
+    // counts $0 up to 5 at `loop`, then halts
+    fn counting_loop() -> Debugger {
+        let mut executable = Executable::new(
+            vec![
+                0, 1, 0, 5, // load $1 #5
+                0, 2, 0, 8, // load $2 #8
+                18, 0, 0, 0, // loop: inc $0
+                10, 0, 1, 0, // neq $0 $1
+                15, 2, 0, 0, // jeq $2
+                5, 0, 0, 0, // hlt
+            ],
+            vec![],
+            0,
+        );
+        executable.symbols.push(ExecutableSymbol {
+            name: "loop".to_string(),
+            address: 8,
+        });
+        let mut vm = VM::new();
+        vm.load_executable(&executable.to_bytes()).unwrap();
+        Debugger::new(vm)
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut debugger = counting_loop();
+        let id = debugger.add_label_breakpoint("loop", None).unwrap();
+        assert_eq!(
+            debugger.run_until_stop(),
+            StopReason::Breakpoint { id, pc: 8 }
+        );
+        assert_eq!(debugger.vm.registers[0], 0);
+        // continuing from a breakpoint runs the loop once more
+        assert_eq!(
+            debugger.run_until_stop(),
+            StopReason::Breakpoint { id, pc: 8 }
+        );
+        assert_eq!(debugger.vm.registers[0], 1);
+        debugger.remove_breakpoint(id).unwrap();
+        assert_eq!(debugger.run_until_stop(), StopReason::Halted { pc: 20 });
+        assert_eq!(debugger.vm.registers[0], 5);
+
+        assert_eq!(
+            debugger.remove_breakpoint(id),
+            Err(DebugError::UnknownBreakpoint(id))
+        );
+        assert_eq!(
+            debugger.add_label_breakpoint("nowhere", None),
+            Err(DebugError::UnknownLabel("nowhere".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        let mut debugger = counting_loop();
+        let condition = Condition {
+            register: 0,
+            cmp: Comparison::Eq,
+            value: 3,
+        };
+        let id = debugger.add_breakpoint(12, Some(condition));
+        assert_eq!(
+            debugger.run_until_stop(),
+            StopReason::Breakpoint { id, pc: 12 }
+        );
+        assert_eq!(debugger.vm.registers[0], 3);
+        assert_eq!(condition.to_string(), "$0 == 3");
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut debugger = counting_loop();
+        let id = debugger.add_watchpoint(Watch::Register(0));
+        assert_eq!(
+            debugger.run_until_stop(),
+            StopReason::Watchpoint {
+                id,
+                pc: 8,
+                old: WatchValue::Register(Some(0)),
+                new: WatchValue::Register(Some(1)),
+            }
+        );
+        debugger.remove_watchpoint(id).unwrap();
+
+        // aloc $0, setm $1 $0
+        let mut vm = VM::new();
+        vm.program = vec![17, 0, 0, 0, 21, 1, 0, 0];
+        vm.registers[0] = 4;
+        vm.registers[1] = HEAP_BASE as i32;
+        let mut debugger = Debugger::new(vm);
+        let id = debugger.add_watchpoint(Watch::Memory {
+            address: HEAP_BASE,
+            len: 4,
+        });
+        let reason = debugger.run_until_stop();
+        assert_eq!(
+            reason,
+            StopReason::Watchpoint {
+                id,
+                pc: 0,
+                old: WatchValue::Memory(None),
+                new: WatchValue::Memory(Some(vec![0, 0, 0, 0])),
+            }
+        );
+        assert_eq!(
+            debugger.run_until_stop(),
+            StopReason::Watchpoint {
+                id,
+                pc: 4,
+                old: WatchValue::Memory(Some(vec![0, 0, 0, 0])),
+                new: WatchValue::Memory(Some(vec![0, 0, 0, 4])),
+            }
+        );
+    }
+
+    #[test]
+    fn test_step() {
+        let mut debugger = counting_loop();
+        assert_eq!(debugger.step_into(), StopReason::Step);
+        assert_eq!(debugger.step_over(), StopReason::Step);
+        assert_eq!(debugger.vm.pc(), 8);
+
+        let mut vm = VM::new();
+        vm.program = vec![18, 0, 0, 0];
+        let mut debugger = Debugger::new(vm);
+        assert_eq!(debugger.step_into(), StopReason::Step);
+        assert_eq!(debugger.step_into(), StopReason::Finished);
+
+        let mut vm = VM::new();
+        vm.program = vec![200, 0, 0, 0];
+        let mut debugger = Debugger::new(vm);
+        assert_eq!(
+            debugger.step_into(),
+            StopReason::Faulted(Fault::new(0, FaultKind::IllegalOpcode { opcode: 200 }))
+        );
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut debugger = counting_loop();
+        assert_eq!(debugger.run_until_stop(), StopReason::Halted { pc: 20 });
+        assert_eq!(debugger.history_len(), 2 + 5 * 3 + 1);
+        assert_eq!(debugger.step_back(), StopReason::Step);
+        assert_eq!(debugger.vm.pc(), 20);
+        // undoing the final neq brings back the flag it overwrote
+        assert_eq!(debugger.step_back(), StopReason::Step);
+        assert_eq!(debugger.step_back(), StopReason::Step);
+        assert!(debugger.vm.equal_flag());
+        assert_eq!(debugger.vm.pc(), 12);
+        assert_eq!(debugger.run_backward(), StopReason::HistoryStart);
+        assert_eq!(debugger.vm.pc(), 0);
+        assert_eq!(debugger.vm.registers[..3], [0, 0, 0]);
+        // running forwards again replays the same steps
+        assert_eq!(debugger.run_until_stop(), StopReason::Halted { pc: 20 });
+        assert_eq!(debugger.vm.registers[0], 5);
+    }
+
+    #[test]
+    fn test_run_backward() {
+        let mut debugger = counting_loop();
+        debugger.run_until_stop();
+        let id = debugger.add_breakpoint(8, None);
+        assert_eq!(
+            debugger.run_backward(),
+            StopReason::Breakpoint { id, pc: 8 }
+        );
+        assert_eq!(debugger.vm.registers[0], 4);
+        debugger.remove_breakpoint(id).unwrap();
+
+        let id = debugger.add_watchpoint(Watch::Register(0));
+        assert_eq!(
+            debugger.run_backward(),
+            StopReason::Watchpoint {
+                id,
+                pc: 8,
+                old: WatchValue::Register(Some(3)),
+                new: WatchValue::Register(Some(4)),
+            }
+        );
+        assert_eq!(debugger.vm.pc(), 8);
+    }
+
+    #[test]
+    fn test_step_back_memory() {
+        // aloc $0, setm $1 $2, aloc $3
+        let mut vm = VM::new();
+        vm.program = vec![17, 0, 0, 0, 21, 1, 2, 0, 17, 3, 0, 0];
+        vm.registers[..4].copy_from_slice(&[8, HEAP_BASE as i32 + 4, 9, -6]);
+        let mut debugger = Debugger::new(vm);
+        debugger.run_until_stop();
+        assert_eq!(debugger.vm.heap(), &[0, 0]);
+        assert_eq!(debugger.step_back(), StopReason::Step);
+        assert_eq!(debugger.vm.heap(), &[0, 0, 0, 0, 0, 0, 0, 9]);
+        assert_eq!(debugger.step_back(), StopReason::Step);
+        assert_eq!(debugger.vm.heap(), &[0; 8]);
+        assert_eq!(debugger.step_back(), StopReason::Step);
+        assert!(debugger.vm.heap().is_empty());
+        assert_eq!(debugger.step_back(), StopReason::HistoryStart);
+    }
+
+    #[test]
+    fn test_history_limit() {
+        let mut debugger = counting_loop();
+        debugger.set_history_limit(4);
+        debugger.run_until_stop();
+        assert_eq!(debugger.history_len(), 4);
+        assert_eq!(debugger.run_backward(), StopReason::HistoryStart);
+        assert_eq!(debugger.vm.pc(), 8);
+        debugger.set_history_limit(0);
+        debugger.step_into();
+        assert_eq!(debugger.step_back(), StopReason::HistoryStart);
+
+        // a step that faults can be undone, clearing the fault
+        let mut vm = VM::new();
+        vm.program = vec![200, 0, 0, 0];
+        let mut debugger = Debugger::new(vm);
+        debugger.step_into();
+        assert_eq!(debugger.step_back(), StopReason::Step);
+        assert_eq!(debugger.vm.fault(), None);
+        assert_eq!(debugger.vm.pc(), 0);
+    }
+}
@@ -0,0 +1,282 @@
+// Source-level debug information: which line of which `.iasm` file each
+// instruction was assembled from, so addresses can be reported as
+// `file.iasm:12:5 (label+8)` rather than as raw offsets.
+//
+// It is stored in the optional debug section of an executable, with integers
+// big endian like the rest of the file:
+//   file count (2), then per file: name length (2), name (UTF-8)
+//   line count (4), then per instruction: address (4), file (2), line (4), column (4)
+// Labels are not repeated here, they come from the symbol section.
+use std::fmt;
+use std::fs;
+
+use crate::executable::{nearest_symbol, ExecutableSymbol};
+use crate::memory::INSTRUCTION_WIDTH;
+
+const LINE_ENTRY_LEN: usize = 14;
+
+// The name a file is recorded and looked up under: its canonical path when it
+// exists, so `lib/../defs.iasm` and `defs.iasm` are the same file
+pub fn canonical_file_name(name: &str) -> String {
+    fs::canonicalize(name)
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|_| name.to_string())
+}
+
+// Where the instruction at `address` came from. `file` indexes
+// `DebugInfo::files`, lines and columns count from 1.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct LineEntry {
+    pub address: u32,
+    pub file: u16,
+    pub line: u32,
+    pub column: u32,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct DebugInfo {
+    pub files: Vec<String>,
+    // in address order
+    pub lines: Vec<LineEntry>,
+}
+
+impl DebugInfo {
+    pub fn new() -> DebugInfo {
+        DebugInfo::default()
+    }
+
+    // The line the instruction covering `address` was assembled from
+    pub fn line_entry(&self, address: usize) -> Option<&LineEntry> {
+        let index = self
+            .lines
+            .partition_point(|entry| entry.address as usize <= address);
+        let entry = self.lines.get(index.checked_sub(1)?)?;
+        if address < entry.address as usize + INSTRUCTION_WIDTH {
+            Some(entry)
+        } else {
+            None
+        }
+    }
+
+    pub fn source_location(&self, address: usize) -> Option<SourceLocation<'_>> {
+        let entry = self.line_entry(address)?;
+        Some(SourceLocation {
+            file: self.files.get(entry.file as usize)?,
+            line: entry.line,
+            column: entry.column,
+        })
+    }
+
+    // The addresses of the instructions that start on `line` of `file`
+    pub fn addresses_of_line<'a>(
+        &'a self,
+        file: &str,
+        line: u32,
+    ) -> impl Iterator<Item = usize> + 'a {
+        let file = self.files.iter().position(|name| name == file);
+        self.lines
+            .iter()
+            .filter(move |entry| Some(entry.file as usize) == file && entry.line == line)
+            .map(|entry| entry.address as usize)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend((self.files.len() as u16).to_be_bytes());
+        for file in &self.files {
+            bytes.extend((file.len() as u16).to_be_bytes());
+            bytes.extend(file.as_bytes());
+        }
+        bytes.extend((self.lines.len() as u32).to_be_bytes());
+        for entry in &self.lines {
+            bytes.extend(entry.address.to_be_bytes());
+            bytes.extend(entry.file.to_be_bytes());
+            bytes.extend(entry.line.to_be_bytes());
+            bytes.extend(entry.column.to_be_bytes());
+        }
+        bytes
+    }
+
+    // None if the section is truncated or names a file it does not list
+    pub fn from_bytes(bytes: &[u8]) -> Option<DebugInfo> {
+        let mut reader = Reader { bytes };
+        let mut files = vec![];
+        for _ in 0..reader.u16()? {
+            let len = reader.u16()? as usize;
+            let name = std::str::from_utf8(reader.take(len)?).ok()?;
+            files.push(name.to_string());
+        }
+        let count = reader.u32()? as usize;
+        if reader.bytes.len() != count.checked_mul(LINE_ENTRY_LEN)? {
+            return None;
+        }
+        let mut lines = Vec::with_capacity(count);
+        for _ in 0..count {
+            let entry = LineEntry {
+                address: reader.u32()?,
+                file: reader.u16()?,
+                line: reader.u32()?,
+                column: reader.u32()?,
+            };
+            if entry.file as usize >= files.len() {
+                return None;
+            }
+            lines.push(entry);
+        }
+        lines.sort_by_key(|entry| entry.address);
+        Some(DebugInfo { files, lines })
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+// A position in a source file, shown as `file.iasm:12:5`
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct SourceLocation<'a> {
+    pub file: &'a str,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for SourceLocation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+// Everything known about an address: its source position when there is debug
+// information and the label it follows when there are symbols. Shown as
+// `file.iasm:12:5 (label+8)`, with the address standing in for the source
+// position when that is unknown.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Location<'a> {
+    pub address: usize,
+    pub source: Option<SourceLocation<'a>>,
+    pub symbol: Option<(&'a str, u32)>,
+}
+
+impl<'a> Location<'a> {
+    pub fn new(
+        debug_info: Option<&'a DebugInfo>,
+        symbols: &'a [ExecutableSymbol],
+        address: usize,
+    ) -> Location<'a> {
+        Location {
+            address,
+            source: debug_info.and_then(|debug_info| debug_info.source_location(address)),
+            symbol: nearest_symbol(symbols, address as u32),
+        }
+    }
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.source {
+            Some(source) => write!(f, "{}", source)?,
+            None => write!(f, "{:#06x}", self.address)?,
+        }
+        if let Some((name, offset)) = self.symbol {
+            write!(f, " ({}+{})", name, offset)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> DebugInfo {
+        DebugInfo {
+            files: vec!["main.iasm".to_string(), "lib.iasm".to_string()],
+            lines: vec![
+                LineEntry {
+                    address: 0,
+                    file: 0,
+                    line: 1,
+                    column: 1,
+                },
+                LineEntry {
+                    address: 4,
+                    file: 0,
+                    line: 3,
+                    column: 5,
+                },
+                LineEntry {
+                    address: 8,
+                    file: 1,
+                    line: 3,
+                    column: 1,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let debug_info = sample();
+        let bytes = debug_info.to_bytes();
+        assert_eq!(DebugInfo::from_bytes(&bytes), Some(debug_info));
+        assert_eq!(DebugInfo::from_bytes(&bytes[..bytes.len() - 1]), None);
+        let mut bad_file = bytes.clone();
+        let last = bad_file.len() - 9;
+        bad_file[last] = 7;
+        assert_eq!(DebugInfo::from_bytes(&bad_file), None);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let debug_info = sample();
+        assert_eq!(debug_info.line_entry(6).map(|entry| entry.line), Some(3));
+        assert_eq!(debug_info.line_entry(12), None);
+        assert_eq!(
+            debug_info.source_location(8).unwrap().to_string(),
+            "lib.iasm:3:1"
+        );
+        let addresses: Vec<usize> = debug_info.addresses_of_line("main.iasm", 3).collect();
+        assert_eq!(addresses, vec![4]);
+        assert_eq!(debug_info.addresses_of_line("other.iasm", 3).count(), 0);
+    }
+
+    #[test]
+    fn test_location() {
+        let debug_info = sample();
+        let symbols = vec![ExecutableSymbol {
+            name: "loop".to_string(),
+            address: 4,
+        }];
+        let location = Location::new(Some(&debug_info), &symbols, 4);
+        assert_eq!(location.to_string(), "main.iasm:3:5 (loop+0)");
+        assert_eq!(
+            Location::new(None, &symbols, 8).to_string(),
+            "0x0008 (loop+4)"
+        );
+        assert_eq!(
+            Location::new(Some(&debug_info), &[], 0).to_string(),
+            "main.iasm:1:1"
+        );
+        assert_eq!(Location::new(None, &[], 12).to_string(), "0x000c");
+    }
+}
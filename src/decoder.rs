@@ -0,0 +1,504 @@
+use std::fmt;
+
+use crate::fusion::fuse;
+use crate::instruction::{decode_instruction, Opcode};
+use crate::memory::{CODE_BASE, CODE_LIMIT, INSTRUCTION_WIDTH};
+
+// An instruction with its operands pulled out of the bytecode, so the VM can
+// execute it without touching the program bytes again
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum DecodedInstruction {
+    Load {
+        reg: u8,
+        value: u16,
+    },
+    Add {
+        r1: u8,
+        r2: u8,
+        dst: u8,
+    },
+    Sub {
+        r1: u8,
+        r2: u8,
+        dst: u8,
+    },
+    Mul {
+        r1: u8,
+        r2: u8,
+        dst: u8,
+    },
+    Div {
+        r1: u8,
+        r2: u8,
+        dst: u8,
+    },
+    Hlt,
+    Jmp {
+        reg: u8,
+    },
+    Jmpf {
+        reg: u8,
+    },
+    Jmpb {
+        reg: u8,
+    },
+    Eq {
+        r1: u8,
+        r2: u8,
+    },
+    Neq {
+        r1: u8,
+        r2: u8,
+    },
+    Gt {
+        r1: u8,
+        r2: u8,
+    },
+    Lt {
+        r1: u8,
+        r2: u8,
+    },
+    Gtq {
+        r1: u8,
+        r2: u8,
+    },
+    Ltq {
+        r1: u8,
+        r2: u8,
+    },
+    Jeq {
+        reg: u8,
+    },
+    Jneq {
+        reg: u8,
+    },
+    Aloc {
+        reg: u8,
+    },
+    Inc {
+        reg: u8,
+    },
+    Dec {
+        reg: u8,
+    },
+    Loadm {
+        addr: u8,
+        dst: u8,
+    },
+    Setm {
+        addr: u8,
+        src: u8,
+    },
+    Illegal {
+        opcode: u8,
+    },
+    // names a register the VM does not have, see `check_registers`
+    InvalidRegister {
+        register: u8,
+    },
+    // superinstructions produced by `fusion::fuse`, each one stands for the
+    // sequence of instructions starting at its slot
+    CompareBranch {
+        cmp: Comparison,
+        r1: u8,
+        r2: u8,
+        jump_if: bool,
+        target: u8,
+    },
+    StepCompareBranch {
+        reg: u8,
+        delta: i8,
+        cmp: Comparison,
+        r1: u8,
+        r2: u8,
+        jump_if: bool,
+        target: u8,
+    },
+    LoadAdd {
+        reg: u8,
+        value: u16,
+        r1: u8,
+        r2: u8,
+        dst: u8,
+    },
+    // the instruction does not lie entirely inside the executable part of the program
+    NotExecutable,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Comparison {
+    Eq,
+    Neq,
+    Gt,
+    Lt,
+    Gtq,
+    Ltq,
+}
+
+impl Comparison {
+    pub fn evaluate(&self, a: i32, b: i32) -> bool {
+        match self {
+            Comparison::Eq => a == b,
+            Comparison::Neq => a != b,
+            Comparison::Gt => a > b,
+            Comparison::Lt => a < b,
+            Comparison::Gtq => a >= b,
+            Comparison::Ltq => a <= b,
+        }
+    }
+}
+
+impl DecodedInstruction {
+    // Splits a comparison instruction into its kind and register operands
+    pub fn comparison(&self) -> Option<(Comparison, u8, u8)> {
+        match *self {
+            DecodedInstruction::Eq { r1, r2 } => Some((Comparison::Eq, r1, r2)),
+            DecodedInstruction::Neq { r1, r2 } => Some((Comparison::Neq, r1, r2)),
+            DecodedInstruction::Gt { r1, r2 } => Some((Comparison::Gt, r1, r2)),
+            DecodedInstruction::Lt { r1, r2 } => Some((Comparison::Lt, r1, r2)),
+            DecodedInstruction::Gtq { r1, r2 } => Some((Comparison::Gtq, r1, r2)),
+            DecodedInstruction::Ltq { r1, r2 } => Some((Comparison::Ltq, r1, r2)),
+            _ => None,
+        }
+    }
+
+    // The highest numbered register the instruction reads or writes
+    pub fn max_register(&self) -> Option<u8> {
+        match *self {
+            DecodedInstruction::Load { reg, .. }
+            | DecodedInstruction::Jmp { reg }
+            | DecodedInstruction::Jmpf { reg }
+            | DecodedInstruction::Jmpb { reg }
+            | DecodedInstruction::Jeq { reg }
+            | DecodedInstruction::Jneq { reg }
+            | DecodedInstruction::Aloc { reg }
+            | DecodedInstruction::Inc { reg }
+            | DecodedInstruction::Dec { reg } => Some(reg),
+            DecodedInstruction::Add { r1, r2, dst }
+            | DecodedInstruction::Sub { r1, r2, dst }
+            | DecodedInstruction::Mul { r1, r2, dst }
+            | DecodedInstruction::Div { r1, r2, dst } => Some(r1.max(r2).max(dst)),
+            DecodedInstruction::Eq { r1, r2 }
+            | DecodedInstruction::Neq { r1, r2 }
+            | DecodedInstruction::Gt { r1, r2 }
+            | DecodedInstruction::Lt { r1, r2 }
+            | DecodedInstruction::Gtq { r1, r2 }
+            | DecodedInstruction::Ltq { r1, r2 } => Some(r1.max(r2)),
+            DecodedInstruction::Loadm { addr, dst } => Some(addr.max(dst)),
+            DecodedInstruction::Setm { addr, src } => Some(addr.max(src)),
+            DecodedInstruction::CompareBranch { r1, r2, target, .. } => {
+                Some(r1.max(r2).max(target))
+            }
+            DecodedInstruction::StepCompareBranch {
+                reg,
+                r1,
+                r2,
+                target,
+                ..
+            } => Some(reg.max(r1).max(r2).max(target)),
+            DecodedInstruction::LoadAdd {
+                reg, r1, r2, dst, ..
+            } => Some(reg.max(r1).max(r2).max(dst)),
+            DecodedInstruction::Hlt
+            | DecodedInstruction::Illegal { .. }
+            | DecodedInstruction::InvalidRegister { .. }
+            | DecodedInstruction::NotExecutable => None,
+        }
+    }
+}
+
+impl Comparison {
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Comparison::Eq => Opcode::EQ,
+            Comparison::Neq => Opcode::NEQ,
+            Comparison::Gt => Opcode::GT,
+            Comparison::Lt => Opcode::LT,
+            Comparison::Gtq => Opcode::GTQ,
+            Comparison::Ltq => Opcode::LTQ,
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.opcode().mnemonic())
+    }
+}
+
+// Shown in assembly syntax, a superinstruction as the sequence it stands for
+impl fmt::Display for DecodedInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let branch = |jump_if: bool| if jump_if { "jeq" } else { "jneq" };
+        match *self {
+            DecodedInstruction::Load { reg, value } => write!(f, "load ${} #{}", reg, value),
+            DecodedInstruction::Add { r1, r2, dst } => write!(f, "add ${} ${} ${}", r1, r2, dst),
+            DecodedInstruction::Sub { r1, r2, dst } => write!(f, "sub ${} ${} ${}", r1, r2, dst),
+            DecodedInstruction::Mul { r1, r2, dst } => write!(f, "mul ${} ${} ${}", r1, r2, dst),
+            DecodedInstruction::Div { r1, r2, dst } => write!(f, "div ${} ${} ${}", r1, r2, dst),
+            DecodedInstruction::Hlt => write!(f, "hlt"),
+            DecodedInstruction::Jmp { reg } => write!(f, "jmp ${}", reg),
+            DecodedInstruction::Jmpf { reg } => write!(f, "jmpf ${}", reg),
+            DecodedInstruction::Jmpb { reg } => write!(f, "jmpb ${}", reg),
+            DecodedInstruction::Eq { .. }
+            | DecodedInstruction::Neq { .. }
+            | DecodedInstruction::Gt { .. }
+            | DecodedInstruction::Lt { .. }
+            | DecodedInstruction::Gtq { .. }
+            | DecodedInstruction::Ltq { .. } => {
+                let (cmp, r1, r2) = self.comparison().unwrap();
+                write!(f, "{} ${} ${}", cmp, r1, r2)
+            }
+            DecodedInstruction::Jeq { reg } => write!(f, "jeq ${}", reg),
+            DecodedInstruction::Jneq { reg } => write!(f, "jneq ${}", reg),
+            DecodedInstruction::Aloc { reg } => write!(f, "aloc ${}", reg),
+            DecodedInstruction::Inc { reg } => write!(f, "inc ${}", reg),
+            DecodedInstruction::Dec { reg } => write!(f, "dec ${}", reg),
+            DecodedInstruction::Loadm { addr, dst } => write!(f, "loadm ${} ${}", addr, dst),
+            DecodedInstruction::Setm { addr, src } => write!(f, "setm ${} ${}", addr, src),
+            DecodedInstruction::Illegal { opcode } => write!(f, "igl {}", opcode),
+            DecodedInstruction::InvalidRegister { register } => {
+                write!(f, "<invalid register ${}>", register)
+            }
+            DecodedInstruction::CompareBranch {
+                cmp,
+                r1,
+                r2,
+                jump_if,
+                target,
+            } => write!(
+                f,
+                "{} ${} ${}; {} ${}",
+                cmp,
+                r1,
+                r2,
+                branch(jump_if),
+                target
+            ),
+            DecodedInstruction::StepCompareBranch {
+                reg,
+                delta,
+                cmp,
+                r1,
+                r2,
+                jump_if,
+                target,
+            } => write!(
+                f,
+                "{} ${}; {} ${} ${}; {} ${}",
+                if delta > 0 { "inc" } else { "dec" },
+                reg,
+                cmp,
+                r1,
+                r2,
+                branch(jump_if),
+                target
+            ),
+            DecodedInstruction::LoadAdd {
+                reg,
+                value,
+                r1,
+                r2,
+                dst,
+            } => write!(f, "load ${} #{}; add ${} ${} ${}", reg, value, r1, r2, dst),
+            DecodedInstruction::NotExecutable => write!(f, "<not executable>"),
+        }
+    }
+}
+
+// Replaces an instruction that names a register outside the first
+// `register_count` with an InvalidRegister, so executing it faults
+pub fn check_registers(
+    instruction: DecodedInstruction,
+    register_count: usize,
+) -> DecodedInstruction {
+    match instruction.max_register() {
+        Some(register) if register as usize >= register_count => {
+            DecodedInstruction::InvalidRegister { register }
+        }
+        _ => instruction,
+    }
+}
+
+// Decodes the instruction starting at `pc`
+pub fn decode(program: &[u8], pc: usize) -> DecodedInstruction {
+    let executable_end = program.len().min(CODE_LIMIT - CODE_BASE);
+    if pc + INSTRUCTION_WIDTH > executable_end {
+        return DecodedInstruction::NotExecutable;
+    }
+    decode_instruction(&program[pc..pc + INSTRUCTION_WIDTH])
+}
+
+// Decodes every instruction slot of a program for a VM with `register_count`
+// registers. Slot `i` holds the instruction at address
+// `CODE_BASE + i * INSTRUCTION_WIDTH`; a trailing partial instruction gets a
+// slot of its own.
+pub fn decode_program(program: &[u8], register_count: usize) -> Vec<DecodedInstruction> {
+    (0..program.len().div_ceil(INSTRUCTION_WIDTH))
+        .map(|slot| {
+            check_registers(
+                decode(program, CODE_BASE + slot * INSTRUCTION_WIDTH),
+                register_count,
+            )
+        })
+        .collect()
+}
+
+// A decoded copy of a program, along with the bytes it was decoded from so
+// the VM can tell when it has gone stale
+#[derive(Debug)]
+pub struct DecodedProgram {
+    source: Vec<u8>,
+    fused: bool,
+    register_count: usize,
+    pub instructions: Vec<DecodedInstruction>,
+}
+
+impl DecodedProgram {
+    // Decodes `program`, optionally running the superinstruction pass over it
+    pub fn new(program: &[u8], fused: bool, register_count: usize) -> DecodedProgram {
+        let instructions = decode_program(program, register_count);
+        DecodedProgram {
+            source: program.to_vec(),
+            fused,
+            register_count,
+            instructions: if fused {
+                fuse(&instructions)
+            } else {
+                instructions
+            },
+        }
+    }
+
+    pub fn is_current(&self, program: &[u8], fused: bool, register_count: usize) -> bool {
+        self.fused == fused && self.register_count == register_count && self.source == program
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::instruction::{disassemble, OperandKind, OPCODES};
+
+    #[test]
+    fn test_decode() {
+        let program = vec![0, 1, 1, 244, 1, 0, 1, 2, 200, 0, 0, 0];
+        assert_eq!(
+            decode(&program, 0),
+            DecodedInstruction::Load { reg: 1, value: 500 }
+        );
+        assert_eq!(
+            decode(&program, 4),
+            DecodedInstruction::Add {
+                r1: 0,
+                r2: 1,
+                dst: 2
+            }
+        );
+        assert_eq!(
+            decode(&program, 8),
+            DecodedInstruction::Illegal { opcode: 200 }
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let program = vec![0, 1, 1, 244, 1, 0, 1, 2, 13, 3, 4, 0, 200, 0, 0, 0];
+        let text: Vec<String> = decode_program(&program, 32)
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(
+            text,
+            vec!["load $1 #500", "add $0 $1 $2", "gtq $3 $4", "igl 200"]
+        );
+        let fused = fuse(&decode_program(
+            &[18, 0, 0, 0, 10, 0, 1, 0, 15, 2, 0, 0],
+            32,
+        ));
+        assert_eq!(fused[0].to_string(), "inc $0; neq $0 $1; jeq $2");
+    }
+
+    // Decoded instructions print the same as the opcode table disassembles them
+    #[test]
+    fn test_display_matches_table() {
+        for info in OPCODES {
+            let bytes = [info.byte, 1, 2, 3];
+            assert_eq!(decode(&bytes, 0).to_string(), disassemble(&bytes));
+        }
+    }
+
+    // Every entry in the table decodes to an instruction that assembles back
+    // to the same bytes, with each register operand read from its own byte
+    #[test]
+    fn test_decode_round_trips_table() {
+        for info in OPCODES {
+            let width: usize = info.operands.iter().map(|operand| operand.width()).sum();
+            let mut bytes = [info.byte, 0, 0, 0];
+            for (i, byte) in bytes[1..=width].iter_mut().enumerate() {
+                *byte = i as u8 + 1;
+            }
+            let decoded = decode(&bytes, 0);
+            assert!(decoded.to_string().starts_with(info.mnemonic));
+            assert_eq!(
+                Assembler::new().assemble(&decoded.to_string()).unwrap(),
+                bytes,
+                "{}",
+                info.mnemonic
+            );
+            let mut offset = 1;
+            let registers = info
+                .operands
+                .iter()
+                .map(|operand| (*operand, operand.read(&bytes, &mut offset)))
+                .filter(|(operand, _)| *operand == OperandKind::Register)
+                .map(|(_, value)| value as u8);
+            assert_eq!(decoded.max_register(), registers.max());
+        }
+    }
+
+    #[test]
+    fn test_decode_program_truncated() {
+        let decoded = decode_program(&[18, 0, 0, 0, 19, 0], 32);
+        assert_eq!(
+            decoded,
+            vec![
+                DecodedInstruction::Inc { reg: 0 },
+                DecodedInstruction::NotExecutable
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decoded_program_staleness() {
+        let mut program = vec![5, 0, 0, 0];
+        let decoded = DecodedProgram::new(&program, true, 32);
+        assert!(decoded.is_current(&program, true, 32));
+        assert!(!decoded.is_current(&program, false, 32));
+        assert!(!decoded.is_current(&program, true, 16));
+        program.push(5);
+        assert!(!decoded.is_current(&program, true, 32));
+    }
+
+    #[test]
+    fn test_check_registers() {
+        // add $0 $9 $2
+        let decoded = decode_program(&[1, 0, 9, 2, 5, 0, 0, 0], 8);
+        assert_eq!(
+            decoded,
+            vec![
+                DecodedInstruction::InvalidRegister { register: 9 },
+                DecodedInstruction::Hlt
+            ]
+        );
+        assert_eq!(
+            check_registers(decode(&[1, 0, 9, 2], 0), 10),
+            DecodedInstruction::Add {
+                r1: 0,
+                r2: 9,
+                dst: 2
+            }
+        );
+    }
+}
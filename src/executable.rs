@@ -0,0 +1,413 @@
+// The on-disk format of an assembled Iridium program. All integers are big
+// endian, like the instruction encoding.
+//
+//   offset  size  field
+//        0     4  magic, "EPIE"
+//        4     2  ISA version the program was assembled for
+//        6     2  flags, none are defined yet so this must be 0
+//        8     4  entry point, the address execution starts at
+//       12     2  number of sections
+//       14     2  reserved, 0
+//       16     4  CRC-32 of the whole file, computed with this field set to 0
+//       20        section table, 12 bytes per section:
+//                   kind (1 byte), 3 reserved bytes, offset (4), length (4)
+//                 section contents, at the offsets given in the table
+//
+// The symbol section is a list of entries of the form
+//   address (4), name length (2), name (UTF-8)
+// The debug section is optional, see `debuginfo` for its contents.
+use std::fmt;
+
+use crate::debuginfo::DebugInfo;
+use crate::memory::{CODE_BASE, CODE_LIMIT, INSTRUCTION_WIDTH, RODATA_BASE, RODATA_LIMIT};
+
+pub const MAGIC: [u8; 4] = *b"EPIE";
+// bumped whenever the instruction set changes in a way old programs would notice
+pub const ISA_VERSION: u16 = 1;
+pub const HEADER_LEN: usize = 20;
+pub const SECTION_ENTRY_LEN: usize = 12;
+const CHECKSUM_OFFSET: usize = 16;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum SectionKind {
+    Code = 1,
+    ReadOnlyData = 2,
+    Symbols = 3,
+    Debug = 4,
+}
+
+impl SectionKind {
+    fn from_byte(byte: u8) -> Option<SectionKind> {
+        match byte {
+            1 => Some(SectionKind::Code),
+            2 => Some(SectionKind::ReadOnlyData),
+            3 => Some(SectionKind::Symbols),
+            4 => Some(SectionKind::Debug),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SectionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SectionKind::Code => write!(f, "code"),
+            SectionKind::ReadOnlyData => write!(f, "rodata"),
+            SectionKind::Symbols => write!(f, "symbols"),
+            SectionKind::Debug => write!(f, "debug"),
+        }
+    }
+}
+
+// A label and the address it stands for
+#[derive(Debug, PartialEq, Clone)]
+pub struct ExecutableSymbol {
+    pub name: String,
+    pub address: u32,
+}
+
+// Finds the label at or closest below `address`, along with how far past it
+// the address lies, for showing addresses as `label+8`
+pub fn nearest_symbol(symbols: &[ExecutableSymbol], address: u32) -> Option<(&str, u32)> {
+    symbols
+        .iter()
+        .filter(|symbol| symbol.address <= address)
+        .max_by_key(|symbol| symbol.address)
+        .map(|symbol| (symbol.name.as_str(), address - symbol.address))
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Executable {
+    pub version: u16,
+    pub flags: u16,
+    pub entry: u32,
+    pub code: Vec<u8>,
+    pub ro_data: Vec<u8>,
+    pub symbols: Vec<ExecutableSymbol>,
+    pub debug_info: Option<DebugInfo>,
+}
+
+impl Executable {
+    pub fn new(code: Vec<u8>, ro_data: Vec<u8>, entry: u32) -> Executable {
+        Executable {
+            version: ISA_VERSION,
+            flags: 0,
+            entry,
+            code,
+            ro_data,
+            symbols: vec![],
+            debug_info: None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut symbols = vec![];
+        for symbol in &self.symbols {
+            symbols.extend(symbol.address.to_be_bytes());
+            symbols.extend((symbol.name.len() as u16).to_be_bytes());
+            symbols.extend(symbol.name.as_bytes());
+        }
+        let mut sections = vec![
+            (SectionKind::Code, self.code.clone()),
+            (SectionKind::ReadOnlyData, self.ro_data.clone()),
+            (SectionKind::Symbols, symbols),
+        ];
+        if let Some(debug_info) = &self.debug_info {
+            sections.push((SectionKind::Debug, debug_info.to_bytes()));
+        }
+
+        let mut bytes = vec![];
+        bytes.extend(MAGIC);
+        bytes.extend(self.version.to_be_bytes());
+        bytes.extend(self.flags.to_be_bytes());
+        bytes.extend(self.entry.to_be_bytes());
+        bytes.extend((sections.len() as u16).to_be_bytes());
+        bytes.extend([0; 2]);
+        bytes.extend([0; 4]); // checksum, filled in below
+        let mut offset = HEADER_LEN + sections.len() * SECTION_ENTRY_LEN;
+        for (kind, contents) in &sections {
+            bytes.extend([*kind as u8, 0, 0, 0]);
+            bytes.extend((offset as u32).to_be_bytes());
+            bytes.extend((contents.len() as u32).to_be_bytes());
+            offset += contents.len();
+        }
+        for (_, contents) in &sections {
+            bytes.extend(contents.iter());
+        }
+        let checksum = crc32(&bytes);
+        bytes[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_be_bytes());
+        bytes
+    }
+
+    // Parses and validates an executable, rejecting anything this VM could not
+    // run as intended
+    pub fn from_bytes(bytes: &[u8]) -> Result<Executable, LoadError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(LoadError::TooShort);
+        }
+        if bytes[0..4] != MAGIC {
+            return Err(LoadError::BadMagic);
+        }
+        let version = read_u16(bytes, 4);
+        if version != ISA_VERSION {
+            return Err(LoadError::UnsupportedVersion(version));
+        }
+        let flags = read_u16(bytes, 6);
+        if flags != 0 {
+            return Err(LoadError::UnsupportedFlags(flags));
+        }
+        let expected = read_u32(bytes, CHECKSUM_OFFSET);
+        let mut zeroed = bytes.to_vec();
+        zeroed[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&[0; 4]);
+        let actual = crc32(&zeroed);
+        if expected != actual {
+            return Err(LoadError::ChecksumMismatch { expected, actual });
+        }
+
+        let entry = read_u32(bytes, 8);
+        let section_count = read_u16(bytes, 12) as usize;
+        let table_end = HEADER_LEN + section_count * SECTION_ENTRY_LEN;
+        if bytes.len() < table_end {
+            return Err(LoadError::TooShort);
+        }
+        let mut code = None;
+        let mut ro_data = None;
+        let mut symbols = None;
+        let mut debug_info = None;
+        for index in 0..section_count {
+            let entry_offset = HEADER_LEN + index * SECTION_ENTRY_LEN;
+            let kind = SectionKind::from_byte(bytes[entry_offset])
+                .ok_or(LoadError::UnknownSection(bytes[entry_offset]))?;
+            let offset = read_u32(bytes, entry_offset + 4) as usize;
+            let len = read_u32(bytes, entry_offset + 8) as usize;
+            let contents = bytes
+                .get(offset..offset + len)
+                .filter(|_| offset >= table_end)
+                .ok_or(LoadError::SectionOutOfBounds(kind))?;
+            let slot = match kind {
+                SectionKind::Code => &mut code,
+                SectionKind::ReadOnlyData => &mut ro_data,
+                SectionKind::Symbols => &mut symbols,
+                SectionKind::Debug => &mut debug_info,
+            };
+            if slot.replace(contents).is_some() {
+                return Err(LoadError::DuplicateSection(kind));
+            }
+        }
+
+        let code = code.ok_or(LoadError::MissingCode)?.to_vec();
+        if code.len() > CODE_LIMIT - CODE_BASE {
+            return Err(LoadError::SectionTooLarge(SectionKind::Code));
+        }
+        let ro_data = ro_data.unwrap_or_default().to_vec();
+        if ro_data.len() > RODATA_LIMIT - RODATA_BASE {
+            return Err(LoadError::SectionTooLarge(SectionKind::ReadOnlyData));
+        }
+        let entry_offset = (entry as usize).wrapping_sub(CODE_BASE);
+        if entry_offset >= code.len() || !entry_offset.is_multiple_of(INSTRUCTION_WIDTH) {
+            return Err(LoadError::BadEntryPoint(entry));
+        }
+        let symbols = match symbols {
+            Some(bytes) => parse_symbols(bytes).ok_or(LoadError::BadSymbolTable)?,
+            None => vec![],
+        };
+        let debug_info = match debug_info {
+            Some(bytes) => Some(DebugInfo::from_bytes(bytes).ok_or(LoadError::BadDebugInfo)?),
+            None => None,
+        };
+        Ok(Executable {
+            version,
+            flags,
+            entry,
+            code,
+            ro_data,
+            symbols,
+            debug_info,
+        })
+    }
+}
+
+fn parse_symbols(mut bytes: &[u8]) -> Option<Vec<ExecutableSymbol>> {
+    let mut symbols = vec![];
+    while !bytes.is_empty() {
+        if bytes.len() < 6 {
+            return None;
+        }
+        let address = read_u32(bytes, 0);
+        let len = read_u16(bytes, 4) as usize;
+        let name = std::str::from_utf8(bytes.get(6..6 + len)?).ok()?;
+        symbols.push(ExecutableSymbol {
+            name: name.to_string(),
+            address,
+        });
+        bytes = &bytes[6 + len..];
+    }
+    Some(symbols)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+// The CRC-32 used by zlib and PNG
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum LoadError {
+    TooShort,
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnsupportedFlags(u16),
+    ChecksumMismatch { expected: u32, actual: u32 },
+    UnknownSection(u8),
+    SectionOutOfBounds(SectionKind),
+    DuplicateSection(SectionKind),
+    SectionTooLarge(SectionKind),
+    MissingCode,
+    BadEntryPoint(u32),
+    BadSymbolTable,
+    BadDebugInfo,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::TooShort => write!(f, "file is too short to be an Iridium executable"),
+            LoadError::BadMagic => write!(f, "not an Iridium executable"),
+            LoadError::UnsupportedVersion(version) => write!(
+                f,
+                "executable is for ISA version {}, this VM runs version {}",
+                version, ISA_VERSION
+            ),
+            LoadError::UnsupportedFlags(flags) => {
+                write!(f, "executable uses unsupported flags {:#06x}", flags)
+            }
+            LoadError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "checksum mismatch, header says {:#010x} but contents give {:#010x}",
+                expected, actual
+            ),
+            LoadError::UnknownSection(kind) => write!(f, "unknown section kind {}", kind),
+            LoadError::SectionOutOfBounds(kind) => {
+                write!(f, "{} section lies outside the file", kind)
+            }
+            LoadError::DuplicateSection(kind) => write!(f, "more than one {} section", kind),
+            LoadError::SectionTooLarge(kind) => {
+                write!(f, "{} section does not fit in its segment", kind)
+            }
+            LoadError::MissingCode => write!(f, "executable has no code section"),
+            LoadError::BadEntryPoint(entry) => write!(
+                f,
+                "entry point {:#06x} is not an instruction in the code section",
+                entry
+            ),
+            LoadError::BadSymbolTable => write!(f, "symbol section is malformed"),
+            LoadError::BadDebugInfo => write!(f, "debug section is malformed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Executable {
+        let mut executable = Executable::new(vec![5, 0, 0, 0, 18, 0, 0, 0], vec![1, 2, 3], 4);
+        executable.symbols.push(ExecutableSymbol {
+            name: "main".to_string(),
+            address: 4,
+        });
+        executable
+    }
+
+    #[test]
+    fn test_nearest_symbol() {
+        let mut symbols = sample().symbols;
+        symbols.push(ExecutableSymbol {
+            name: "start".to_string(),
+            address: 0,
+        });
+        assert_eq!(nearest_symbol(&symbols, 0), Some(("start", 0)));
+        assert_eq!(nearest_symbol(&symbols, 12), Some(("main", 8)));
+        assert_eq!(nearest_symbol(&symbols[..1], 0), None);
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = sample().to_bytes();
+        assert_eq!(&bytes[0..4], b"EPIE");
+        assert_eq!(Executable::from_bytes(&bytes), Ok(sample()));
+
+        let mut executable = sample();
+        executable.debug_info = Some(DebugInfo {
+            files: vec!["main.iasm".to_string()],
+            lines: vec![],
+        });
+        let with_debug_info = executable.to_bytes();
+        assert_eq!(read_u16(&with_debug_info, 12), 4);
+        assert_eq!(Executable::from_bytes(&with_debug_info), Ok(executable));
+    }
+
+    #[test]
+    fn test_rejects_bad_files() {
+        let bytes = sample().to_bytes();
+        assert_eq!(
+            Executable::from_bytes(&bytes[..10]),
+            Err(LoadError::TooShort)
+        );
+        assert_eq!(
+            Executable::from_bytes(&[5, 0, 0, 0, 18, 0, 0, 0, 5, 0, 0, 0, 5, 0, 0, 0, 5, 0, 0, 0]),
+            Err(LoadError::BadMagic)
+        );
+
+        let mut old = bytes.clone();
+        old[5] = 0;
+        assert_eq!(
+            Executable::from_bytes(&old),
+            Err(LoadError::UnsupportedVersion(0))
+        );
+
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        assert!(matches!(
+            Executable::from_bytes(&corrupted),
+            Err(LoadError::ChecksumMismatch { .. })
+        ));
+
+        let mut executable = sample();
+        executable.entry = 2;
+        assert_eq!(
+            Executable::from_bytes(&executable.to_bytes()),
+            Err(LoadError::BadEntryPoint(2))
+        );
+        executable.entry = 8;
+        assert_eq!(
+            Executable::from_bytes(&executable.to_bytes()),
+            Err(LoadError::BadEntryPoint(8))
+        );
+    }
+}
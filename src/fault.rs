@@ -0,0 +1,61 @@
+use std::fmt;
+
+use crate::memory::MemoryError;
+
+// Raised by the VM when an instruction cannot be executed; `pc` is the
+// address of the instruction that caused it
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Fault {
+    pub pc: usize,
+    pub kind: FaultKind,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum FaultKind {
+    IllegalOpcode { opcode: u8 },
+    MisalignedJump { target: usize },
+    Memory(MemoryError),
+    InvalidRegister { register: u8 },
+    // `requested` is the heap size the ALOC asked for, which may be negative
+    OutOfMemory { requested: i64, limit: usize },
+    DivisionByZero,
+    ArithmeticOverflow,
+}
+
+impl Fault {
+    pub fn new(pc: usize, kind: FaultKind) -> Fault {
+        Fault { pc, kind }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "fault at pc {:#06x}: {}", self.pc, self.kind)
+    }
+}
+
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FaultKind::IllegalOpcode { opcode } => write!(f, "illegal opcode {}", opcode),
+            FaultKind::MisalignedJump { target } => {
+                write!(
+                    f,
+                    "jump to {:#06x} is not on an instruction boundary",
+                    target
+                )
+            }
+            FaultKind::Memory(e) => write!(f, "{}", e),
+            FaultKind::InvalidRegister { register } => {
+                write!(f, "register ${} does not exist", register)
+            }
+            FaultKind::OutOfMemory { requested, limit } => write!(
+                f,
+                "cannot resize the heap to {} bytes, the limit is {} bytes",
+                requested, limit
+            ),
+            FaultKind::DivisionByZero => write!(f, "division by zero"),
+            FaultKind::ArithmeticOverflow => write!(f, "arithmetic overflow"),
+        }
+    }
+}
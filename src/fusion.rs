@@ -0,0 +1,169 @@
+use crate::decoder::DecodedInstruction;
+
+// Rewrites a decoded instruction stream so that common instruction sequences
+// run as a single superinstruction. The stream keeps one entry per instruction
+// slot: a superinstruction replaces only the first instruction of its
+// sequence, so a jump into the middle of the sequence still finds the
+// original instruction there.
+pub fn fuse(instructions: &[DecodedInstruction]) -> Vec<DecodedInstruction> {
+    (0..instructions.len())
+        .map(|slot| fuse_at(&instructions[slot..]).unwrap_or(instructions[slot]))
+        .collect()
+}
+
+// Tries each pattern against the start of `window`, longest first
+fn fuse_at(window: &[DecodedInstruction]) -> Option<DecodedInstruction> {
+    match window {
+        // inc/dec, compare and branch, e.g. the body of a counting loop
+        [step, compare, branch, ..] => step_compare_branch(*step, *compare, *branch),
+        _ => None,
+    }
+    .or_else(|| match window {
+        [compare, branch, ..] => compare_branch(*compare, *branch),
+        _ => None,
+    })
+    .or_else(|| match window {
+        [load, add, ..] => load_add(*load, *add),
+        _ => None,
+    })
+}
+
+fn compare_branch(
+    compare: DecodedInstruction,
+    branch: DecodedInstruction,
+) -> Option<DecodedInstruction> {
+    let (cmp, r1, r2) = compare.comparison()?;
+    let (jump_if, target) = match branch {
+        DecodedInstruction::Jeq { reg } => (true, reg),
+        DecodedInstruction::Jneq { reg } => (false, reg),
+        _ => return None,
+    };
+    Some(DecodedInstruction::CompareBranch {
+        cmp,
+        r1,
+        r2,
+        jump_if,
+        target,
+    })
+}
+
+fn step_compare_branch(
+    step: DecodedInstruction,
+    compare: DecodedInstruction,
+    branch: DecodedInstruction,
+) -> Option<DecodedInstruction> {
+    let (reg, delta) = match step {
+        DecodedInstruction::Inc { reg } => (reg, 1),
+        DecodedInstruction::Dec { reg } => (reg, -1),
+        _ => return None,
+    };
+    match compare_branch(compare, branch)? {
+        DecodedInstruction::CompareBranch {
+            cmp,
+            r1,
+            r2,
+            jump_if,
+            target,
+        } => Some(DecodedInstruction::StepCompareBranch {
+            reg,
+            delta,
+            cmp,
+            r1,
+            r2,
+            jump_if,
+            target,
+        }),
+        _ => None,
+    }
+}
+
+fn load_add(load: DecodedInstruction, add: DecodedInstruction) -> Option<DecodedInstruction> {
+    match (load, add) {
+        (DecodedInstruction::Load { reg, value }, DecodedInstruction::Add { r1, r2, dst })
+            if reg == r1 || reg == r2 =>
+        {
+            Some(DecodedInstruction::LoadAdd {
+                reg,
+                value,
+                r1,
+                r2,
+                dst,
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::Comparison;
+
+    #[test]
+    fn test_fuse_keeps_one_entry_per_slot() {
+        let original = vec![
+            DecodedInstruction::Inc { reg: 0 },
+            DecodedInstruction::Neq { r1: 0, r2: 1 },
+            DecodedInstruction::Jeq { reg: 2 },
+            DecodedInstruction::Hlt,
+        ];
+        let fused = fuse(&original);
+        assert_eq!(fused.len(), original.len());
+        assert_eq!(
+            fused[0],
+            DecodedInstruction::StepCompareBranch {
+                reg: 0,
+                delta: 1,
+                cmp: Comparison::Neq,
+                r1: 0,
+                r2: 1,
+                jump_if: true,
+                target: 2
+            }
+        );
+        // a jump straight to the compare still gets a superinstruction
+        assert_eq!(
+            fused[1],
+            DecodedInstruction::CompareBranch {
+                cmp: Comparison::Neq,
+                r1: 0,
+                r2: 1,
+                jump_if: true,
+                target: 2
+            }
+        );
+        assert_eq!(fused[2], original[2]);
+        assert_eq!(fused[3], original[3]);
+    }
+
+    #[test]
+    fn test_fuse_load_add() {
+        let original = vec![
+            DecodedInstruction::Load { reg: 1, value: 7 },
+            DecodedInstruction::Add {
+                r1: 0,
+                r2: 1,
+                dst: 0,
+            },
+            DecodedInstruction::Load { reg: 3, value: 7 },
+            DecodedInstruction::Add {
+                r1: 0,
+                r2: 1,
+                dst: 0,
+            },
+        ];
+        let fused = fuse(&original);
+        assert_eq!(
+            fused[0],
+            DecodedInstruction::LoadAdd {
+                reg: 1,
+                value: 7,
+                r1: 0,
+                r2: 1,
+                dst: 0
+            }
+        );
+        // the loaded register is not an operand of the add
+        assert_eq!(fused[2], original[2]);
+    }
+}
@@ -0,0 +1,604 @@
+// A GDB remote serial protocol stub, so debugger frontends that speak RSP can
+// drive a VM over a local TCP socket. It serves the registers, memory,
+// breakpoints, write watchpoints, single stepping and continuing, and
+// describes the Iridium register set with a target description.
+//
+// Registers are numbered $0 up to the VM's register count, followed by the
+// pc, the equal flag and the remainder register. Every register is 32 bits
+// and sent big endian, the same byte order as values in VM memory.
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+
+use crate::debugger::{Debugger, StopReason, Watch};
+use crate::fault::FaultKind;
+
+// signal numbers GDB expects in stop replies
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+const PACKET_SIZE: usize = 4096;
+
+pub struct GdbStub {
+    pub debugger: Debugger,
+    exited: bool, // the program halted or finished, so it cannot be resumed
+}
+
+impl GdbStub {
+    pub fn new(debugger: Debugger) -> GdbStub {
+        GdbStub {
+            debugger,
+            exited: false,
+        }
+    }
+
+    // Waits for a single frontend to connect on `address` and serves it until
+    // it detaches or disconnects
+    pub fn listen<A: ToSocketAddrs>(&mut self, address: A) -> io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        let (stream, _) = listener.accept()?;
+        self.serve(stream)
+    }
+
+    // Serves packets read from `stream` until the frontend detaches, kills
+    // the program or closes the connection
+    pub fn serve<S: Read + Write>(&mut self, stream: S) -> io::Result<()> {
+        let mut stream = BufReader::new(stream);
+        while let Some(packet) = read_packet(&mut stream)? {
+            let packet = match packet {
+                Some(packet) => packet,
+                None => {
+                    stream.get_mut().write_all(b"-")?;
+                    continue;
+                }
+            };
+            stream.get_mut().write_all(b"+")?;
+            let done = packet == "D" || packet == "k";
+            if let Some(reply) = self.handle_packet(&packet) {
+                stream.get_mut().write_all(frame(&reply).as_bytes())?;
+            }
+            stream.get_mut().flush()?;
+            if done {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Answers a single packet, without the framing. Packets this stub does
+    // not support get the empty reply, as the protocol asks; only `k` gets none.
+    pub fn handle_packet(&mut self, packet: &str) -> Option<String> {
+        // commands are one ASCII character; anything else, such as the
+        // replacement character for an invalid byte, gets the empty reply
+        let (command, args) = match packet.get(..1) {
+            Some(command) => (command, &packet[1..]),
+            None => ("", ""),
+        };
+        let reply = match command {
+            "?" => self.stop_reply(&StopReason::Step),
+            "g" => {
+                let mut reply = String::new();
+                for register in 0..self.register_count() {
+                    write!(reply, "{:08x}", self.read_register(register).unwrap()).unwrap();
+                }
+                reply
+            }
+            "G" => match decode_hex(args) {
+                Some(bytes) if bytes.len() == self.register_count() * 4 => {
+                    for (register, value) in bytes.chunks(4).enumerate() {
+                        self.write_register(register, u32::from_be_bytes(word(value)));
+                    }
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16)
+                .ok()
+                .and_then(|register| self.read_register(register))
+            {
+                Some(value) => format!("{:08x}", value),
+                None => "E01".to_string(),
+            },
+            "P" => {
+                let write = args.split_once('=').and_then(|(register, value)| {
+                    let register = usize::from_str_radix(register, 16).ok()?;
+                    let value = decode_hex(value).filter(|v| v.len() == 4)?;
+                    (register < self.register_count()).then_some((register, value))
+                });
+                match write {
+                    Some((register, value)) => {
+                        self.write_register(register, u32::from_be_bytes(word(&value)));
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "m" => {
+                let memory = parse_address_len(args)
+                    .and_then(|(address, len)| self.debugger.vm.read_memory(address, len).ok());
+                match memory {
+                    Some(bytes) => encode_hex(bytes),
+                    None => "E01".to_string(),
+                }
+            }
+            "M" => {
+                let written = args.split_once(':').and_then(|(range, data)| {
+                    let (address, len) = parse_address_len(range)?;
+                    let bytes = decode_hex(data).filter(|bytes| bytes.len() == len)?;
+                    self.debugger.vm.write_memory(address, &bytes).ok()
+                });
+                match written {
+                    Some(()) => {
+                        self.debugger.clear_history();
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "Z" | "z" => self.breakpoint_packet(command == "Z", args),
+            "s" | "c" => {
+                if self.exited {
+                    return Some("W00".to_string());
+                }
+                if !args.is_empty() {
+                    // resuming somewhere else
+                    match usize::from_str_radix(args, 16) {
+                        Ok(address) => self.write_register(self.pc_register(), address as u32),
+                        Err(_) => return Some("E01".to_string()),
+                    }
+                }
+                let reason = if command == "s" {
+                    self.debugger.step_into()
+                } else {
+                    self.debugger.run_until_stop()
+                };
+                self.stop_reply(&reason)
+            }
+            "H" => "OK".to_string(),
+            "D" => "OK".to_string(),
+            "k" => return None,
+            "q" => self.query(args),
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn query(&self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            return format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE);
+        }
+        if args == "Attached" {
+            return "1".to_string();
+        }
+        if args == "C" {
+            return "QC1".to_string();
+        }
+        if args == "fThreadInfo" {
+            return "m1".to_string();
+        }
+        if args == "sThreadInfo" {
+            return "l".to_string();
+        }
+        if let Some(request) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            let xml = self.target_description();
+            return match parse_address_len(request) {
+                Some((offset, len)) if offset <= xml.len() => {
+                    // the length comes from the client, so it may be anything
+                    let end = offset.saturating_add(len).min(xml.len());
+                    let marker = if end == xml.len() { 'l' } else { 'm' };
+                    format!("{}{}", marker, &xml[offset..end])
+                }
+                _ => "E01".to_string(),
+            };
+        }
+        String::new()
+    }
+
+    fn breakpoint_packet(&mut self, insert: bool, args: &str) -> String {
+        let mut parts = args.split(',');
+        let kind = parts.next();
+        let address = parts.next().and_then(|a| usize::from_str_radix(a, 16).ok());
+        let len = parts.next().and_then(|l| usize::from_str_radix(l, 16).ok());
+        let (kind, address, len) = match (kind, address, len) {
+            (Some(kind), Some(address), Some(len)) => (kind, address, len),
+            _ => return "E01".to_string(),
+        };
+        match kind {
+            // software and hardware breakpoints are the same thing here
+            "0" | "1" => {
+                if insert {
+                    self.debugger.add_breakpoint(address, None);
+                } else {
+                    let ids: Vec<usize> = self
+                        .debugger
+                        .breakpoints()
+                        .iter()
+                        .filter(|b| b.address == address && b.condition.is_none())
+                        .map(|b| b.id)
+                        .collect();
+                    for id in ids {
+                        self.debugger.remove_breakpoint(id).unwrap();
+                    }
+                }
+                "OK".to_string()
+            }
+            // write watchpoints
+            "2" => {
+                let watch = Watch::Memory { address, len };
+                if insert {
+                    self.debugger.add_watchpoint(watch);
+                } else {
+                    let ids: Vec<usize> = self
+                        .debugger
+                        .watchpoints()
+                        .iter()
+                        .filter(|w| w.watch == watch)
+                        .map(|w| w.id)
+                        .collect();
+                    for id in ids {
+                        self.debugger.remove_watchpoint(id).unwrap();
+                    }
+                }
+                "OK".to_string()
+            }
+            // read and access watchpoints cannot be detected
+            _ => String::new(),
+        }
+    }
+
+    fn stop_reply(&mut self, reason: &StopReason) -> String {
+        match reason {
+            StopReason::Halted { .. } | StopReason::Finished => {
+                self.exited = true;
+                "W00".to_string()
+            }
+            StopReason::Faulted(fault) => {
+                let signal = match fault.kind {
+                    FaultKind::IllegalOpcode { .. } | FaultKind::InvalidRegister { .. } => SIGILL,
+                    FaultKind::DivisionByZero | FaultKind::ArithmeticOverflow => SIGFPE,
+                    FaultKind::MisalignedJump { .. }
+                    | FaultKind::Memory(_)
+                    | FaultKind::OutOfMemory { .. } => SIGSEGV,
+                };
+                format!("S{:02x}", signal)
+            }
+            StopReason::Watchpoint { id, .. } => {
+                let watch = self
+                    .debugger
+                    .watchpoints()
+                    .iter()
+                    .find(|w| w.id == *id)
+                    .map(|w| w.watch);
+                match watch {
+                    Some(Watch::Memory { address, .. }) => {
+                        format!("T{:02x}watch:{:x};", SIGTRAP, address)
+                    }
+                    _ => format!("S{:02x}", SIGTRAP),
+                }
+            }
+            StopReason::Step | StopReason::Breakpoint { .. } | StopReason::HistoryStart => {
+                format!("S{:02x}", SIGTRAP)
+            }
+        }
+    }
+
+    // general purpose registers, then pc, equal flag and remainder
+    fn register_count(&self) -> usize {
+        self.debugger.vm.registers.len() + 3
+    }
+
+    fn pc_register(&self) -> usize {
+        self.debugger.vm.registers.len()
+    }
+
+    fn read_register(&self, register: usize) -> Option<u32> {
+        let vm = &self.debugger.vm;
+        let general = vm.registers.len();
+        match register {
+            r if r < general => Some(vm.registers[r] as u32),
+            r if r == general => Some(vm.pc() as u32),
+            r if r == general + 1 => Some(vm.equal_flag() as u32),
+            r if r == general + 2 => Some(vm.remainder()),
+            _ => None,
+        }
+    }
+
+    fn write_register(&mut self, register: usize, value: u32) {
+        let general = self.debugger.vm.registers.len();
+        let vm = &mut self.debugger.vm;
+        match register {
+            r if r < general => vm.registers[r] = value as i32,
+            r if r == general => vm.set_pc(value as usize),
+            r if r == general + 1 => vm.set_equal_flag(value != 0),
+            r if r == general + 2 => vm.set_remainder(value),
+            _ => return,
+        }
+        // undoing steps recorded before the change would lose it
+        self.debugger.clear_history();
+    }
+
+    // The target description XML for the VM's register set
+    pub fn target_description(&self) -> String {
+        let mut xml = String::from(
+            "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n<feature name=\"org.iridium.core\">\n",
+        );
+        for register in 0..self.debugger.vm.registers.len() {
+            writeln!(
+                xml,
+                "<reg name=\"r{}\" bitsize=\"32\" type=\"int32\" regnum=\"{}\"/>",
+                register, register
+            )
+            .unwrap();
+        }
+        let general = self.debugger.vm.registers.len();
+        writeln!(
+            xml,
+            "<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{}\"/>",
+            general
+        )
+        .unwrap();
+        writeln!(
+            xml,
+            "<reg name=\"equal_flag\" bitsize=\"32\" type=\"uint32\" regnum=\"{}\"/>",
+            general + 1
+        )
+        .unwrap();
+        writeln!(
+            xml,
+            "<reg name=\"remainder\" bitsize=\"32\" type=\"uint32\" regnum=\"{}\"/>",
+            general + 2
+        )
+        .unwrap();
+        xml.push_str("</feature>\n</target>\n");
+        xml
+    }
+}
+
+// Reads the next packet, skipping acknowledgements and interrupt requests.
+// Returns Ok(None) at the end of the stream and Ok(Some(None)) for a packet
+// whose checksum does not match, which should be asked for again.
+fn read_packet<R: BufRead>(reader: &mut R) -> io::Result<Option<Option<String>>> {
+    let mut byte = [0u8];
+    loop {
+        if reader.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] == b'$' {
+            break;
+        }
+    }
+    let mut data = vec![];
+    reader.read_until(b'#', &mut data)?;
+    if data.pop() != Some(b'#') {
+        return Ok(None);
+    }
+    let mut checksum = [0u8; 2];
+    reader.read_exact(&mut checksum)?;
+    let expected = std::str::from_utf8(&checksum)
+        .ok()
+        .and_then(|c| u8::from_str_radix(c, 16).ok());
+    if expected != Some(checksum_of(&data)) {
+        return Ok(Some(None));
+    }
+    Ok(Some(Some(
+        String::from_utf8_lossy(&unescape(&data)).into_owned(),
+    )))
+}
+
+// `}` escapes the byte after it, XORed with 0x20
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut bytes = data.iter();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'}' => {
+                if let Some(next) = bytes.next() {
+                    out.push(next ^ 0x20);
+                }
+            }
+            byte => out.push(*byte),
+        }
+    }
+    out
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+// Wraps a reply in `$...#checksum`, escaping the characters that need it
+pub fn frame(data: &str) -> String {
+    let mut escaped = vec![];
+    for byte in data.bytes() {
+        match byte {
+            b'$' | b'#' | b'}' | b'*' => escaped.extend([b'}', byte ^ 0x20]),
+            byte => escaped.push(byte),
+        }
+    }
+    let checksum = checksum_of(&escaped);
+    format!("${}#{:02x}", String::from_utf8(escaped).unwrap(), checksum)
+}
+
+fn parse_address_len(args: &str) -> Option<(usize, usize)> {
+    let (address, len) = args.split_once(',')?;
+    Some((
+        usize::from_str_radix(address, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn word(bytes: &[u8]) -> [u8; 4] {
+    [bytes[0], bytes[1], bytes[2], bytes[3]]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+    use std::thread;
+
+    use super::*;
+    use crate::config::VmConfig;
+    use crate::memory::HEAP_BASE;
+    use crate::vm::VM;
+
+    // counts $0 up to 5, then halts
+    fn counting_loop() -> GdbStub {
+        let config = VmConfig::builder().register_count(4).build().unwrap();
+        let mut vm = VM::with_config(config);
+        vm.program = vec![
+            0, 1, 0, 5, // load $1 #5
+            0, 2, 0, 8, // load $2 #8
+            18, 0, 0, 0, // inc $0
+            10, 0, 1, 0, // neq $0 $1
+            15, 2, 0, 0, // jeq $2
+            5, 0, 0, 0, // hlt
+        ];
+        GdbStub::new(Debugger::new(vm))
+    }
+
+    // Sends packets the way a frontend would and collects the replies
+    struct Client {
+        reader: BufReader<TcpStream>,
+    }
+
+    impl Client {
+        fn send(&mut self, packet: &str) -> String {
+            self.reader
+                .get_mut()
+                .write_all(frame(packet).as_bytes())
+                .unwrap();
+            let mut ack = [0u8];
+            self.reader.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+            let reply = read_packet(&mut self.reader).unwrap().unwrap().unwrap();
+            self.reader.get_mut().write_all(b"+").unwrap();
+            reply
+        }
+    }
+
+    #[test]
+    fn test_framing() {
+        assert_eq!(frame("OK"), "$OK#9a");
+        assert_eq!(frame("a#b"), "$a}\u{3}b#43");
+        let mut reader = BufReader::new(&b"+$a}\x03b#43$OK#00"[..]);
+        assert_eq!(
+            read_packet(&mut reader).unwrap(),
+            Some(Some("a#b".to_string()))
+        );
+        assert_eq!(read_packet(&mut reader).unwrap(), Some(None));
+        assert_eq!(read_packet(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_handle_packet() {
+        let mut stub = counting_loop();
+        assert_eq!(stub.handle_packet("?").unwrap(), "S05");
+        assert_eq!(stub.handle_packet("p1").unwrap(), "00000000");
+        assert_eq!(stub.handle_packet("P1=0000000a").unwrap(), "OK");
+        assert_eq!(stub.debugger.vm.registers[1], 10);
+        assert_eq!(stub.handle_packet("p9").unwrap(), "E01");
+        assert_eq!(
+            stub.handle_packet("g").unwrap(),
+            "00000000".to_string() + "0000000a" + &"00000000".repeat(5)
+        );
+        assert_eq!(stub.handle_packet("m0,4").unwrap(), "00010005");
+        assert_eq!(stub.handle_packet("M0,4:05000000").unwrap(), "E01");
+        assert_eq!(stub.handle_packet("vMustReplyEmpty").unwrap(), "");
+        assert_eq!(stub.handle_packet("Z4,0,4").unwrap(), "");
+        assert_eq!(stub.handle_packet("k"), None);
+        assert_eq!(stub.handle_packet("é").unwrap(), "");
+        assert_eq!(stub.handle_packet("\u{fffd}g").unwrap(), "");
+        assert_eq!(stub.handle_packet("").unwrap(), "");
+
+        let xml = stub.target_description();
+        assert!(xml.contains("<reg name=\"r3\" bitsize=\"32\" type=\"int32\" regnum=\"3\"/>"));
+        assert!(xml.contains("<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"4\"/>"));
+        let first = stub
+            .handle_packet("qXfer:features:read:target.xml:0,10")
+            .unwrap();
+        assert_eq!(first, format!("m{}", &xml[..16]));
+        let rest = stub
+            .handle_packet(&format!(
+                "qXfer:features:read:target.xml:10,{:x}",
+                xml.len()
+            ))
+            .unwrap();
+        assert_eq!(rest, format!("l{}", &xml[16..]));
+        let huge = stub
+            .handle_packet("qXfer:features:read:target.xml:1,ffffffffffffffff")
+            .unwrap();
+        assert_eq!(huge, format!("l{}", &xml[1..]));
+        assert_eq!(stub.handle_packet("m0,ffffffffffffffff").unwrap(), "E01");
+        assert_eq!(stub.handle_packet("mffffffffffffffff,2").unwrap(), "E01");
+    }
+
+    #[test]
+    fn test_scripted_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut stub = counting_loop();
+            let (stream, _) = listener.accept().unwrap();
+            stub.serve(stream).unwrap();
+            stub.debugger.vm.registers.clone()
+        });
+        let mut client = Client {
+            reader: BufReader::new(TcpStream::connect(address).unwrap()),
+        };
+
+        assert!(client
+            .send("qSupported:xmlRegisters=i386")
+            .contains("qXfer:features:read+"));
+        assert_eq!(client.send("Hg0"), "OK");
+        assert_eq!(client.send("?"), "S05");
+        // break on the inc, the second time round the loop
+        assert_eq!(client.send("Z0,8,4"), "OK");
+        assert_eq!(client.send("c"), "S05");
+        assert_eq!(client.send("p4"), "00000008");
+        assert_eq!(client.send("c"), "S05");
+        assert_eq!(client.send("p0"), "00000001");
+        assert_eq!(client.send("z0,8,4"), "OK");
+        assert_eq!(client.send("s"), "S05");
+        assert_eq!(client.send("p0"), "00000002");
+        // set $0 so the loop ends on the next pass
+        assert_eq!(client.send("P0=00000004"), "OK");
+        assert_eq!(client.send("c"), "W00");
+        assert_eq!(client.send("D"), "OK");
+        assert_eq!(server.join().unwrap()[0], 5);
+    }
+
+    #[test]
+    fn test_watchpoint_and_memory() {
+        // aloc $0, setm $1 $0
+        let mut vm = VM::new();
+        vm.program = vec![17, 0, 0, 0, 21, 1, 0, 0];
+        vm.registers[0] = 4;
+        vm.registers[1] = HEAP_BASE as i32;
+        let mut stub = GdbStub::new(Debugger::new(vm));
+        assert_eq!(stub.handle_packet("s").unwrap(), "S05");
+        assert_eq!(stub.handle_packet("Z2,1000000,4").unwrap(), "OK");
+        assert_eq!(stub.handle_packet("c").unwrap(), "T05watch:1000000;");
+        assert_eq!(stub.handle_packet("m1000000,4").unwrap(), "00000004");
+        assert_eq!(stub.handle_packet("M1000000,2:abcd").unwrap(), "OK");
+        assert_eq!(stub.debugger.vm.heap(), &[0xab, 0xcd, 0, 4]);
+        assert_eq!(stub.handle_packet("z2,1000000,4").unwrap(), "OK");
+        assert!(stub.debugger.watchpoints().is_empty());
+        assert_eq!(stub.handle_packet("c").unwrap(), "W00");
+    }
+}
@@ -1,28 +1,158 @@
 use nom::types::CompleteStr;
 
+use crate::decoder::DecodedInstruction;
+
+// The kinds of operand an instruction takes, in the order they are encoded
+// after the opcode byte
 #[derive(Debug, PartialEq, Copy, Clone)]
-pub enum Opcode {
-    LOAD, // load $0 #10
-    ADD,  // add $0 $1 $2
-    SUB,  // sub $0 $1 $2
-    MUL,  // mul $0 $1 $2
-    DIV,  // div $0 $1 $2
-    HLT,  // hlt
-    JMP,  // jmp $0
-    JMPF, // jmpf $0
-    JMPB, // jmpb $0
-    EQ,   // eq $0 $1
-    NEQ,  // neq $0 $1
-    GT,   // gt $0 $1
-    LT,   // lt $0 $1
-    GTQ,  // gtq $0 $1
-    LTQ,  // ltq $0 $1
-    JEQ,  // jeq $0
-    JNEQ, // jneq $0
-    ALOC, // aloc $0
-    INC,  // inc $0
-    DEC,  // dec $0
-    IGL,  // illegal
+pub enum OperandKind {
+    Register,  // $0, one byte
+    Immediate, // #100 or the address of a label, two bytes
+}
+
+impl OperandKind {
+    // the number of bytes the operand takes in an encoded instruction
+    pub fn width(self) -> usize {
+        match self {
+            OperandKind::Register => 1,
+            OperandKind::Immediate => 2,
+        }
+    }
+
+    // Reads the operand at `offset` in an encoded instruction and moves
+    // `offset` past it. Missing bytes read as zero.
+    pub fn read(self, bytes: &[u8], offset: &mut usize) -> u16 {
+        let byte = |index: usize| bytes.get(index).copied().unwrap_or(0);
+        let value = match self {
+            OperandKind::Register => byte(*offset) as u16,
+            OperandKind::Immediate => u16::from_be_bytes([byte(*offset), byte(*offset + 1)]),
+        };
+        *offset += self.width();
+        value
+    }
+
+    // how the operand is written in `.help`
+    pub fn placeholder(self) -> &'static str {
+        match self {
+            OperandKind::Register => "$reg",
+            OperandKind::Immediate => "#value",
+        }
+    }
+}
+
+// Everything there is to know about an opcode
+#[derive(Debug, PartialEq)]
+pub struct OpcodeInfo {
+    pub opcode: Opcode,
+    pub mnemonic: &'static str,
+    pub byte: u8,
+    pub operands: &'static [OperandKind],
+    pub doc: &'static str,
+}
+
+impl OpcodeInfo {
+    // e.g. `add $reg $reg $reg`
+    pub fn usage(&self) -> String {
+        let mut usage = self.mnemonic.to_string();
+        for operand in self.operands {
+            usage.push(' ');
+            usage.push_str(operand.placeholder());
+        }
+        usage
+    }
+}
+
+// Declares the instruction set from one table, so the byte values, mnemonics
+// and operands of `Opcode` cannot drift apart. Each entry names the
+// `DecodedInstruction` it decodes to, with one field per operand in encoding
+// order.
+macro_rules! opcodes {
+    ($($name:ident = $byte:literal, $mnemonic:literal,
+        $variant:ident { $($field:ident: $operand:ident),* }, $doc:literal;)*) => {
+        #[derive(Debug, PartialEq, Copy, Clone)]
+        pub enum Opcode {
+            $($name = $byte,)*
+            IGL = 255, // any byte that is not an opcode
+        }
+
+        pub const OPCODES: &[OpcodeInfo] = &[
+            $(OpcodeInfo {
+                opcode: Opcode::$name,
+                mnemonic: $mnemonic,
+                byte: $byte,
+                operands: &[$(OperandKind::$operand),*],
+                doc: $doc,
+            },)*
+        ];
+
+        // Decodes the instruction at the start of `bytes`, reading its
+        // operands in the order the table lists them
+        pub fn decode_instruction(bytes: &[u8]) -> DecodedInstruction {
+            let opcode = bytes.first().copied().unwrap_or(0);
+            let mut offset = 1;
+            match Opcode::from(opcode) {
+                $(Opcode::$name => DecodedInstruction::$variant {
+                    $($field: OperandKind::$operand.read(bytes, &mut offset) as _,)*
+                },)*
+                Opcode::IGL => DecodedInstruction::Illegal { opcode },
+            }
+        }
+    };
+}
+
+opcodes! {
+    LOAD = 0, "load", Load { reg: Register, value: Immediate }, "load a value into a register";
+    ADD = 1, "add", Add { r1: Register, r2: Register, dst: Register }, "add two registers into a third";
+    SUB = 2, "sub", Sub { r1: Register, r2: Register, dst: Register }, "subtract the second register from the first into a third";
+    MUL = 3, "mul", Mul { r1: Register, r2: Register, dst: Register }, "multiply two registers into a third";
+    DIV = 4, "div", Div { r1: Register, r2: Register, dst: Register }, "divide the first register by the second into a third, keeping the remainder";
+    HLT = 5, "hlt", Hlt {}, "stop the program";
+    JMP = 6, "jmp", Jmp { reg: Register }, "jump to the address in a register";
+    JMPF = 7, "jmpf", Jmpf { reg: Register }, "jump forwards by the number of bytes in a register";
+    JMPB = 8, "jmpb", Jmpb { reg: Register }, "jump backwards by the number of bytes in a register";
+    EQ = 9, "eq", Eq { r1: Register, r2: Register }, "set the equal flag if two registers are equal";
+    NEQ = 10, "neq", Neq { r1: Register, r2: Register }, "set the equal flag if two registers differ";
+    GT = 11, "gt", Gt { r1: Register, r2: Register }, "set the equal flag if the first register is greater";
+    LT = 12, "lt", Lt { r1: Register, r2: Register }, "set the equal flag if the first register is less";
+    GTQ = 13, "gtq", Gtq { r1: Register, r2: Register }, "set the equal flag if the first register is greater or equal";
+    LTQ = 14, "ltq", Ltq { r1: Register, r2: Register }, "set the equal flag if the first register is less or equal";
+    JEQ = 15, "jeq", Jeq { reg: Register }, "jump to the address in a register if the equal flag is set";
+    JNEQ = 16, "jneq", Jneq { reg: Register }, "jump to the address in a register if the equal flag is clear";
+    ALOC = 17, "aloc", Aloc { reg: Register }, "grow the heap by the number of bytes in a register";
+    INC = 18, "inc", Inc { reg: Register }, "add one to a register";
+    DEC = 19, "dec", Dec { reg: Register }, "subtract one from a register";
+    LOADM = 20, "loadm", Loadm { addr: Register, dst: Register }, "load the word at the address in the first register into the second";
+    SETM = 21, "setm", Setm { addr: Register, src: Register }, "store the second register at the address in the first";
+}
+
+// Opcodes by byte value, for decoding
+const BY_BYTE: [Opcode; 256] = {
+    let mut table = [Opcode::IGL; 256];
+    let mut i = 0;
+    while i < OPCODES.len() {
+        table[OPCODES[i].byte as usize] = OPCODES[i].opcode;
+        i += 1;
+    }
+    table
+};
+
+impl Opcode {
+    // None for IGL, which is not in the table
+    pub fn info(self) -> Option<&'static OpcodeInfo> {
+        OPCODES.iter().find(|info| info.opcode == self)
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        self.info().map_or("igl", |info| info.mnemonic)
+    }
+
+    // Mnemonics are not case sensitive
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Opcode> {
+        OPCODES
+            .iter()
+            .find(|info| info.mnemonic.eq_ignore_ascii_case(mnemonic))
+            .map(|info| info.opcode)
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -32,71 +162,47 @@ pub struct Instruction {
 
 impl Instruction {
     pub fn new(opcode: Opcode) -> Instruction {
-        Instruction { opcode: opcode }
+        Instruction { opcode }
     }
 }
 
 impl From<u8> for Opcode {
     fn from(v: u8) -> Self {
-        match v {
-            0 => Opcode::LOAD,
-            1 => Opcode::ADD,
-            2 => Opcode::SUB,
-            3 => Opcode::MUL,
-            4 => Opcode::DIV,
-            5 => Opcode::HLT,
-            6 => Opcode::JMP,
-            7 => Opcode::JMPF,
-            8 => Opcode::JMPB,
-            9 => Opcode::EQ,
-            10 => Opcode::NEQ,
-            11 => Opcode::GT,
-            12 => Opcode::LT,
-            13 => Opcode::GTQ,
-            14 => Opcode::LTQ,
-            15 => Opcode::JEQ,
-            16 => Opcode::JNEQ,
-            17 => Opcode::ALOC,
-            18 => Opcode::INC,
-            19 => Opcode::DEC,
-            _ => Opcode::IGL,
-        }
+        BY_BYTE[v as usize]
     }
 }
 
 impl<'a> From<CompleteStr<'a>> for Opcode {
     fn from(v: CompleteStr<'a>) -> Self {
-        let s = v.to_uppercase();
-
-        match s.as_str() {
-            "LOAD" => Opcode::LOAD,
-            "ADD" => Opcode::ADD,
-            "SUB" => Opcode::SUB,
-            "MUL" => Opcode::MUL,
-            "DIV" => Opcode::DIV,
-            "HLT" => Opcode::HLT,
-            "JMP" => Opcode::JMP,
-            "JMPF" => Opcode::JMPF,
-            "JMPB" => Opcode::JMPB,
-            "EQ" => Opcode::EQ,
-            "NEQ" => Opcode::NEQ,
-            "GT" => Opcode::GT,
-            "LT" => Opcode::LT,
-            "GTQ" => Opcode::GTQ,
-            "LTQ" => Opcode::LTQ,
-            "JEQ" => Opcode::JEQ,
-            "JNEQ" => Opcode::JNEQ,
-            "ALOC" => Opcode::ALOC,
-            "INC" => Opcode::INC,
-            "DEC" => Opcode::DEC,
-            _ => Opcode::IGL,
+        Opcode::from_mnemonic(&v).unwrap_or(Opcode::IGL)
+    }
+}
+
+// Disassembles the instruction at the start of `bytes` from its opcode's
+// operands, e.g. `load $0 #100`. Missing bytes read as zero.
+pub fn disassemble(bytes: &[u8]) -> String {
+    let byte = |index: usize| bytes.get(index).copied().unwrap_or(0);
+    let info = match Opcode::from(byte(0)).info() {
+        Some(info) => info,
+        None => return format!("igl {}", byte(0)),
+    };
+    let mut text = info.mnemonic.to_string();
+    let mut offset = 1;
+    for operand in info.operands {
+        let value = operand.read(bytes, &mut offset);
+        match operand {
+            OperandKind::Register => text.push_str(&format!(" ${}", value)),
+            OperandKind::Immediate => text.push_str(&format!(" #{}", value)),
         }
     }
+    text
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::Assembler;
+    use crate::memory::INSTRUCTION_WIDTH;
 
     #[test]
     fn test_create_hlt() {
@@ -117,4 +223,44 @@ mod tests {
         let opcode = Opcode::from(CompleteStr("illegal"));
         assert_eq!(opcode, Opcode::IGL);
     }
+
+    // Every opcode in the table parses, encodes, decodes and disassembles
+    // back to where it started
+    #[test]
+    fn test_table_round_trip() {
+        for info in OPCODES {
+            assert_eq!(info.opcode as u8, info.byte);
+            assert_eq!(Opcode::from(info.byte), info.opcode);
+            assert_eq!(Opcode::from_mnemonic(info.mnemonic), Some(info.opcode));
+            assert_eq!(
+                Opcode::from(CompleteStr(&info.mnemonic.to_uppercase())),
+                info.opcode
+            );
+            assert_eq!(info.opcode.mnemonic(), info.mnemonic);
+            let width: usize = info.operands.iter().map(|operand| operand.width()).sum();
+            assert!(width < INSTRUCTION_WIDTH, "{} does not fit", info.mnemonic);
+
+            let mut source = info.mnemonic.to_string();
+            for (i, operand) in info.operands.iter().enumerate() {
+                match operand {
+                    OperandKind::Register => source.push_str(&format!(" ${}", i + 1)),
+                    OperandKind::Immediate => source.push_str(" #258"),
+                }
+            }
+            let bytes = Assembler::new().assemble(&source).unwrap();
+            assert_eq!(bytes[0], info.byte);
+            assert_eq!(disassemble(&bytes), source);
+        }
+        let bytes: Vec<u8> = OPCODES.iter().map(|info| info.byte).collect();
+        assert!((0..bytes.len()).all(|i| !bytes[i + 1..].contains(&bytes[i])));
+        assert_eq!(Opcode::from(200), Opcode::IGL);
+        assert_eq!(disassemble(&[200, 0, 0, 0]), "igl 200");
+    }
+
+    #[test]
+    fn test_usage() {
+        assert_eq!(Opcode::LOAD.info().unwrap().usage(), "load $reg #value");
+        assert_eq!(Opcode::HLT.info().unwrap().usage(), "hlt");
+        assert_eq!(Opcode::IGL.info(), None);
+    }
 }
@@ -0,0 +1,61 @@
+use std::ffi::c_void;
+use std::ptr;
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 2;
+const MAP_ANONYMOUS: i32 = 0x20;
+
+// libc is always linked on Linux, so there is no need for a binding crate
+extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, off: i64)
+        -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+// A private mapping holding machine code. It is only ever writable while the
+// code is copied in, after that it is read + execute.
+pub struct ExecutableMemory {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl ExecutableMemory {
+    pub fn new(code: &[u8]) -> Option<ExecutableMemory> {
+        let len = code.len().max(1);
+        unsafe {
+            let ptr = mmap(
+                ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            // MAP_FAILED
+            if ptr as isize == -1 {
+                return None;
+            }
+            let memory = ExecutableMemory { ptr, len };
+            ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, code.len());
+            if mprotect(ptr, len, PROT_READ | PROT_EXEC) != 0 {
+                return None;
+            }
+            Some(memory)
+        }
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr as *const u8
+    }
+}
+
+impl Drop for ExecutableMemory {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.ptr, self.len);
+        }
+    }
+}
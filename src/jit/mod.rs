@@ -0,0 +1,342 @@
+// Tiered JIT for x86-64 Linux. Hot basic blocks of the decoded program are
+// compiled to native code that works directly on the VM's register file and
+// equal flag. Anything a block cannot do natively (memory access, division,
+// arithmetic overflow, a bad jump target, ...) is handed back to the
+// interpreter at the exact instruction it would have faulted or panicked on,
+// so the JIT never changes what a program does.
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("the `jit` feature only supports x86-64 Linux");
+
+mod executable_memory;
+mod x86;
+
+use crate::config::MAX_REGISTER_COUNT;
+use crate::decoder::{decode_program, Comparison, DecodedInstruction};
+use crate::memory::{CODE_BASE, CODE_LIMIT, INSTRUCTION_WIDTH};
+use executable_memory::ExecutableMemory;
+use x86::*;
+
+// number of times the interpreter reaches an instruction before a block
+// starting there is compiled
+pub const DEFAULT_THRESHOLD: u32 = 1000;
+
+type BlockFn = unsafe extern "sysv64" fn(*mut i32, *mut bool) -> u64;
+
+pub struct CompiledBlock {
+    _memory: ExecutableMemory,
+    entry: BlockFn,
+}
+
+impl CompiledBlock {
+    fn new(code: &[u8]) -> Option<CompiledBlock> {
+        let memory = ExecutableMemory::new(code)?;
+        let entry = unsafe { std::mem::transmute::<*const u8, BlockFn>(memory.as_ptr()) };
+        Some(CompiledBlock {
+            _memory: memory,
+            entry,
+        })
+    }
+
+    /// Runs the block and returns the pc to continue from, plus whether the
+    /// instruction at that pc has to go through the interpreter.
+    ///
+    /// # Safety
+    ///
+    /// `registers` must hold at least as many registers as the block was
+    /// compiled for and `equal_flag` must be valid for writes.
+    pub unsafe fn call(&self, registers: *mut i32, equal_flag: *mut bool) -> (usize, bool) {
+        let result = (self.entry)(registers, equal_flag);
+        (result as u32 as usize, result & INTERPRET_NEXT != 0)
+    }
+}
+
+enum Tier {
+    Interpreted(u32),
+    Compiled(CompiledBlock),
+    // nothing at this slot can be compiled
+    Unsupported,
+}
+
+pub struct Jit {
+    threshold: u32,
+    // blocks index the register file directly, so they are only valid for
+    // as many registers as they were compiled for
+    register_count: usize,
+    source: Vec<u8>,
+    instructions: Vec<DecodedInstruction>,
+    tiers: Vec<Tier>,
+}
+
+impl Jit {
+    pub fn new(program: &[u8], threshold: u32, register_count: usize) -> Jit {
+        // register operands are checked against the VM when a block is compiled
+        let instructions = decode_program(program, MAX_REGISTER_COUNT);
+        Jit {
+            threshold,
+            register_count,
+            source: program.to_vec(),
+            tiers: instructions.iter().map(|_| Tier::Interpreted(0)).collect(),
+            instructions,
+        }
+    }
+
+    pub fn is_current(&self, program: &[u8], threshold: u32, register_count: usize) -> bool {
+        self.threshold == threshold
+            && self.register_count == register_count
+            && self.source == program
+    }
+
+    // Returns the compiled block starting at `slot`. Until the slot gets hot
+    // this only counts the visit, compiling the block once it crosses the
+    // threshold.
+    pub fn block(&mut self, slot: usize) -> Option<&CompiledBlock> {
+        if let Tier::Interpreted(count) = &mut self.tiers[slot] {
+            *count += 1;
+            if *count < self.threshold {
+                return None;
+            }
+            let code_end = self.source.len().min(CODE_LIMIT - CODE_BASE);
+            self.tiers[slot] =
+                compile_block(&self.instructions, slot, code_end, self.register_count)
+                    .and_then(|code| CompiledBlock::new(&code))
+                    .map_or(Tier::Unsupported, Tier::Compiled);
+        }
+        match &self.tiers[slot] {
+            Tier::Compiled(block) => Some(block),
+            _ => None,
+        }
+    }
+
+    pub fn compiled_blocks(&self) -> usize {
+        self.tiers
+            .iter()
+            .filter(|t| matches!(t, Tier::Compiled(_)))
+            .count()
+    }
+}
+
+fn condition_code(cmp: Comparison) -> u8 {
+    match cmp {
+        Comparison::Eq => CC_EQUAL,
+        Comparison::Neq => CC_NOT_EQUAL,
+        Comparison::Gt => CC_GREATER,
+        Comparison::Lt => CC_LESS,
+        Comparison::Gtq => CC_GREATER_EQUAL,
+        Comparison::Ltq => CC_LESS_EQUAL,
+    }
+}
+
+// Compiles the basic block starting at `slot`. Returns None when not even
+// its first instruction can run natively.
+pub fn compile_block(
+    instructions: &[DecodedInstruction],
+    slot: usize,
+    code_end: usize,
+    register_count: usize,
+) -> Option<Vec<u8>> {
+    let mut e = Emitter::new();
+    let valid = |regs: &[u8]| regs.iter().all(|r| (*r as usize) < register_count);
+    for (i, instruction) in instructions.iter().enumerate().skip(slot) {
+        let pc = (CODE_BASE + i * INSTRUCTION_WIDTH) as u32;
+        match *instruction {
+            DecodedInstruction::Load { reg, value } if valid(&[reg]) => {
+                e.store_immediate(reg, value as i32);
+            }
+            DecodedInstruction::Add { r1, r2, dst } if valid(&[r1, r2, dst]) => {
+                e.load_eax(r1);
+                e.add_eax(r2);
+                e.exit_to_interpreter_if(CC_OVERFLOW, pc);
+                e.store_eax(dst);
+            }
+            DecodedInstruction::Sub { r1, r2, dst } if valid(&[r1, r2, dst]) => {
+                e.load_eax(r1);
+                e.sub_eax(r2);
+                e.exit_to_interpreter_if(CC_OVERFLOW, pc);
+                e.store_eax(dst);
+            }
+            DecodedInstruction::Mul { r1, r2, dst } if valid(&[r1, r2, dst]) => {
+                e.load_eax(r1);
+                e.imul_eax(r2);
+                e.exit_to_interpreter_if(CC_OVERFLOW, pc);
+                e.store_eax(dst);
+            }
+            DecodedInstruction::Inc { reg } | DecodedInstruction::Dec { reg } if valid(&[reg]) => {
+                let delta = if matches!(instruction, DecodedInstruction::Inc { .. }) {
+                    1
+                } else {
+                    -1
+                };
+                e.load_eax(reg);
+                e.add_eax_immediate(delta);
+                e.exit_to_interpreter_if(CC_OVERFLOW, pc);
+                e.store_eax(reg);
+            }
+            DecodedInstruction::Jmp { reg } if valid(&[reg]) => {
+                emit_jump(&mut e, reg, pc, slot, code_end);
+                return Some(e.finish());
+            }
+            DecodedInstruction::Jeq { reg } | DecodedInstruction::Jneq { reg } if valid(&[reg]) => {
+                e.test_flag();
+                // flag clear for jeq, or set for jneq, falls through to the next instruction
+                let not_taken = if matches!(instruction, DecodedInstruction::Jeq { .. }) {
+                    CC_EQUAL
+                } else {
+                    CC_NOT_EQUAL
+                };
+                let skip = e.skip_if(not_taken ^ 1);
+                e.exit(pc + INSTRUCTION_WIDTH as u32);
+                e.patch_skip(skip);
+                emit_jump(&mut e, reg, pc, slot, code_end);
+                return Some(e.finish());
+            }
+            _ => match instruction.comparison() {
+                Some((cmp, r1, r2)) if valid(&[r1, r2]) => {
+                    e.load_eax(r1);
+                    e.cmp_eax(r2);
+                    e.set_flag(condition_code(cmp));
+                }
+                _ => {
+                    if i == slot {
+                        return None;
+                    }
+                    e.exit_to_interpreter(pc);
+                    return Some(e.finish());
+                }
+            },
+        }
+    }
+    // ran off the end of the program
+    e.exit((CODE_BASE + instructions.len() * INSTRUCTION_WIDTH) as u32);
+    Some(e.finish())
+}
+
+// Jumps to the address in `reg`. A jump back to the start of the block loops
+// without leaving native code. Otherwise only targets the interpreter would
+// accept without faulting are taken natively: the start of an instruction in
+// the code segment, or its very end.
+fn emit_jump(e: &mut Emitter, reg: u8, pc: u32, slot: usize, code_end: usize) {
+    let aligned_end = code_end - (code_end - CODE_BASE) % INSTRUCTION_WIDTH;
+    e.load_eax(reg);
+    e.cmp_eax_immediate((CODE_BASE + slot * INSTRUCTION_WIDTH) as u32);
+    e.restart_if(CC_EQUAL);
+    e.cmp_eax_immediate(code_end as u32);
+    let skip = e.skip_if(CC_EQUAL);
+    e.test_eax_immediate(INSTRUCTION_WIDTH as u32 - 1);
+    e.exit_to_interpreter_if(CC_NOT_EQUAL, pc);
+    e.cmp_eax_immediate(aligned_end as u32);
+    e.exit_to_interpreter_if(CC_ABOVE_EQUAL, pc);
+    e.patch_skip(skip);
+    e.ret();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_block(program: &[u8], registers: &mut [i32; 32], flag: &mut bool) -> (usize, bool) {
+        let instructions = decode_program(program, MAX_REGISTER_COUNT);
+        let code = compile_block(&instructions, 0, program.len(), 32).unwrap();
+        let block = CompiledBlock::new(&code).unwrap();
+        unsafe { block.call(registers.as_mut_ptr(), flag) }
+    }
+
+    #[test]
+    fn test_compiled_arithmetic() {
+        let mut registers = [0; 32];
+        let mut flag = false;
+        let program = vec![
+            0, 0, 1, 244, // load $0 #500
+            0, 1, 0, 7, // load $1 #7
+            1, 0, 1, 2, // add $0 $1 $2
+            2, 0, 1, 3, // sub $0 $1 $3
+            3, 0, 1, 4, // mul $0 $1 $4
+            19, 4, 0, 0, // dec $4
+            11, 2, 3, 0, // gt $2 $3
+        ];
+        let result = run_block(&program, &mut registers, &mut flag);
+        assert_eq!(result, (program.len(), false));
+        assert_eq!(&registers[..5], &[500, 7, 507, 493, 3499]);
+        assert!(flag);
+    }
+
+    #[test]
+    fn test_overflow_falls_back_to_interpreter() {
+        let mut registers = [0; 32];
+        registers[0] = i32::MAX;
+        let mut flag = false;
+        let program = vec![18, 1, 0, 0, 18, 0, 0, 0, 18, 1, 0, 0];
+        let result = run_block(&program, &mut registers, &mut flag);
+        assert_eq!(result, (4, true));
+        assert_eq!(registers[0], i32::MAX);
+        assert_eq!(registers[1], 1);
+    }
+
+    #[test]
+    fn test_compiled_branches() {
+        let program = vec![
+            9, 0, 1, 0, // eq $0 $1
+            15, 2, 0, 0, // jeq $2
+            5, 0, 0, 0, // hlt
+        ];
+        let mut flag = false;
+        // taken to a valid target
+        let mut registers = [0; 32];
+        registers[2] = 8;
+        assert_eq!(run_block(&program, &mut registers, &mut flag), (8, false));
+        // taken to the end of the program
+        registers[2] = 12;
+        assert_eq!(run_block(&program, &mut registers, &mut flag), (12, false));
+        // a misaligned target is left for the interpreter to fault on
+        registers[2] = 6;
+        assert_eq!(run_block(&program, &mut registers, &mut flag), (4, true));
+        registers[2] = 16;
+        assert_eq!(run_block(&program, &mut registers, &mut flag), (4, true));
+        // not taken
+        registers[1] = 1;
+        assert_eq!(run_block(&program, &mut registers, &mut flag), (8, false));
+        assert!(!flag);
+    }
+
+    #[test]
+    fn test_loop_stays_native() {
+        let program = vec![
+            18, 0, 0, 0, // inc $0
+            10, 0, 1, 0, // neq $0 $1
+            15, 2, 0, 0, // jeq $2
+        ];
+        let mut registers = [0; 32];
+        registers[1] = 1000;
+        let mut flag = false;
+        assert_eq!(run_block(&program, &mut registers, &mut flag), (12, false));
+        assert_eq!(registers[0], 1000);
+    }
+
+    #[test]
+    fn test_unsupported_instruction_ends_block() {
+        let program = vec![18, 0, 0, 0, 4, 0, 1, 2];
+        let instructions = decode_program(&program, MAX_REGISTER_COUNT);
+        assert!(compile_block(&instructions, 1, 8, 32).is_none());
+        let mut registers = [0; 32];
+        let mut flag = false;
+        assert_eq!(run_block(&program, &mut registers, &mut flag), (4, true));
+        assert_eq!(registers[0], 1);
+    }
+
+    #[test]
+    fn test_tiering_threshold() {
+        let program = vec![18, 0, 0, 0, 5, 0, 0, 0];
+        let mut jit = Jit::new(&program, 3, 32);
+        assert!(jit.block(0).is_none());
+        assert!(jit.block(0).is_none());
+        assert!(jit.block(0).is_some());
+        assert_eq!(jit.compiled_blocks(), 1);
+        // hlt cannot be compiled, so its slot gives up after getting hot
+        for _ in 0..3 {
+            assert!(jit.block(1).is_none());
+        }
+        assert_eq!(jit.compiled_blocks(), 1);
+        assert!(jit.is_current(&program, 3, 32));
+        assert!(!jit.is_current(&program, 4, 32));
+        assert!(!jit.is_current(&program, 3, 16));
+    }
+}
@@ -0,0 +1,192 @@
+// Just enough of an x86-64 encoder for the JIT. Compiled blocks use the
+// System V calling convention: rdi points at the register file, rsi at the
+// equal flag, and the pc to continue from is returned in rax. Bit 32 of the
+// result is set when the instruction at that pc has to be interpreted.
+
+pub const INTERPRET_NEXT: u64 = 1 << 32;
+
+// condition codes, used as the low nibble of jcc/setcc
+pub const CC_OVERFLOW: u8 = 0x0;
+pub const CC_ABOVE_EQUAL: u8 = 0x3;
+pub const CC_EQUAL: u8 = 0x4;
+pub const CC_NOT_EQUAL: u8 = 0x5;
+pub const CC_LESS: u8 = 0xC;
+pub const CC_GREATER_EQUAL: u8 = 0xD;
+pub const CC_LESS_EQUAL: u8 = 0xE;
+pub const CC_GREATER: u8 = 0xF;
+
+#[derive(Default)]
+pub struct Emitter {
+    pub code: Vec<u8>,
+    // rel32 fields to patch once the block is finished, with the pc to hand
+    // back to the interpreter
+    exits: Vec<(usize, u32)>,
+}
+
+impl Emitter {
+    pub fn new() -> Emitter {
+        Emitter::default()
+    }
+
+    fn register_operand(&mut self, opcode: &[u8], reg: u8) {
+        // [rdi + disp32] with eax as the other operand
+        self.code.extend_from_slice(opcode);
+        self.code.push(0x87);
+        self.code.extend_from_slice(&(reg as i32 * 4).to_le_bytes());
+    }
+
+    // mov eax, [rdi + reg * 4]
+    pub fn load_eax(&mut self, reg: u8) {
+        self.register_operand(&[0x8B], reg);
+    }
+
+    // mov [rdi + reg * 4], eax
+    pub fn store_eax(&mut self, reg: u8) {
+        self.register_operand(&[0x89], reg);
+    }
+
+    // add eax, [rdi + reg * 4]
+    pub fn add_eax(&mut self, reg: u8) {
+        self.register_operand(&[0x03], reg);
+    }
+
+    // sub eax, [rdi + reg * 4]
+    pub fn sub_eax(&mut self, reg: u8) {
+        self.register_operand(&[0x2B], reg);
+    }
+
+    // imul eax, [rdi + reg * 4]
+    pub fn imul_eax(&mut self, reg: u8) {
+        self.register_operand(&[0x0F, 0xAF], reg);
+    }
+
+    // cmp eax, [rdi + reg * 4]
+    pub fn cmp_eax(&mut self, reg: u8) {
+        self.register_operand(&[0x3B], reg);
+    }
+
+    // mov dword [rdi + reg * 4], imm32
+    pub fn store_immediate(&mut self, reg: u8, value: i32) {
+        self.register_operand(&[0xC7], reg);
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // add eax, imm8
+    pub fn add_eax_immediate(&mut self, value: i8) {
+        self.code.extend_from_slice(&[0x83, 0xC0, value as u8]);
+    }
+
+    // cmp eax, imm32
+    pub fn cmp_eax_immediate(&mut self, value: u32) {
+        self.code.push(0x3D);
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // test eax, imm32
+    pub fn test_eax_immediate(&mut self, value: u32) {
+        self.code.push(0xA9);
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // setcc byte [rsi]
+    pub fn set_flag(&mut self, condition: u8) {
+        self.code.extend_from_slice(&[0x0F, 0x90 | condition, 0x06]);
+    }
+
+    // cmp byte [rsi], 0
+    pub fn test_flag(&mut self) {
+        self.code.extend_from_slice(&[0x80, 0x3E, 0x00]);
+    }
+
+    // Leaves the block with the value already in eax as the next pc
+    pub fn ret(&mut self) {
+        self.code.push(0xC3);
+    }
+
+    // Leaves the block with `pc` as the next pc
+    pub fn exit(&mut self, pc: u32) {
+        self.code.push(0xB8);
+        self.code.extend_from_slice(&pc.to_le_bytes());
+        self.ret();
+    }
+
+    // Leaves the block, asking the interpreter to run the instruction at `pc`
+    pub fn exit_to_interpreter(&mut self, pc: u32) {
+        // mov rax, imm64
+        self.code.extend_from_slice(&[0x48, 0xB8]);
+        self.code
+            .extend_from_slice(&(INTERPRET_NEXT | pc as u64).to_le_bytes());
+        self.ret();
+    }
+
+    // Jumps to a stub that hands the instruction at `pc` to the interpreter
+    // when `condition` holds
+    pub fn exit_to_interpreter_if(&mut self, condition: u8, pc: u32) {
+        self.code.extend_from_slice(&[0x0F, 0x80 | condition]);
+        self.exits.push((self.code.len(), pc));
+        self.code.extend_from_slice(&[0; 4]);
+    }
+
+    // Jumps back to the first byte of the block when `condition` holds
+    pub fn restart_if(&mut self, condition: u8) {
+        self.code.extend_from_slice(&[0x0F, 0x80 | condition]);
+        let rel = -(self.code.len() as i32 + 4);
+        self.code.extend_from_slice(&rel.to_le_bytes());
+    }
+
+    // Emits a short forward jcc, returning the offset to hand to `patch_skip`
+    pub fn skip_if(&mut self, condition: u8) -> usize {
+        self.code.extend_from_slice(&[0x70 | condition, 0]);
+        self.code.len()
+    }
+
+    // Points a jump emitted by `skip_if` at the current end of the code
+    pub fn patch_skip(&mut self, skip: usize) {
+        self.code[skip - 1] = (self.code.len() - skip) as u8;
+    }
+
+    // Emits the stubs used by `exit_to_interpreter_if` and returns the finished code
+    pub fn finish(mut self) -> Vec<u8> {
+        for (field, pc) in std::mem::take(&mut self.exits) {
+            let stub = self.code.len();
+            let rel = (stub - (field + 4)) as i32;
+            self.code[field..field + 4].copy_from_slice(&rel.to_le_bytes());
+            self.exit_to_interpreter(pc);
+        }
+        self.code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encodings() {
+        let mut e = Emitter::new();
+        e.load_eax(2);
+        e.store_immediate(1, 500);
+        e.set_flag(CC_EQUAL);
+        e.exit(12);
+        assert_eq!(
+            e.finish(),
+            vec![
+                0x8B, 0x87, 8, 0, 0, 0, // mov eax, [rdi + 8]
+                0xC7, 0x87, 4, 0, 0, 0, 0xF4, 0x01, 0, 0, // mov dword [rdi + 4], 500
+                0x0F, 0x94, 0x06, // sete [rsi]
+                0xB8, 12, 0, 0, 0, 0xC3, // mov eax, 12; ret
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exit_stubs_are_patched() {
+        let mut e = Emitter::new();
+        e.exit_to_interpreter_if(CC_OVERFLOW, 4);
+        e.exit(8);
+        let code = e.finish();
+        // jo rel32 lands on the stub straight after `mov eax, 8; ret`
+        assert_eq!(&code[..6], &[0x0F, 0x80, 6, 0, 0, 0]);
+        assert_eq!(&code[12..], &[0x48, 0xB8, 4, 0, 0, 0, 1, 0, 0, 0, 0xC3]);
+    }
+}
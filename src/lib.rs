@@ -0,0 +1,24 @@
+#[macro_use]
+extern crate nom;
+
+pub mod assembler;
+pub mod config;
+pub mod dap;
+pub mod debugger;
+pub mod debuginfo;
+pub mod decoder;
+pub mod executable;
+pub mod fault;
+pub mod fusion;
+pub mod gdbstub;
+pub mod instruction;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod json;
+pub mod memory;
+pub mod profiler;
+pub mod repl;
+pub mod trace;
+pub mod translator;
+pub mod verifier;
+pub mod vm;
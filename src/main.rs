@@ -1,12 +1,18 @@
-#[macro_use]
-extern crate nom;
+use std::io;
 
-pub mod assembler;
-pub mod instruction;
-pub mod repl;
-pub mod vm;
+use iridium::dap::DapServer;
+use iridium::repl;
 
 fn main() {
+    // `--dap` speaks the Debug Adapter Protocol on stdin and stdout instead
+    // of starting the REPL
+    if std::env::args().any(|arg| arg == "--dap") {
+        let stdin = io::stdin();
+        if let Err(e) = DapServer::new().serve(&mut stdin.lock(), &mut io::stdout()) {
+            eprintln!("Error: {}", e);
+        }
+        return;
+    }
     let mut repl = repl::REPL::new();
     repl.run();
 }
@@ -0,0 +1,293 @@
+use std::fmt;
+
+// Layout of the VM's address space. Code and read-only data live in the low
+// 64 KiB so that any address inside them fits in a LOAD immediate.
+pub const CODE_BASE: usize = 0x0000_0000;
+pub const CODE_LIMIT: usize = 0x0000_8000;
+pub const RODATA_BASE: usize = 0x0000_8000;
+pub const RODATA_LIMIT: usize = 0x0001_0000;
+pub const STACK_BASE: usize = 0x0001_0000;
+pub const HEAP_BASE: usize = 0x0100_0000;
+pub const DEFAULT_STACK_SIZE: usize = 0x0001_0000;
+pub const MAX_STACK_SIZE: usize = HEAP_BASE - STACK_BASE;
+pub const DEFAULT_HEAP_LIMIT: usize = 0x0100_0000;
+// addresses are 32 bits wide, so the heap can reach at most the top of that range
+pub const MAX_HEAP_SIZE: usize = 0x1_0000_0000 - HEAP_BASE;
+
+// every instruction is encoded in exactly 4 bytes, so instruction boundaries
+// are the multiples of this inside the code segment
+pub const INSTRUCTION_WIDTH: usize = 4;
+// JMPF and JMPB count their distance from just past their register operand,
+// this many bytes into the instruction
+pub const RELATIVE_JUMP_BASE: usize = 2;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Execute => write!(f, "execute"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    pub const READ_EXECUTE: Permissions = Permissions {
+        read: true,
+        write: false,
+        execute: true,
+    };
+    pub const READ_ONLY: Permissions = Permissions {
+        read: true,
+        write: false,
+        execute: false,
+    };
+    pub const READ_WRITE: Permissions = Permissions {
+        read: true,
+        write: true,
+        execute: false,
+    };
+
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        }
+    }
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            if self.read { 'r' } else { '-' },
+            if self.write { 'w' } else { '-' },
+            if self.execute { 'x' } else { '-' }
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum SegmentKind {
+    Code,
+    ReadOnlyData,
+    Stack,
+    Heap,
+}
+
+impl SegmentKind {
+    pub fn permissions(&self) -> Permissions {
+        match self {
+            SegmentKind::Code => Permissions::READ_EXECUTE,
+            SegmentKind::ReadOnlyData => Permissions::READ_ONLY,
+            SegmentKind::Stack | SegmentKind::Heap => Permissions::READ_WRITE,
+        }
+    }
+}
+
+impl fmt::Display for SegmentKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SegmentKind::Code => write!(f, "code"),
+            SegmentKind::ReadOnlyData => write!(f, "rodata"),
+            SegmentKind::Stack => write!(f, "stack"),
+            SegmentKind::Heap => write!(f, "heap"),
+        }
+    }
+}
+
+// A mapped range of the address space, [base, base + len)
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Segment {
+    pub kind: SegmentKind,
+    pub base: usize,
+    pub len: usize,
+}
+
+impl Segment {
+    pub fn new(kind: SegmentKind, base: usize, len: usize) -> Segment {
+        Segment { kind, base, len }
+    }
+
+    pub fn end(&self) -> usize {
+        self.base + self.len
+    }
+
+    pub fn contains(&self, address: usize, len: usize) -> bool {
+        // a wild jump can produce an address near usize::MAX, so avoid overflowing
+        address >= self.base
+            && address
+                .checked_add(len)
+                .is_some_and(|end| end <= self.end())
+    }
+
+    pub fn permissions(&self) -> Permissions {
+        self.kind.permissions()
+    }
+}
+
+// Snapshot of the segments currently mapped by a VM
+#[derive(Debug, PartialEq)]
+pub struct MemoryMap {
+    pub segments: [Segment; 4],
+}
+
+impl MemoryMap {
+    pub fn new(code_len: usize, rodata_len: usize, stack_len: usize, heap_len: usize) -> MemoryMap {
+        MemoryMap {
+            segments: [
+                // anything past a segment's limit is simply left unmapped
+                Segment::new(
+                    SegmentKind::Code,
+                    CODE_BASE,
+                    code_len.min(CODE_LIMIT - CODE_BASE),
+                ),
+                Segment::new(
+                    SegmentKind::ReadOnlyData,
+                    RODATA_BASE,
+                    rodata_len.min(RODATA_LIMIT - RODATA_BASE),
+                ),
+                Segment::new(
+                    SegmentKind::Stack,
+                    STACK_BASE,
+                    stack_len.min(MAX_STACK_SIZE),
+                ),
+                Segment::new(SegmentKind::Heap, HEAP_BASE, heap_len),
+            ],
+        }
+    }
+
+    pub fn segment(&self, kind: SegmentKind) -> &Segment {
+        // segments are stored in declaration order of SegmentKind
+        &self.segments[kind as usize]
+    }
+
+    // Finds the segment that holds every byte of [address, address + len)
+    pub fn find(&self, address: usize, len: usize) -> Option<&Segment> {
+        self.segments.iter().find(|s| s.contains(address, len))
+    }
+
+    // Checks that the whole access lands inside one segment that permits it
+    pub fn check(
+        &self,
+        address: usize,
+        len: usize,
+        access: Access,
+    ) -> Result<&Segment, MemoryError> {
+        match self.find(address, len) {
+            Some(segment) if segment.permissions().allows(access) => Ok(segment),
+            Some(segment) => Err(MemoryError::Protection {
+                address,
+                access,
+                segment: segment.kind,
+            }),
+            None => Err(MemoryError::Unmapped { address, access }),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum MemoryError {
+    Unmapped {
+        address: usize,
+        access: Access,
+    },
+    Protection {
+        address: usize,
+        access: Access,
+        segment: SegmentKind,
+    },
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryError::Unmapped { address, access } => {
+                write!(f, "{} of unmapped address {:#010x}", access, address)
+            }
+            MemoryError::Protection {
+                address,
+                access,
+                segment,
+            } => write!(
+                f,
+                "{} of address {:#010x} violates {} segment permissions ({})",
+                access,
+                address,
+                segment,
+                segment.permissions()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permissions() {
+        assert!(Permissions::READ_EXECUTE.allows(Access::Execute));
+        assert!(!Permissions::READ_EXECUTE.allows(Access::Write));
+        assert!(!Permissions::READ_ONLY.allows(Access::Execute));
+        assert!(Permissions::READ_WRITE.allows(Access::Write));
+        assert_eq!(Permissions::READ_EXECUTE.to_string(), "r-x");
+    }
+
+    #[test]
+    fn test_memory_map_check() {
+        let map = MemoryMap::new(8, 4, 16, 32);
+        assert_eq!(
+            map.check(4, 4, Access::Execute).unwrap().kind,
+            SegmentKind::Code
+        );
+        assert_eq!(
+            map.check(0, 4, Access::Write),
+            Err(MemoryError::Protection {
+                address: 0,
+                access: Access::Write,
+                segment: SegmentKind::Code
+            })
+        );
+        assert_eq!(
+            map.check(RODATA_BASE, 4, Access::Execute),
+            Err(MemoryError::Protection {
+                address: RODATA_BASE,
+                access: Access::Execute,
+                segment: SegmentKind::ReadOnlyData
+            })
+        );
+        assert!(map.check(HEAP_BASE + 28, 4, Access::Write).is_ok());
+        assert_eq!(
+            map.check(usize::MAX - 1, 4, Access::Execute),
+            Err(MemoryError::Unmapped {
+                address: usize::MAX - 1,
+                access: Access::Execute
+            })
+        );
+        // straddles the end of the heap
+        assert_eq!(
+            map.check(HEAP_BASE + 30, 4, Access::Read),
+            Err(MemoryError::Unmapped {
+                address: HEAP_BASE + 30,
+                access: Access::Read
+            })
+        );
+    }
+}
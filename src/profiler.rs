@@ -0,0 +1,275 @@
+// Counts what a program spends its time on: how often each opcode and each
+// instruction address is executed, and the wall time spent in each class of
+// opcode. Turned on with `VM::enable_profiling`.
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::debuginfo::{DebugInfo, Location};
+use crate::executable::{nearest_symbol, ExecutableSymbol};
+use crate::instruction::Opcode;
+use crate::memory::{CODE_BASE, INSTRUCTION_WIDTH};
+
+// the number of addresses listed in the report, hottest first
+const HOT_ADDRESSES: usize = 10;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+pub enum OpcodeClass {
+    Load,
+    Arithmetic,
+    Comparison,
+    Branch,
+    Memory,
+    Control,
+}
+
+impl OpcodeClass {
+    pub const ALL: [OpcodeClass; 6] = [
+        OpcodeClass::Load,
+        OpcodeClass::Arithmetic,
+        OpcodeClass::Comparison,
+        OpcodeClass::Branch,
+        OpcodeClass::Memory,
+        OpcodeClass::Control,
+    ];
+
+    pub fn of(opcode: Opcode) -> OpcodeClass {
+        match opcode {
+            Opcode::LOAD => OpcodeClass::Load,
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV | Opcode::INC | Opcode::DEC => {
+                OpcodeClass::Arithmetic
+            }
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTQ | Opcode::LTQ => {
+                OpcodeClass::Comparison
+            }
+            Opcode::JMP | Opcode::JMPF | Opcode::JMPB | Opcode::JEQ | Opcode::JNEQ => {
+                OpcodeClass::Branch
+            }
+            Opcode::ALOC | Opcode::LOADM | Opcode::SETM => OpcodeClass::Memory,
+            Opcode::HLT | Opcode::IGL => OpcodeClass::Control,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            OpcodeClass::Load => "load",
+            OpcodeClass::Arithmetic => "arithmetic",
+            OpcodeClass::Comparison => "comparison",
+            OpcodeClass::Branch => "branch",
+            OpcodeClass::Memory => "memory",
+            OpcodeClass::Control => "control",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Profile {
+    opcode_counts: [u64; 256],
+    slot_counts: Vec<u64>, // indexed by instruction slot, grown as the program runs
+    class_counts: [u64; 6],
+    class_time: [Duration; 6],
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile {
+            opcode_counts: [0; 256],
+            slot_counts: vec![],
+            class_counts: [0; 6],
+            class_time: [Duration::ZERO; 6],
+        }
+    }
+
+    // Records one execution of the instruction with opcode byte `opcode` at `pc`
+    pub fn record(&mut self, pc: usize, opcode: u8, elapsed: Duration) {
+        self.opcode_counts[opcode as usize] += 1;
+        let slot = (pc - CODE_BASE) / INSTRUCTION_WIDTH;
+        if slot >= self.slot_counts.len() {
+            self.slot_counts.resize(slot + 1, 0);
+        }
+        self.slot_counts[slot] += 1;
+        let class = OpcodeClass::of(Opcode::from(opcode)) as usize;
+        self.class_counts[class] += 1;
+        self.class_time[class] += elapsed;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.opcode_counts.iter().sum()
+    }
+
+    pub fn opcode_count(&self, opcode: u8) -> u64 {
+        self.opcode_counts[opcode as usize]
+    }
+
+    pub fn pc_count(&self, pc: usize) -> u64 {
+        let slot = (pc - CODE_BASE) / INSTRUCTION_WIDTH;
+        self.slot_counts.get(slot).copied().unwrap_or(0)
+    }
+
+    pub fn class_count(&self, class: OpcodeClass) -> u64 {
+        self.class_counts[class as usize]
+    }
+
+    pub fn class_time(&self, class: OpcodeClass) -> Duration {
+        self.class_time[class as usize]
+    }
+
+    // Every executed address with its count, in address order
+    pub fn pc_counts(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.slot_counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(slot, count)| (CODE_BASE + slot * INSTRUCTION_WIDTH, *count))
+    }
+
+    // A human readable summary, with addresses shown as source locations when
+    // there is debug information and relative to the nearest label
+    pub fn report(&self, debug_info: Option<&DebugInfo>, symbols: &[ExecutableSymbol]) -> String {
+        let total = self.total();
+        let percent = |count: u64| 100.0 * count as f64 / total.max(1) as f64;
+        let mut out = String::new();
+        writeln!(out, "{} instructions executed", total).unwrap();
+
+        writeln!(out, "by opcode:").unwrap();
+        let mut opcodes: Vec<(u8, u64)> = (0..=255u8)
+            .map(|opcode| (opcode, self.opcode_count(opcode)))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (opcode, count) in opcodes {
+            let name = match Opcode::from(opcode) {
+                Opcode::IGL => format!("igl({})", opcode),
+                code => code.mnemonic().to_string(),
+            };
+            writeln!(out, "  {:<10} {:>10} {:>6.2}%", name, count, percent(count)).unwrap();
+        }
+
+        writeln!(out, "by class:").unwrap();
+        for class in OpcodeClass::ALL {
+            let count = self.class_count(class);
+            if count > 0 {
+                writeln!(
+                    out,
+                    "  {:<10} {:>10} {:>12?}",
+                    class.name(),
+                    count,
+                    self.class_time(class)
+                )
+                .unwrap();
+            }
+        }
+
+        writeln!(out, "hot addresses:").unwrap();
+        let mut hot: Vec<(usize, u64)> = self.pc_counts().collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (pc, count) in hot.into_iter().take(HOT_ADDRESSES) {
+            let location = Location::new(debug_info, symbols, pc).to_string();
+            writeln!(
+                out,
+                "  {:<32} {:>10} {:>6.2}%",
+                location,
+                count,
+                percent(count)
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    // Instruction counts in the folded stack format read by flamegraph tools,
+    // one `frame;frame count` line per stack. The VM has no call stack, so
+    // each stack is the single label enclosing the executed addresses.
+    pub fn folded_stacks(&self, symbols: &[ExecutableSymbol]) -> String {
+        let mut stacks: Vec<(String, u64)> = vec![];
+        for (pc, count) in self.pc_counts() {
+            let frame = match nearest_symbol(symbols, pc as u32) {
+                Some((name, _)) => name.to_string(),
+                None => "[unlabelled]".to_string(),
+            };
+            match stacks.iter_mut().find(|(name, _)| *name == frame) {
+                Some((_, total)) => *total += count,
+                None => stacks.push((frame, count)),
+            }
+        }
+        let mut out = String::new();
+        for (frame, count) in stacks {
+            writeln!(out, "{} {}", frame, count).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debuginfo::LineEntry;
+
+    fn symbols() -> Vec<ExecutableSymbol> {
+        vec![
+            ExecutableSymbol {
+                name: "main".to_string(),
+                address: 0,
+            },
+            ExecutableSymbol {
+                name: "loop".to_string(),
+                address: 8,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_record() {
+        let mut profile = Profile::new();
+        profile.record(0, Opcode::LOAD as u8, Duration::from_nanos(5));
+        profile.record(8, Opcode::INC as u8, Duration::from_nanos(3));
+        profile.record(8, Opcode::INC as u8, Duration::from_nanos(3));
+        profile.record(12, Opcode::ADD as u8, Duration::from_nanos(1));
+        assert_eq!(profile.total(), 4);
+        assert_eq!(profile.opcode_count(Opcode::INC as u8), 2);
+        assert_eq!(profile.pc_count(8), 2);
+        assert_eq!(profile.pc_count(4), 0);
+        assert_eq!(profile.pc_count(400), 0);
+        assert_eq!(profile.class_count(OpcodeClass::Arithmetic), 3);
+        assert_eq!(
+            profile.class_time(OpcodeClass::Arithmetic),
+            Duration::from_nanos(7)
+        );
+        assert_eq!(
+            profile.pc_counts().collect::<Vec<_>>(),
+            vec![(0, 1), (8, 2), (12, 1)]
+        );
+    }
+
+    #[test]
+    fn test_report_and_folded_stacks() {
+        let mut profile = Profile::new();
+        profile.record(0, Opcode::LOAD as u8, Duration::ZERO);
+        profile.record(8, Opcode::INC as u8, Duration::ZERO);
+        profile.record(12, Opcode::INC as u8, Duration::ZERO);
+        profile.record(16, 200, Duration::ZERO);
+        let report = profile.report(None, &symbols());
+        assert!(report.starts_with("4 instructions executed\n"));
+        assert!(report.contains("  inc                 2  50.00%\n"));
+        assert!(report.contains("igl(200)"));
+        assert!(report.contains("0x000c (loop+4) "));
+        let debug_info = DebugInfo {
+            files: vec!["count.iasm".to_string()],
+            lines: vec![LineEntry {
+                address: 12,
+                file: 0,
+                line: 5,
+                column: 3,
+            }],
+        };
+        let report = profile.report(Some(&debug_info), &symbols());
+        assert!(report.contains("count.iasm:5:3 (loop+4) "));
+        assert_eq!(profile.folded_stacks(&symbols()), "main 1\nloop 3\n");
+        assert_eq!(profile.folded_stacks(&[]), "[unlabelled] 4\n");
+    }
+}
@@ -1,4 +1,3 @@
-use nom::types::CompleteStr;
 use std;
 use std::fs::File;
 use std::io::Write;
@@ -7,19 +6,37 @@ use std::num::ParseIntError;
 use std::path::Path;
 use vm::VM;
 
-use crate::assembler::program_parsers::program;
+use crate::assembler::assembler_errors::AssemblerError;
+use crate::assembler::Assembler;
+use crate::config::VmConfig;
+use crate::debugger::{Condition, Debugger, StopReason, Watch};
+use crate::decoder::{decode, Comparison};
+use crate::gdbstub::GdbStub;
+use crate::instruction::{disassemble, OPCODES};
+use crate::memory::INSTRUCTION_WIDTH;
+use crate::trace::{TraceFormat, Tracer};
 use crate::vm;
 
 // REPL: read evaluate print loop
 pub struct REPL {
     command_buffer: Vec<String>,
-    vm: VM, // the vm the REPL will use to execute code
+    debugger: Debugger, // wraps the vm the REPL will use to execute code
+}
+
+impl Default for REPL {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl REPL {
     pub fn new() -> REPL {
+        REPL::with_config(VmConfig::default())
+    }
+
+    pub fn with_config(config: VmConfig) -> REPL {
         REPL {
-            vm: VM::new(),
+            debugger: Debugger::new(VM::with_config(config)),
             command_buffer: vec![], // the buffer to store the commands, user can press up-arrow and see what they ran
         }
     }
@@ -54,23 +71,153 @@ impl REPL {
                         println!("{}", command);
                     }
                 }
+                ".help" => {
+                    println!("Listing instructions:");
+                    for info in OPCODES {
+                        println!("  {:<24} {}", info.usage(), info.doc);
+                    }
+                    println!("End of instruction listing");
+                }
                 ".program" => {
                     println!("Listing instructions currently in VM's program vector:");
-                    for instruction in &self.vm.program {
-                        print!("{}, ", instruction);
+                    for (address, bytes) in self
+                        .debugger
+                        .vm
+                        .program
+                        .chunks(INSTRUCTION_WIDTH)
+                        .enumerate()
+                    {
+                        println!(
+                            "{:#06x}  {}",
+                            address * INSTRUCTION_WIDTH,
+                            disassemble(bytes)
+                        );
                     }
                     println!("End of program listing");
                 }
                 ".registers" => {
                     println!("Listing registers and all contents:");
-                    println!("{:?}", self.vm.registers);
+                    println!("{:?}", self.debugger.vm.registers);
                     println!("End of register listing");
                 }
+                ".segments" => {
+                    println!("Listing memory segments:");
+                    for segment in self.debugger.vm.memory_map().segments.iter() {
+                        println!(
+                            "{:<7} {:#010x}-{:#010x} {}",
+                            segment.kind.to_string(),
+                            segment.base,
+                            segment.end(),
+                            segment.permissions()
+                        );
+                    }
+                    println!("End of segment listing");
+                }
+                ".verify" => match self.debugger.vm.verify() {
+                    Ok(()) => println!("Program verified"),
+                    Err(diagnostics) => {
+                        for diagnostic in diagnostics {
+                            println!("{}", diagnostic);
+                        }
+                    }
+                },
+                ".profile" => match self.debugger.vm.take_profile() {
+                    Some(profile) => {
+                        print!(
+                            "{}",
+                            profile
+                                .report(self.debugger.vm.debug_info(), self.debugger.vm.symbols())
+                        );
+                        println!("Profiling is now turned off");
+                    }
+                    None => {
+                        self.debugger.vm.enable_profiling();
+                        println!("Profiling is now turned on");
+                    }
+                },
+                ".trace" => {
+                    if let Some(tracer) = self.debugger.vm.stop_trace() {
+                        match tracer.finish() {
+                            Ok(()) => println!("Tracing is now turned off"),
+                            Err(e) => println!("Error writing trace: {}", e),
+                        }
+                        continue;
+                    }
+                    print!("Please enter the path to write the trace to: ");
+                    io::stdout().flush().expect("Unable to flush stdout");
+
+                    let mut tmp = String::new();
+                    stdin
+                        .read_line(&mut tmp)
+                        .expect("Unable to read from stdin");
+                    match File::create(tmp.trim()) {
+                        Ok(file) => {
+                            let writer = Box::new(io::BufWriter::new(file));
+                            self.debugger
+                                .vm
+                                .start_trace(Tracer::new(writer, TraceFormat::JsonLines));
+                            println!("Tracing is now turned on");
+                        }
+                        Err(e) => println!("Unable to create file: {}", e),
+                    }
+                }
+                ".gdb" => {
+                    print!("Please enter the address to listen on, e.g. 127.0.0.1:1234: ");
+                    io::stdout().flush().expect("Unable to flush stdout");
+
+                    let mut tmp = String::new();
+                    stdin
+                        .read_line(&mut tmp)
+                        .expect("Unable to read from stdin");
+                    // the stub borrows the debugger until the frontend detaches
+                    let debugger = std::mem::replace(&mut self.debugger, Debugger::new(VM::new()));
+                    let mut stub = GdbStub::new(debugger);
+                    println!("Waiting for a GDB connection on {}", tmp.trim());
+                    if let Err(e) = stub.listen(tmp.trim()) {
+                        println!("GDB connection failed: {}", e);
+                    }
+                    self.debugger = stub.debugger;
+                }
+                ".step" => {
+                    let reason = self.debugger.step_into();
+                    self.report_stop(&reason);
+                }
+                ".continue" => {
+                    let reason = self.debugger.run_until_stop();
+                    self.report_stop(&reason);
+                }
+                ".step_back" => {
+                    let reason = self.debugger.step_back();
+                    self.report_stop(&reason);
+                }
+                ".reverse_continue" => {
+                    let reason = self.debugger.run_backward();
+                    self.report_stop(&reason);
+                }
+                command if command.starts_with(".break ") => {
+                    match self.add_breakpoint(&command[".break ".len()..]) {
+                        Ok(id) => println!("Breakpoint {} set", id),
+                        Err(e) => println!("{}", e),
+                    }
+                }
+                command if command.starts_with(".watch ") => {
+                    match parse_watch(command[".watch ".len()..].trim()) {
+                        Some(watch) => {
+                            let id = self.debugger.add_watchpoint(watch);
+                            println!("Watchpoint {} set on {}", id, watch);
+                        }
+                        None => println!("Usage: .watch $register | .watch address [length]"),
+                    }
+                }
                 ".hex" => {
-                    self.vm.parse_hex_flag = !self.vm.parse_hex_flag;
+                    self.debugger.vm.parse_hex_flag = !self.debugger.vm.parse_hex_flag;
                     println!(
                         "Hex parsing is now turned {}",
-                        if self.vm.parse_hex_flag { "on" } else { "off" }
+                        if self.debugger.vm.parse_hex_flag {
+                            "on"
+                        } else {
+                            "off"
+                        }
                     );
                 }
                 ".load_file" => {
@@ -87,22 +234,42 @@ impl REPL {
                     let mut contents = String::new();
                     f.read_to_string(&mut contents)
                         .expect("Something went wrong reading the file");
-                    let program = match program(CompleteStr(&contents)) {
-                        Ok((_, program)) => program,
+                    if let Err(errors) = self.load_source(tmp, &contents) {
+                        for error in errors {
+                            println!("{}", error);
+                        }
+                        continue;
+                    }
+                }
+                ".load_executable" => {
+                    print!("Please enter the path to the executable you wish to load: ");
+                    io::stdout().flush().expect("Unable to flush stdout");
+
+                    let mut tmp = String::new();
+                    stdin
+                        .read_line(&mut tmp)
+                        .expect("Unable to read from stdin");
+                    let bytes = match std::fs::read(tmp.trim()) {
+                        Ok(bytes) => bytes,
                         Err(e) => {
-                            println!("Error parsing program: {:?}", e);
+                            println!("Unable to read file: {}", e);
                             continue;
                         }
                     };
-                    self.vm.program.append(&mut program.to_bytes());
+                    match self.debugger.vm.load_executable(&bytes) {
+                        Ok(()) => {
+                            println!("Loaded {} bytes of code", self.debugger.vm.program.len())
+                        }
+                        Err(e) => println!("Error loading executable: {}", e),
+                    }
                 }
                 _ => {
-                    if self.vm.parse_hex_flag {
+                    if self.debugger.vm.parse_hex_flag {
                         let res = self.parse_hex(buffer);
                         match res {
                             Ok(bytes) => {
                                 for byte in bytes {
-                                    self.vm.add_byte(byte);
+                                    self.debugger.vm.add_byte(byte);
                                 }
                             }
                             Err(_e) => {
@@ -110,21 +277,77 @@ impl REPL {
                             }
                         };
                     } else {
-                        let program = match program(buffer.into()) {
-                            Ok((_, program)) => program,
-                            Err(_) => {
-                                println!("Invalid input. Please enter a valid program");
+                        let mut assembler = Assembler::with_config(self.debugger.vm.config())
+                            .with_source_name("<repl>");
+                        match assembler.assemble(buffer) {
+                            Ok(mut program) => self.debugger.vm.program.append(&mut program),
+                            Err(errors) => {
+                                for error in errors {
+                                    println!("{}", error);
+                                }
                                 continue;
                             }
-                        };
-                        self.vm.program.append(&mut program.to_bytes());
+                        }
+                    }
+                    // stepped through the debugger so `.step_back` can undo it
+                    self.debugger.step_into();
+                    if let Some(fault) = self.debugger.vm.fault() {
+                        println!("{}", self.debugger.vm.describe_fault(&fault));
+                        self.debugger.vm.clear_fault();
                     }
-                    self.vm.run_once();
                 }
             }
         }
     }
 
+    // Assembles `source`, read from the file `name`, into the VM in place of
+    // whatever was loaded before, along with its debug information and labels
+    fn load_source(&mut self, name: &str, source: &str) -> Result<(), Vec<AssemblerError>> {
+        let vm = &mut self.debugger.vm;
+        let mut assembler = Assembler::with_config(vm.config()).with_source_name(name);
+        let program = assembler.assemble(source)?;
+        let labels = assembler.labels();
+        vm.load_program(program, assembler.ro_data)
+            .expect("the assembler keeps code and data inside their segments");
+        vm.set_symbols(labels);
+        vm.set_debug_info(Some(assembler.debug_info));
+        Ok(())
+    }
+
+    // Sets a breakpoint from `.break` arguments: an address or label, then
+    // optionally a condition such as `if $0 == 3`
+    fn add_breakpoint(&mut self, args: &str) -> Result<usize, String> {
+        let usage = "Usage: .break address|label [if $register op value]".to_string();
+        let (location, condition) = match args.split_once(" if ") {
+            Some((location, condition)) => (
+                location.trim(),
+                Some(parse_condition(condition).ok_or(usage.clone())?),
+            ),
+            None => (args.trim(), None),
+        };
+        if location.is_empty() {
+            return Err(usage);
+        }
+        match parse_number(location) {
+            Some(address) => Ok(self.debugger.add_breakpoint(address, condition)),
+            None => self
+                .debugger
+                .add_label_breakpoint(location, condition)
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    fn report_stop(&self, reason: &StopReason) {
+        let vm = &self.debugger.vm;
+        match reason {
+            StopReason::Faulted(fault) => println!("{}", vm.describe_fault(fault)),
+            reason => println!("{}", reason),
+        }
+        if vm.fault().is_none() && vm.pc() < vm.program.len() {
+            println!("{}: {}", vm.location(vm.pc()), decode(&vm.program, vm.pc()));
+        }
+    }
+
     // allows users to input hex strings to add to the VM's program
     // Accepts a hexadecimal string WITHOUT a leading `0x` and returns a Vec of u8
     // Example for a LOAD command: 00 01 03 E8
@@ -141,3 +364,125 @@ impl REPL {
         Ok(res)
     }
 }
+
+// Reads a decimal or `0x` prefixed hexadecimal number
+fn parse_number(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn parse_register(s: &str) -> Option<u8> {
+    s.strip_prefix('$')?.parse().ok()
+}
+
+// Reads a condition of the form `$register op value`
+fn parse_condition(s: &str) -> Option<Condition> {
+    let parts: Vec<&str> = s.split_whitespace().collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    let cmp = match parts[1] {
+        "==" => Comparison::Eq,
+        "!=" => Comparison::Neq,
+        ">" => Comparison::Gt,
+        "<" => Comparison::Lt,
+        ">=" => Comparison::Gtq,
+        "<=" => Comparison::Ltq,
+        _ => return None,
+    };
+    Some(Condition {
+        register: parse_register(parts[0])?,
+        cmp,
+        value: parts[2].parse().ok()?,
+    })
+}
+
+// Reads a register (`$3`) or an address with an optional length, which
+// defaults to a word
+fn parse_watch(s: &str) -> Option<Watch> {
+    let parts: Vec<&str> = s.split_whitespace().collect();
+    match parts.as_slice() {
+        [register] if register.starts_with('$') => Some(Watch::Register(parse_register(register)?)),
+        [address] => Some(Watch::Memory {
+            address: parse_number(address)?,
+            len: 4,
+        }),
+        [address, len] => Some(Watch::Memory {
+            address: parse_number(address)?,
+            len: parse_number(len)?,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{CODE_BASE, RODATA_BASE};
+
+    #[test]
+    fn test_parse_condition() {
+        assert_eq!(
+            parse_condition("$2 >= -4"),
+            Some(Condition {
+                register: 2,
+                cmp: Comparison::Gtq,
+                value: -4
+            })
+        );
+        assert_eq!(parse_condition("$2 =< 4"), None);
+        assert_eq!(parse_condition("2 == 4"), None);
+    }
+
+    #[test]
+    fn test_parse_watch() {
+        assert_eq!(parse_watch("$7"), Some(Watch::Register(7)));
+        assert_eq!(
+            parse_watch("0x1000000 2"),
+            Some(Watch::Memory {
+                address: 0x0100_0000,
+                len: 2
+            })
+        );
+        assert_eq!(parse_watch("$x"), None);
+    }
+
+    #[test]
+    fn test_add_breakpoint() {
+        let mut repl = REPL::new();
+        assert_eq!(repl.add_breakpoint("0x10 if $1 != 0"), Ok(1));
+        assert_eq!(
+            repl.debugger.breakpoints()[0].condition.unwrap().cmp,
+            Comparison::Neq
+        );
+        assert!(repl.add_breakpoint("main").is_err());
+        assert!(repl.add_breakpoint("4 if $1").is_err());
+    }
+
+    #[test]
+    fn test_load_source() {
+        let mut repl = REPL::new();
+        repl.load_source("count.iasm", "load $0 #2\nloop: dec $0\nhlt\n")
+            .unwrap();
+        assert_eq!(repl.add_breakpoint("loop"), Ok(1));
+        assert_eq!(
+            repl.debugger.vm.location(4).to_string(),
+            "count.iasm:2:1 (loop+0)"
+        );
+        assert!(repl.load_source("bad.iasm", "load $0\n").is_err());
+
+        // a second file replaces the first, so its labels and data resolve
+        repl.debugger.vm.run();
+        let source = "load $1 @msg\nload $2 @end\njmp $2\nhlt\nend: hlt\nmsg: .asciiz \"hi\"\n";
+        repl.load_source("second.iasm", source).unwrap();
+        assert_eq!(repl.debugger.vm.pc(), CODE_BASE);
+        assert_eq!(repl.debugger.vm.ro_data, b"hi\0");
+        repl.debugger.vm.run();
+        assert_eq!(repl.debugger.vm.registers[1], RODATA_BASE as i32);
+        assert_eq!(repl.debugger.vm.registers[2], 16);
+        // just past the hlt at `end`
+        assert_eq!(repl.debugger.vm.pc(), CODE_BASE + 17);
+    }
+}
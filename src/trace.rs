@@ -0,0 +1,498 @@
+// Structured execution traces: one record per executed instruction, with the
+// registers it changed, the flags after it ran and the memory it wrote.
+// Traces are written as JSON Lines or in a compact binary format, and
+// `read_trace` reads either back so two runs can be compared with
+// `first_divergence`. JSON Lines records also name the source location of
+// the instruction when the program has debug information or labels; the
+// binary format leaves it out.
+//
+// The binary format starts with the magic "ITRC" followed by the records,
+// with integers big endian like the instruction encoding:
+//   pc (4), instruction length (1), instruction bytes,
+//   equal flag (1), remainder (4), heap length (4),
+//   register change count (1), then per change: register (1), old (4), new (4)
+//   memory write count (1), then per write: address (4), length (1), old bytes, new bytes
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+
+use crate::debuginfo::Location;
+use crate::decoder::{decode, DecodedInstruction};
+use crate::instruction::Opcode;
+use crate::json::{Json, JsonError};
+
+pub const BINARY_MAGIC: [u8; 4] = *b"ITRC";
+
+// The state an instruction can change, captured just before it runs
+#[derive(Debug, PartialEq, Clone)]
+pub struct Snapshot {
+    pub pc: usize,
+    pub registers: Vec<i32>,
+    pub equal_flag: bool,
+    pub remainder: u32,
+    pub heap_len: usize,
+    // bytes the instruction is about to overwrite or discard, and where they
+    // are: a SETM's target, or the end of the heap an ALOC shrinks away
+    pub memory: Option<(usize, Vec<u8>)>,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct RegisterChange {
+    pub register: u8,
+    pub old: i32,
+    pub new: i32,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MemoryWrite {
+    pub address: u32,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceRecord {
+    pub pc: u32,
+    // the encoded instruction, shorter than an instruction if the program was truncated
+    pub bytes: Vec<u8>,
+    pub registers: Vec<RegisterChange>,
+    pub equal_flag: bool,
+    pub remainder: u32,
+    pub heap_len: u32,
+    pub memory: Vec<MemoryWrite>,
+}
+
+impl TraceRecord {
+    pub fn instruction(&self) -> DecodedInstruction {
+        decode(&self.bytes, 0)
+    }
+
+    pub fn to_json(&self) -> Json {
+        let registers = self
+            .registers
+            .iter()
+            .map(|change| {
+                Json::object(vec![
+                    ("register", Json::from(change.register as i64)),
+                    ("old", Json::from(change.old as i64)),
+                    ("new", Json::from(change.new as i64)),
+                ])
+            })
+            .collect();
+        let memory = self
+            .memory
+            .iter()
+            .map(|write| {
+                Json::object(vec![
+                    ("address", Json::from(write.address as i64)),
+                    ("old", bytes_to_json(&write.old)),
+                    ("new", bytes_to_json(&write.new)),
+                ])
+            })
+            .collect();
+        Json::object(vec![
+            ("pc", Json::from(self.pc as i64)),
+            ("instruction", Json::from(self.instruction().to_string())),
+            ("bytes", bytes_to_json(&self.bytes)),
+            ("registers", Json::Array(registers)),
+            ("equal_flag", Json::from(self.equal_flag)),
+            ("remainder", Json::from(self.remainder as i64)),
+            ("heap_len", Json::from(self.heap_len as i64)),
+            ("memory", Json::Array(memory)),
+        ])
+    }
+
+    // The `instruction` member is only there for people reading the trace,
+    // records are rebuilt from the bytes
+    pub fn from_json(json: &Json) -> Option<TraceRecord> {
+        let number = |json: &Json, name: &str| json.get(name).and_then(Json::as_i64);
+        let mut registers = vec![];
+        for change in json.get("registers")?.as_array()? {
+            registers.push(RegisterChange {
+                register: number(change, "register")? as u8,
+                old: number(change, "old")? as i32,
+                new: number(change, "new")? as i32,
+            });
+        }
+        let mut memory = vec![];
+        for write in json.get("memory")?.as_array()? {
+            memory.push(MemoryWrite {
+                address: number(write, "address")? as u32,
+                old: bytes_from_json(write.get("old")?)?,
+                new: bytes_from_json(write.get("new")?)?,
+            });
+        }
+        Some(TraceRecord {
+            pc: number(json, "pc")? as u32,
+            bytes: bytes_from_json(json.get("bytes")?)?,
+            registers,
+            equal_flag: json.get("equal_flag")?.as_bool()?,
+            remainder: number(json, "remainder")? as u32,
+            heap_len: number(json, "heap_len")? as u32,
+            memory,
+        })
+    }
+
+    pub fn to_binary(&self, out: &mut Vec<u8>) {
+        out.extend(self.pc.to_be_bytes());
+        out.push(self.bytes.len() as u8);
+        out.extend(&self.bytes);
+        out.push(self.equal_flag as u8);
+        out.extend(self.remainder.to_be_bytes());
+        out.extend(self.heap_len.to_be_bytes());
+        out.push(self.registers.len() as u8);
+        for change in &self.registers {
+            out.push(change.register);
+            out.extend(change.old.to_be_bytes());
+            out.extend(change.new.to_be_bytes());
+        }
+        out.push(self.memory.len() as u8);
+        for write in &self.memory {
+            out.extend(write.address.to_be_bytes());
+            out.push(write.new.len() as u8);
+            out.extend(&write.old);
+            out.extend(&write.new);
+        }
+    }
+
+    // Reads one record from the front of `bytes`, returning it and the rest
+    pub fn from_binary(bytes: &[u8]) -> Option<(TraceRecord, &[u8])> {
+        let mut reader = Reader { bytes };
+        let pc = reader.u32()?;
+        let len = reader.u8()? as usize;
+        let instruction = reader.take(len)?.to_vec();
+        let equal_flag = reader.u8()? != 0;
+        let remainder = reader.u32()?;
+        let heap_len = reader.u32()?;
+        let mut registers = vec![];
+        for _ in 0..reader.u8()? {
+            registers.push(RegisterChange {
+                register: reader.u8()?,
+                old: reader.u32()? as i32,
+                new: reader.u32()? as i32,
+            });
+        }
+        let mut memory = vec![];
+        for _ in 0..reader.u8()? {
+            let address = reader.u32()?;
+            let len = reader.u8()? as usize;
+            memory.push(MemoryWrite {
+                address,
+                old: reader.take(len)?.to_vec(),
+                new: reader.take(len)?.to_vec(),
+            });
+        }
+        let record = TraceRecord {
+            pc,
+            bytes: instruction,
+            registers,
+            equal_flag,
+            remainder,
+            heap_len,
+            memory,
+        };
+        Some((record, reader.bytes))
+    }
+}
+
+fn bytes_to_json(bytes: &[u8]) -> Json {
+    Json::Array(bytes.iter().map(|b| Json::from(*b as i64)).collect())
+}
+
+fn bytes_from_json(json: &Json) -> Option<Vec<u8>> {
+    json.as_array()?
+        .iter()
+        .map(|b| b.as_i64().and_then(|b| u8::try_from(b).ok()))
+        .collect()
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let b = self.take(4)?;
+        Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum TraceFormat {
+    JsonLines,
+    Binary,
+}
+
+// Which instructions get a record. An empty opcode list means every opcode.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct TraceFilter {
+    pub pcs: Option<Range<usize>>,
+    pub opcodes: Vec<Opcode>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, pc: usize, opcode: u8) -> bool {
+        let in_range = match &self.pcs {
+            Some(range) => range.contains(&pc),
+            None => true,
+        };
+        in_range && (self.opcodes.is_empty() || self.opcodes.contains(&Opcode::from(opcode)))
+    }
+}
+
+// Writes trace records as the VM executes, see `VM::start_trace`
+pub struct Tracer {
+    writer: Box<dyn Write>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    started: bool, // whether the binary magic has been written
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(writer: Box<dyn Write>, format: TraceFormat) -> Tracer {
+        Tracer {
+            writer,
+            format,
+            filter: TraceFilter::default(),
+            started: false,
+            error: None,
+        }
+    }
+
+    pub fn with_filter(mut self, filter: TraceFilter) -> Tracer {
+        self.filter = filter;
+        self
+    }
+
+    pub fn filter(&self) -> &TraceFilter {
+        &self.filter
+    }
+
+    // Writes a record, with the location of its instruction if known. Once a
+    // write fails nothing more is written, and the error is reported by `finish`.
+    pub fn write(&mut self, record: &TraceRecord, location: Option<&Location>) {
+        if self.error.is_some() {
+            return;
+        }
+        let result = match self.format {
+            TraceFormat::JsonLines => {
+                let mut json = record.to_json();
+                if let (Json::Object(members), Some(location)) = (&mut json, location) {
+                    members.insert(
+                        2,
+                        ("location".to_string(), Json::from(location.to_string())),
+                    );
+                }
+                writeln!(self.writer, "{}", json)
+            }
+            TraceFormat::Binary => {
+                let mut bytes = vec![];
+                if !self.started {
+                    bytes.extend(BINARY_MAGIC);
+                    self.started = true;
+                }
+                record.to_binary(&mut bytes);
+                self.writer.write_all(&bytes)
+            }
+        };
+        if let Err(e) = result {
+            self.error = Some(e);
+        }
+    }
+
+    // Flushes the trace, reporting the first error writing it
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        if self.format == TraceFormat::Binary && !self.started {
+            self.writer.write_all(&BINARY_MAGIC)?;
+        }
+        self.writer.flush()
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum TraceError {
+    // `line` counts from 1
+    Json { line: usize, error: JsonError },
+    BadRecord { index: usize },
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceError::Json { line, error } => write!(f, "line {}: {}", line, error),
+            TraceError::BadRecord { index } => write!(f, "trace record {} is malformed", index),
+        }
+    }
+}
+
+// Reads a trace in either format, telling them apart by the binary magic
+pub fn read_trace(bytes: &[u8]) -> Result<Vec<TraceRecord>, TraceError> {
+    let mut records = vec![];
+    if let Some(mut rest) = bytes.strip_prefix(&BINARY_MAGIC) {
+        while !rest.is_empty() {
+            let (record, next) = TraceRecord::from_binary(rest).ok_or(TraceError::BadRecord {
+                index: records.len(),
+            })?;
+            records.push(record);
+            rest = next;
+        }
+        return Ok(records);
+    }
+    let text = String::from_utf8_lossy(bytes);
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let json = Json::parse(line).map_err(|error| TraceError::Json { line: i + 1, error })?;
+        let record = TraceRecord::from_json(&json).ok_or(TraceError::BadRecord {
+            index: records.len(),
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+// Where two traces first differ. One side is None when that trace ended first.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Divergence {
+    pub index: usize,
+    pub left: Option<TraceRecord>,
+    pub right: Option<TraceRecord>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let side = |record: &Option<TraceRecord>| match record {
+            Some(record) => format!("{:#06x} {}", record.pc, record.instruction()),
+            None => "end of trace".to_string(),
+        };
+        write!(
+            f,
+            "traces diverge at record {}: {} vs {}",
+            self.index,
+            side(&self.left),
+            side(&self.right)
+        )
+    }
+}
+
+pub fn first_divergence(left: &[TraceRecord], right: &[TraceRecord]) -> Option<Divergence> {
+    let index = (0..left.len().max(right.len())).find(|i| left.get(*i) != right.get(*i))?;
+    Some(Divergence {
+        index,
+        left: left.get(index).cloned(),
+        right: right.get(index).cloned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> TraceRecord {
+        TraceRecord {
+            pc: 8,
+            bytes: vec![21, 0, 1, 0],
+            registers: vec![RegisterChange {
+                register: 2,
+                old: -1,
+                new: 7,
+            }],
+            equal_flag: true,
+            remainder: 3,
+            heap_len: 16,
+            memory: vec![MemoryWrite {
+                address: 0x0100_0000,
+                old: vec![0, 0, 0, 0],
+                new: vec![0, 0, 0, 7],
+            }],
+        }
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let json = sample().to_json();
+        assert_eq!(
+            json.get("instruction").unwrap().as_str(),
+            Some("setm $0 $1")
+        );
+        assert_eq!(TraceRecord::from_json(&json), Some(sample()));
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let mut bytes = vec![];
+        sample().to_binary(&mut bytes);
+        let (record, rest) = TraceRecord::from_binary(&bytes).unwrap();
+        assert_eq!(record, sample());
+        assert!(rest.is_empty());
+        assert_eq!(TraceRecord::from_binary(&bytes[..bytes.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_read_trace() {
+        let json = format!("{}\n{}\n", sample().to_json(), sample().to_json());
+        assert_eq!(read_trace(json.as_bytes()), Ok(vec![sample(), sample()]));
+        assert!(matches!(
+            read_trace(b"{\"pc\":1}\n"),
+            Err(TraceError::BadRecord { index: 0 })
+        ));
+        assert!(matches!(
+            read_trace(b"\n{"),
+            Err(TraceError::Json { line: 2, .. })
+        ));
+
+        let mut binary = BINARY_MAGIC.to_vec();
+        sample().to_binary(&mut binary);
+        assert_eq!(read_trace(&binary), Ok(vec![sample()]));
+        binary.push(0);
+        assert_eq!(read_trace(&binary), Err(TraceError::BadRecord { index: 1 }));
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = TraceFilter {
+            pcs: Some(4..12),
+            opcodes: vec![Opcode::INC],
+        };
+        assert!(filter.matches(8, Opcode::INC as u8));
+        assert!(!filter.matches(12, Opcode::INC as u8));
+        assert!(!filter.matches(8, Opcode::DEC as u8));
+        assert!(TraceFilter::default().matches(400, 200));
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let mut other = sample();
+        other.registers[0].new = 8;
+        let left = vec![sample(), sample()];
+        assert_eq!(first_divergence(&left, &left), None);
+        let divergence = first_divergence(&left, &[sample(), other.clone()]).unwrap();
+        assert_eq!(divergence.index, 1);
+        assert_eq!(divergence.right, Some(other));
+        let divergence = first_divergence(&left, &left[..1]).unwrap();
+        assert_eq!(divergence.index, 1);
+        assert_eq!(divergence.right, None);
+        assert_eq!(
+            divergence.to_string(),
+            "traces diverge at record 1: 0x0008 setm $0 $1 vs end of trace"
+        );
+    }
+}
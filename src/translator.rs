@@ -0,0 +1,572 @@
+// Ahead-of-time translation of Iridium bytecode to a standalone C program.
+//
+// Every register becomes a local, every instruction slot becomes a label, and
+// jumps through registers go via a switch over the valid targets. The address
+// space is laid out exactly as in the VM, with the same permission checks and
+// fault messages, so the translated program behaves like `VM::run` on the
+// same bytecode.
+//
+// Compiling with `-DIRIDIUM_DUMP_STATE` makes the program print the final
+// machine state, which is what the tests compare against the VM.
+use std::fmt::Write;
+
+use crate::config::{ArithmeticMode, VmConfig};
+use crate::decoder::{decode_program, DecodedInstruction};
+use crate::memory::{
+    CODE_BASE, CODE_LIMIT, HEAP_BASE, INSTRUCTION_WIDTH, RELATIVE_JUMP_BASE, RODATA_BASE,
+    RODATA_LIMIT, STACK_BASE,
+};
+
+const RUNTIME: &str = r#"#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+enum { ACCESS_READ, ACCESS_WRITE, ACCESS_EXECUTE };
+static const char *access_names[] = {"read", "write", "execute"};
+
+struct segment {
+    const char *name;
+    uint64_t base, len;
+    int permissions[3];
+    uint8_t *bytes;
+};
+
+static uint8_t stack_memory[STACK_SIZE + 1];
+static uint8_t *heap;
+static uint64_t heap_len;
+static struct segment segments[4];
+static int faulted;
+
+static void init_segments(void) {
+    struct segment code = {"code", CODE_BASE, CODE_LEN, {1, 0, 1}, (uint8_t *)code_bytes};
+    struct segment rodata = {"rodata", RODATA_BASE, RODATA_LEN, {1, 0, 0}, (uint8_t *)rodata_bytes};
+    struct segment stack = {"stack", STACK_BASE, STACK_SIZE, {1, 1, 0}, stack_memory};
+    struct segment heap_segment = {"heap", HEAP_BASE, 0, {1, 1, 0}, NULL};
+    segments[0] = code;
+    segments[1] = rodata;
+    segments[2] = stack;
+    segments[3] = heap_segment;
+}
+
+static void print_permissions(const struct segment *s) {
+    fprintf(stderr, "%c%c%c", s->permissions[0] ? 'r' : '-', s->permissions[1] ? 'w' : '-',
+            s->permissions[2] ? 'x' : '-');
+}
+
+/* Checks an access the same way the VM's memory map does, printing the fault
+   and returning NULL when it is not allowed */
+static uint8_t *access_memory(uint64_t pc, uint64_t address, uint64_t len, int access) {
+    int i;
+    segments[3].len = heap_len;
+    segments[3].bytes = heap;
+    for (i = 0; i < 4; i++) {
+        struct segment *s = &segments[i];
+        if (address >= s->base && address - s->base <= s->len && len <= s->len - (address - s->base)) {
+            if (s->permissions[access]) {
+                return s->bytes + (address - s->base);
+            }
+            fprintf(stderr, "fault at pc 0x%04llx: %s of address 0x%08llx violates %s segment permissions (",
+                    (unsigned long long)pc, access_names[access], (unsigned long long)address, s->name);
+            print_permissions(s);
+            fprintf(stderr, ")\n");
+            faulted = 1;
+            return NULL;
+        }
+    }
+    fprintf(stderr, "fault at pc 0x%04llx: %s of unmapped address 0x%08llx\n", (unsigned long long)pc,
+            access_names[access], (unsigned long long)address);
+    faulted = 1;
+    return NULL;
+}
+
+static void fault_illegal_opcode(uint64_t pc, unsigned opcode) {
+    fprintf(stderr, "fault at pc 0x%04llx: illegal opcode %u\n", (unsigned long long)pc, opcode);
+    faulted = 1;
+}
+
+static void fault_misaligned_jump(uint64_t pc, uint64_t target) {
+    fprintf(stderr, "fault at pc 0x%04llx: jump to 0x%04llx is not on an instruction boundary\n",
+            (unsigned long long)pc, (unsigned long long)target);
+    faulted = 1;
+}
+
+static void fault(uint64_t pc, const char *message) {
+    fprintf(stderr, "fault at pc 0x%04llx: %s\n", (unsigned long long)pc, message);
+    faulted = 1;
+}
+
+static int aloc(uint64_t pc, int32_t bytes) {
+    int64_t new_end = (int64_t)heap_len + bytes;
+    if (new_end < 0 || new_end > (int64_t)HEAP_LIMIT) {
+        fprintf(stderr, "fault at pc 0x%04llx: cannot resize the heap to %lld bytes, the limit is %llu bytes\n",
+                (unsigned long long)pc, (long long)new_end, (unsigned long long)HEAP_LIMIT);
+        faulted = 1;
+        return 0;
+    }
+    heap = realloc(heap, new_end ? (size_t)new_end : 1);
+    if (new_end > (int64_t)heap_len) {
+        memset(heap + heap_len, 0, (size_t)(new_end - (int64_t)heap_len));
+    }
+    heap_len = (uint64_t)new_end;
+    return 1;
+}
+
+static int32_t read_word(const uint8_t *m) {
+    return (int32_t)((uint32_t)m[0] << 24 | (uint32_t)m[1] << 16 | (uint32_t)m[2] << 8 | (uint32_t)m[3]);
+}
+
+static void write_word(uint8_t *m, int32_t value) {
+    m[0] = (uint8_t)((uint32_t)value >> 24);
+    m[1] = (uint8_t)((uint32_t)value >> 16);
+    m[2] = (uint8_t)((uint32_t)value >> 8);
+    m[3] = (uint8_t)value;
+}
+"#;
+
+// Translates `program`, with `ro_data` as its read-only data segment, into C
+// that behaves like a VM created with `config`. Output always goes to stdout.
+pub fn translate_to_c(program: &[u8], ro_data: &[u8], config: &VmConfig) -> String {
+    let code_end = program.len().min(CODE_LIMIT - CODE_BASE);
+    let rodata_len = ro_data.len().min(RODATA_LIMIT - RODATA_BASE);
+    let instructions = decode_program(program, config.register_count());
+    let mut out = String::new();
+
+    writeln!(
+        out,
+        "/* translated from {} bytes of Iridium bytecode */",
+        program.len()
+    )
+    .unwrap();
+    writeln!(out, "#define CODE_BASE {:#x}ull", CODE_BASE).unwrap();
+    writeln!(out, "#define CODE_LEN {}ull", code_end).unwrap();
+    writeln!(out, "#define RODATA_BASE {:#x}ull", RODATA_BASE).unwrap();
+    writeln!(out, "#define RODATA_LEN {}ull", rodata_len).unwrap();
+    writeln!(out, "#define STACK_BASE {:#x}ull", STACK_BASE).unwrap();
+    writeln!(out, "#define STACK_SIZE {}ull", config.stack_size()).unwrap();
+    writeln!(out, "#define HEAP_BASE {:#x}ull", HEAP_BASE).unwrap();
+    writeln!(out, "#define HEAP_LIMIT {}ull", config.max_heap_bytes()).unwrap();
+    writeln!(
+        out,
+        "#define ARITHMETIC_CHECKED {}",
+        (config.arithmetic() == ArithmeticMode::Checked) as u8
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "static const uint8_t code_bytes[] = {};",
+        byte_array(program)
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "static const uint8_t rodata_bytes[] = {};",
+        byte_array(ro_data)
+    )
+    .unwrap();
+    // the defines above are used by the runtime, which needs the includes first
+    out = format!(
+        "#include <stdint.h>\n{}{}",
+        out,
+        RUNTIME.replace("#include <stdint.h>\n", "")
+    );
+
+    out.push_str("\nint main(void) {\n");
+    for reg in 0..config.register_count() {
+        writeln!(out, "    int32_t r{} = 0;", reg).unwrap();
+    }
+    out.push_str("    int32_t result;\n");
+    out.push_str("    int equal_flag = 0;\n");
+    out.push_str("    uint32_t remainder = 0;\n");
+    out.push_str("    uint64_t pc = 0, target = 0, jump_pc = 0;\n");
+    out.push_str("    init_segments();\n");
+
+    for (slot, instruction) in instructions.iter().enumerate() {
+        let pc = CODE_BASE + slot * INSTRUCTION_WIDTH;
+        writeln!(out, "L_{:04x}:", pc).unwrap();
+        translate_instruction(&mut out, *instruction, pc);
+    }
+    // falling off the end of the program
+    writeln!(out, "    pc = {};\n    goto done;", program.len()).unwrap();
+
+    out.push_str("dispatch:\n");
+    // jumping to the end of the code segment is a halt, unless the program runs
+    // on past it, in which case the next fetch faults
+    let end_of_code = if code_end < program.len() {
+        format!("goto L_{:04x};", CODE_BASE + code_end)
+    } else {
+        "goto done;".to_string()
+    };
+    writeln!(
+        out,
+        "    if (target == CODE_BASE + CODE_LEN) {{\n        pc = target;\n        {}\n    }}",
+        end_of_code
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "    if (target >= CODE_BASE && target < CODE_BASE + CODE_LEN && (target - CODE_BASE) % {} != 0) {{\n        fault_misaligned_jump(jump_pc, target);\n        pc = jump_pc + {};\n        goto done;\n    }}",
+        INSTRUCTION_WIDTH, INSTRUCTION_WIDTH
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "    if (!access_memory(jump_pc, target, {}, ACCESS_EXECUTE)) {{\n        pc = jump_pc + {};\n        goto done;\n    }}",
+        INSTRUCTION_WIDTH, INSTRUCTION_WIDTH
+    )
+    .unwrap();
+    out.push_str("    switch (target) {\n");
+    for slot in 0..code_end / INSTRUCTION_WIDTH {
+        let pc = CODE_BASE + slot * INSTRUCTION_WIDTH;
+        writeln!(out, "    case {:#x}: goto L_{:04x};", pc, pc).unwrap();
+    }
+    out.push_str("    }\n    abort();\n");
+
+    out.push_str("done:\n#ifdef IRIDIUM_DUMP_STATE\n");
+    out.push_str(
+        "    printf(\"pc=%llu equal_flag=%d remainder=%u heap_len=%llu\\n\", (unsigned long long)pc, equal_flag, remainder, (unsigned long long)heap_len);\n",
+    );
+    out.push_str("    printf(\"registers=");
+    for reg in 0..config.register_count() {
+        out.push_str(if reg == 0 { "%d" } else { ",%d" });
+    }
+    out.push_str("\\n\"");
+    for reg in 0..config.register_count() {
+        write!(out, ", r{}", reg).unwrap();
+    }
+    out.push_str(");\n#endif\n");
+    out.push_str("    (void)pc;\n    return faulted;\n}\n");
+    out
+}
+
+fn byte_array(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        // C does not allow empty arrays
+        return "{0}".to_string();
+    }
+    let items: Vec<String> = bytes.iter().map(|b| b.to_string()).collect();
+    format!("{{{}}}", items.join(", "))
+}
+
+fn translate_instruction(out: &mut String, instruction: DecodedInstruction, pc: usize) {
+    let next = pc + INSTRUCTION_WIDTH;
+    // the builtins store the wrapped result even when they report an overflow
+    let arithmetic = |out: &mut String, builtin: &str, a: String, b: String, dst: u8| {
+        writeln!(
+            out,
+            "    if (__builtin_{}_overflow({}, {}, &result) && ARITHMETIC_CHECKED) {{\n        fault({}, \"arithmetic overflow\");\n        pc = {};\n        goto done;\n    }}\n    r{} = result;",
+            builtin, a, b, pc, next, dst
+        )
+        .unwrap();
+    };
+    let reg = |r: u8| format!("r{}", r);
+    let compare = |out: &mut String, op: &str, r1: u8, r2: u8| {
+        writeln!(out, "    equal_flag = r{} {} r{};", r1, op, r2).unwrap();
+    };
+    let jump = |out: &mut String, target: String| {
+        writeln!(
+            out,
+            "    target = {};\n    jump_pc = {};\n    goto dispatch;",
+            target, pc
+        )
+        .unwrap();
+    };
+    let conditional_jump = |out: &mut String, condition: &str, reg: u8| {
+        writeln!(
+            out,
+            "    if ({}) {{\n        target = (uint32_t)r{};\n        jump_pc = {};\n        goto dispatch;\n    }}",
+            condition, reg, pc
+        )
+        .unwrap();
+    };
+    match instruction {
+        DecodedInstruction::Load { reg, value } => {
+            writeln!(out, "    r{} = {};", reg, value).unwrap();
+        }
+        DecodedInstruction::Add { r1, r2, dst } => arithmetic(out, "add", reg(r1), reg(r2), dst),
+        DecodedInstruction::Sub { r1, r2, dst } => arithmetic(out, "sub", reg(r1), reg(r2), dst),
+        DecodedInstruction::Mul { r1, r2, dst } => arithmetic(out, "mul", reg(r1), reg(r2), dst),
+        DecodedInstruction::Div { r1, r2, dst } => {
+            writeln!(
+                out,
+                "    {{\n        int32_t a = r{}, b = r{};\n        if (b == 0) {{\n            fault({}, \"division by zero\");\n            pc = {};\n            goto done;\n        }}\n        if (a == INT32_MIN && b == -1) {{\n            if (ARITHMETIC_CHECKED) {{\n                fault({}, \"arithmetic overflow\");\n                pc = {};\n                goto done;\n            }}\n            r{} = INT32_MIN;\n            remainder = 0;\n        }} else {{\n            r{} = a / b;\n            remainder = (uint32_t)(a % b);\n        }}\n    }}",
+                r1, r2, pc, next, pc, next, dst, dst
+            )
+            .unwrap();
+        }
+        DecodedInstruction::Hlt => {
+            writeln!(
+                out,
+                "    puts(\"HLT encountered\");\n    pc = {};\n    goto done;",
+                pc + 1
+            )
+            .unwrap();
+        }
+        DecodedInstruction::Jmp { reg } => jump(out, format!("(uint32_t)r{}", reg)),
+        DecodedInstruction::Jmpf { reg } => jump(
+            out,
+            format!(
+                "(uint64_t)((int64_t){} + r{})",
+                pc + RELATIVE_JUMP_BASE,
+                reg
+            ),
+        ),
+        DecodedInstruction::Jmpb { reg } => jump(
+            out,
+            format!(
+                "(uint64_t)((int64_t){} - r{})",
+                pc + RELATIVE_JUMP_BASE,
+                reg
+            ),
+        ),
+        DecodedInstruction::Eq { r1, r2 } => compare(out, "==", r1, r2),
+        DecodedInstruction::Neq { r1, r2 } => compare(out, "!=", r1, r2),
+        DecodedInstruction::Gt { r1, r2 } => compare(out, ">", r1, r2),
+        DecodedInstruction::Lt { r1, r2 } => compare(out, "<", r1, r2),
+        DecodedInstruction::Gtq { r1, r2 } => compare(out, ">=", r1, r2),
+        DecodedInstruction::Ltq { r1, r2 } => compare(out, "<=", r1, r2),
+        DecodedInstruction::Jeq { reg } => conditional_jump(out, "equal_flag", reg),
+        DecodedInstruction::Jneq { reg } => conditional_jump(out, "!equal_flag", reg),
+        DecodedInstruction::Aloc { reg } => {
+            writeln!(
+                out,
+                "    if (!aloc({}, r{})) {{\n        pc = {};\n        goto done;\n    }}",
+                pc, reg, next
+            )
+            .unwrap();
+        }
+        DecodedInstruction::Inc { reg: r } => arithmetic(out, "add", reg(r), "1".into(), r),
+        DecodedInstruction::Dec { reg: r } => arithmetic(out, "sub", reg(r), "1".into(), r),
+        DecodedInstruction::Loadm { addr, dst } => {
+            writeln!(
+                out,
+                "    {{\n        const uint8_t *m = access_memory({}, (uint32_t)r{}, 4, ACCESS_READ);\n        if (!m) {{\n            pc = {};\n            goto done;\n        }}\n        r{} = read_word(m);\n    }}",
+                pc, addr, next, dst
+            )
+            .unwrap();
+        }
+        DecodedInstruction::Setm { addr, src } => {
+            writeln!(
+                out,
+                "    {{\n        uint8_t *m = access_memory({}, (uint32_t)r{}, 4, ACCESS_WRITE);\n        if (!m) {{\n            pc = {};\n            goto done;\n        }}\n        write_word(m, r{});\n    }}",
+                pc, addr, next, src
+            )
+            .unwrap();
+        }
+        DecodedInstruction::Illegal { opcode } => {
+            writeln!(
+                out,
+                "    fault_illegal_opcode({}, {});\n    pc = {};\n    goto done;",
+                pc,
+                opcode,
+                pc + 1
+            )
+            .unwrap();
+        }
+        DecodedInstruction::InvalidRegister { register } => {
+            writeln!(
+                out,
+                "    fault({}, \"register ${} does not exist\");\n    pc = {};\n    goto done;",
+                pc, register, next
+            )
+            .unwrap();
+        }
+        DecodedInstruction::NotExecutable => {
+            writeln!(
+                out,
+                "    access_memory({}, {}, {}, ACCESS_EXECUTE);\n    pc = {};\n    goto done;",
+                pc, pc, INSTRUCTION_WIDTH, pc
+            )
+            .unwrap();
+        }
+        // only produced by the superinstruction pass, which is not run here
+        DecodedInstruction::CompareBranch { .. }
+        | DecodedInstruction::StepCompareBranch { .. }
+        | DecodedInstruction::LoadAdd { .. } => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{OutputSink, VmConfigBuilder};
+    use crate::vm::VM;
+    use std::process::Command;
+
+    // Compiles and runs the translation, returning (exit code, stdout, stderr),
+    // or None when there is no C compiler to test with
+    fn compile_and_run(
+        name: &str,
+        program: &[u8],
+        ro_data: &[u8],
+        config: &VmConfig,
+    ) -> Option<(i32, String, String)> {
+        let dir = std::env::temp_dir().join(format!("iridium-translator-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join(format!("{}.c", name));
+        let binary = dir.join(name);
+        std::fs::write(&source, translate_to_c(program, ro_data, config)).unwrap();
+        let compiled = Command::new("cc")
+            .arg("-O1")
+            .arg("-DIRIDIUM_DUMP_STATE")
+            .arg("-o")
+            .arg(&binary)
+            .arg(&source)
+            .output();
+        match compiled {
+            Ok(output) => assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            Err(_) => {
+                println!("no C compiler available, skipping");
+                return None;
+            }
+        }
+        let output = Command::new(&binary).output().unwrap();
+        Some((
+            output.status.code().unwrap(),
+            String::from_utf8(output.stdout).unwrap(),
+            String::from_utf8(output.stderr).unwrap(),
+        ))
+    }
+
+    fn expected_state(vm: &VM) -> String {
+        let registers: Vec<String> = vm.registers.iter().map(|r| r.to_string()).collect();
+        format!(
+            "pc={} equal_flag={} remainder={} heap_len={}\nregisters={}\n",
+            vm.pc(),
+            vm.equal_flag() as i32,
+            vm.remainder(),
+            vm.heap().len(),
+            registers.join(",")
+        )
+    }
+
+    fn assert_matches_vm(name: &str, program: Vec<u8>, ro_data: Vec<u8>) {
+        assert_matches_config(name, program, ro_data, VmConfig::builder());
+    }
+
+    fn assert_matches_config(
+        name: &str,
+        program: Vec<u8>,
+        ro_data: Vec<u8>,
+        config: VmConfigBuilder,
+    ) {
+        let (sink, output) = OutputSink::buffer();
+        let config = config.output(sink).build().unwrap();
+        let mut vm = VM::with_config(config.clone());
+        vm.program = program.clone();
+        vm.ro_data = ro_data.clone();
+        vm.run();
+        let (code, stdout, stderr) = match compile_and_run(name, &program, &ro_data, &config) {
+            Some(result) => result,
+            None => return,
+        };
+        let printed = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert_eq!(stdout, format!("{}{}", printed, expected_state(&vm)));
+        match vm.fault() {
+            Some(fault) => {
+                assert_eq!(code, 1);
+                assert_eq!(stderr, format!("{}\n", fault));
+            }
+            None => {
+                assert_eq!(code, 0);
+                assert_eq!(stderr, "");
+            }
+        }
+    }
+
+    #[test]
+    fn test_translate_emits_labels() {
+        let c = translate_to_c(&[0, 0, 1, 244, 5, 0, 0, 0], &[], &VmConfig::default());
+        assert!(c.contains("L_0000:\n    r0 = 500;"));
+        assert!(c.contains("L_0004:\n    puts(\"HLT encountered\");"));
+        assert!(c.contains("case 0x4: goto L_0004;"));
+    }
+
+    #[test]
+    fn test_translated_loop() {
+        assert_matches_vm(
+            "loop",
+            vec![
+                0, 0, 0, 0, // load $0 #0
+                0, 1, 3, 232, // load $1 #1000
+                0, 2, 0, 12, // load $2 #12
+                18, 0, 0, 0, // inc $0
+                3, 0, 0, 3, // mul $0 $0 $3
+                4, 3, 1, 4, // div $3 $1 $4
+                10, 0, 1, 0, // neq $0 $1
+                15, 2, 0, 0, // jeq $2
+                5, 0, 0, 0, // hlt
+            ],
+            vec![],
+        );
+    }
+
+    #[test]
+    fn test_translated_memory() {
+        assert_matches_vm(
+            "memory",
+            vec![
+                0, 0, 0, 8, // load $0 #8
+                17, 0, 0, 0, // aloc $0
+                0, 1, 1, 0, // load $1 #256
+                3, 1, 1, 5, // mul $1 $1 $5
+                3, 5, 1, 1, // mul $5 $1 $1 (0x100_0000, the heap)
+                0, 2, 128, 0, // load $2 #32768 (rodata)
+                20, 2, 3, 0, // loadm $2 $3
+                21, 1, 3, 0, // setm $1 $3
+                20, 1, 4, 0, // loadm $1 $4
+                21, 2, 3, 0, // setm $2 $3, faults
+            ],
+            vec![1, 2, 3, 4],
+        );
+    }
+
+    #[test]
+    fn test_translated_faults() {
+        // misaligned jump
+        assert_matches_vm("misaligned", vec![0, 0, 0, 2, 6, 0, 0, 0], vec![]);
+        // jump into the stack
+        assert_matches_vm("stack", vec![0, 0, 1, 0, 3, 0, 0, 0, 6, 0, 0, 0], vec![]);
+        // illegal opcode
+        assert_matches_vm("illegal", vec![18, 0, 0, 0, 99, 0, 0, 0], vec![]);
+        // truncated trailing instruction
+        assert_matches_vm("truncated", vec![18, 0, 0, 0, 18, 0], vec![]);
+        // jmpb before the start of the program
+        assert_matches_vm("jmpb", vec![0, 0, 0, 12, 8, 0, 0, 0], vec![]);
+    }
+
+    #[test]
+    fn test_translated_config() {
+        let overflow = vec![
+            0, 0, 255, 255, // load $0 #65535
+            3, 0, 0, 0, // mul $0 $0 $0
+            3, 0, 0, 0, // mul $0 $0 $0
+            4, 0, 1, 2, // div $0 $1 $2
+        ];
+        assert_matches_vm("wrapping", overflow.clone(), vec![]);
+        assert_matches_config(
+            "checked",
+            overflow,
+            vec![],
+            VmConfig::builder().arithmetic(ArithmeticMode::Checked),
+        );
+        let small = vec![
+            0, 0, 0, 16, // load $0 #16
+            17, 0, 0, 0, // aloc $0
+            17, 0, 0, 0, // aloc $0, over the limit
+        ];
+        let config = VmConfig::builder().max_heap_bytes(20).stack_size(0);
+        assert_matches_config("heap", small, vec![], config.clone());
+        // inc $7
+        assert_matches_config(
+            "registers",
+            vec![18, 1, 0, 0, 18, 7, 0, 0],
+            vec![],
+            config.register_count(4),
+        );
+    }
+}
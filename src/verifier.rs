@@ -0,0 +1,361 @@
+use std::fmt;
+
+use crate::decoder::{decode, DecodedInstruction};
+use crate::memory::{CODE_BASE, CODE_LIMIT, INSTRUCTION_WIDTH, RELATIVE_JUMP_BASE};
+
+// A problem the verifier found, at the offset of the instruction it concerns
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Diagnostic {
+    pub offset: usize,
+    pub kind: DiagnosticKind,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum DiagnosticKind {
+    UnknownOpcode { opcode: u8 },
+    InvalidRegister { register: u8 },
+    // the program ends partway through an instruction of `len` bytes
+    TruncatedInstruction { len: usize },
+    // the program runs past the end of the code segment
+    ProgramTooLarge { len: usize },
+    MisalignedJump { target: usize },
+    JumpOutOfRange { target: usize },
+}
+
+impl Diagnostic {
+    pub fn new(offset: usize, kind: DiagnosticKind) -> Diagnostic {
+        Diagnostic { offset, kind }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#06x}: {}", self.offset, self.kind)
+    }
+}
+
+impl fmt::Display for DiagnosticKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiagnosticKind::UnknownOpcode { opcode } => write!(f, "unknown opcode {}", opcode),
+            DiagnosticKind::InvalidRegister { register } => {
+                write!(f, "register ${} does not exist", register)
+            }
+            DiagnosticKind::TruncatedInstruction { len } => write!(
+                f,
+                "truncated instruction, {} of {} bytes present",
+                len, INSTRUCTION_WIDTH
+            ),
+            DiagnosticKind::ProgramTooLarge { len } => write!(
+                f,
+                "program is {} bytes, the code segment holds at most {}",
+                len,
+                CODE_LIMIT - CODE_BASE
+            ),
+            DiagnosticKind::MisalignedJump { target } => write!(
+                f,
+                "jump to {:#06x} is not on an instruction boundary",
+                target
+            ),
+            DiagnosticKind::JumpOutOfRange { target } => {
+                write!(f, "jump to {:#06x} is outside the program", target)
+            }
+        }
+    }
+}
+
+// Checks a program before it is run on a VM with `register_count` registers.
+// Every instruction is checked for a known opcode and valid registers, and
+// jumps are checked wherever the register holding their target has a value
+// that can be worked out from the LOADs and arithmetic leading up to them.
+pub fn verify(program: &[u8], register_count: usize) -> Result<(), Vec<Diagnostic>> {
+    let mut diagnostics = vec![];
+    let code_end = program.len().min(CODE_LIMIT - CODE_BASE);
+    if program.len() > code_end {
+        diagnostics.push(Diagnostic::new(
+            CODE_BASE + code_end,
+            DiagnosticKind::ProgramTooLarge { len: program.len() },
+        ));
+    }
+    let slots = code_end / INSTRUCTION_WIDTH;
+    if !code_end.is_multiple_of(INSTRUCTION_WIDTH) {
+        diagnostics.push(Diagnostic::new(
+            CODE_BASE + slots * INSTRUCTION_WIDTH,
+            DiagnosticKind::TruncatedInstruction {
+                len: code_end % INSTRUCTION_WIDTH,
+            },
+        ));
+    }
+
+    let mut instructions = Vec::with_capacity(slots);
+    for slot in 0..slots {
+        let offset = CODE_BASE + slot * INSTRUCTION_WIDTH;
+        let instruction = decode(program, offset);
+        if let DecodedInstruction::Illegal { opcode } = instruction {
+            diagnostics.push(Diagnostic::new(
+                offset,
+                DiagnosticKind::UnknownOpcode { opcode },
+            ));
+        }
+        if let Some(register) = instruction.max_register() {
+            if register as usize >= register_count {
+                diagnostics.push(Diagnostic::new(
+                    offset,
+                    DiagnosticKind::InvalidRegister { register },
+                ));
+            }
+        }
+        instructions.push(instruction);
+    }
+
+    // jump targets only mean something once the registers are known to be valid
+    if diagnostics.is_empty() {
+        diagnostics.extend(check_jumps(&instructions, code_end, register_count));
+    }
+    if diagnostics.is_empty() {
+        Ok(())
+    } else {
+        diagnostics.sort_by_key(|d| d.offset);
+        Err(diagnostics)
+    }
+}
+
+// What is known about each register on entry to an instruction; None means
+// the register could hold anything
+type Registers = Vec<Option<i32>>;
+
+// Propagates constant register values through the program's control flow and
+// checks every jump whose target comes out as a constant
+fn check_jumps(
+    instructions: &[DecodedInstruction],
+    code_end: usize,
+    register_count: usize,
+) -> Vec<Diagnostic> {
+    let unknown: Registers = vec![None; register_count];
+    // None until the slot is found to be reachable
+    let mut entry: Vec<Option<Registers>> = vec![None; instructions.len()];
+    let mut worklist = vec![];
+    // the host may set registers before running, so nothing is known at the start
+    if !instructions.is_empty() {
+        entry[0] = Some(unknown.clone());
+        worklist.push(0);
+    }
+    let mut jumps_anywhere = false;
+
+    while let Some(slot) = worklist.pop() {
+        let mut registers = entry[slot].clone().unwrap();
+        let instruction = instructions[slot];
+        let mut successors = vec![];
+        match jump_target(instruction, &registers, slot) {
+            Some(Some(target)) => {
+                if let Ok(Some(target_slot)) = check_target(target, code_end) {
+                    successors.push(target_slot);
+                }
+            }
+            Some(None) => jumps_anywhere = true,
+            None => {}
+        }
+
+        transfer(instruction, &mut registers);
+        let falls_through = !matches!(
+            instruction,
+            DecodedInstruction::Hlt
+                | DecodedInstruction::Illegal { .. }
+                | DecodedInstruction::Jmp { .. }
+                | DecodedInstruction::Jmpf { .. }
+                | DecodedInstruction::Jmpb { .. }
+        );
+        if falls_through && slot + 1 < instructions.len() {
+            successors.push(slot + 1);
+        }
+        for successor in successors {
+            if merge(&mut entry[successor], &registers) {
+                worklist.push(successor);
+            }
+        }
+
+        // a jump through an unknown register could land on any instruction
+        if worklist.is_empty() && jumps_anywhere {
+            jumps_anywhere = false;
+            for (slot, state) in entry.iter_mut().enumerate() {
+                if merge(state, &unknown) {
+                    worklist.push(slot);
+                }
+            }
+        }
+    }
+
+    // only the final states say which targets are really constant
+    let mut diagnostics = vec![];
+    for (slot, registers) in entry.iter().enumerate() {
+        let registers = match registers {
+            Some(registers) => registers,
+            None => continue,
+        };
+        if let Some(Some(target)) = jump_target(instructions[slot], registers, slot) {
+            if let Err(kind) = check_target(target, code_end) {
+                diagnostics.push(Diagnostic::new(CODE_BASE + slot * INSTRUCTION_WIDTH, kind));
+            }
+        }
+    }
+    diagnostics
+}
+
+// The target of a jump instruction in `slot`, if the instruction is a jump,
+// and if its target register holds a known value
+fn jump_target(
+    instruction: DecodedInstruction,
+    registers: &Registers,
+    slot: usize,
+) -> Option<Option<usize>> {
+    let base = (CODE_BASE + slot * INSTRUCTION_WIDTH + RELATIVE_JUMP_BASE) as i64;
+    match instruction {
+        DecodedInstruction::Jmp { reg }
+        | DecodedInstruction::Jeq { reg }
+        | DecodedInstruction::Jneq { reg } => {
+            Some(registers[reg as usize].map(|t| t as u32 as usize))
+        }
+        DecodedInstruction::Jmpf { reg } => {
+            Some(registers[reg as usize].map(|t| (base + t as i64) as usize))
+        }
+        DecodedInstruction::Jmpb { reg } => {
+            Some(registers[reg as usize].map(|t| (base - t as i64) as usize))
+        }
+        _ => None,
+    }
+}
+
+// Checks a jump target the way the VM does, returning the slot it lands on,
+// or None for a jump to the end of the program, which halts it
+fn check_target(target: usize, code_end: usize) -> Result<Option<usize>, DiagnosticKind> {
+    if target == CODE_BASE + code_end {
+        Ok(None)
+    } else if !(CODE_BASE..CODE_BASE + code_end).contains(&target) {
+        Err(DiagnosticKind::JumpOutOfRange { target })
+    } else if !(target - CODE_BASE).is_multiple_of(INSTRUCTION_WIDTH) {
+        Err(DiagnosticKind::MisalignedJump { target })
+    } else {
+        Ok(Some((target - CODE_BASE) / INSTRUCTION_WIDTH))
+    }
+}
+
+// Merges `incoming` into the state of a slot, returning whether it changed
+fn merge(state: &mut Option<Registers>, incoming: &Registers) -> bool {
+    match state {
+        None => {
+            *state = Some(incoming.clone());
+            true
+        }
+        Some(registers) => {
+            let mut changed = false;
+            for (known, value) in registers.iter_mut().zip(incoming) {
+                if known.is_some() && known != value {
+                    *known = None;
+                    changed = true;
+                }
+            }
+            changed
+        }
+    }
+}
+
+// Updates the known register values for the effect of one instruction
+fn transfer(instruction: DecodedInstruction, registers: &mut Registers) {
+    let binary = |registers: &Registers, r1: u8, r2: u8, op: fn(i32, i32) -> i32| {
+        Some(op(registers[r1 as usize]?, registers[r2 as usize]?))
+    };
+    match instruction {
+        DecodedInstruction::Load { reg, value } => registers[reg as usize] = Some(value as i32),
+        DecodedInstruction::Add { r1, r2, dst } => {
+            registers[dst as usize] = binary(registers, r1, r2, i32::wrapping_add)
+        }
+        DecodedInstruction::Sub { r1, r2, dst } => {
+            registers[dst as usize] = binary(registers, r1, r2, i32::wrapping_sub)
+        }
+        DecodedInstruction::Mul { r1, r2, dst } => {
+            registers[dst as usize] = binary(registers, r1, r2, i32::wrapping_mul)
+        }
+        DecodedInstruction::Inc { reg } => {
+            registers[reg as usize] = registers[reg as usize].map(|r| r.wrapping_add(1))
+        }
+        DecodedInstruction::Dec { reg } => {
+            registers[reg as usize] = registers[reg as usize].map(|r| r.wrapping_sub(1))
+        }
+        DecodedInstruction::Div { dst, .. } | DecodedInstruction::Loadm { dst, .. } => {
+            registers[dst as usize] = None
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_accepts_loop() {
+        let program = vec![
+            0, 0, 0, 0, // load $0 #0
+            0, 1, 0, 100, // load $1 #100
+            0, 2, 0, 8, // load $2 #8
+            18, 0, 0, 0, // inc $0
+            10, 0, 1, 0, // neq $0 $1
+            15, 2, 0, 0, // jeq $2
+            5, 0, 0, 0, // hlt
+        ];
+        assert_eq!(verify(&program, 32), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_rejects_bad_instructions() {
+        let program = vec![
+            200, 0, 0, 0, // unknown opcode
+            1, 0, 40, 2, // add $0 $40 $2
+            5, 0, // truncated hlt
+        ];
+        assert_eq!(
+            verify(&program, 32),
+            Err(vec![
+                Diagnostic::new(0, DiagnosticKind::UnknownOpcode { opcode: 200 }),
+                Diagnostic::new(4, DiagnosticKind::InvalidRegister { register: 40 }),
+                Diagnostic::new(8, DiagnosticKind::TruncatedInstruction { len: 2 }),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_verify_rejects_bad_jumps() {
+        let program = vec![
+            0, 0, 0, 6, // load $0 #6
+            0, 1, 0, 64, // load $1 #64
+            15, 0, 0, 0, // jeq $0, misaligned
+            16, 1, 0, 0, // jneq $1, past the end
+            0, 2, 0, 4, // load $2 #4
+            18, 2, 0, 0, // inc $2, so $2 is 5
+            8, 2, 0, 0, // jmpb $2, 26 - 5 is misaligned
+        ];
+        assert_eq!(
+            verify(&program, 32),
+            Err(vec![
+                Diagnostic::new(8, DiagnosticKind::MisalignedJump { target: 6 }),
+                Diagnostic::new(12, DiagnosticKind::JumpOutOfRange { target: 64 }),
+                Diagnostic::new(24, DiagnosticKind::MisalignedJump { target: 21 }),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_verify_merges_paths() {
+        // $0 is 2 on one path into the jmp and 24 on the other, so its target
+        // is not known; the jump to the end of the program is allowed either way
+        let program = vec![
+            0, 0, 0, 2, // load $0 #2
+            0, 1, 0, 16, // load $1 #16
+            15, 1, 0, 0, // jeq $1
+            0, 0, 0, 24, // load $0 #24
+            6, 0, 0, 0, // jmp $0
+            5, 0, 0, 0, // hlt
+        ];
+        assert_eq!(verify(&program, 32), Ok(()));
+    }
+}
@@ -1,33 +1,88 @@
-use crate::instruction::Opcode;
+use std::time::Instant;
+
+use crate::config::{ArithmeticMode, VmConfig};
+use crate::debuginfo::{DebugInfo, Location};
+use crate::decoder::{check_registers, decode, DecodedInstruction, DecodedProgram};
+use crate::executable::{Executable, ExecutableSymbol, LoadError, SectionKind};
+use crate::fault::{Fault, FaultKind};
+#[cfg(feature = "jit")]
+use crate::jit::Jit;
+use crate::memory::{
+    Access, MemoryError, MemoryMap, SegmentKind, CODE_BASE, CODE_LIMIT, HEAP_BASE,
+    INSTRUCTION_WIDTH, RELATIVE_JUMP_BASE, RODATA_BASE, RODATA_LIMIT,
+};
+use crate::profiler::Profile;
+use crate::trace::{MemoryWrite, RegisterChange, Snapshot, TraceRecord, Tracer};
+use crate::verifier::{verify, Diagnostic};
 
 pub struct VM {
-    pub registers: [i32; 32],
-    // array of registers so we can have the location of each register at compile time
-    pc: usize,                // program counter
-    pub program: Vec<u8>,     // program stored as byte code in a vector
-    heap: Vec<u8>,            // heap to store data
-    remainder: u32,           // remainder register for division instruction
+    pub registers: Vec<i32>,         // as many registers as the config asks for
+    pc: usize,                       // program counter
+    pub program: Vec<u8>, // program stored as byte code in a vector, mapped as the code segment
+    pub ro_data: Vec<u8>, // read-only data segment
+    heap: Vec<u8>,        // heap to store data
+    stack: Vec<u8>,       // stack segment
+    remainder: u32,       // remainder register for division instruction
     equal_flag: bool, // contains the result of the last comparison operation, usually mips uses another register
     pub parse_hex_flag: bool, // flag to turn on hex parsing
+    fault: Option<Fault>, // set when an instruction faults, the VM will not run until it is cleared
+    decoded: Option<DecodedProgram>, // pre-decoded copy of the program used by run_decoded
+    verified: Option<(Vec<u8>, usize)>, // program and register count that last passed `verify`
+    symbols: Vec<ExecutableSymbol>, // labels from the executable the program was loaded from
+    debug_info: Option<DebugInfo>, // source lines of the program, when it was assembled with them
+    pub superinstruction_flag: bool, // flag to fuse common instruction sequences in run_decoded
+    config: VmConfig, // settings the VM was created with
+    profile: Option<Profile>, // counts collected while profiling is enabled
+    tracer: Option<Tracer>, // where executed instructions are recorded while tracing
+    #[cfg(feature = "jit")]
+    jit: Option<Jit>, // native code for the hot blocks of the program, used by run_jit
+    #[cfg(feature = "jit")]
+    pub jit_threshold: u32, // how often a block has to be reached before it is compiled
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
+        VM::with_config(VmConfig::default())
+    }
+
+    pub fn with_config(config: VmConfig) -> VM {
         VM {
-            registers: [0; 32],
+            registers: vec![0; config.register_count()],
             pc: 0,
             program: vec![],
+            ro_data: vec![],
             heap: vec![],
+            stack: vec![0; config.stack_size()],
             remainder: 0,
             equal_flag: false,
             parse_hex_flag: false,
+            fault: None,
+            decoded: None,
+            verified: None,
+            symbols: vec![],
+            debug_info: None,
+            superinstruction_flag: true,
+            profile: None,
+            tracer: None,
+            #[cfg(feature = "jit")]
+            jit: None,
+            #[cfg(feature = "jit")]
+            jit_threshold: crate::jit::DEFAULT_THRESHOLD,
+            config,
         }
     }
 
     pub fn run(&mut self) {
+        let check = !self.is_verified();
         let mut flag = false;
         while !flag {
-            flag = self.execute_instruction();
+            flag = self.step(check);
         }
     }
 
@@ -35,163 +90,632 @@ impl VM {
         self.execute_instruction();
     }
 
+    // Decodes the cached copy of the program up front, so `run_decoded` does not
+    // have to. Called lazily whenever the cache is missing or stale.
+    pub fn predecode(&mut self) {
+        let stale = match &self.decoded {
+            Some(cache) => !cache.is_current(
+                &self.program,
+                self.superinstruction_flag,
+                self.registers.len(),
+            ),
+            None => true,
+        };
+        if stale {
+            self.decoded = Some(DecodedProgram::new(
+                &self.program,
+                self.superinstruction_flag,
+                self.registers.len(),
+            ));
+        }
+    }
+
+    // Runs the program from the pre-decoded instruction cache rather than
+    // decoding each instruction from the bytecode as it is reached. While
+    // profiling or tracing, superinstructions are skipped so every instruction
+    // is seen on its own.
+    pub fn run_decoded(&mut self) {
+        self.predecode();
+        let cache = self.decoded.take().unwrap();
+        while self.fault.is_none() && self.pc < self.program.len() {
+            let offset = self.pc - CODE_BASE;
+            // jumps always land on a slot, only the host can leave the pc elsewhere
+            let instruction = if offset.is_multiple_of(INSTRUCTION_WIDTH)
+                && (!self.is_observed() || !self.superinstruction_flag)
+            {
+                cache.instructions[offset / INSTRUCTION_WIDTH]
+            } else {
+                check_registers(decode(&self.program, self.pc), self.registers.len())
+            };
+            if self.dispatch(instruction) {
+                break;
+            }
+        }
+        self.decoded = Some(cache);
+    }
+
+    // Runs the program in the interpreter, compiling blocks to native code
+    // once they get hot and running those instead. Native code is not
+    // profiled or traced, so while either is on everything is interpreted.
+    #[cfg(feature = "jit")]
+    pub fn run_jit(&mut self) {
+        let mut jit = match self.jit.take() {
+            Some(jit)
+                if jit.is_current(&self.program, self.jit_threshold, self.registers.len()) =>
+            {
+                jit
+            }
+            _ => Jit::new(&self.program, self.jit_threshold, self.registers.len()),
+        };
+        let check = !self.is_verified();
+        while self.fault.is_none() && self.pc < self.program.len() {
+            let offset = self.pc - CODE_BASE;
+            if offset.is_multiple_of(INSTRUCTION_WIDTH) && !self.is_observed() {
+                let slot = offset / INSTRUCTION_WIDTH;
+                if let Some(block) = jit.block(slot) {
+                    let (pc, interpret) =
+                        unsafe { block.call(self.registers.as_mut_ptr(), &mut self.equal_flag) };
+                    self.pc = pc;
+                    if !interpret {
+                        continue;
+                    }
+                }
+            }
+            if self.step(check) {
+                break;
+            }
+        }
+        self.jit = Some(jit);
+    }
+
+    // Executes the instruction at the pc. Checking its registers costs less
+    // than finding out whether the program is still verified, so a single
+    // step always does.
     pub fn execute_instruction(&mut self) -> bool {
-        if self.pc >= self.program.len() {
+        self.step(true)
+    }
+
+    // Runs the verifier over the current program. Once it passes, `run` skips
+    // the register checks it would otherwise do on every instruction, until
+    // the program or register file changes.
+    pub fn verify(&mut self) -> Result<(), Vec<Diagnostic>> {
+        verify(&self.program, self.registers.len())?;
+        self.verified = Some((self.program.clone(), self.registers.len()));
+        Ok(())
+    }
+
+    // Compares the whole program, so runs ask once rather than every step
+    pub fn is_verified(&self) -> bool {
+        match &self.verified {
+            Some((program, register_count)) => {
+                *register_count == self.registers.len() && *program == self.program
+            }
+            None => false,
+        }
+    }
+
+    // Decodes and executes the instruction at the pc, checking its register
+    // operands first unless the program has been verified
+    fn step(&mut self, check: bool) -> bool {
+        if self.fault.is_some() || self.pc >= self.program.len() {
             return true;
         }
+        let instruction = decode(&self.program, self.pc);
+        if check {
+            self.dispatch(check_registers(instruction, self.registers.len()))
+        } else {
+            self.dispatch(instruction)
+        }
+    }
 
-        match self.decode_opcode() {
-            Opcode::HLT => {
-                println!("HLT encountered");
-                return true;
-            }
-            Opcode::LOAD => {
-                let register = self.next_8_bits() as usize;
-                let number = self.next_16_bits() as u16;
-                self.registers[register] = number as i32;
-            }
-            Opcode::ADD => {
-                let r1 = self.registers[self.next_8_bits() as usize];
-                let r2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = r1 + r2;
-            }
-            Opcode::SUB => {
-                let r1 = self.registers[self.next_8_bits() as usize];
-                let r2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = r1 - r2;
-            }
-            Opcode::MUL => {
-                let r1 = self.registers[self.next_8_bits() as usize];
-                let r2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = r1 * r2;
-            }
-            Opcode::DIV => {
-                let r1 = self.registers[self.next_8_bits() as usize];
-                let r2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = r1 / r2;
-                self.remainder = (r1 % r2) as u32;
-            }
-            Opcode::JMP => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc = target as usize;
-            }
-            Opcode::JMPF => {
-                let val = self.registers[self.next_8_bits() as usize];
-                self.pc += val as usize;
-            }
-            Opcode::JMPB => {
-                let val = self.registers[self.next_8_bits() as usize];
-                self.pc -= val as usize;
-            }
-            Opcode::EQ => {
-                let r1 = self.registers[self.next_8_bits() as usize];
-                let r2 = self.registers[self.next_8_bits() as usize];
-                if r1 == r2 {
-                    self.equal_flag = true;
-                } else {
-                    self.equal_flag = false;
+    // Executes an instruction, recording it in the profile and the trace when
+    // they are enabled
+    fn dispatch(&mut self, instruction: DecodedInstruction) -> bool {
+        if !self.is_observed() {
+            return self.execute(instruction);
+        }
+        let pc = self.pc;
+        let opcode = self.program[pc];
+        let before = match &self.tracer {
+            Some(tracer) if tracer.filter().matches(pc, opcode) => Some(self.snapshot()),
+            _ => None,
+        };
+        let started = Instant::now();
+        let stop = self.execute(instruction);
+        let elapsed = started.elapsed();
+        if let Some(profile) = &mut self.profile {
+            profile.record(pc, opcode, elapsed);
+        }
+        if let Some(before) = before {
+            let record = self.trace_record(&before);
+            let location = Location::new(self.debug_info.as_ref(), &self.symbols, pc);
+            let known = location.source.is_some() || location.symbol.is_some();
+            let tracer = self.tracer.as_mut().unwrap();
+            tracer.write(&record, Some(&location).filter(|_| known));
+        }
+        stop
+    }
+
+    fn is_observed(&self) -> bool {
+        self.profile.is_some() || self.tracer.is_some()
+    }
+
+    // Captures the state the instruction at the pc may change, before it is
+    // executed, so it can be traced or undone with `restore`
+    pub fn snapshot(&self) -> Snapshot {
+        let register = |reg: u8| self.registers.get(reg as usize).copied();
+        let memory = match decode(&self.program, self.pc) {
+            DecodedInstruction::Setm { addr, .. } => register(addr).and_then(|address| {
+                let address = address as u32 as usize;
+                self.read_memory(address, 4)
+                    .ok()
+                    .map(|bytes| (address, bytes.to_vec()))
+            }),
+            // shrinking the heap discards the bytes past its new end
+            DecodedInstruction::Aloc { reg } => match register(reg) {
+                Some(bytes) if bytes < 0 && bytes.unsigned_abs() as usize <= self.heap.len() => {
+                    let end = self.heap.len() - bytes.unsigned_abs() as usize;
+                    Some((HEAP_BASE + end, self.heap[end..].to_vec()))
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        Snapshot {
+            pc: self.pc,
+            registers: self.registers.clone(),
+            equal_flag: self.equal_flag,
+            remainder: self.remainder,
+            heap_len: self.heap.len(),
+            memory,
+        }
+    }
+
+    // Puts the VM back in the state `snapshot` was taken in, undoing the
+    // instruction executed since then. Any fault it raised is cleared.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.pc = snapshot.pc;
+        self.registers.clone_from(&snapshot.registers);
+        self.equal_flag = snapshot.equal_flag;
+        self.remainder = snapshot.remainder;
+        self.heap.resize(snapshot.heap_len, 0);
+        if let Some((address, bytes)) = &snapshot.memory {
+            // the bytes came from writable memory that is mapped again now
+            self.write_memory(*address, bytes).unwrap();
+        }
+        self.fault = None;
+    }
+
+    // Describes what the instruction executed since `before` was taken changed
+    fn trace_record(&self, before: &Snapshot) -> TraceRecord {
+        let end = (before.pc + INSTRUCTION_WIDTH).min(self.program.len());
+        let registers = before
+            .registers
+            .iter()
+            .zip(&self.registers)
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(register, (old, new))| RegisterChange {
+                register: register as u8,
+                old: *old,
+                new: *new,
+            })
+            .collect();
+        // a SETM that did not fault wrote its bytes, even if they did not change
+        let memory = match &before.memory {
+            Some((address, old)) if self.fault.is_none() => {
+                match self.read_memory(*address, old.len()) {
+                    Ok(new) => vec![MemoryWrite {
+                        address: *address as u32,
+                        old: old.clone(),
+                        new: new.to_vec(),
+                    }],
+                    _ => vec![],
                 }
-                self.next_8_bits();
             }
-            Opcode::NEQ => {
-                let r1 = self.registers[self.next_8_bits() as usize];
-                let r2 = self.registers[self.next_8_bits() as usize];
-                if r1 != r2 {
-                    self.equal_flag = true;
-                } else {
-                    self.equal_flag = false;
+            _ => vec![],
+        };
+        TraceRecord {
+            pc: before.pc as u32,
+            bytes: self.program[before.pc..end].to_vec(),
+            registers,
+            equal_flag: self.equal_flag,
+            remainder: self.remainder,
+            heap_len: self.heap.len() as u32,
+            memory,
+        }
+    }
+
+    // Executes one instruction located at the current pc. Returns true when
+    // the VM should stop, either because it halted or because it faulted.
+    fn execute(&mut self, instruction: DecodedInstruction) -> bool {
+        let start = self.pc;
+        self.pc = start + INSTRUCTION_WIDTH;
+        match instruction {
+            DecodedInstruction::NotExecutable => {
+                // the whole instruction has to be fetched from executable memory
+                self.pc = start;
+                let map = self.memory_map();
+                if let Err(e) = map.check(start, INSTRUCTION_WIDTH, Access::Execute) {
+                    return self.raise(start, FaultKind::Memory(e));
                 }
-                self.next_8_bits();
             }
-            Opcode::GT => {
-                let r1 = self.registers[self.next_8_bits() as usize];
-                let r2 = self.registers[self.next_8_bits() as usize];
-                if r1 > r2 {
-                    self.equal_flag = true;
-                } else {
-                    self.equal_flag = false;
+            DecodedInstruction::Hlt => {
+                self.pc = start + 1;
+                self.config.output().write_line("HLT encountered");
+                return true;
+            }
+            DecodedInstruction::Load { reg, value } => {
+                self.registers[reg as usize] = value as i32;
+            }
+            DecodedInstruction::Add { r1, r2, dst } => {
+                let result =
+                    self.registers[r1 as usize].overflowing_add(self.registers[r2 as usize]);
+                return self.set_arithmetic(start, dst, result);
+            }
+            DecodedInstruction::Sub { r1, r2, dst } => {
+                let result =
+                    self.registers[r1 as usize].overflowing_sub(self.registers[r2 as usize]);
+                return self.set_arithmetic(start, dst, result);
+            }
+            DecodedInstruction::Mul { r1, r2, dst } => {
+                let result =
+                    self.registers[r1 as usize].overflowing_mul(self.registers[r2 as usize]);
+                return self.set_arithmetic(start, dst, result);
+            }
+            DecodedInstruction::Div { r1, r2, dst } => {
+                let r1 = self.registers[r1 as usize];
+                let r2 = self.registers[r2 as usize];
+                if r2 == 0 {
+                    return self.raise(start, FaultKind::DivisionByZero);
                 }
-                self.next_8_bits();
+                // only i32::MIN / -1 overflows, and its remainder is always 0
+                if self.set_arithmetic(start, dst, r1.overflowing_div(r2)) {
+                    return true;
+                }
+                self.remainder = r1.wrapping_rem(r2) as u32;
             }
-            Opcode::LT => {
-                let r1 = self.registers[self.next_8_bits() as usize];
-                let r2 = self.registers[self.next_8_bits() as usize];
-                if r1 < r2 {
-                    self.equal_flag = true;
-                } else {
-                    self.equal_flag = false;
+            DecodedInstruction::Jmp { reg } => {
+                let target = self.registers[reg as usize];
+                return self.jump(start, target as u32 as usize);
+            }
+            DecodedInstruction::Jmpf { reg } => {
+                let base = (start + RELATIVE_JUMP_BASE) as i64;
+                let target = base + self.registers[reg as usize] as i64;
+                return self.jump(start, target as usize);
+            }
+            DecodedInstruction::Jmpb { reg } => {
+                let base = (start + RELATIVE_JUMP_BASE) as i64;
+                let target = base - self.registers[reg as usize] as i64;
+                return self.jump(start, target as usize);
+            }
+            DecodedInstruction::Eq { r1, r2 } => {
+                self.equal_flag = self.registers[r1 as usize] == self.registers[r2 as usize];
+            }
+            DecodedInstruction::Neq { r1, r2 } => {
+                self.equal_flag = self.registers[r1 as usize] != self.registers[r2 as usize];
+            }
+            DecodedInstruction::Gt { r1, r2 } => {
+                self.equal_flag = self.registers[r1 as usize] > self.registers[r2 as usize];
+            }
+            DecodedInstruction::Lt { r1, r2 } => {
+                self.equal_flag = self.registers[r1 as usize] < self.registers[r2 as usize];
+            }
+            DecodedInstruction::Gtq { r1, r2 } => {
+                self.equal_flag = self.registers[r1 as usize] >= self.registers[r2 as usize];
+            }
+            DecodedInstruction::Ltq { r1, r2 } => {
+                self.equal_flag = self.registers[r1 as usize] <= self.registers[r2 as usize];
+            }
+            DecodedInstruction::Jeq { reg } => {
+                if self.equal_flag {
+                    let target = self.registers[reg as usize];
+                    return self.jump(start, target as u32 as usize);
                 }
-                self.next_8_bits();
             }
-            Opcode::GTQ => {
-                let r1 = self.registers[self.next_8_bits() as usize];
-                let r2 = self.registers[self.next_8_bits() as usize];
-                if r1 >= r2 {
-                    self.equal_flag = true;
-                } else {
-                    self.equal_flag = false;
+            DecodedInstruction::Jneq { reg } => {
+                if !self.equal_flag {
+                    let target = self.registers[reg as usize];
+                    return self.jump(start, target as u32 as usize);
                 }
-                self.next_8_bits();
             }
-            Opcode::LTQ => {
-                let r1 = self.registers[self.next_8_bits() as usize];
-                let r2 = self.registers[self.next_8_bits() as usize];
-                if r1 <= r2 {
-                    self.equal_flag = true;
-                } else {
-                    self.equal_flag = false;
+            DecodedInstruction::Aloc { reg } => {
+                let requested = self.heap.len() as i64 + self.registers[reg as usize] as i64;
+                let limit = self.config.max_heap_bytes();
+                if requested < 0 || requested as usize > limit {
+                    return self.raise(start, FaultKind::OutOfMemory { requested, limit });
                 }
-                self.next_8_bits();
+                self.heap.resize(requested as usize, 0);
             }
-            Opcode::JEQ => {
-                let target = self.registers[self.next_8_bits() as usize];
-                if self.equal_flag {
-                    self.pc = target as usize;
+            DecodedInstruction::Inc { reg } => {
+                let result = self.registers[reg as usize].overflowing_add(1);
+                return self.set_arithmetic(start, reg, result);
+            }
+            DecodedInstruction::Dec { reg } => {
+                let result = self.registers[reg as usize].overflowing_sub(1);
+                return self.set_arithmetic(start, reg, result);
+            }
+            DecodedInstruction::Loadm { addr, dst } => {
+                let address = self.registers[addr as usize] as u32 as usize;
+                match self.read_memory(address, 4) {
+                    Ok(bytes) => {
+                        self.registers[dst as usize] =
+                            i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                    }
+                    Err(e) => return self.raise(start, FaultKind::Memory(e)),
                 }
             }
-            Opcode::JNEQ => {
-                let target = self.registers[self.next_8_bits() as usize];
-                if !self.equal_flag {
-                    self.pc = target as usize;
+            DecodedInstruction::Setm { addr, src } => {
+                let address = self.registers[addr as usize] as u32 as usize;
+                let value = self.registers[src as usize];
+                if let Err(e) = self.write_memory(address, &value.to_be_bytes()) {
+                    return self.raise(start, FaultKind::Memory(e));
                 }
             }
-            Opcode::ALOC => {
-                let bytes = self.registers[self.next_8_bits() as usize];
-                let new_end = self.heap.len() as i32 + bytes;
-                self.heap.resize(new_end as usize, 0);
+            DecodedInstruction::Illegal { opcode } => {
+                self.pc = start + 1;
+                return self.raise(start, FaultKind::IllegalOpcode { opcode });
             }
-            Opcode::INC => {
-                let register = self.next_8_bits() as usize;
-                self.registers[register] += 1;
+            DecodedInstruction::InvalidRegister { register } => {
+                return self.raise(start, FaultKind::InvalidRegister { register });
             }
-            Opcode::DEC => {
-                let register = self.next_8_bits() as usize;
-                self.registers[register] -= 1;
+            DecodedInstruction::CompareBranch {
+                cmp,
+                r1,
+                r2,
+                jump_if,
+                target,
+            } => {
+                self.equal_flag =
+                    cmp.evaluate(self.registers[r1 as usize], self.registers[r2 as usize]);
+                return self.fused_branch(start + INSTRUCTION_WIDTH, jump_if, target);
             }
-            _ => {
-                println!("Unrecognized opcode found! Terminating!");
-                return true;
+            DecodedInstruction::StepCompareBranch {
+                reg,
+                delta,
+                cmp,
+                r1,
+                r2,
+                jump_if,
+                target,
+            } => {
+                let result = self.registers[reg as usize].overflowing_add(delta as i32);
+                if self.set_arithmetic(start, reg, result) {
+                    return true;
+                }
+                self.equal_flag =
+                    cmp.evaluate(self.registers[r1 as usize], self.registers[r2 as usize]);
+                return self.fused_branch(start + 2 * INSTRUCTION_WIDTH, jump_if, target);
+            }
+            DecodedInstruction::LoadAdd {
+                reg,
+                value,
+                r1,
+                r2,
+                dst,
+            } => {
+                self.registers[reg as usize] = value as i32;
+                self.pc = start + 2 * INSTRUCTION_WIDTH;
+                let result =
+                    self.registers[r1 as usize].overflowing_add(self.registers[r2 as usize]);
+                return self.set_arithmetic(start + INSTRUCTION_WIDTH, dst, result);
             }
         }
-        return false;
+        false
     }
 
-    fn decode_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.pc]);
-        self.pc += 1;
-        return opcode;
+    // Finishes a superinstruction with the JEQ/JNEQ found at `branch`, leaving the pc
+    // and any fault exactly where executing that branch on its own would
+    fn fused_branch(&mut self, branch: usize, jump_if: bool, target: u8) -> bool {
+        self.pc = branch + INSTRUCTION_WIDTH;
+        if self.equal_flag == jump_if {
+            let target = self.registers[target as usize];
+            return self.jump(branch, target as u32 as usize);
+        }
+        false
     }
 
-    fn next_8_bits(&mut self) -> u8 {
-        let res = self.program[self.pc];
-        self.pc += 1;
-        return res;
+    // Moves the pc to `target`, faulting if it is not the start of an instruction in the
+    // code segment. Jumping to the very end of the program halts it, as falling off the
+    // end would.
+    fn jump(&mut self, start: usize, target: usize) -> bool {
+        let map = self.memory_map();
+        let code = map.segment(SegmentKind::Code);
+        if target != code.end() {
+            if code.contains(target, 1) && !(target - CODE_BASE).is_multiple_of(INSTRUCTION_WIDTH) {
+                return self.raise(start, FaultKind::MisalignedJump { target });
+            }
+            if let Err(e) = map.check(target, INSTRUCTION_WIDTH, Access::Execute) {
+                return self.raise(start, FaultKind::Memory(e));
+            }
+        }
+        self.pc = target;
+        false
+    }
+
+    // Stores the result of an arithmetic instruction at `pc` in `dst`. An
+    // overflowed result is kept in wrapping mode and faults in checked mode.
+    fn set_arithmetic(&mut self, pc: usize, dst: u8, (value, overflowed): (i32, bool)) -> bool {
+        if overflowed && self.config.arithmetic() == ArithmeticMode::Checked {
+            return self.raise(pc, FaultKind::ArithmeticOverflow);
+        }
+        self.registers[dst as usize] = value;
+        false
+    }
+
+    fn raise(&mut self, pc: usize, kind: FaultKind) -> bool {
+        self.fault = Some(Fault::new(pc, kind));
+        true
+    }
+
+    pub fn fault(&self) -> Option<Fault> {
+        self.fault
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn equal_flag(&self) -> bool {
+        self.equal_flag
+    }
+
+    pub fn remainder(&self) -> u32 {
+        self.remainder
+    }
+
+    // Setters for debuggers that edit the machine state
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    pub fn set_equal_flag(&mut self, flag: bool) {
+        self.equal_flag = flag;
+    }
+
+    pub fn set_remainder(&mut self, remainder: u32) {
+        self.remainder = remainder;
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    // Loads an executable file, replacing the program and read-only data and
+    // moving the pc to its entry point
+    pub fn load_executable(&mut self, bytes: &[u8]) -> Result<(), LoadError> {
+        let executable = Executable::from_bytes(bytes)?;
+        self.load_program(executable.code, executable.ro_data)?;
+        self.symbols = executable.symbols;
+        self.debug_info = executable.debug_info;
+        self.pc = executable.entry as usize;
+        Ok(())
+    }
+
+    // Replaces the program and read-only data, moving the pc to the start of
+    // the code. Labels are assembled from CODE_BASE and RODATA_BASE, so a
+    // program cannot be added to one already loaded. Like an executable's
+    // sections, each must fit in its segment.
+    pub fn load_program(&mut self, program: Vec<u8>, ro_data: Vec<u8>) -> Result<(), LoadError> {
+        if program.len() > CODE_LIMIT - CODE_BASE {
+            return Err(LoadError::SectionTooLarge(SectionKind::Code));
+        }
+        if ro_data.len() > RODATA_LIMIT - RODATA_BASE {
+            return Err(LoadError::SectionTooLarge(SectionKind::ReadOnlyData));
+        }
+        self.program = program;
+        self.ro_data = ro_data;
+        self.symbols = vec![];
+        self.debug_info = None;
+        self.pc = CODE_BASE;
+        self.fault = None;
+        Ok(())
+    }
+
+    pub fn symbols(&self) -> &[ExecutableSymbol] {
+        &self.symbols
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    // For programs put in place without an executable, e.g. by assembling
+    // source directly into `program`
+    pub fn set_debug_info(&mut self, debug_info: Option<DebugInfo>) {
+        self.debug_info = debug_info;
+    }
+
+    // The labels of such a program, for locations and label breakpoints
+    pub fn set_symbols(&mut self, symbols: Vec<ExecutableSymbol>) {
+        self.symbols = symbols;
+    }
+
+    // Where `address` is in the source, as far as the debug information and
+    // labels tell
+    pub fn location(&self, address: usize) -> Location<'_> {
+        Location::new(self.debug_info.as_ref(), &self.symbols, address)
+    }
+
+    // A fault with its source location, e.g.
+    // `fault at count.iasm:3:1 (loop+4): division by zero`
+    pub fn describe_fault(&self, fault: &Fault) -> String {
+        format!("fault at {}: {}", self.location(fault.pc), fault.kind)
+    }
+
+    // Starts counting executed instructions, see `profiler::Profile`. Any
+    // profile collected so far is discarded.
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Profile::new());
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    // Stops profiling and hands back what was collected
+    pub fn take_profile(&mut self) -> Option<Profile> {
+        self.profile.take()
+    }
+
+    // Starts writing a record for every executed instruction the tracer's
+    // filter lets through, replacing any tracer already running
+    pub fn start_trace(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    // Stops tracing, handing the tracer back so it can be finished
+    pub fn stop_trace(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    pub fn config(&self) -> &VmConfig {
+        &self.config
     }
 
-    fn next_16_bits(&mut self) -> u16 {
-        let res = ((self.program[self.pc] as u16) << 8) | self.program[self.pc + 1] as u16;
-        self.pc += 2;
-        return res;
+    pub fn clear_fault(&mut self) {
+        self.fault = None;
+    }
+
+    pub fn memory_map(&self) -> MemoryMap {
+        MemoryMap::new(
+            self.program.len(),
+            self.ro_data.len(),
+            self.stack.len(),
+            self.heap.len(),
+        )
+    }
+
+    // Reads guest memory, subject to the same permission checks as LOADM
+    pub fn read_memory(&self, address: usize, len: usize) -> Result<&[u8], MemoryError> {
+        let segment = *self.memory_map().check(address, len, Access::Read)?;
+        let offset = address - segment.base;
+        Ok(&self.segment_bytes(segment.kind)[offset..offset + len])
+    }
+
+    // Writes guest memory, subject to the same permission checks as SETM
+    pub fn write_memory(&mut self, address: usize, bytes: &[u8]) -> Result<(), MemoryError> {
+        let segment = *self
+            .memory_map()
+            .check(address, bytes.len(), Access::Write)?;
+        let offset = address - segment.base;
+        let target = match segment.kind {
+            SegmentKind::Heap => &mut self.heap,
+            SegmentKind::Stack => &mut self.stack,
+            // no other segment is writable
+            SegmentKind::Code | SegmentKind::ReadOnlyData => unreachable!(),
+        };
+        target[offset..offset + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn segment_bytes(&self, kind: SegmentKind) -> &[u8] {
+        match kind {
+            SegmentKind::Code => &self.program,
+            SegmentKind::ReadOnlyData => &self.ro_data,
+            SegmentKind::Stack => &self.stack,
+            SegmentKind::Heap => &self.heap,
+        }
     }
 
     pub fn add_byte(&mut self, byte: u8) {
@@ -208,6 +732,10 @@ mod tests {
     use std::vec;
 
     use super::*;
+    use crate::config::OutputSink;
+    use crate::instruction::Opcode;
+    use crate::memory::{DEFAULT_STACK_SIZE, HEAP_BASE, RODATA_BASE, STACK_BASE};
+    use crate::trace::{first_divergence, read_trace, TraceFilter, TraceFormat};
 
     #[test]
     fn test_create_vm() {
@@ -285,10 +813,10 @@ mod tests {
     #[test]
     fn test_jmp_opcode() {
         let mut test_vm = VM::new();
-        test_vm.registers[0] = 5;
-        test_vm.program = vec![6, 0, 0, 0];
+        test_vm.registers[0] = 8;
+        test_vm.program = vec![6, 0, 0, 0, 5, 0, 0, 0, 5, 0, 0, 0];
         test_vm.run_once();
-        assert_eq!(test_vm.pc, 5);
+        assert_eq!(test_vm.pc, 8);
     }
 
     #[test]
@@ -300,6 +828,16 @@ mod tests {
         assert_eq!(test_vm.pc, 4);
     }
 
+    #[test]
+    fn test_jmpb_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 6;
+        test_vm.program = vec![5, 0, 0, 0, 8, 0, 0, 0];
+        test_vm.pc = 4;
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 0);
+    }
+
     #[test]
     fn test_eq_opcode() {
         let mut test_vm = VM::new();
@@ -307,10 +845,10 @@ mod tests {
         test_vm.registers[1] = 10;
         test_vm.program = vec![9, 0, 1, 0, 9, 0, 1, 0];
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert!(test_vm.equal_flag);
         test_vm.registers[1] = 20;
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        assert!(!test_vm.equal_flag);
     }
 
     #[test]
@@ -318,12 +856,22 @@ mod tests {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 10;
-        test_vm.registers[2] = 20;
-        test_vm.program = vec![9, 0, 1, 0, 15, 2, 0, 0];
+        test_vm.registers[2] = 12;
+        test_vm.program = vec![9, 0, 1, 0, 15, 2, 0, 0, 5, 0, 0, 0, 5, 0, 0, 0];
+        test_vm.run_once();
+        assert!(test_vm.equal_flag);
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert_eq!(test_vm.pc, 12);
+    }
+
+    #[test]
+    fn test_jeq_not_taken() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 12;
+        test_vm.program = vec![15, 0, 0, 0, 5, 0, 0, 0];
         test_vm.run_once();
-        assert_eq!(test_vm.pc, 20);
+        assert_eq!(test_vm.pc, 4);
+        assert_eq!(test_vm.fault(), None);
     }
 
     #[test]
@@ -352,4 +900,773 @@ mod tests {
         test_vm.run_once();
         assert_eq!(test_vm.registers[0], 9);
     }
+
+    #[test]
+    fn test_illegal_opcode_faults() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![200, 0, 0, 0];
+        test_vm.run();
+        assert_eq!(
+            test_vm.fault(),
+            Some(Fault::new(0, FaultKind::IllegalOpcode { opcode: 200 }))
+        );
+    }
+
+    #[test]
+    fn test_misaligned_jump_faults() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 5;
+        test_vm.program = vec![5, 0, 0, 0, 6, 0, 0, 0, 5, 0, 0, 0];
+        test_vm.pc = 4;
+        test_vm.run();
+        assert_eq!(
+            test_vm.fault(),
+            Some(Fault::new(4, FaultKind::MisalignedJump { target: 5 }))
+        );
+        // the VM refuses to continue until the fault is cleared
+        assert!(test_vm.execute_instruction());
+        test_vm.clear_fault();
+        assert_eq!(test_vm.fault(), None);
+    }
+
+    #[test]
+    fn test_jump_into_data_faults() {
+        let mut test_vm = VM::new();
+        test_vm.ro_data = vec![5, 0, 0, 0];
+        test_vm.registers[0] = RODATA_BASE as i32;
+        test_vm.program = vec![6, 0, 0, 0];
+        test_vm.run();
+        assert_eq!(
+            test_vm.fault(),
+            Some(Fault::new(
+                0,
+                FaultKind::Memory(MemoryError::Protection {
+                    address: RODATA_BASE,
+                    access: Access::Execute,
+                    segment: SegmentKind::ReadOnlyData,
+                })
+            ))
+        );
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_jump_outside_code_faults() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 20;
+        test_vm.program = vec![6, 0, 0, 0];
+        test_vm.run();
+        assert_eq!(
+            test_vm.fault(),
+            Some(Fault::new(
+                0,
+                FaultKind::Memory(MemoryError::Unmapped {
+                    address: 20,
+                    access: Access::Execute,
+                })
+            ))
+        );
+    }
+
+    #[test]
+    fn test_truncated_instruction_faults() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![18, 0];
+        test_vm.run();
+        assert_eq!(
+            test_vm.fault(),
+            Some(Fault::new(
+                0,
+                FaultKind::Memory(MemoryError::Unmapped {
+                    address: 0,
+                    access: Access::Execute,
+                })
+            ))
+        );
+    }
+
+    #[test]
+    fn test_setm_loadm_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 8;
+        test_vm.registers[1] = HEAP_BASE as i32 + 4;
+        test_vm.registers[2] = -559038737;
+        test_vm.program = vec![17, 0, 0, 0, 21, 1, 2, 0, 20, 1, 3, 0];
+        test_vm.run();
+        assert_eq!(test_vm.fault(), None);
+        assert_eq!(test_vm.heap, vec![0, 0, 0, 0, 0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(test_vm.registers[3], -559038737);
+    }
+
+    #[test]
+    fn test_write_to_code_faults() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 0;
+        test_vm.registers[1] = 42;
+        test_vm.program = vec![21, 0, 1, 0];
+        test_vm.run();
+        assert_eq!(
+            test_vm.fault(),
+            Some(Fault::new(
+                0,
+                FaultKind::Memory(MemoryError::Protection {
+                    address: 0,
+                    access: Access::Write,
+                    segment: SegmentKind::Code,
+                })
+            ))
+        );
+        assert_eq!(test_vm.program, vec![21, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_rodata_is_read_only() {
+        let mut test_vm = VM::new();
+        test_vm.ro_data = vec![0, 0, 1, 0];
+        assert_eq!(test_vm.read_memory(RODATA_BASE, 4), Ok(&[0, 0, 1, 0][..]));
+        assert!(test_vm.write_memory(RODATA_BASE, &[1]).is_err());
+        test_vm.registers[0] = RODATA_BASE as i32;
+        test_vm.program = vec![20, 0, 1, 0];
+        test_vm.run();
+        assert_eq!(test_vm.fault(), None);
+        assert_eq!(test_vm.registers[1], 256);
+    }
+
+    #[test]
+    fn test_stack_is_writable() {
+        let mut test_vm = VM::new();
+        assert!(test_vm.write_memory(STACK_BASE, &[1, 2, 3, 4]).is_ok());
+        assert_eq!(test_vm.read_memory(STACK_BASE, 4), Ok(&[1, 2, 3, 4][..]));
+        assert!(test_vm
+            .read_memory(STACK_BASE + DEFAULT_STACK_SIZE - 2, 4)
+            .is_err());
+    }
+
+    // counts $0 up to 1000 then halts
+    fn counting_loop() -> Vec<u8> {
+        vec![
+            0, 0, 0, 0, // load $0 #0
+            0, 1, 3, 232, // load $1 #1000
+            0, 2, 0, 12, // load $2 #12
+            18, 0, 0, 0, // inc $0
+            10, 0, 1, 0, // neq $0 $1
+            15, 2, 0, 0, // jeq $2
+            5, 0, 0, 0, // hlt
+        ]
+    }
+
+    #[test]
+    fn test_run_decoded_matches_run() {
+        let mut byte_vm = VM::new();
+        byte_vm.program = counting_loop();
+        byte_vm.run();
+        let mut decoded_vm = VM::new();
+        decoded_vm.program = counting_loop();
+        decoded_vm.run_decoded();
+        assert_eq!(decoded_vm.registers, byte_vm.registers);
+        assert_eq!(decoded_vm.registers[0], 1000);
+        assert_eq!(decoded_vm.pc, byte_vm.pc);
+        assert_eq!(decoded_vm.equal_flag, byte_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_run_decoded_faults() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 6;
+        test_vm.program = vec![5, 0, 0, 0, 6, 0, 0, 0];
+        test_vm.pc = 4;
+        test_vm.run_decoded();
+        assert_eq!(
+            test_vm.fault(),
+            Some(Fault::new(4, FaultKind::MisalignedJump { target: 6 }))
+        );
+        let mut test_vm = VM::new();
+        test_vm.program = vec![18, 0, 0, 0, 200, 0];
+        test_vm.run_decoded();
+        assert_eq!(test_vm.registers[0], 1);
+        assert_eq!(
+            test_vm.fault(),
+            Some(Fault::new(
+                4,
+                FaultKind::Memory(MemoryError::Unmapped {
+                    address: 4,
+                    access: Access::Execute,
+                })
+            ))
+        );
+    }
+
+    #[test]
+    fn test_decoded_cache_invalidated_on_change() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![18, 0, 0, 0];
+        test_vm.run_decoded();
+        assert_eq!(test_vm.registers[0], 1);
+        // rewrite the instruction in place, the cache must not run the old one
+        test_vm.program[0] = 19;
+        test_vm.pc = 0;
+        test_vm.run_decoded();
+        assert_eq!(test_vm.registers[0], 0);
+        test_vm.add_bytes(vec![18, 1, 0, 0]);
+        test_vm.run_decoded();
+        assert_eq!(test_vm.registers[1], 1);
+    }
+
+    // Tiny deterministic generator so the differential tests are reproducible
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn below(&mut self, bound: u32) -> u32 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((self.0 >> 33) % bound as u64) as u32
+        }
+    }
+
+    // Builds a random program that only ever branches forwards, so it always
+    // terminates. Arithmetic stays on $0-$3 with small values so it cannot
+    // overflow. Each branch jumps through its own register, loaded by a
+    // prologue, so a jump can land anywhere, including inside a fusable
+    // sequence. Some jump targets are deliberately bad.
+    fn random_program(rng: &mut Lcg) -> Vec<u8> {
+        const BODY_SLOTS: usize = 20;
+        let reg = |rng: &mut Lcg| rng.below(4) as u8;
+        let mut body: Vec<[u8; 4]> = vec![];
+        // (slot of the jump within the body, register holding its target)
+        let mut jumps: Vec<(usize, u8)> = vec![];
+        while body.len() < BODY_SLOTS {
+            match rng.below(7) {
+                0 => body.push([0, reg(rng), 0, rng.below(64) as u8]),
+                1 => body.push([1 + rng.below(2) as u8, reg(rng), reg(rng), reg(rng)]),
+                2 => body.push([18 + rng.below(2) as u8, reg(rng), 0, 0]),
+                3 => {
+                    // load-then-add, fusable when the loaded register is an operand
+                    let r = reg(rng);
+                    body.push([0, r, 0, rng.below(64) as u8]);
+                    body.push([1, r, reg(rng), reg(rng)]);
+                }
+                4 => body.push([20 + rng.below(2) as u8, reg(rng), reg(rng), 0]),
+                _ => {
+                    let target = 4 + jumps.len() as u8;
+                    match rng.below(3) {
+                        0 => body.push([6, target, 0, 0]),
+                        group => {
+                            if group == 2 {
+                                body.push([18 + rng.below(2) as u8, reg(rng), 0, 0]);
+                            }
+                            body.push([9 + rng.below(6) as u8, reg(rng), reg(rng), 0]);
+                            body.push([15 + rng.below(2) as u8, target, 0, 0]);
+                        }
+                    }
+                    jumps.push((body.len() - 1, target));
+                }
+            }
+        }
+        body.push([5, 0, 0, 0]);
+
+        let prologue = jumps.len();
+        let last_slot = (prologue + body.len() - 1) as u32;
+        let mut program: Vec<u8> = vec![];
+        for (slot, target_reg) in &jumps {
+            let from = (prologue + slot) as u32 + 1;
+            let mut target = (from + rng.below(last_slot + 1 - from)) * 4;
+            match rng.below(10) {
+                0 => target += 2,
+                1 => target = (last_slot + 5) * 4,
+                _ => {}
+            }
+            program.extend([0, *target_reg, (target >> 8) as u8, target as u8]);
+        }
+        for instruction in body {
+            program.extend(instruction);
+        }
+        program
+    }
+
+    fn assert_same_state(expected: &VM, actual: &VM, program: &[u8]) {
+        assert_eq!(actual.registers, expected.registers, "{:?}", program);
+        assert_eq!(actual.pc, expected.pc, "{:?}", program);
+        assert_eq!(actual.equal_flag, expected.equal_flag, "{:?}", program);
+        assert_eq!(actual.remainder, expected.remainder, "{:?}", program);
+        assert_eq!(actual.heap, expected.heap, "{:?}", program);
+        assert_eq!(actual.fault(), expected.fault(), "{:?}", program);
+    }
+
+    #[test]
+    fn test_superinstructions_match_byte_interpreter() {
+        let mut rng = Lcg(0x1d);
+        let mut fused_slots = 0;
+        for _ in 0..500 {
+            let program = random_program(&mut rng);
+            let mut byte_vm = VM::new();
+            byte_vm.program = program.clone();
+            byte_vm.registers[1] = 3;
+            byte_vm.run();
+            for flag in [true, false] {
+                let mut decoded_vm = VM::new();
+                decoded_vm.program = program.clone();
+                decoded_vm.registers[1] = 3;
+                decoded_vm.superinstruction_flag = flag;
+                decoded_vm.run_decoded();
+                assert_same_state(&byte_vm, &decoded_vm, &program);
+            }
+            fused_slots += crate::fusion::fuse(&crate::decoder::decode_program(&program, 32))
+                .iter()
+                .zip(crate::decoder::decode_program(&program, 32))
+                .filter(|(fused, plain)| **fused != *plain)
+                .count();
+        }
+        // make sure the generator really exercises the superinstructions
+        assert!(fused_slots > 500);
+    }
+
+    #[test]
+    fn test_superinstruction_fault_pc() {
+        // the jeq of an inc/neq/jeq sequence jumps to a misaligned target
+        let program = vec![
+            0, 7, 0, 10, // load $7 #10
+            18, 0, 0, 0, // inc $0
+            10, 0, 1, 0, // neq $0 $1
+            15, 7, 0, 0, // jeq $7
+            5, 0, 0, 0, // hlt
+        ];
+        let mut byte_vm = VM::new();
+        byte_vm.program = program.clone();
+        byte_vm.run();
+        let mut decoded_vm = VM::new();
+        decoded_vm.program = program.clone();
+        decoded_vm.run_decoded();
+        assert_same_state(&byte_vm, &decoded_vm, &program);
+        assert_eq!(
+            decoded_vm.fault(),
+            Some(Fault::new(12, FaultKind::MisalignedJump { target: 10 }))
+        );
+        assert_eq!(decoded_vm.pc, 16);
+    }
+
+    #[test]
+    fn test_countdown_loop_with_superinstructions() {
+        // decrement $0 from 500 until it reaches $1 (zero)
+        let program = vec![
+            0, 0, 1, 244, // load $0 #500
+            0, 2, 0, 8, // load $2 #8
+            19, 0, 0, 0, // dec $0
+            10, 0, 1, 0, // neq $0 $1
+            15, 2, 0, 0, // jeq $2
+            5, 0, 0, 0, // hlt
+        ];
+        let mut byte_vm = VM::new();
+        byte_vm.program = program.clone();
+        byte_vm.run();
+        let mut decoded_vm = VM::new();
+        decoded_vm.program = program.clone();
+        decoded_vm.run_decoded();
+        assert_same_state(&byte_vm, &decoded_vm, &program);
+        assert_eq!(decoded_vm.registers[0], 0);
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn test_jit_matches_byte_interpreter() {
+        let mut rng = Lcg(0x2f);
+        for _ in 0..500 {
+            let program = random_program(&mut rng);
+            let mut byte_vm = VM::new();
+            byte_vm.program = program.clone();
+            byte_vm.registers[1] = 3;
+            byte_vm.run();
+            let mut jit_vm = VM::new();
+            jit_vm.program = program.clone();
+            jit_vm.registers[1] = 3;
+            jit_vm.jit_threshold = 1;
+            jit_vm.run_jit();
+            assert_same_state(&byte_vm, &jit_vm, &program);
+        }
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn test_jit_compiles_hot_loop() {
+        let mut byte_vm = VM::new();
+        byte_vm.program = counting_loop();
+        byte_vm.run();
+        let mut jit_vm = VM::new();
+        jit_vm.program = counting_loop();
+        jit_vm.jit_threshold = 10;
+        jit_vm.run_jit();
+        assert_same_state(&byte_vm, &jit_vm, &counting_loop());
+        assert!(jit_vm.jit.as_ref().unwrap().compiled_blocks() > 0);
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn test_jit_recompiles_for_fewer_registers() {
+        // the compiled loop uses $31, which is gone once the register file
+        // shrinks, so it has to be recompiled rather than write past the end
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            0, 31, 0, 1, // load $31 #1
+            5, 0, 0, 0, // hlt
+        ];
+        test_vm.jit_threshold = 1;
+        test_vm.run_jit();
+        assert_eq!(test_vm.registers[31], 1);
+        test_vm.registers.truncate(4);
+        test_vm.pc = CODE_BASE;
+        test_vm.run_jit();
+        assert_eq!(test_vm.registers.len(), 4);
+        assert!(test_vm.fault().is_some());
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn test_jit_overflow_matches_interpreter() {
+        // the compiled inc overflows on its last iteration; the interpreter
+        // takes over so it still wraps or faults as the config says
+        for mode in [ArithmeticMode::Wrapping, ArithmeticMode::Checked] {
+            let run = |jit: bool| {
+                let config = VmConfig::builder().arithmetic(mode).build().unwrap();
+                let mut test_vm = VM::with_config(config);
+                test_vm.program = vec![
+                    0, 2, 0, 4, // load $2 #4
+                    18, 0, 0, 0, // inc $0
+                    10, 0, 1, 0, // neq $0 $1
+                    15, 2, 0, 0, // jeq $2
+                ];
+                test_vm.registers[0] = i32::MAX - 20;
+                test_vm.registers[1] = i32::MIN + 5;
+                test_vm.jit_threshold = 2;
+                if jit {
+                    test_vm.run_jit();
+                } else {
+                    test_vm.run();
+                }
+                test_vm
+            };
+            assert_same_state(&run(false), &run(true), &[]);
+        }
+    }
+
+    #[test]
+    fn test_with_config() {
+        let (sink, output) = OutputSink::buffer();
+        let config = VmConfig::builder()
+            .register_count(4)
+            .stack_size(16)
+            .output(sink)
+            .build()
+            .unwrap();
+        let mut test_vm = VM::with_config(config);
+        assert_eq!(test_vm.registers.len(), 4);
+        assert_eq!(test_vm.memory_map().segment(SegmentKind::Stack).len, 16);
+        test_vm.program = vec![5, 0, 0, 0];
+        test_vm.run();
+        assert_eq!(output.lock().unwrap().as_slice(), b"HLT encountered\n");
+    }
+
+    #[test]
+    fn test_aloc_over_limit_faults() {
+        let config = VmConfig::builder().max_heap_bytes(1024).build().unwrap();
+        let mut test_vm = VM::with_config(config);
+        test_vm.registers[0] = 1000;
+        test_vm.registers[1] = i32::MAX;
+        test_vm.registers[2] = -2000;
+        test_vm.program = vec![17, 0, 0, 0, 17, 1, 0, 0];
+        test_vm.run();
+        assert_eq!(
+            test_vm.fault(),
+            Some(Fault::new(
+                4,
+                FaultKind::OutOfMemory {
+                    requested: 1000 + i32::MAX as i64,
+                    limit: 1024
+                }
+            ))
+        );
+        assert_eq!(test_vm.heap.len(), 1000);
+        // shrinking below zero is refused too
+        test_vm.clear_fault();
+        test_vm.program = vec![17, 2, 0, 0];
+        test_vm.pc = 0;
+        test_vm.run();
+        assert_eq!(
+            test_vm.fault(),
+            Some(Fault::new(
+                0,
+                FaultKind::OutOfMemory {
+                    requested: -1000,
+                    limit: 1024
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_modes() {
+        // mul $0 $0 $1
+        let program = vec![3, 0, 0, 1];
+        let mut wrapping = VM::new();
+        wrapping.registers[0] = 1 << 16;
+        wrapping.program = program.clone();
+        wrapping.run();
+        assert_eq!(wrapping.registers[1], 0);
+        assert_eq!(wrapping.fault(), None);
+
+        let config = VmConfig::builder()
+            .arithmetic(ArithmeticMode::Checked)
+            .build()
+            .unwrap();
+        let mut checked = VM::with_config(config);
+        checked.registers[0] = 1 << 16;
+        checked.program = program;
+        checked.run();
+        assert_eq!(checked.registers[1], 0);
+        assert_eq!(
+            checked.fault(),
+            Some(Fault::new(0, FaultKind::ArithmeticOverflow))
+        );
+    }
+
+    #[test]
+    fn test_division_by_zero_faults() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 7;
+        test_vm.program = vec![4, 0, 1, 2];
+        test_vm.run();
+        assert_eq!(
+            test_vm.fault(),
+            Some(Fault::new(0, FaultKind::DivisionByZero))
+        );
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_invalid_register_faults() {
+        let config = VmConfig::builder().register_count(8).build().unwrap();
+        // inc $0, then add $0 $8 $1
+        let program = vec![18, 0, 0, 0, 1, 0, 8, 1];
+        let mut byte_vm = VM::with_config(config.clone());
+        byte_vm.program = program.clone();
+        byte_vm.run();
+        assert_eq!(
+            byte_vm.fault(),
+            Some(Fault::new(4, FaultKind::InvalidRegister { register: 8 }))
+        );
+        let mut decoded_vm = VM::with_config(config);
+        decoded_vm.program = program.clone();
+        decoded_vm.run_decoded();
+        assert_same_state(&byte_vm, &decoded_vm, &program);
+    }
+
+    #[test]
+    fn test_verify_program() {
+        let mut test_vm = VM::new();
+        test_vm.program = counting_loop();
+        assert!(!test_vm.is_verified());
+        assert_eq!(test_vm.verify(), Ok(()));
+        assert!(test_vm.is_verified());
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], 1000);
+        // any change to the program needs verifying again
+        test_vm.program.extend([1, 0, 40, 0]);
+        assert!(!test_vm.is_verified());
+        assert_eq!(
+            test_vm.verify(),
+            Err(vec![Diagnostic::new(
+                28,
+                crate::verifier::DiagnosticKind::InvalidRegister { register: 40 }
+            )])
+        );
+        assert!(!test_vm.is_verified());
+
+        // single steps check the instruction they run whether or not the
+        // program is verified
+        test_vm.program = counting_loop();
+        test_vm.pc = CODE_BASE;
+        assert_eq!(test_vm.verify(), Ok(()));
+        test_vm.program[1] = 40;
+        test_vm.execute_instruction();
+        assert_eq!(
+            test_vm.fault(),
+            Some(Fault::new(0, FaultKind::InvalidRegister { register: 40 }))
+        );
+    }
+
+    #[test]
+    fn test_load_executable() {
+        // starts at the inc, skipping the load
+        let mut executable = Executable::new(
+            vec![0, 0, 0, 100, 18, 0, 0, 0, 5, 0, 0, 0],
+            vec![9, 9, 9, 9],
+            4,
+        );
+        executable.symbols.push(ExecutableSymbol {
+            name: "main".to_string(),
+            address: 4,
+        });
+        let mut test_vm = VM::new();
+        test_vm.load_executable(&executable.to_bytes()).unwrap();
+        assert_eq!(test_vm.pc, 4);
+        assert_eq!(test_vm.symbols(), &executable.symbols[..]);
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], 1);
+        assert_eq!(test_vm.read_memory(RODATA_BASE, 4), Ok(&[9, 9, 9, 9][..]));
+
+        let mut bytes = executable.to_bytes();
+        bytes[0] = b'X';
+        assert_eq!(test_vm.load_executable(&bytes), Err(LoadError::BadMagic));
+    }
+
+    #[test]
+    fn test_load_program_limits() {
+        let mut test_vm = VM::new();
+        let code = [5, 0, 0, 0].repeat((CODE_LIMIT - CODE_BASE) / 4);
+        assert_eq!(test_vm.load_program(code.clone(), vec![]), Ok(()));
+        // code past the code segment could never run, as it is not mapped
+        let mut too_long = code;
+        too_long.extend([5, 0, 0, 0]);
+        assert_eq!(
+            test_vm.load_program(too_long, vec![]),
+            Err(LoadError::SectionTooLarge(SectionKind::Code))
+        );
+        assert_eq!(
+            test_vm.load_program(vec![], vec![0; RODATA_LIMIT - RODATA_BASE + 1]),
+            Err(LoadError::SectionTooLarge(SectionKind::ReadOnlyData))
+        );
+        assert_eq!(test_vm.program.len(), CODE_LIMIT - CODE_BASE);
+    }
+
+    #[test]
+    fn test_profiling() {
+        let mut byte_vm = VM::new();
+        byte_vm.program = counting_loop();
+        byte_vm.enable_profiling();
+        byte_vm.run();
+        let profile = byte_vm.take_profile().unwrap();
+        assert_eq!(profile.total(), 3 + 3 * 1000 + 1);
+        assert_eq!(profile.opcode_count(Opcode::INC as u8), 1000);
+        assert_eq!(profile.pc_count(12), 1000);
+        assert_eq!(profile.pc_count(24), 1);
+        assert!(byte_vm.profile().is_none());
+
+        // superinstructions are not used while profiling, so the counts agree
+        let mut decoded_vm = VM::new();
+        decoded_vm.program = counting_loop();
+        decoded_vm.enable_profiling();
+        decoded_vm.run_decoded();
+        assert_eq!(decoded_vm.registers, byte_vm.registers);
+        let decoded = decoded_vm.profile().unwrap();
+        assert_eq!(
+            decoded.pc_counts().collect::<Vec<_>>(),
+            profile.pc_counts().collect::<Vec<_>>()
+        );
+    }
+
+    // a writer the test can still read from after handing it to a tracer
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuffer {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(program: Vec<u8>, format: TraceFormat, filter: TraceFilter) -> Vec<TraceRecord> {
+        let buffer = SharedBuffer::default();
+        let mut test_vm = VM::new();
+        test_vm.program = program;
+        test_vm.registers[0] = HEAP_BASE as i32;
+        test_vm.registers[1] = 4;
+        test_vm.start_trace(Tracer::new(Box::new(buffer.clone()), format).with_filter(filter));
+        test_vm.run_decoded();
+        test_vm.stop_trace().unwrap().finish().unwrap();
+        let bytes = buffer.0.lock().unwrap().clone();
+        read_trace(&bytes).unwrap()
+    }
+
+    #[test]
+    fn test_trace() {
+        // aloc $1, setm $0 $1, inc $1, eq $1 $1, hlt
+        let program = vec![
+            17, 1, 0, 0, 21, 0, 1, 0, 18, 1, 0, 0, 9, 1, 1, 0, 5, 0, 0, 0,
+        ];
+        let records = trace(
+            program.clone(),
+            TraceFormat::JsonLines,
+            TraceFilter::default(),
+        );
+        assert_eq!(records.len(), 5);
+        assert_eq!(records[0].heap_len, 4);
+        assert_eq!(
+            records[1].memory,
+            vec![MemoryWrite {
+                address: HEAP_BASE as u32,
+                old: vec![0, 0, 0, 0],
+                new: vec![0, 0, 0, 4],
+            }]
+        );
+        assert_eq!(
+            records[2].registers,
+            vec![RegisterChange {
+                register: 1,
+                old: 4,
+                new: 5
+            }]
+        );
+        assert!(records[3].equal_flag);
+        assert_eq!(records[4].instruction(), DecodedInstruction::Hlt);
+        assert_eq!(
+            trace(program.clone(), TraceFormat::Binary, TraceFilter::default()),
+            records
+        );
+
+        let filter = TraceFilter {
+            pcs: Some(4..16),
+            opcodes: vec![Opcode::INC, Opcode::EQ],
+        };
+        let filtered = trace(program.clone(), TraceFormat::Binary, filter);
+        assert_eq!(filtered, records[2..4].to_vec());
+
+        // inc $0 instead of inc $1
+        let mut changed = program;
+        changed[9] = 0;
+        let divergence = first_divergence(
+            &records,
+            &trace(changed, TraceFormat::JsonLines, TraceFilter::default()),
+        )
+        .unwrap();
+        assert_eq!(divergence.index, 2);
+    }
+
+    #[test]
+    fn test_source_locations() {
+        let mut asm = crate::assembler::Assembler::new().with_source_name("jump.iasm");
+        let bytes = asm.assemble_executable("load $0 #2\n  jmp $0\n").unwrap();
+        let mut test_vm = VM::new();
+        test_vm.load_executable(&bytes).unwrap();
+        assert_eq!(test_vm.location(4).to_string(), "jump.iasm:2:3");
+
+        let buffer = SharedBuffer::default();
+        test_vm.start_trace(Tracer::new(
+            Box::new(buffer.clone()),
+            TraceFormat::JsonLines,
+        ));
+        test_vm.run();
+        let fault = test_vm.fault().unwrap();
+        assert_eq!(
+            test_vm.describe_fault(&fault),
+            "fault at jump.iasm:2:3: jump to 0x0002 is not on an instruction boundary"
+        );
+        test_vm.stop_trace().unwrap().finish().unwrap();
+        let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(text.contains(r#""location":"jump.iasm:1:1""#));
+        assert_eq!(read_trace(text.as_bytes()).unwrap().len(), 2);
+    }
 }